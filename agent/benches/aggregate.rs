@@ -0,0 +1,95 @@
+//! Capture pipeline throughput over canned batches: frames are parsed, aggregated into flows
+//! and handed to a sink that only counts them. `--max-flows` is set so every frame takes
+//! the path that decides whether it starts a new flow.
+//!
+//! Run with `cargo bench --bench aggregate`.
+
+use std::net::Ipv4Addr;
+
+use clap::Parser;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use mikaboshi_agent::packet::Packet;
+use mikaboshi_agent::{Agent, Args, FlowSink, SliceSource};
+
+/// Frames per batch, a busy link's worth for one default --batch-interval.
+const FRAMES: u32 = 100_000;
+
+/// Counts what reaches it, so the pipeline has somewhere to send its flows.
+#[derive(Default)]
+struct CountingSink {
+    flows: usize,
+}
+
+#[mikaboshi_agent::async_trait]
+impl FlowSink for CountingSink {
+    fn name(&self) -> &str {
+        "counting"
+    }
+
+    async fn send(&mut self, flows: &[Packet]) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        self.flows += flows.len();
+        Ok(flows.len())
+    }
+}
+
+/// An Ethernet/IPv4/TCP frame of flow number `flow`, sent from 192.0.2.10 to port 443.
+fn frame(flow: u32) -> Vec<u8> {
+    let dst = Ipv4Addr::from(0x0a00_0000 + flow / 16);
+    let src_port = 30000 + (flow % 16) as u16;
+    let mut frame = vec![0x02, 0, 0, 0, 0, 0x07, 0x02, 0, 0, 0, 0, 0x10, 0x08, 0x00];
+    frame.extend([0x45, 0, 0, 40, 0, 0, 0x40, 0, 64, 6, 0, 0, 192, 0, 2, 10]);
+    frame.extend(dst.octets());
+    frame.extend(src_port.to_be_bytes());
+    frame.extend(443u16.to_be_bytes());
+    frame.extend([0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x10, 0xff, 0xff, 0, 0, 0, 0]);
+    frame
+}
+
+/// `FRAMES` frames spread round-robin over `flows` flows.
+fn batch(flows: u32) -> Vec<Vec<u8>> {
+    (0..FRAMES).map(|i| frame(i % flows)).collect()
+}
+
+fn agent(max_flows: u32) -> Agent {
+    let max_flows = max_flows.to_string();
+    let args = Args::try_parse_from([
+        "mikaboshi-agent",
+        "--no-local-filter",
+        "--include-loopback=false",
+        "--batch-size",
+        "1000000",
+        // One batch per run, flushed when the frames run out
+        "--batch-interval",
+        "3600000",
+        "--max-flows",
+        max_flows.as_str(),
+    ])
+    .unwrap();
+    Agent::new(args).unwrap()
+}
+
+fn aggregate(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    // Many packets per flow; every frame a new flow (a port scan); and a scan past --max-flows
+    let cases = [
+        ("steady_1k_flows", 1_000, FRAMES),
+        ("scan_100k_flows", FRAMES, FRAMES),
+        ("scan_capped_10k", FRAMES, 10_000),
+    ];
+    let mut group = c.benchmark_group("aggregate");
+    group.throughput(Throughput::Elements(FRAMES.into()));
+    for (name, flows, max_flows) in cases {
+        let frames = batch(flows);
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || (agent(max_flows), SliceSource::new(pcap::Linktype(1), frames.clone())),
+                |(agent, source)| runtime.block_on(agent.run_source(source, CountingSink::default())).unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, aggregate);
+criterion_main!(benches);
@@ -8,5 +8,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("cargo:rerun-if-changed={}", proto_file);
     tonic_build::compile_protos(proto_file)?;
+    embed_build_info();
     Ok(())
 }
+
+include!("../build-support/build_info.rs");
@@ -0,0 +1,113 @@
+//! Prefix-preserving IP pseudonymization (`--anonymize`), after Crypto-PAn.
+//!
+//! Two addresses sharing an n-bit prefix map to two addresses sharing an n-bit prefix, so
+//! subnets stay subnets in the topology view. Each output bit is the input bit flipped by
+//! the top bit of AES over the preceding input bits, which makes the mapping deterministic
+//! for a given key: agents configured with the same `--anon-key` agree on every address.
+//!
+//! MAC addresses (ARP entries, `--aggregate mac-flow`) have no prefixes worth keeping and
+//! are simply encrypted, so the same MAC still maps to the same pseudonym.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use aes::Aes128;
+
+/// Cached mappings are dropped wholesale past this many, to bound memory on busy links.
+const MAX_CACHED: usize = 65536;
+
+/// Parse a 32-byte key given as 64 hex digits.
+pub fn parse_key(hex: &str) -> Result<[u8; 32], String> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err("--anon-key must be 64 hex digits (32 bytes)".to_string());
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| "--anon-key must be 64 hex digits (32 bytes)".to_string())?;
+    }
+    Ok(key)
+}
+
+pub struct Anonymizer {
+    cipher: Aes128,
+    /// Fills the bits after the prefix being encrypted: the second key half under AES
+    pad: u128,
+    preserve_private: bool,
+    cache: HashMap<IpAddr, IpAddr>,
+}
+
+impl Anonymizer {
+    pub fn new(key: [u8; 32], preserve_private: bool) -> Self {
+        let cipher = Aes128::new(GenericArray::from_slice(&key[..16]));
+        let mut pad = GenericArray::clone_from_slice(&key[16..]);
+        cipher.encrypt_block(&mut pad);
+        Self {
+            cipher,
+            pad: u128::from_be_bytes(pad.into()),
+            preserve_private,
+            cache: HashMap::new(),
+        }
+    }
+
+    pub fn anonymize(&mut self, ip: IpAddr) -> IpAddr {
+        if self.preserve_private && is_private(ip) {
+            return ip;
+        }
+        if let Some(mapped) = self.cache.get(&ip) {
+            return *mapped;
+        }
+        // Addresses are left-aligned in the 128-bit block, so IPv4 uses the top 32 bits
+        let mapped = match ip {
+            IpAddr::V4(v4) => {
+                let bits = (u32::from(v4) as u128) << 96;
+                IpAddr::V4(Ipv4Addr::from((self.permute(bits, 32) >> 96) as u32))
+            }
+            IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(self.permute(u128::from(v6), 128))),
+        };
+        if self.cache.len() >= MAX_CACHED {
+            self.cache.clear();
+        }
+        self.cache.insert(ip, mapped);
+        mapped
+    }
+
+    /// A pseudonym for `mac`: AES of the address under the same key, marked as a unicast,
+    /// locally administered address so it isn't taken for a vendor's. The all-zero and
+    /// broadcast addresses name no host and are kept.
+    pub fn anonymize_mac(&self, mac: [u8; 6]) -> [u8; 6] {
+        if mac == [0; 6] || mac == [0xff; 6] {
+            return mac;
+        }
+        let mut input = self.pad.to_be_bytes();
+        input[..6].copy_from_slice(&mac);
+        let mut block = GenericArray::from(input);
+        self.cipher.encrypt_block(&mut block);
+        let mut mapped = [0u8; 6];
+        mapped.copy_from_slice(&block[..6]);
+        mapped[0] = (mapped[0] & 0xfc) | 0x02;
+        mapped
+    }
+
+    /// Flip each of the top `len` bits of `original` by a bit derived from the bits above it.
+    fn permute(&self, original: u128, len: u32) -> u128 {
+        let mut flips: u128 = 0;
+        for i in 0..len {
+            let prefix_mask = if i == 0 { 0 } else { u128::MAX << (128 - i) };
+            let input = (original & prefix_mask) | (self.pad & !prefix_mask);
+            let mut block = GenericArray::from(input.to_be_bytes());
+            self.cipher.encrypt_block(&mut block);
+            flips |= ((block[0] >> 7) as u128) << (127 - i);
+        }
+        original ^ flips
+    }
+}
+
+/// RFC 1918 and unique local (fc00::/7) addresses, kept as-is with `--anon-preserve-private`.
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_private(),
+        IpAddr::V6(v6) => v6.segments()[0] & 0xfe00 == 0xfc00,
+    }
+}
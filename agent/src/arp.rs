@@ -0,0 +1,39 @@
+//! ARP parsing for `--track-arp`.
+//!
+//! Only Ethernet/IPv4 ARP is understood; anything else yields `None`.
+
+use std::net::Ipv4Addr;
+
+pub const ETHER_TYPE_ARP: u16 = 0x0806;
+
+const HARDWARE_ETHERNET: u16 = 1;
+const PROTOCOL_IPV4: u16 = 0x0800;
+/// htype, ptype, hlen, plen, oper, then two (MAC, IPv4) pairs
+const MESSAGE_LEN: usize = 8 + 2 * (6 + 4);
+
+/// The IP-to-MAC bindings announced by one ARP message.
+pub struct ArpMessage {
+    pub sender_mac: [u8; 6],
+    pub sender_ip: Ipv4Addr,
+    /// All zeros in requests, where the target MAC is what is being asked for
+    pub target_mac: [u8; 6],
+    pub target_ip: Ipv4Addr,
+}
+
+pub fn parse(payload: &[u8]) -> Option<ArpMessage> {
+    let message = payload.get(..MESSAGE_LEN)?;
+    let htype = u16::from_be_bytes([message[0], message[1]]);
+    let ptype = u16::from_be_bytes([message[2], message[3]]);
+    if htype != HARDWARE_ETHERNET || ptype != PROTOCOL_IPV4 || message[4] != 6 || message[5] != 4 {
+        return None;
+    }
+
+    let mac = |at: usize| -> [u8; 6] { message[at..at + 6].try_into().unwrap() };
+    let ip = |at: usize| Ipv4Addr::new(message[at], message[at + 1], message[at + 2], message[at + 3]);
+    Some(ArpMessage {
+        sender_mac: mac(8),
+        sender_ip: ip(14),
+        target_mac: mac(18),
+        target_ip: ip(24),
+    })
+}
@@ -0,0 +1,153 @@
+//! Synthetic load for capacity planning (`--benchmark <pps>`).
+//!
+//! Unlike `--mock`, which sleeps a random few milliseconds between packets, the generator
+//! is paced against the wall clock to hold a target rate, and what was actually achieved
+//! is printed when the run ends (after `--duration`, 30 seconds by default).
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::{Rng, SeedableRng};
+use tokio::sync::mpsc;
+
+use crate::packet::{self, Packet};
+use crate::{packet_from_key, Args, FlowKey, FlowStats};
+
+/// Run length when `--duration` isn't given.
+pub const DEFAULT_DURATION_SECS: u64 = 30;
+
+/// How often the generator catches up with the target rate.
+const TICK: Duration = Duration::from_millis(10);
+/// Remote hosts the synthetic flows are spread over.
+const PEERS: u16 = 256;
+/// Client ports per peer, so flows repeat and aggregate like real traffic.
+const CLIENT_PORTS: u16 = 64;
+const SERVER_PORTS: [i32; 4] = [22, 53, 80, 443];
+
+static GENERATED: AtomicU64 = AtomicU64::new(0);
+static SENT_BATCHES: AtomicU64 = AtomicU64::new(0);
+static SENT_FLOWS: AtomicU64 = AtomicU64::new(0);
+static BACKPRESSURE_DROPPED: AtomicU64 = AtomicU64::new(0);
+static STREAM_ERRORS: AtomicU64 = AtomicU64::new(0);
+static ELAPSED_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Count a stream that ended in an error during the run.
+pub fn record_stream_error() {
+    STREAM_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Generate `pps` packets per second into `tx` until `stop` is set or the channel closes.
+pub async fn generate(tx: mpsc::Sender<Vec<Packet>>, args: &Args, pps: u64, stop: Arc<AtomicBool>) {
+    let agent = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let mut rng = match args.mock_seed {
+        Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+        None => rand::rngs::StdRng::from_entropy(),
+    };
+
+    let mut buffer: HashMap<FlowKey, FlowStats> = HashMap::with_capacity(args.batch_size);
+    let flush_interval = Duration::from_millis(args.batch_interval);
+    let mut ticker = tokio::time::interval(TICK);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let started = Instant::now();
+    let mut last_flush = started;
+    let mut generated: u64 = 0;
+
+    while !stop.load(Ordering::SeqCst) && !tx.is_closed() {
+        ticker.tick().await;
+        let due = (started.elapsed().as_secs_f64() * pps as f64) as u64;
+        // At most a second's worth per tick, so a generator that can't keep up still yields
+        let batch_end = due.min(generated + pps);
+        while generated < batch_end {
+            let peer_index: u16 = rng.gen_range(0..PEERS);
+            let peer = IpAddr::V4(Ipv4Addr::new(10, 99, (peer_index >> 8) as u8, peer_index as u8));
+            let client_port = 49152 + i32::from(rng.gen_range(0..CLIENT_PORTS));
+            let server_port = SERVER_PORTS[rng.gen_range(0..SERVER_PORTS.len())];
+            let proto = if server_port == 53 { packet::Protocol::Udp } else { packet::Protocol::Tcp };
+            // Requests from the agent and the responses to them
+            let (src_ip, dst_ip, src_port, dst_port) = if rng.gen_bool(0.5) {
+                (agent, peer, client_port, server_port)
+            } else {
+                (peer, agent, server_port, client_port)
+            };
+            let key = FlowKey {
+                src_ip,
+                dst_ip,
+                src_is_agent: src_ip == agent,
+                dst_is_agent: dst_ip == agent,
+                proto: proto.into(),
+                src_port,
+                dst_port,
+                icmp_type: 0,
+                icmp_code: 0,
+                src_mac: None,
+                dst_mac: None,
+                vlan: None,
+            };
+            let stats = buffer.entry(key).or_default();
+            stats.size += rng.gen_range(64..1500);
+            stats.packets += 1;
+            generated += 1;
+
+            if buffer.len() >= args.batch_size && !send(&mut buffer, &tx) {
+                break;
+            }
+        }
+        if last_flush.elapsed() >= flush_interval {
+            if !send(&mut buffer, &tx) {
+                break;
+            }
+            last_flush = Instant::now();
+        }
+    }
+
+    // The last partial batch is waited for rather than dropped
+    let packets: Vec<Packet> = buffer.drain().map(|(key, stats)| packet_from_key(key, stats)).collect();
+    if !packets.is_empty() {
+        let flows = packets.len() as u64;
+        if tx.send(packets).await.is_ok() {
+            SENT_BATCHES.fetch_add(1, Ordering::Relaxed);
+            SENT_FLOWS.fetch_add(flows, Ordering::Relaxed);
+        }
+    }
+    GENERATED.store(generated, Ordering::Relaxed);
+    ELAPSED_MS.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Hand the buffered flows to the sender without waiting; a full queue drops the batch and
+/// counts it. Returns false once the channel is closed.
+fn send(buffer: &mut HashMap<FlowKey, FlowStats>, tx: &mpsc::Sender<Vec<Packet>>) -> bool {
+    use tokio::sync::mpsc::error::TrySendError;
+
+    if buffer.is_empty() {
+        return true;
+    }
+    let packets: Vec<Packet> = buffer.drain().map(|(key, stats)| packet_from_key(key, stats)).collect();
+    let flows = packets.len() as u64;
+    match tx.try_send(packets) {
+        Ok(()) => {
+            SENT_BATCHES.fetch_add(1, Ordering::Relaxed);
+            SENT_FLOWS.fetch_add(flows, Ordering::Relaxed);
+            true
+        }
+        Err(TrySendError::Full(_)) => {
+            BACKPRESSURE_DROPPED.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
+/// Print what the run achieved against its target rate.
+pub fn report(pps: u64) {
+    let generated = GENERATED.load(Ordering::Relaxed);
+    let elapsed = Duration::from_millis(ELAPSED_MS.load(Ordering::Relaxed));
+    let achieved = if elapsed.is_zero() { 0.0 } else { generated as f64 / elapsed.as_secs_f64() };
+    println!("Benchmark: {:.1}s at a target of {} pps", elapsed.as_secs_f64(), pps);
+    println!("  Packets generated: {} ({:.0} pps, {:.1}% of target)", generated, achieved, achieved * 100.0 / pps.max(1) as f64);
+    println!("  Batches queued for sending: {} ({} flows)", SENT_BATCHES.load(Ordering::Relaxed), SENT_FLOWS.load(Ordering::Relaxed));
+    println!("  Batches dropped on a full send queue: {}", BACKPRESSURE_DROPPED.load(Ordering::Relaxed));
+    println!("  Stream errors: {}", STREAM_ERRORS.load(Ordering::Relaxed));
+}
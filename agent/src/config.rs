@@ -0,0 +1,172 @@
+//! TOML config file support (`--config`).
+//!
+//! Keys mirror the `Args` fields. A value from the file only applies when the option
+//! wasn't given on the command line or through its environment variable, giving the
+//! precedence CLI flag > env var > config file > default.
+
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+
+use crate::{AggregateMode, Args, Direction, GroupField, LogFormat, MockScenario, VlanSelector};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    log_format: Option<LogFormat>,
+    server: Option<String>,
+    agent_id: Option<String>,
+    site_label: Option<String>,
+    device: Option<String>,
+    include_loopback: Option<bool>,
+    no_local_filter: Option<bool>,
+    transit: Option<bool>,
+    direction: Option<Direction>,
+    vlan: Option<Vec<VlanSelector>>,
+    anonymize: Option<bool>,
+    anon_key: Option<String>,
+    anon_preserve_private: Option<bool>,
+    local_ip: Option<Vec<std::net::IpAddr>>,
+    local_cidr: Option<Vec<ipnet::IpNet>>,
+    allow_cidr: Option<Vec<ipnet::IpNet>>,
+    deny_cidr: Option<Vec<ipnet::IpNet>>,
+    exclude_endpoint: Option<Vec<String>>,
+    snapshot: Option<i32>,
+    promiscuous: Option<bool>,
+    immediate: Option<bool>,
+    nano_timestamps: Option<bool>,
+    pcap_timeout_ms: Option<i32>,
+    buffer_size: Option<i32>,
+    datalink: Option<String>,
+    highlight_filter: Option<String>,
+    mock: Option<bool>,
+    no_mock_fallback: Option<bool>,
+    mock_seed: Option<u64>,
+    mock_count: Option<u64>,
+    benchmark: Option<u64>,
+    mock_scenario: Option<MockScenario>,
+    mock_trace: Option<String>,
+    mock_trace_speed: Option<f64>,
+    ipv6: Option<bool>,
+    batch_size: Option<usize>,
+    batch_interval: Option<u64>,
+    align_flush: Option<bool>,
+    send_queue_depth: Option<usize>,
+    max_message_size: Option<usize>,
+    batch_bytes: Option<u64>,
+    max_pps: Option<u64>,
+    top_flows: Option<usize>,
+    max_flows: Option<usize>,
+    duration: Option<u64>,
+    heartbeat_interval: Option<u64>,
+    compress: Option<bool>,
+    aggregate: Option<AggregateMode>,
+    group_by: Option<Vec<GroupField>>,
+    aggregation_stats: Option<bool>,
+    reassemble: Option<bool>,
+    parse_sni: Option<bool>,
+    parse_dns: Option<bool>,
+    tcp_detail: Option<bool>,
+    decap_gre: Option<bool>,
+    track_arp: Option<bool>,
+    dedup_ms: Option<u64>,
+    sketch: Option<bool>,
+    label_services: Option<bool>,
+    flag_anomalies: Option<bool>,
+    services_file: Option<String>,
+    otlp_endpoint: Option<String>,
+    netflow_collector: Option<String>,
+    output_tsv: Option<String>,
+    tee: Option<bool>,
+    metrics_addr: Option<std::net::SocketAddr>,
+}
+
+pub fn load(path: &str) -> Result<FileConfig, Box<dyn std::error::Error>> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read config file {}: {}", path, e))?;
+    let config = toml::from_str(&text).map_err(|e| format!("Failed to parse config file {}: {}", path, e))?;
+    Ok(config)
+}
+
+/// True when the option came from the command line or its environment variable.
+fn set_explicitly(matches: &ArgMatches, id: &str) -> bool {
+    matches!(
+        matches.value_source(id),
+        Some(ValueSource::CommandLine) | Some(ValueSource::EnvVariable)
+    )
+}
+
+impl FileConfig {
+    /// Fill in every option not set explicitly with its value from the file.
+    pub fn apply(self, args: &mut Args, matches: &ArgMatches) {
+        macro_rules! merge {
+            ($($field:ident),* $(,)?) => {
+                $(
+                    if let Some(value) = self.$field {
+                        if !set_explicitly(matches, stringify!($field)) {
+                            args.$field = value;
+                        }
+                    }
+                )*
+            };
+        }
+        macro_rules! merge_optional {
+            ($($field:ident),* $(,)?) => {
+                $(
+                    if let Some(value) = self.$field {
+                        if !set_explicitly(matches, stringify!($field)) {
+                            args.$field = Some(value);
+                        }
+                    }
+                )*
+            };
+        }
+
+        merge!(
+            log_format,
+            server,
+            device,
+            no_local_filter,
+            transit,
+            direction,
+            vlan,
+            anonymize,
+            anon_preserve_private,
+            local_ip,
+            local_cidr,
+            allow_cidr,
+            deny_cidr,
+            exclude_endpoint,
+            snapshot,
+            promiscuous,
+            immediate,
+            nano_timestamps,
+            pcap_timeout_ms,
+            mock,
+            no_mock_fallback,
+            mock_scenario,
+            mock_trace_speed,
+            ipv6,
+            batch_size,
+            batch_interval,
+            align_flush,
+            send_queue_depth,
+            heartbeat_interval,
+            compress,
+            aggregate,
+            group_by,
+            aggregation_stats,
+            reassemble,
+            parse_sni,
+            parse_dns,
+            tcp_detail,
+            decap_gre,
+            track_arp,
+            dedup_ms,
+            sketch,
+            label_services,
+            flag_anomalies,
+            tee,
+        );
+        merge_optional!(agent_id, anon_key, site_label, services_file, buffer_size, datalink, highlight_filter, include_loopback, max_message_size, batch_bytes, mock_seed, mock_count, mock_trace, benchmark, max_pps, max_flows, top_flows, duration, otlp_endpoint, netflow_collector, output_tsv, metrics_addr);
+    }
+}
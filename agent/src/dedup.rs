@@ -0,0 +1,61 @@
+//! Duplicate frame suppression for `--dedup-ms`.
+//!
+//! Overlapping captures (`any` plus one of its member devices, or a tap that sees both
+//! sides of a bridge) can deliver the same frame twice. Frames are fingerprinted from their
+//! addresses, IP id, ports, length and the start of their payload; a fingerprint seen again
+//! within the window is a duplicate. Unlike flow aggregation, which sums, this drops the copy.
+
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// Payload bytes mixed into the fingerprint so unrelated frames of equal length differ.
+pub const PAYLOAD_PREFIX: usize = 32;
+
+pub fn fingerprint(parts: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    parts.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub struct DedupWindow {
+    window: Duration,
+    /// When each fingerprint was first seen
+    seen: HashMap<u64, Instant>,
+    last_purge: Instant,
+}
+
+impl DedupWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashMap::new(),
+            last_purge: Instant::now(),
+        }
+    }
+
+    /// Record a frame, returning true if the same fingerprint was seen less than a window ago.
+    pub fn is_duplicate(&mut self, fingerprint: u64, now: Instant) -> bool {
+        if now.duration_since(self.last_purge) >= self.window {
+            let window = self.window;
+            self.seen.retain(|_, first_seen| now.duration_since(*first_seen) < window);
+            self.last_purge = now;
+        }
+
+        match self.seen.entry(fingerprint) {
+            // Measured from the first copy, so a steady run of identical frames isn't all dropped
+            Entry::Occupied(mut entry) => {
+                if now.duration_since(*entry.get()) < self.window {
+                    return true;
+                }
+                entry.insert(now);
+                false
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                false
+            }
+        }
+    }
+}
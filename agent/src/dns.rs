@@ -0,0 +1,71 @@
+//! Minimal DNS message parsing, just enough to read the first question's name.
+//!
+//! Truncated messages, bad label lengths and compression loops all yield `None`.
+
+const HEADER_LEN: usize = 12;
+/// Longest name allowed by RFC 1035 (in presentation form, without the trailing dot).
+const MAX_NAME_LEN: usize = 253;
+/// Bound on compression pointer jumps so a pointer loop can't spin forever.
+const MAX_JUMPS: usize = 16;
+
+/// Return the name of the first question in a DNS message, e.g. `example.com`.
+pub fn parse_query_name(message: &[u8]) -> Option<String> {
+    if message.len() < HEADER_LEN {
+        return None;
+    }
+    let qdcount = u16::from_be_bytes([message[4], message[5]]);
+    if qdcount == 0 {
+        return None;
+    }
+    read_name(message, HEADER_LEN)
+}
+
+fn read_name(message: &[u8], start: usize) -> Option<String> {
+    let mut name = String::new();
+    let mut pos = start;
+    let mut jumps = 0;
+
+    loop {
+        let len = *message.get(pos)? as usize;
+        match len & 0xC0 {
+            0x00 => {
+                if len == 0 {
+                    break;
+                }
+                let label = message.get(pos + 1..pos + 1 + len)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                for &b in label {
+                    // Keep the output printable; escape anything unusual
+                    if b.is_ascii_graphic() {
+                        name.push(b as char);
+                    } else {
+                        name.push_str(&format!("\\{:03}", b));
+                    }
+                }
+                if name.len() > MAX_NAME_LEN {
+                    return None;
+                }
+                pos += 1 + len;
+            }
+            0xC0 => {
+                jumps += 1;
+                if jumps > MAX_JUMPS {
+                    return None;
+                }
+                let low = *message.get(pos + 1)? as usize;
+                pos = ((len & 0x3F) << 8) | low;
+            }
+            // 0x40 / 0x80 are reserved label types
+            _ => return None,
+        }
+    }
+
+    if name.is_empty() {
+        // Root query
+        Some(".".to_string())
+    } else {
+        Some(name)
+    }
+}
@@ -0,0 +1,69 @@
+//! Why the agent, a session with the server, or the capture feeding it ended.
+//!
+//! The reconnect loop retries most of these every few seconds, but some can't get better by
+//! waiting: a server that refuses our credentials, or a filter that doesn't compile. Those
+//! stop the agent instead, and are what [`crate::run_cli`] and an embedded
+//! [`crate::Agent`] return.
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum AgentError {
+    /// No server accepted a connection
+    Connect(Box<tonic::transport::Error>),
+    /// The server (or a proxy in front of it) refused the stream
+    Auth(Box<tonic::Status>),
+    /// The stream failed or ended while we were still sending
+    Stream(String),
+    /// The capture device couldn't be opened
+    Device(String),
+    /// The capture device couldn't be opened and mock traffic wasn't allowed in its place
+    NoCapture(String),
+    /// The capture device stopped yielding frames and should be reopened
+    CaptureLost(pcap::Error),
+    /// A setting that can't work as given, such as a BPF filter that doesn't compile
+    Invalid(String),
+    /// A local output (TSV file, IPFIX collector or OTLP exporter) couldn't be set up
+    Output(String),
+}
+
+impl AgentError {
+    /// Whether trying again later could succeed.
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, AgentError::Auth(_) | AgentError::Invalid(_) | AgentError::NoCapture(_) | AgentError::Output(_))
+    }
+
+    /// A stream that ended with `status`.
+    pub fn from_status(status: tonic::Status) -> Self {
+        match status.code() {
+            tonic::Code::Unauthenticated | tonic::Code::PermissionDenied => AgentError::Auth(Box::new(status)),
+            _ => AgentError::Stream(status.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for AgentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AgentError::Connect(e) => write!(f, "connection failed: {}", e),
+            AgentError::Auth(status) => write!(f, "server refused the agent: {}", status.message()),
+            AgentError::Stream(message) => write!(f, "stream failed: {}", message),
+            AgentError::Device(message) => write!(f, "{}", message),
+            AgentError::NoCapture(message) => write!(f, "{} (not falling back to mock traffic)", message),
+            AgentError::CaptureLost(e) => write!(f, "capture device lost: {}", e),
+            AgentError::Invalid(message) => write!(f, "{}", message),
+            AgentError::Output(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for AgentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AgentError::Connect(e) => Some(e.as_ref()),
+            AgentError::Auth(status) => Some(status.as_ref()),
+            AgentError::CaptureLost(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! IPFIX (RFC 7011) export of the aggregated flows (`--netflow-collector`).
+//!
+//! Each batch becomes one or more UDP messages of data records, one record per flow, so
+//! the agent can feed nfdump, Elastiflow and other collectors directly. Over UDP the
+//! collector may start listening at any time, so the templates are re-sent periodically.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::net::UdpSocket;
+
+use crate::packet::{Packet, Protocol};
+
+const VERSION: u16 = 10;
+const TEMPLATE_SET_ID: u16 = 2;
+const TEMPLATE_ID_IPV4: u16 = 256;
+const TEMPLATE_ID_IPV6: u16 = 257;
+
+/// How often templates are re-sent (RFC 7011 section 8.4 leaves the interval to the exporter).
+const TEMPLATE_REFRESH: Duration = Duration::from_secs(60);
+/// Largest message sent, to stay clear of fragmentation on a 1500-byte MTU.
+const MAX_MESSAGE_LEN: usize = 1400;
+const MESSAGE_HEADER_LEN: usize = 16;
+const SET_HEADER_LEN: usize = 4;
+
+/// protocolIdentifier for flows whose IP protocol number wasn't kept (IANA "Reserved").
+const PROTOCOL_UNKNOWN: u8 = 255;
+
+/// (Information Element id, length) of each field of a data record, in record order.
+fn template_fields(ipv6: bool) -> Vec<(u16, u16)> {
+    let (src, dst, icmp) = if ipv6 {
+        ((27, 16), (28, 16), (139, 2)) // sourceIPv6Address, destinationIPv6Address, icmpTypeCodeIPv6
+    } else {
+        ((8, 4), (12, 4), (32, 2)) // sourceIPv4Address, destinationIPv4Address, icmpTypeCodeIPv4
+    };
+    vec![
+        src,
+        dst,
+        (7, 2),  // sourceTransportPort
+        (11, 2), // destinationTransportPort
+        (4, 1),  // protocolIdentifier
+        icmp,
+        (61, 1),  // flowDirection
+        (1, 8),   // octetDeltaCount
+        (2, 8),   // packetDeltaCount
+        (152, 8), // flowStartMilliseconds
+        (153, 8), // flowEndMilliseconds
+    ]
+}
+
+fn record_len(ipv6: bool) -> usize {
+    template_fields(ipv6).iter().map(|&(_, len)| len as usize).sum()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+pub struct Exporter {
+    socket: UdpSocket,
+    /// Data records sent so far, modulo 2^32, as the header's sequence number requires
+    sequence: u32,
+    templates_sent: Option<Instant>,
+    /// End of the previous batch, the start of any flow in the next one that carries no
+    /// capture times
+    last_export_ms: u64,
+}
+
+impl Exporter {
+    pub async fn connect(collector: &str) -> std::io::Result<Self> {
+        let bind = if collector.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(collector).await?;
+        Ok(Self {
+            socket,
+            sequence: 0,
+            templates_sent: None,
+            last_export_ms: now_millis(),
+        })
+    }
+
+    /// Send `flows` as data records, preceded by the templates when they are due.
+    /// Returns the number of records sent; ARP entries have no IPFIX flow and are skipped.
+    pub async fn export(&mut self, flows: &[Packet]) -> std::io::Result<usize> {
+        let start_ms = self.last_export_ms;
+        let end_ms = now_millis();
+        self.last_export_ms = end_ms;
+
+        if self.templates_sent.is_none_or(|sent| sent.elapsed() >= TEMPLATE_REFRESH) {
+            let message = self.message(&self.template_set());
+            self.socket.send(&message).await?;
+            self.templates_sent = Some(Instant::now());
+        }
+
+        let mut sent = 0;
+        for ipv6 in [false, true] {
+            let records: Vec<Vec<u8>> = flows
+                .iter()
+                .filter(|p| p.is_ipv6 == ipv6)
+                .filter_map(|p| encode_record(p, start_ms, end_ms))
+                .collect();
+            let per_message = (MAX_MESSAGE_LEN - MESSAGE_HEADER_LEN - SET_HEADER_LEN) / record_len(ipv6);
+            for chunk in records.chunks(per_message) {
+                let set_id = if ipv6 { TEMPLATE_ID_IPV6 } else { TEMPLATE_ID_IPV4 };
+                let mut set = set_header(set_id, chunk.iter().map(Vec::len).sum());
+                for record in chunk {
+                    set.extend_from_slice(record);
+                }
+                let message = self.message(&set);
+                self.socket.send(&message).await?;
+                self.sequence = self.sequence.wrapping_add(chunk.len() as u32);
+                sent += chunk.len();
+            }
+        }
+        Ok(sent)
+    }
+
+    fn template_set(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (template_id, ipv6) in [(TEMPLATE_ID_IPV4, false), (TEMPLATE_ID_IPV6, true)] {
+            let fields = template_fields(ipv6);
+            body.extend_from_slice(&template_id.to_be_bytes());
+            body.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+            for (id, len) in fields {
+                body.extend_from_slice(&id.to_be_bytes());
+                body.extend_from_slice(&len.to_be_bytes());
+            }
+        }
+        let mut set = set_header(TEMPLATE_SET_ID, body.len());
+        set.extend_from_slice(&body);
+        set
+    }
+
+    fn message(&self, sets: &[u8]) -> Vec<u8> {
+        let export_time = (now_millis() / 1000) as u32;
+        let mut message = Vec::with_capacity(MESSAGE_HEADER_LEN + sets.len());
+        message.extend_from_slice(&VERSION.to_be_bytes());
+        message.extend_from_slice(&((MESSAGE_HEADER_LEN + sets.len()) as u16).to_be_bytes());
+        message.extend_from_slice(&export_time.to_be_bytes());
+        message.extend_from_slice(&self.sequence.to_be_bytes());
+        // Observation domain; collectors tell agents apart by their source address
+        message.extend_from_slice(&0u32.to_be_bytes());
+        message.extend_from_slice(sets);
+        message
+    }
+}
+
+fn set_header(set_id: u16, body_len: usize) -> Vec<u8> {
+    let mut set = Vec::with_capacity(SET_HEADER_LEN + body_len);
+    set.extend_from_slice(&set_id.to_be_bytes());
+    set.extend_from_slice(&((SET_HEADER_LEN + body_len) as u16).to_be_bytes());
+    set
+}
+
+/// One data record laid out as `template_fields(p.is_ipv6)`, or `None` for ARP entries and
+/// packets whose addresses don't match their family. The flow's start and end are its
+/// first and last capture times, or the export window `start_ms..end_ms` when it has none.
+fn encode_record(p: &Packet, start_ms: u64, end_ms: u64) -> Option<Vec<u8>> {
+    let protocol = match Protocol::try_from(p.proto) {
+        Ok(Protocol::Tcp) => 6,
+        Ok(Protocol::Udp) => 17,
+        Ok(Protocol::Icmp) if p.is_ipv6 => 58,
+        Ok(Protocol::Icmp) => 1,
+        Ok(Protocol::Arp) => return None,
+        _ => PROTOCOL_UNKNOWN,
+    };
+
+    let address_len = if p.is_ipv6 { 16 } else { 4 };
+    if p.src_ip.len() != address_len || p.dst_ip.len() != address_len {
+        return None;
+    }
+
+    let mut record = Vec::with_capacity(record_len(p.is_ipv6));
+    record.extend_from_slice(&p.src_ip);
+    record.extend_from_slice(&p.dst_ip);
+    record.extend_from_slice(&(p.src_port as u16).to_be_bytes());
+    record.extend_from_slice(&(p.dst_port as u16).to_be_bytes());
+    record.push(protocol);
+    record.extend_from_slice(&(((p.icmp_type as u16) << 8) | (p.icmp_code as u16 & 0xff)).to_be_bytes());
+    // 0 = ingress, 1 = egress, as seen from the agent
+    record.push(u8::from(p.src_is_agent));
+    record.extend_from_slice(&(p.size.max(0) as u64).to_be_bytes());
+    record.extend_from_slice(&(p.packets.max(0) as u64).to_be_bytes());
+    let (flow_start, flow_end) = if p.first_seen == 0 {
+        (start_ms, end_ms)
+    } else {
+        (p.first_seen, p.last_seen.max(p.first_seen))
+    };
+    record.extend_from_slice(&flow_start.to_be_bytes());
+    record.extend_from_slice(&flow_end.to_be_bytes());
+    Some(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// flowStartMilliseconds and flowEndMilliseconds, the last two fields of a record.
+    fn flow_times(record: &[u8]) -> (u64, u64) {
+        let end = record.len();
+        let read = |at: usize| u64::from_be_bytes(record[at..at + 8].try_into().unwrap());
+        (read(end - 16), read(end - 8))
+    }
+
+    #[test]
+    fn flow_times_come_from_the_capture() {
+        let mut packet = Packet {
+            src_ip: vec![192, 0, 2, 10],
+            dst_ip: vec![198, 51, 100, 7],
+            proto: Protocol::Tcp.into(),
+            first_seen: 1_000,
+            last_seen: 4_000,
+            ..Default::default()
+        };
+        assert_eq!(flow_times(&encode_record(&packet, 0, 9_000).unwrap()), (1_000, 4_000));
+
+        // Without capture times the export window stands in
+        packet.first_seen = 0;
+        packet.last_seen = 0;
+        assert_eq!(flow_times(&encode_record(&packet, 5_000, 9_000).unwrap()), (5_000, 9_000));
+    }
+}
@@ -0,0 +1,3401 @@
+//! The mikaboshi agent: packet capture, flow aggregation and delivery to a server.
+//!
+//! The binary is a thin wrapper around [`run_cli`]. To embed the capture and aggregation
+//! pipeline elsewhere, build an [`Agent`] from [`Args`] (e.g. with `Args::try_parse_from`)
+//! and run it into a [`FlowSink`]. Both report failures as an [`AgentError`].
+
+use clap::{CommandFactory, FromArgMatches, Parser};
+use pcap::{Capture, Device};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration};
+use tonic::transport::Channel;
+use tracing::{debug, error, info, warn};
+
+pub mod packet {
+    tonic::include_proto!("packet");
+}
+
+mod anonymize;
+mod arp;
+mod benchmark;
+mod config;
+mod dedup;
+mod dns;
+mod error;
+mod ipfix;
+mod lifecycle;
+mod linkprobe;
+mod otlp;
+mod reassembly;
+mod services;
+mod sinks;
+mod source;
+mod summary;
+mod tcp;
+mod tls;
+mod trace;
+mod tsv;
+
+use packet::agent_service_client::AgentServiceClient;
+pub use error::AgentError;
+pub use sinks::FlowSink;
+/// For implementing [`FlowSink`] without depending on async-trait directly.
+pub use tonic::async_trait;
+use packet::Packet;
+pub use source::{Frame, PacketSource, SliceSource};
+
+/// Version and commit of this build, as `--version` prints it and the server lists it on `/agents`.
+const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("MIKABOSHI_GIT_HASH"));
+
+/// Agent settings, as given on the command line, in the environment or in a config file.
+#[derive(Parser, Debug, Clone)]
+#[command(author, version = VERSION, about, long_about = None)]
+pub struct Args {
+    /// Log output format
+    #[arg(long, env = "MIKABOSHI_AGENT_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// TOML file providing defaults for any option not set on the command line or environment
+    #[arg(long, env = "MIKABOSHI_AGENT_CONFIG")]
+    config: Option<String>,
+
+    #[arg(long, env = "MIKABOSHI_AGENT_SERVER", default_value = "localhost:50051")]
+    server: String,
+
+    /// Name this agent reports to the server (defaults to its primary IP)
+    #[arg(long, env = "MIKABOSHI_AGENT_ID")]
+    agent_id: Option<String>,
+
+    /// Site or zone this agent sits in (e.g. datacenter-east); the server tags its flows with it
+    #[arg(long, env = "MIKABOSHI_AGENT_SITE_LABEL")]
+    site_label: Option<String>,
+
+    #[arg(long, env = "MIKABOSHI_AGENT_DEVICE", default_value = "any")]
+    device: String,
+
+    /// Count loopback addresses as the agent's own and keep loopback flows. Defaults to on
+    /// when --device is a loopback device and off otherwise, `any` included
+    #[arg(long, env = "MIKABOSHI_AGENT_INCLUDE_LOOPBACK", num_args = 0..=1, default_missing_value = "true")]
+    include_loopback: Option<bool>,
+
+    /// Forward all observed flows, not just those involving the agent (SPAN/mirror port monitoring)
+    #[arg(long, env = "MIKABOSHI_AGENT_NO_LOCAL_FILTER", default_value_t = false)]
+    no_local_filter: bool,
+
+    /// With --no-local-filter, still tell the agent's own flows apart and mark the rest as
+    /// transit, e.g. for a router that sees only one direction of a conversation
+    #[arg(long, env = "MIKABOSHI_AGENT_TRANSIT", default_value_t = false)]
+    transit: bool,
+
+    /// Keep only traffic leaving the agent (out), only traffic arriving at it (in), or both
+    #[arg(long, env = "MIKABOSHI_AGENT_DIRECTION", value_enum, default_value_t = Direction::Both)]
+    direction: Direction,
+
+    /// Replace every address that isn't the agent's own with a prefix-preserving pseudonym
+    /// derived from --anon-key, and its MAC address with an encrypted one (live capture only)
+    #[arg(long, env = "MIKABOSHI_AGENT_ANONYMIZE", default_value_t = false)]
+    anonymize: bool,
+
+    /// 32-byte key for --anonymize as 64 hex digits; agents sharing it map addresses alike
+    #[arg(long, env = "MIKABOSHI_AGENT_ANON_KEY")]
+    anon_key: Option<String>,
+
+    /// Leave RFC 1918 and unique local IPv6 addresses unchanged with --anonymize
+    #[arg(long, env = "MIKABOSHI_AGENT_ANON_PRESERVE_PRIVATE", default_value_t = false)]
+    anon_preserve_private: bool,
+
+    /// Keep only frames on these VLAN IDs (repeatable or comma-separated); `untagged` selects
+    /// frames without a tag. Double-tagged frames are matched on their outer tag
+    #[arg(long, env = "MIKABOSHI_AGENT_VLAN", value_delimiter = ',')]
+    vlan: Vec<VlanSelector>,
+
+    /// Extra address to treat as the agent's own (repeatable or comma-separated)
+    #[arg(long, env = "MIKABOSHI_AGENT_LOCAL_IP", value_delimiter = ',')]
+    local_ip: Vec<IpAddr>,
+
+    /// Extra CIDR range to treat as the agent's own (repeatable or comma-separated)
+    #[arg(long, env = "MIKABOSHI_AGENT_LOCAL_CIDR", value_delimiter = ',')]
+    local_cidr: Vec<ipnet::IpNet>,
+
+    /// Only capture flows with an endpoint in one of these CIDR ranges (repeatable or
+    /// comma-separated); all flows when none is given
+    #[arg(long, env = "MIKABOSHI_AGENT_ALLOW_CIDR", value_delimiter = ',')]
+    allow_cidr: Vec<ipnet::IpNet>,
+
+    /// Never capture flows with an endpoint in one of these CIDR ranges (repeatable or
+    /// comma-separated); wins over --allow-cidr
+    #[arg(long, env = "MIKABOSHI_AGENT_DENY_CIDR", value_delimiter = ',')]
+    deny_cidr: Vec<ipnet::IpNet>,
+
+    /// Keep the agent's own traffic to this endpoint out of the capture, as HOST:PORT or
+    /// HOST for all ports (repeatable or comma-separated). The OTLP endpoint is excluded
+    /// without it
+    #[arg(long, env = "MIKABOSHI_AGENT_EXCLUDE_ENDPOINT", value_delimiter = ',')]
+    exclude_endpoint: Vec<String>,
+
+    /// Bytes captured per frame; 0 picks a length from the enabled features
+    #[arg(long, env = "MIKABOSHI_AGENT_SNAPSHOT", default_value_t = 0)]
+    snapshot: i32,
+
+    #[arg(long, env = "MIKABOSHI_AGENT_PROMISCUOUS", default_value_t = false)]
+    promiscuous: bool,
+
+    /// Deliver packets as soon as they arrive instead of letting pcap buffer them
+    #[arg(long, env = "MIKABOSHI_AGENT_IMMEDIATE", default_value_t = false)]
+    immediate: bool,
+
+    /// Ask pcap for nanosecond capture timestamps; kept at microseconds, with a warning,
+    /// where the platform doesn't offer them
+    #[arg(long, env = "MIKABOSHI_AGENT_NANO_TIMESTAMPS", default_value_t = false)]
+    nano_timestamps: bool,
+
+    /// pcap read timeout (ms). The flush timer, heartbeats and shutdown are only checked when a
+    /// read returns, so lower values mean more wakeups and higher values delay flushes when idle
+    #[arg(long, env = "MIKABOSHI_AGENT_PCAP_TIMEOUT_MS", default_value_t = 100)]
+    pcap_timeout_ms: i32,
+
+    /// Kernel capture buffer size in bytes (pcap default when unset)
+    #[arg(long, env = "MIKABOSHI_AGENT_BUFFER_SIZE")]
+    buffer_size: Option<i32>,
+
+    /// Link type to capture with, by name (e.g. EN10MB, LINUX_SLL) or number, for devices
+    /// offering several; the ones available are logged at startup
+    #[arg(long, env = "MIKABOSHI_AGENT_DATALINK")]
+    datalink: Option<String>,
+
+    /// BPF expression marking the flows it matches as highlighted, without dropping the rest;
+    /// every captured frame is run through it, which costs CPU on busy links
+    #[arg(long, env = "MIKABOSHI_AGENT_HIGHLIGHT_FILTER")]
+    highlight_filter: Option<String>,
+
+    #[arg(long, env = "MIKABOSHI_AGENT_MOCK", default_value_t = false)]
+    mock: bool,
+
+    /// Exit instead of switching to mock traffic when the live capture can't be opened
+    #[arg(long, env = "MIKABOSHI_AGENT_NO_MOCK_FALLBACK", default_value_t = false)]
+    no_mock_fallback: bool,
+
+    /// Seed for the mock traffic generator; the same seed reproduces the same traffic
+    #[arg(long, env = "MIKABOSHI_AGENT_MOCK_SEED")]
+    mock_seed: Option<u64>,
+
+    /// Stop after generating this many mock packets, closing the stream and exiting
+    #[arg(long, env = "MIKABOSHI_AGENT_MOCK_COUNT")]
+    mock_count: Option<u64>,
+
+    /// Stream synthetic traffic to the server at this many packets per second for
+    /// --duration (30s by default), then print the achieved rate, dropped batches and stream errors
+    #[arg(long, env = "MIKABOSHI_AGENT_BENCHMARK")]
+    benchmark: Option<u64>,
+
+    /// Traffic pattern generated in mock mode
+    #[arg(long, env = "MIKABOSHI_AGENT_MOCK_SCENARIO", value_enum, default_value_t = MockScenario::Steady)]
+    mock_scenario: MockScenario,
+
+    /// Replay the flows of this file (as written by --output-tsv) in a loop instead of
+    /// generating random mock traffic; implies --mock
+    #[arg(long, env = "MIKABOSHI_AGENT_MOCK_TRACE")]
+    mock_trace: Option<String>,
+
+    /// Speed up (above 1) or slow down (below 1) the recorded spacing of --mock-trace
+    #[arg(long, env = "MIKABOSHI_AGENT_MOCK_TRACE_SPEED", default_value_t = 1.0)]
+    mock_trace_speed: f64,
+
+    #[arg(long, env = "MIKABOSHI_AGENT_IPV6", default_value_t = false)]
+    ipv6: bool,
+
+    #[arg(long, default_value_t = false)]
+    list_devices: bool,
+
+    /// Print the capture plan (device, BPF filter, local addresses, batching) after opening
+    /// the device and compiling the filter, then exit without capturing
+    #[arg(long, default_value_t = false)]
+    check: bool,
+
+    #[arg(long, env = "MIKABOSHI_AGENT_BATCH_SIZE", default_value_t = 50000)]
+    batch_size: usize,
+
+    #[arg(long, env = "MIKABOSHI_AGENT_BATCH_INTERVAL", default_value_t = 100)]
+    batch_interval: u64,
+
+    /// Send timed batches at wall-clock multiples of --batch-interval (e.g. on the second for
+    /// 1000) instead of counting from when the agent started, so agents' batches line up
+    #[arg(long, env = "MIKABOSHI_AGENT_ALIGN_FLUSH", default_value_t = false)]
+    align_flush: bool,
+
+    /// Also send the buffered flows once the frames behind them add up to this many bytes.
+    /// Whichever of --batch-size, --batch-interval and this is reached first sends the batch
+    #[arg(long, env = "MIKABOSHI_AGENT_BATCH_BYTES")]
+    batch_bytes: Option<u64>,
+
+    /// Batches that may wait between the capture thread and the sender; once full, batches
+    /// are dropped and counted as backpressure
+    #[arg(long, env = "MIKABOSHI_AGENT_SEND_QUEUE_DEPTH", default_value_t = 32)]
+    send_queue_depth: usize,
+
+    /// Largest gRPC message (bytes) sent to or accepted from the server. Defaults to room for
+    /// a full --batch-size batch of worst-case flows, and never less than 4 MiB
+    #[arg(long, env = "MIKABOSHI_AGENT_MAX_MESSAGE_SIZE")]
+    max_message_size: Option<usize>,
+
+    /// Hard ceiling on packets accepted per second; extra packets are dropped and counted
+    #[arg(long, env = "MIKABOSHI_AGENT_MAX_PPS")]
+    max_pps: Option<u64>,
+
+    /// Cap on flows started per batch interval; packets of further new flows are dropped
+    /// and counted, so a flood of unique flows (e.g. a port scan) can't grow the flow table
+    /// and the server's state with it
+    #[arg(long, env = "MIKABOSHI_AGENT_MAX_FLOWS")]
+    max_flows: Option<usize>,
+
+    /// Send only the K flows with the most bytes of each batch at full detail, and the
+    /// rest summed into one entry per protocol with unspecified addresses and no ports
+    #[arg(long, env = "MIKABOSHI_AGENT_TOP_FLOWS", value_name = "K")]
+    top_flows: Option<usize>,
+
+    /// Stop after capturing for this many seconds, flushing the last batch and closing the
+    /// stream as on Ctrl-C, then exit with status 0
+    #[arg(long, env = "MIKABOSHI_AGENT_DURATION")]
+    duration: Option<u64>,
+
+    /// Send an empty batch after this many ms without captured traffic so an idle agent
+    /// doesn't look disconnected (0 disables)
+    #[arg(long, env = "MIKABOSHI_AGENT_HEARTBEAT_INTERVAL", default_value_t = 0)]
+    heartbeat_interval: u64,
+
+    /// Gzip-compress packet batches sent to the server
+    #[arg(long, env = "MIKABOSHI_AGENT_COMPRESS", default_value_t = false)]
+    compress: bool,
+
+    /// Which fields identify a flow when aggregating packets into a batch
+    #[arg(long, env = "MIKABOSHI_AGENT_AGGREGATE", value_enum, default_value_t = AggregateMode::Flow)]
+    aggregate: AggregateMode,
+
+    /// Aggregate on exactly these fields instead of an --aggregate mode (comma-separated:
+    /// src_ip, dst_ip, src_port, dst_port, proto, vlan); the others are zeroed
+    #[arg(long, env = "MIKABOSHI_AGENT_GROUP_BY", value_delimiter = ',')]
+    group_by: Vec<GroupField>,
+
+    /// Reassemble IPv4 fragments so each datagram is counted once against its full flow
+    #[arg(long, env = "MIKABOSHI_AGENT_REASSEMBLE", default_value_t = false)]
+    reassemble: bool,
+
+    /// Extract the SNI host name from TLS ClientHello messages
+    #[arg(long, env = "MIKABOSHI_AGENT_PARSE_SNI", default_value_t = false)]
+    parse_sni: bool,
+
+    /// Report GRE-tunnelled traffic with the inner addresses and ports (one level deep)
+    #[arg(long, env = "MIKABOSHI_AGENT_DECAP_GRE", default_value_t = false)]
+    decap_gre: bool,
+
+    /// Record IP-to-MAC bindings from ARP frames as ARP entries (Ethernet only)
+    #[arg(long, env = "MIKABOSHI_AGENT_TRACK_ARP", default_value_t = false)]
+    track_arp: bool,
+
+    /// Drop a frame identical to one seen less than this many ms earlier, for overlapping
+    /// captures that deliver frames twice (0 disables)
+    #[arg(long, env = "MIKABOSHI_AGENT_DEDUP_MS", default_value_t = 0)]
+    dedup_ms: u64,
+
+    /// Log capture stats every minute even when nothing went wrong, to follow how many
+    /// packets each sent flow stands for
+    #[arg(long, env = "MIKABOSHI_AGENT_AGGREGATION_STATS", default_value_t = false)]
+    aggregation_stats: bool,
+
+    /// Include each flow's mean packet size in the batches it is sent in
+    #[arg(long, env = "MIKABOSHI_AGENT_SKETCH", default_value_t = false)]
+    sketch: bool,
+
+    /// Report each TCP flow's last advertised window and MSS option
+    #[arg(long, env = "MIKABOSHI_AGENT_TCP_DETAIL", default_value_t = false)]
+    tcp_detail: bool,
+
+    /// Label each flow with the service of its server-side port (e.g. 443 as https)
+    #[arg(long, env = "MIKABOSHI_AGENT_LABEL_SERVICES", default_value_t = false)]
+    label_services: bool,
+
+    /// Extra port names for --label-services in /etc/services format, overriding the built-in ones
+    #[arg(long, env = "MIKABOSHI_AGENT_SERVICES_FILE")]
+    services_file: Option<String>,
+
+    /// Mark flows whose transport doesn't fit their service port (e.g. NTP over TCP) and,
+    /// with --parse-sni, plaintext HTTP or SSH on TLS ports
+    #[arg(long, env = "MIKABOSHI_AGENT_FLAG_ANOMALIES", default_value_t = false)]
+    flag_anomalies: bool,
+
+    /// Extract the queried name from DNS messages on port 53
+    #[arg(long, env = "MIKABOSHI_AGENT_PARSE_DNS", default_value_t = false)]
+    parse_dns: bool,
+
+    /// Also export the flows sent to the server as OTLP metrics to this collector (e.g. http://collector:4317)
+    #[arg(long, env = "MIKABOSHI_AGENT_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// Serve connection lifecycle counters in Prometheus text format on this address (e.g. 127.0.0.1:9101)
+    #[arg(long, env = "MIKABOSHI_AGENT_METRICS_ADDR")]
+    metrics_addr: Option<std::net::SocketAddr>,
+
+    /// Export flows as IPFIX to this collector (host:port) instead of streaming them to the server
+    #[arg(long, env = "MIKABOSHI_AGENT_NETFLOW_COLLECTOR")]
+    netflow_collector: Option<String>,
+
+    /// Write flows as tab-separated lines (conn.log style) to this file instead of streaming
+    /// them to the server
+    #[arg(long, env = "MIKABOSHI_AGENT_OUTPUT_TSV")]
+    output_tsv: Option<String>,
+
+    /// Keep streaming to the server with --output-tsv or --netflow-collector, which then
+    /// receive a copy of every batch instead of replacing the server
+    #[arg(long, env = "MIKABOSHI_AGENT_TEE", default_value_t = false)]
+    tee: bool,
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+struct FlowKey {
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    src_is_agent: bool,
+    dst_is_agent: bool,
+    proto: i32, // store as i32 to match proto enum value
+    src_port: i32,
+    dst_port: i32,
+    // Kept in every aggregation mode so echo requests don't merge with unreachables
+    icmp_type: i32,
+    icmp_code: i32,
+    src_mac: Option<[u8; 6]>,
+    dst_mac: Option<[u8; 6]>,
+    // Outer VLAN ID; only --group-by vlan keeps it
+    vlan: Option<u16>,
+}
+
+#[derive(clap::ValueEnum, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per event, for log ingestion
+    Json,
+}
+
+/// Which side of the agent captured traffic must be on to be kept.
+#[derive(clap::ValueEnum, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Direction {
+    /// Inbound and outbound
+    Both,
+    /// Only packets addressed to the agent
+    In,
+    /// Only packets sent by the agent
+    Out,
+}
+
+impl Direction {
+    fn keeps(self, src_is_agent: bool, dst_is_agent: bool) -> bool {
+        match self {
+            Direction::Both => true,
+            Direction::In => dst_is_agent,
+            Direction::Out => src_is_agent,
+        }
+    }
+}
+
+/// One `--vlan` value: a VLAN ID, or frames that carry no tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VlanSelector {
+    Id(u16),
+    Untagged,
+}
+
+impl VlanSelector {
+    fn from_id(id: u16) -> Result<Self, String> {
+        if id > 4095 {
+            return Err(format!("VLAN ID {} is out of range (0-4095)", id));
+        }
+        Ok(VlanSelector::Id(id))
+    }
+
+    /// Whether a frame with these VLAN headers is on one of `selectors`.
+    fn selects(selectors: &[VlanSelector], vlan: &Option<etherparse::VlanHeader>) -> bool {
+        let frame = outer_vlan_id(vlan).map_or(VlanSelector::Untagged, VlanSelector::Id);
+        selectors.contains(&frame)
+    }
+}
+
+/// A frame's on-the-wire length as a flow size. pcap takes the length from the capture
+/// header, which in a crafted file can claim far more than an `i32` holds.
+fn wire_size(len: u32) -> i32 {
+    i32::try_from(len).unwrap_or(i32::MAX)
+}
+
+/// The VLAN a frame is on: its only tag, or the outer one of a double-tagged frame.
+fn outer_vlan_id(vlan: &Option<etherparse::VlanHeader>) -> Option<u16> {
+    use etherparse::VlanHeader;
+
+    match vlan {
+        Some(VlanHeader::Single(tag)) => Some(tag.vlan_identifier),
+        Some(VlanHeader::Double(tags)) => Some(tags.outer.vlan_identifier),
+        None => None,
+    }
+}
+
+impl std::str::FromStr for VlanSelector {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        if text.eq_ignore_ascii_case("untagged") {
+            return Ok(VlanSelector::Untagged);
+        }
+        let id = text.parse().map_err(|_| format!("Invalid VLAN {:?}: expected an ID or \"untagged\"", text))?;
+        VlanSelector::from_id(id)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for VlanSelector {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Config files may write IDs as numbers or strings
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Id(u16),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Id(id) => VlanSelector::from_id(id),
+            Raw::Text(text) => text.parse(),
+        }
+        .map_err(serde::de::Error::custom)
+    }
+}
+
+/// How much of the flow identity is kept when aggregating packets.
+#[derive(clap::ValueEnum, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum AggregateMode {
+    /// Full flow: addresses, ports and direction
+    Flow,
+    /// Full flow plus source and destination MAC addresses
+    MacFlow,
+    /// Full flow with both directions merged; endpoints are ordered by (address, port)
+    Bidirectional,
+    /// Addresses and direction only; ports are collapsed
+    HostPair,
+    /// Addresses only; ports and direction are collapsed
+    Host,
+}
+
+/// A field `--group-by` can keep in the aggregation key.
+#[derive(clap::ValueEnum, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum GroupField {
+    #[value(name = "src_ip")]
+    SrcIp,
+    #[value(name = "dst_ip")]
+    DstIp,
+    #[value(name = "src_port")]
+    SrcPort,
+    #[value(name = "dst_port")]
+    DstPort,
+    /// The protocol, along with the ICMP type and code
+    Proto,
+    Vlan,
+}
+
+impl FlowKey {
+    /// The key a packet is aggregated under: `--group-by` when given, else `--aggregate`.
+    fn for_aggregation(self, args: &Args) -> FlowKey {
+        if args.group_by.is_empty() {
+            self.collapse(args.aggregate)
+        } else {
+            self.group(&args.group_by)
+        }
+    }
+
+    /// Keep only the `fields` of the key, zeroing the rest. An address that isn't kept
+    /// becomes the unspecified address of its family and loses its is_agent flag.
+    fn group(mut self, fields: &[GroupField]) -> FlowKey {
+        let unspecified = |ip: IpAddr| match ip {
+            IpAddr::V4(_) => IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
+        };
+        if !fields.contains(&GroupField::SrcIp) {
+            self.src_ip = unspecified(self.src_ip);
+            self.src_is_agent = false;
+        }
+        if !fields.contains(&GroupField::DstIp) {
+            self.dst_ip = unspecified(self.dst_ip);
+            self.dst_is_agent = false;
+        }
+        if !fields.contains(&GroupField::SrcPort) {
+            self.src_port = 0;
+        }
+        if !fields.contains(&GroupField::DstPort) {
+            self.dst_port = 0;
+        }
+        if !fields.contains(&GroupField::Proto) {
+            self.proto = packet::Protocol::Unknown.into();
+            self.icmp_type = 0;
+            self.icmp_code = 0;
+        }
+        if !fields.contains(&GroupField::Vlan) {
+            self.vlan = None;
+        }
+        self.src_mac = None;
+        self.dst_mac = None;
+        self
+    }
+
+    /// Drop the parts of the key the aggregation mode ignores so those packets merge.
+    fn collapse(mut self, mode: AggregateMode) -> FlowKey {
+        self.vlan = None;
+        // ARP entries are IP-to-MAC bindings, so they always keep their addresses
+        if mode != AggregateMode::MacFlow && self.proto != i32::from(packet::Protocol::Arp) {
+            self.src_mac = None;
+            self.dst_mac = None;
+        }
+        if mode == AggregateMode::Bidirectional {
+            // The is_agent flags move with their endpoints, so the agent side stays known
+            if (self.dst_ip, self.dst_port) < (self.src_ip, self.src_port) {
+                std::mem::swap(&mut self.src_ip, &mut self.dst_ip);
+                std::mem::swap(&mut self.src_port, &mut self.dst_port);
+                std::mem::swap(&mut self.src_is_agent, &mut self.dst_is_agent);
+            }
+            return self;
+        }
+        if matches!(mode, AggregateMode::Flow | AggregateMode::MacFlow) {
+            return self;
+        }
+        self.src_port = 0;
+        self.dst_port = 0;
+        if mode == AggregateMode::Host {
+            // Canonical direction: agent side first, otherwise the lower address first
+            let swap = if self.src_is_agent != self.dst_is_agent {
+                self.dst_is_agent
+            } else {
+                self.dst_ip < self.src_ip
+            };
+            if swap {
+                std::mem::swap(&mut self.src_ip, &mut self.dst_ip);
+                std::mem::swap(&mut self.src_is_agent, &mut self.dst_is_agent);
+                std::mem::swap(&mut self.src_mac, &mut self.dst_mac);
+            }
+        }
+        self
+    }
+}
+
+/// Values accumulated for a flow between flushes.
+#[derive(Debug, Default, Clone)]
+struct FlowStats {
+    size: i32,
+    packets: i64,
+    /// Lowest TTL / hop limit seen
+    ttl: Option<u8>,
+    /// DiffServ code point of the most recent packet
+    dscp: u8,
+    sni: Option<String>,
+    dns_query: Option<String>,
+    /// Capture times of the earliest and latest packet, Unix ns; 0 when unknown
+    first_seen: u64,
+    last_seen: u64,
+    /// Most recent TCP window and MSS, with --tcp-detail
+    tcp_window: Option<u16>,
+    tcp_mss: Option<u16>,
+    /// Some packet matched --highlight-filter
+    highlighted: bool,
+    /// Some packet looked out of place for its port, with --flag-anomalies
+    anomalous: bool,
+    /// `PacketFlag` bits of any packet
+    flags: u32,
+}
+
+impl FlowStats {
+    /// Fold what another packet (or datagram) of the same flow adds.
+    fn add(&mut self, other: FlowStats) {
+        // A long-lived flow (or crafted lengths) must not overflow, which would panic in debug builds
+        self.size = self.size.saturating_add(other.size);
+        self.packets = self.packets.saturating_add(other.packets);
+        if let Some(ttl) = other.ttl {
+            self.ttl = Some(self.ttl.map_or(ttl, |min| min.min(ttl)));
+        }
+        self.dscp = other.dscp;
+        if other.sni.is_some() {
+            self.sni = other.sni;
+        }
+        if other.dns_query.is_some() {
+            self.dns_query = other.dns_query;
+        }
+        if other.first_seen != 0 && (self.first_seen == 0 || other.first_seen < self.first_seen) {
+            self.first_seen = other.first_seen;
+        }
+        self.last_seen = self.last_seen.max(other.last_seen);
+        if other.tcp_window.is_some() {
+            self.tcp_window = other.tcp_window;
+        }
+        if other.tcp_mss.is_some() {
+            self.tcp_mss = other.tcp_mss;
+        }
+        self.highlighted |= other.highlighted;
+        self.anomalous |= other.anomalous;
+        self.flags |= other.flags;
+    }
+}
+
+/// Validate `args` and resolve the settings that depend on each other.
+fn prepare(args: &mut Args) -> Result<(), AgentError> {
+    validate_batching(args).map_err(AgentError::Invalid)?;
+    choose_snaplen(args);
+    if args.no_local_filter && args.direction != Direction::Both {
+        // Nothing is the agent's own on a mirror port, so a direction would drop everything
+        return Err(AgentError::Invalid("--direction needs the local filter; it can't be combined with --no-local-filter".into()));
+    }
+    if args.benchmark.is_some() {
+        if args.netflow_collector.is_some() || args.output_tsv.is_some() {
+            return Err(AgentError::Invalid("--benchmark measures the server; it can't be combined with --netflow-collector or --output-tsv".into()));
+        }
+        if args.duration.is_none() {
+            args.duration = Some(benchmark::DEFAULT_DURATION_SECS);
+        }
+    }
+    if args.transit && !args.no_local_filter {
+        return Err(AgentError::Invalid("--transit needs --no-local-filter; without it flows not involving the agent are dropped".into()));
+    }
+    if args.services_file.is_some() && !args.label_services && !args.flag_anomalies {
+        return Err(AgentError::Invalid("--services-file needs --label-services or --flag-anomalies".into()));
+    }
+    if !args.group_by.is_empty() && args.aggregate != AggregateMode::Flow {
+        return Err(AgentError::Invalid("--group-by replaces --aggregate; pick one".into()));
+    }
+    if args.tee && args.netflow_collector.is_none() && args.output_tsv.is_none() {
+        return Err(AgentError::Invalid("--tee needs --output-tsv or --netflow-collector to copy flows to".into()));
+    }
+    if let Some(datalink) = &args.datalink {
+        parse_datalink(datalink).map_err(|e| AgentError::Invalid(format!("Invalid --datalink: {}", e)))?;
+    }
+    for endpoint in &args.exclude_endpoint {
+        endpoint_clause(endpoint).map_err(|e| AgentError::Invalid(format!("Invalid --exclude-endpoint {:?}: {}", endpoint, e)))?;
+    }
+    if !(args.mock_trace_speed > 0.0 && args.mock_trace_speed.is_finite()) {
+        return Err(AgentError::Invalid("--mock-trace-speed must be above 0".into()));
+    }
+    if let Some(path) = &args.mock_trace {
+        // Read once up front so a bad trace fails startup
+        trace::load(path, args).map_err(AgentError::Invalid)?;
+        args.mock = true;
+    }
+    if args.anonymize {
+        // Checked up front so a bad key fails startup rather than the capture thread
+        match &args.anon_key {
+            Some(key) => {
+                anonymize::parse_key(key).map_err(AgentError::Invalid)?;
+            }
+            None => return Err(AgentError::Invalid("--anonymize needs --anon-key".into())),
+        }
+    }
+    Ok(())
+}
+
+/// The capture and aggregation pipeline without the server connection.
+pub struct Agent {
+    args: Args,
+    stop: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    mock_fallback: bool,
+}
+
+impl Agent {
+    pub fn new(mut args: Args) -> Result<Self, AgentError> {
+        prepare(&mut args)?;
+        Ok(Self {
+            args,
+            stop: Arc::default(),
+            paused: Arc::default(),
+            mock_fallback: false,
+        })
+    }
+
+    /// Generate mock traffic when the capture device can't be opened, as the binary does,
+    /// instead of failing [`Agent::run`].
+    pub fn mock_fallback(mut self, enabled: bool) -> Self {
+        self.mock_fallback = enabled;
+        self
+    }
+
+    /// Setting this stops the pipeline once the buffered flows are flushed to the sink.
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    /// While this is set, captured frames are read and discarded.
+    pub fn pause_handle(&self) -> Arc<AtomicBool> {
+        self.paused.clone()
+    }
+
+    /// Capture from the configured device (or generate mock traffic with `--mock`) into
+    /// `sink` until stopped, the capture ends or the sink closes.
+    pub async fn run<S: FlowSink + 'static>(self, sink: S) -> Result<(), AgentError> {
+        let (tx, drain) = sinks::spawn(vec![Box::new(sink) as Box<dyn FlowSink>], self.args.send_queue_depth, false, None);
+        produce_flows(tx, &self.args, &[], self.stop, self.paused, Arc::default(), self.mock_fallback).await?;
+        let _ = drain.await;
+        Ok(())
+    }
+
+    /// Run the pipeline over the frames of any `source`, e.g. canned frames in a test, until
+    /// the source fails, `stop` is set or the sink closes.
+    pub async fn run_source<P: PacketSource + Send + 'static, S: FlowSink + 'static>(self, mut source: P, sink: S) -> Result<(), AgentError> {
+        let (tx, drain) = sinks::spawn(vec![Box::new(sink) as Box<dyn FlowSink>], self.args.send_queue_depth, false, None);
+        let Agent { args, stop, paused, .. } = self;
+        let result = tokio::task::spawn_blocking(move || -> Result<(), AgentError> {
+            let local_ips = detect_local_addrs(&args);
+            let parser = FrameParser::new(&args, source.datalink(), local_ips).map_err(AgentError::Invalid)?;
+            let (capture_stats, error) = capture_loop(&mut source, parser, &tx, &stop, &paused);
+            summary::add_capture(&capture_stats);
+            match error {
+                Some(e) => Err(AgentError::CaptureLost(e)),
+                None => Ok(()),
+            }
+        })
+        .await
+        .map_err(|e| AgentError::Device(format!("Capture thread failed: {}", e)))?;
+        let _ = drain.await;
+        result
+    }
+}
+
+/// Parse the command line (and `--config`), then run the agent as the binary does.
+pub async fn run_cli() -> Result<(), AgentError> {
+    let started = std::time::Instant::now();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).map_err(|e| AgentError::Invalid(e.to_string()))?;
+    if let Some(path) = args.config.clone() {
+        config::load(&path).map_err(|e| AgentError::Invalid(e.to_string()))?.apply(&mut args, &matches);
+    }
+
+    // Initialised after the config file so it can choose the format too
+    match args.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::init(),
+        LogFormat::Json => tracing_subscriber::fmt().json().init(),
+    }
+    if let Some(path) = &args.config {
+        info!(path = %path, "Loaded config file");
+    }
+    prepare(&mut args)?;
+
+    // Several comma-separated servers give failover; each is tried in turn
+    let servers: Vec<String> = args
+        .server
+        .split(',')
+        .map(str::trim)
+        .filter(|server| !server.is_empty())
+        .map(|server| if server.starts_with("http") { server.to_string() } else { format!("http://{}", server) })
+        .collect();
+    if servers.is_empty() {
+        return Err(AgentError::Invalid("--server needs at least one address".to_string()));
+    }
+
+    // Our own traffic to any of them (and to a teed IPFIX collector) is kept out of the capture
+    let mut server_ports: Vec<u16> = servers.iter().map(|server| extract_port(server).unwrap_or(50051)).collect();
+    if args.tee {
+        server_ports.extend(args.netflow_collector.as_deref().and_then(extract_port));
+    }
+    server_ports.sort_unstable();
+    server_ports.dedup();
+
+    if args.list_devices {
+        match Device::list() {
+            Ok(devices) => {
+                println!("Available devices:");
+                for device in devices {
+                    println!("  Name: {}", device.name);
+                    println!("  Description: {:?}", device.desc);
+                    for address in device.addresses {
+                        println!("    Address: {:?}", address.addr);
+                    }
+                    println!();
+                }
+            }
+            Err(e) => eprintln!("Failed to list devices: {}", e),
+        }
+        return Ok(());
+    }
+
+    if args.check {
+        // The same ports the capture will leave out: the IPFIX collector's, the servers', or both with --tee
+        let excluded_ports: Vec<u16> = if args.tee || (args.netflow_collector.is_none() && args.output_tsv.is_none()) {
+            server_ports.clone()
+        } else {
+            args.netflow_collector.as_deref().and_then(extract_port).into_iter().collect()
+        };
+        return check_plan(&args, &servers, &excluded_ports);
+    }
+
+    // Set on Ctrl-C so the capture loop can flush its buffer and the stream can close cleanly
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_signal = stop.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("Ctrl-C received, flushing buffered packets and stopping");
+            stop_signal.store(true, Ordering::SeqCst);
+        }
+        // A second Ctrl-C skips the flush
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+
+    if let Some(secs) = args.duration {
+        let stop = stop.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(secs)).await;
+            info!(duration_secs = secs, "Capture duration reached, flushing buffered packets and stopping");
+            stop.store(true, Ordering::SeqCst);
+        });
+    }
+
+    // Toggled by SIGUSR1 to quiet the agent during maintenance without dropping its stream
+    let paused = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    {
+        let paused = paused.clone();
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut usr1 = match signal(SignalKind::user_defined1()) {
+                Ok(usr1) => usr1,
+                Err(e) => {
+                    warn!(error = %e, "Failed to install the SIGUSR1 handler; pausing is unavailable");
+                    return;
+                }
+            };
+            while usr1.recv().await.is_some() {
+                if paused.fetch_xor(true, Ordering::SeqCst) {
+                    info!("SIGUSR1 received, resuming capture");
+                } else {
+                    info!("SIGUSR1 received, pausing capture; packets are discarded until the next SIGUSR1");
+                }
+            }
+        });
+    }
+
+    // Outlives reconnects so counters keep accumulating across them
+    let otlp = match &args.otlp_endpoint {
+        Some(endpoint) => {
+            let exporter = otlp::FlowExporter::new(endpoint).map_err(|e| AgentError::Output(format!("Failed to start the OTLP exporter for {}: {}", endpoint, e)))?;
+            info!(endpoint = %endpoint, "Exporting flows as OTLP metrics");
+            Some(Arc::new(exporter))
+        }
+        None => None,
+    };
+
+    let mut outputs = sinks::build(&args).await?;
+    let replaces_server = !outputs.is_empty() && !args.tee;
+    if let Some(exporter) = &otlp {
+        outputs.push(sinks::otlp(exporter.clone()));
+    }
+    if replaces_server {
+        let result = run_local_export(&args, outputs, stop, paused).await;
+        shutdown_otlp(otlp);
+        summary::log(started);
+        return result;
+    }
+    if args.tee {
+        info!(outputs = ?sinks::names(&outputs), "Copying flows streamed to the server to local outputs");
+    }
+
+    let services = if args.label_services || args.flag_anomalies {
+        let mut table = services::ServiceTable::builtin();
+        if let Some(path) = &args.services_file {
+            table.extend_from_file(path).map_err(AgentError::Invalid)?;
+        }
+        Some(Arc::new(table))
+    } else {
+        None
+    };
+
+    let lifecycle = Arc::new(lifecycle::Lifecycle::default());
+    if let Some(addr) = args.metrics_addr {
+        let lifecycle = lifecycle.clone();
+        tokio::spawn(async move {
+            if let Err(e) = lifecycle::serve(addr, lifecycle).await {
+                error!(addr = %addr, error = %e, "Agent metrics endpoint failed");
+            }
+        });
+    }
+
+    // Index of the server that last accepted a connection; reconnects start there
+    let mut preferred = 0;
+    // Connection attempts since the last session that reached the server
+    let mut attempt: u64 = 0;
+    // Set when the session ended in a way retrying can't fix
+    let mut fatal: Option<AgentError> = None;
+    loop {
+        attempt += 1;
+        let mut client = None;
+        let mut last_error: Option<AgentError> = None;
+        for offset in 0..servers.len() {
+            let index = (preferred + offset) % servers.len();
+            let server_url = &servers[index];
+            info!(server = %server_url, attempt, "Connecting");
+            match AgentServiceClient::connect(server_url.clone()).await {
+                Ok(connected) => {
+                    lifecycle.connected(server_url, attempt);
+                    client = Some(connected);
+                    preferred = index;
+                    break;
+                }
+                Err(e) => {
+                    if servers.len() > 1 {
+                        warn!(server = %server_url, error = %e, "Failed to connect, trying the next server");
+                    }
+                    last_error = Some(AgentError::Connect(Box::new(e)));
+                }
+            }
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+
+        let server = client.is_some().then(|| servers[preferred].as_str());
+        let result = match client {
+            Some(client) => {
+                lifecycle.streaming(&servers[preferred]);
+                run_agent(client, &args, &server_ports, stop.clone(), paused.clone(), &mut outputs, services.clone()).await
+            }
+            None => Err(last_error.unwrap_or_else(|| AgentError::Stream("No server reachable".to_string()))),
+        };
+        match result {
+            Ok(_) => {
+                info!("Agent stopped normally");
+                break;
+            },
+            Err(e) => {
+                lifecycle.disconnected(server, attempt, &e);
+                if args.benchmark.is_some() {
+                    benchmark::record_stream_error();
+                }
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                if !e.is_retryable() {
+                    error!(error = %e, "Not retrying; fix the cause and restart the agent");
+                    fatal = Some(e);
+                    break;
+                }
+                // A session that got as far as streaming starts the count over
+                if server.is_some() {
+                    attempt = 0;
+                }
+                lifecycle.reconnecting(attempt + 1, RECONNECT_DELAY);
+                sleep(RECONNECT_DELAY).await;
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Closes the local outputs, which have seen every batch of the last session by now
+    drop(outputs);
+    shutdown_otlp(otlp);
+    summary::log(started);
+    if let Some(pps) = args.benchmark {
+        benchmark::report(pps);
+    }
+    match fatal {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn shutdown_otlp(otlp: Option<Arc<otlp::FlowExporter>>) {
+    if let Some(exporter) = otlp {
+        if let Err(e) = exporter.shutdown() {
+            warn!(error = %e, "Failed to flush the OTLP exporter");
+        }
+    }
+}
+
+/// How long to wait before trying the servers again after the stream ends.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Addresses that count as the agent's own when setting `src_is_agent` / `dst_is_agent`.
+#[derive(Debug, Default)]
+struct LocalAddrs {
+    ips: HashSet<IpAddr>,
+    nets: Vec<ipnet::IpNet>,
+}
+
+impl LocalAddrs {
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.ips.contains(ip) || self.nets.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// Whether `--allow-cidr` / `--deny-cidr` let a flow between `src` and `dst` through: one
+/// touching a denied range never does, and with allowed ranges one of its endpoints must be
+/// in them.
+fn cidr_allows(args: &Args, src: IpAddr, dst: IpAddr) -> bool {
+    let touches = |nets: &[ipnet::IpNet]| nets.iter().any(|net| net.contains(&src) || net.contains(&dst));
+    if touches(&args.deny_cidr) {
+        return false;
+    }
+    args.allow_cidr.is_empty() || touches(&args.allow_cidr)
+}
+
+/// Optional `Packet` fields this agent fills in, named as in the proto, so the server and
+/// dashboard can tell a field that is off from one that is empty.
+fn capabilities(args: &Args) -> Vec<&'static str> {
+    let mut fields = Vec::new();
+    if args.parse_sni {
+        fields.push("sni");
+    }
+    if args.parse_dns {
+        fields.push("dns_query");
+    }
+    if args.tcp_detail {
+        fields.extend(["tcp_window", "tcp_mss"]);
+    }
+    // Only on Ethernet captures, which the server learns from link_type
+    if args.group_by.is_empty() && args.aggregate == AggregateMode::MacFlow {
+        fields.extend(["src_mac", "dst_mac"]);
+    }
+    if args.group_by.contains(&GroupField::Vlan) {
+        fields.push("vlan");
+    }
+    if args.sketch {
+        fields.push("avg_size");
+    }
+    if args.label_services {
+        fields.push("service");
+    }
+    if args.transit {
+        fields.push("transit");
+    }
+    if args.highlight_filter.is_some() {
+        fields.push("highlighted");
+    }
+    if args.flag_anomalies {
+        fields.push("anomalous");
+    }
+    fields
+}
+
+/// The address this agent's `*_is_agent` flags refer to: the first `--local-ip`, else the first
+/// non-loopback address of the capture device (IPv4 preferred), or loopback in mock mode or
+/// when none is found.
+fn primary_ip(args: &Args) -> IpAddr {
+    let loopback = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+    if args.mock {
+        return loopback;
+    }
+    if let Some(ip) = args.local_ip.first() {
+        return *ip;
+    }
+    let Ok(devices) = Device::list() else {
+        return loopback;
+    };
+    let mut candidates: Vec<IpAddr> = devices
+        .iter()
+        .filter(|d| args.device == "any" || d.name == args.device)
+        .flat_map(|d| d.addresses.iter().map(|a| a.addr))
+        .filter(|ip| !ip.is_loopback() && !ip.is_unspecified())
+        .collect();
+    // Stable sort keeps device order within each family
+    candidates.sort_by_key(|ip| ip.is_ipv6());
+    candidates.first().copied().unwrap_or(loopback)
+}
+
+/// Actionable advice for the common reasons opening a live capture fails.
+fn capture_error_hint(message: &str) -> Option<&'static str> {
+    let message = message.to_lowercase();
+    if message.contains("permission") || message.contains("operation not permitted") {
+        Some("capturing needs root or CAP_NET_RAW (plus CAP_NET_ADMIN for promiscuous mode), e.g. sudo setcap cap_net_raw,cap_net_admin=eip ./mikaboshi-agent")
+    } else if message.contains("no such device") || message.contains("doesn't exist") {
+        Some("the capture device was not found; run with --list-devices to see what is available")
+    } else {
+        None
+    }
+}
+
+/// Shortest flush interval accepted; anything lower just burns CPU flushing tiny batches.
+const MIN_BATCH_INTERVAL_MS: u64 = 10;
+/// Largest batch accepted; the capture buffer preallocates this many entries.
+const MAX_BATCH_SIZE: usize = 1_000_000;
+
+/// Reject a zero batch size and clamp batch settings that would misbehave to sane values.
+fn validate_batching(args: &mut Args) -> Result<(), String> {
+    if args.batch_size == 0 {
+        return Err("--batch-size must be at least 1".to_string());
+    }
+    if args.batch_size > MAX_BATCH_SIZE {
+        warn!(batch_size = args.batch_size, clamped = MAX_BATCH_SIZE, "--batch-size is too large, clamping");
+        args.batch_size = MAX_BATCH_SIZE;
+    }
+    if args.send_queue_depth == 0 {
+        return Err("--send-queue-depth must be at least 1".to_string());
+    }
+    if args.max_message_size == Some(0) {
+        return Err("--max-message-size must be at least 1".to_string());
+    }
+    if args.batch_bytes == Some(0) {
+        return Err("--batch-bytes must be at least 1".to_string());
+    }
+    if args.top_flows == Some(0) {
+        return Err("--top-flows must be at least 1".to_string());
+    }
+    if args.batch_interval < MIN_BATCH_INTERVAL_MS {
+        warn!(batch_interval_ms = args.batch_interval, clamped = MIN_BATCH_INTERVAL_MS, "--batch-interval is too small, clamping");
+        args.batch_interval = MIN_BATCH_INTERVAL_MS;
+    }
+    Ok(())
+}
+
+/// tonic's own message size limit, which --max-message-size never defaults below.
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+/// Generous encoded size of one flow: both addresses, a full SNI and DNS name, the labels
+/// and every fixed field.
+const MAX_FLOW_ENCODED_LEN: usize = 1024;
+
+/// The gRPC message size limit: `--max-message-size`, or room for a full batch.
+fn max_message_size(args: &Args) -> usize {
+    args.max_message_size
+        .unwrap_or_else(|| args.batch_size.saturating_mul(MAX_FLOW_ENCODED_LEN).max(DEFAULT_MAX_MESSAGE_SIZE))
+}
+
+/// Snapshot length when only L3/L4 headers are read.
+const HEADERS_SNAPLEN: i32 = 128;
+/// Room for two header stacks when GRE is decapsulated.
+const TUNNEL_SNAPLEN: i32 = 256;
+/// Whole frames, for parsers that need the payload (a ClientHello easily exceeds 1KB).
+const FULL_SNAPLEN: i32 = 65535;
+
+/// Pick a snapshot length for the enabled features unless `--snapshot` was given.
+fn choose_snaplen(args: &mut Args) {
+    if args.snapshot > 0 {
+        return;
+    }
+    args.snapshot = if args.parse_sni || args.parse_dns {
+        FULL_SNAPLEN
+    } else if args.decap_gre {
+        TUNNEL_SNAPLEN
+    } else {
+        HEADERS_SNAPLEN
+    };
+    info!(snaplen = args.snapshot, parse_sni = args.parse_sni, parse_dns = args.parse_dns, decap_gre = args.decap_gre, "Chose snapshot length");
+}
+
+fn extract_port(addr: &str) -> Option<u16> {
+    // Remove protocol if present
+    let clean_addr = addr.trim_start_matches("http://").trim_start_matches("https://");
+    
+    // Find last colon
+    if let Some(idx) = clean_addr.rfind(':') {
+        if let Ok(port) = clean_addr[idx+1..].parse::<u16>() {
+            return Some(port);
+        }
+    }
+    None
+}
+
+async fn run_agent(mut client: AgentServiceClient<Channel>, args: &Args, server_ports: &[u16], stop: Arc<AtomicBool>, paused: Arc<AtomicBool>, outputs: &mut Vec<Box<dyn FlowSink>>, services: Option<Arc<services::ServiceTable>>) -> Result<(), AgentError> {
+    if args.compress {
+        client = client.send_compressed(tonic::codec::CompressionEncoding::Gzip);
+    }
+    let max_message_size = max_message_size(args);
+    client = client
+        .max_encoding_message_size(max_message_size)
+        .max_decoding_message_size(max_message_size);
+    // Filled in by the capture thread once the device is open
+    let link_type: Arc<std::sync::OnceLock<String>> = Arc::default();
+    let (grpc, batches) = sinks::grpc(link_type.clone(), max_message_size);
+    let request_stream = tokio_stream::wrappers::ReceiverStream::new(batches);
+
+    // Tell the server which address "is_agent" refers to and who we are
+    let agent_ip = primary_ip(args);
+    let agent_id = args.agent_id.clone().unwrap_or_else(|| agent_ip.to_string());
+    let mut request = tonic::Request::new(request_stream);
+    request.metadata_mut().insert("mikaboshi-agent-ip", agent_ip.to_string().parse().map_err(|e| AgentError::Invalid(format!("Agent IP is not a valid header value: {}", e)))?);
+    match agent_id.parse() {
+        Ok(value) => {
+            request.metadata_mut().insert("mikaboshi-agent-id", value);
+        }
+        Err(_) => warn!(agent_id = %agent_id, "Agent id is not a valid header value; the server will identify us by IP"),
+    }
+    if let Some(site) = &args.site_label {
+        match site.parse() {
+            Ok(value) => {
+                request.metadata_mut().insert("mikaboshi-agent-site", value);
+            }
+            Err(_) => warn!(site = %site, "Site label is not a valid header value; flows will be sent without it"),
+        }
+    }
+    // Fixed for the life of the stream, so it is announced once with the identity
+    let capabilities = capabilities(args);
+    request.metadata_mut().insert(
+        "mikaboshi-agent-capabilities",
+        capabilities.join(",").parse().map_err(|e| AgentError::Invalid(format!("Capabilities are not a valid header value: {}", e)))?,
+    );
+    request.metadata_mut().insert("mikaboshi-agent-version", tonic::metadata::MetadataValue::from_static(VERSION));
+    info!(agent_id = %agent_id, agent_ip = %agent_ip, site = ?args.site_label, capabilities = ?capabilities, version = VERSION, "Reporting to server");
+
+    // Done before the fan-out so --tee outputs get the same flows as the server
+    let sketch = args.sketch;
+    let transit = args.transit;
+    let label_services = args.label_services;
+    let flag_anomalies = args.flag_anomalies;
+    // An address --group-by leaves out is unspecified, not a host the server should track
+    let grouped = !args.group_by.is_empty()
+        && (!args.group_by.contains(&GroupField::SrcIp) || !args.group_by.contains(&GroupField::DstIp));
+    let annotate = move |packets: &mut [Packet]| {
+        if sketch {
+            for p in packets.iter_mut() {
+                p.avg_size = (p.size as i64 / p.packets.max(1)) as i32;
+            }
+        }
+        if transit {
+            for p in packets.iter_mut() {
+                p.transit = !p.src_is_agent && !p.dst_is_agent;
+            }
+        }
+        if grouped {
+            for p in packets.iter_mut() {
+                p.flags |= packet::PacketFlag::Grouped as u32;
+            }
+        }
+        if let Some(services) = &services {
+            for p in packets.iter_mut() {
+                if label_services {
+                    if let Some(name) = services.label(p) {
+                        p.service = name.to_string();
+                    }
+                }
+                // Added to what the capture already flagged from payloads
+                if flag_anomalies && services.transport_mismatch(p) {
+                    p.anomalous = true;
+                }
+            }
+        }
+    };
+    // The stream goes first; the local outputs are lent to this session and handed back
+    let mut session_sinks = vec![grpc];
+    session_sinks.append(outputs);
+    let (tx, fanout) = sinks::spawn(session_sinks, args.send_queue_depth, false, Some(Box::new(annotate)));
+
+    // Spawn the gRPC client stream handler
+    let mut client_clone = client.clone();
+    let stream_handle = tokio::spawn(async move {
+        match client_clone.stream_packets(request).await {
+            Ok(_) => {
+                info!("Stream completed");
+                Ok(())
+            }
+            Err(status) => {
+                error!(error = %status, "Stream error");
+                Err(AgentError::from_status(status))
+            }
+        }
+    });
+
+    // Set when a --mock-count run has produced all of its packets
+    let produced = produce_flows(tx, args, server_ports, stop.clone(), paused, link_type, !args.no_mock_fallback).await;
+    // Dropping the stream's sink ends the request stream once the last batch is through
+    if let Ok(mut session_sinks) = fanout.await {
+        session_sinks.remove(0);
+        *outputs = session_sinks;
+    }
+    let mock_finished = produced?;
+
+    // Wait for stream to finish (which means disconnected)
+    let streamed = stream_handle
+        .await
+        .unwrap_or_else(|e| Err(AgentError::Stream(format!("stream task failed: {}", e))));
+
+    if stop.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    match streamed {
+        Ok(()) if mock_finished => {
+            info!("Mock packet count reached");
+            Ok(())
+        }
+        Ok(()) => Err(AgentError::Stream("Connection lost".to_string())),
+        Err(e) => Err(e),
+    }
+}
+
+/// Capture flows (or generate mock ones) into `tx` until capture ends, `stop` is set or the
+/// receiver goes away, leaving `excluded_ports` out of the capture. A device that can't be
+/// opened is replaced by mock traffic only with `mock_fallback`. Returns true when a
+/// `--mock-count` run produced all of its packets.
+async fn produce_flows(tx: mpsc::Sender<Vec<Packet>>, args: &Args, excluded_ports: &[u16], stop: Arc<AtomicBool>, paused: Arc<AtomicBool>, link_type: Arc<std::sync::OnceLock<String>>, mock_fallback: bool) -> Result<bool, AgentError> {
+    let mut mock_finished = false;
+    if let Some(pps) = args.benchmark {
+        info!(pps, duration_secs = ?args.duration, batch_size = args.batch_size, batch_interval_ms = args.batch_interval, "Starting benchmark");
+        benchmark::generate(tx, args, pps, stop).await;
+    } else if args.mock {
+        info!(
+            scenario = ?args.mock_scenario,
+            trace = ?args.mock_trace,
+            seed = ?args.mock_seed,
+            count = ?args.mock_count,
+            batch_size = args.batch_size,
+            batch_interval_ms = args.batch_interval,
+            "Starting in MOCK mode"
+        );
+        mock_finished = generate_mock_traffic(tx, args, stop.clone()).await;
+    } else {
+        info!(
+            device = %args.device,
+            batch_size = args.batch_size,
+            batch_interval_ms = args.batch_interval,
+            snaplen = args.snapshot,
+            immediate = args.immediate,
+            nano_timestamps = args.nano_timestamps,
+            buffer_size = ?args.buffer_size,
+            "Starting in LIVE capture mode"
+        );
+        let tx_clone = tx.clone();
+        let args_clone = args.clone();
+        let stop_clone = stop.clone();
+        let excluded_ports = excluded_ports.to_vec();
+        
+        // pcap capture blocks
+        let result = tokio::task::spawn_blocking(move || {
+            run_live_capture(args_clone, tx_clone, &excluded_ports, stop_clone, paused, link_type)
+        }).await.map_err(|e| AgentError::Device(format!("Capture thread failed: {}", e)))?;
+
+        if let Err(e) = result {
+             // A lost capture, or a device that hasn't come back yet: retry instead of faking
+             // traffic. Settings that can't work are not papered over with mock traffic either
+             if matches!(e, AgentError::CaptureLost(_) | AgentError::Invalid(_)) {
+                 return Err(e);
+             }
+             if CAPTURE_LOST.load(Ordering::SeqCst) {
+                 warn!(device = %args.device, error = %e, "Capture device is still unavailable");
+                 return Err(e);
+             }
+             let message = e.to_string();
+             if let Some(hint) = capture_error_hint(&message) {
+                 error!(device = %args.device, error = %message, hint, "Error opening device");
+             } else {
+                 error!(device = %args.device, error = %message, "Error opening device");
+             }
+             if !mock_fallback {
+                 return Err(AgentError::NoCapture(message));
+             }
+             warn!("Falling back to MOCK mode, the traffic sent from now on is fake");
+             mock_finished = generate_mock_traffic(tx, args, stop.clone()).await;
+        } else {
+            // Close our end so the consumer finishes once the final batch is sent
+            drop(tx);
+        }
+    }
+    Ok(mock_finished)
+}
+
+/// `--output-tsv`/`--netflow-collector` without `--tee`: write flows to the local outputs
+/// instead of streaming them to the server.
+async fn run_local_export(args: &Args, outputs: Vec<Box<dyn FlowSink>>, stop: Arc<AtomicBool>, paused: Arc<AtomicBool>) -> Result<(), AgentError> {
+    info!(outputs = ?sinks::names(&outputs), "Writing flows to local outputs instead of streaming them to the server");
+    let (tx, outputs_handle) = sinks::spawn(outputs, args.send_queue_depth, true, None);
+
+    // Our own export datagrams are kept out of the capture
+    let excluded_ports: Vec<u16> = args.netflow_collector.as_deref().and_then(extract_port).into_iter().collect();
+    produce_flows(tx, args, &excluded_ports, stop, paused, Arc::default(), !args.no_mock_fallback).await?;
+    let _ = outputs_handle.await;
+    Ok(())
+}
+
+/// Whether a flow runs from the client to the server, by the well-known port heuristic: the
+/// side with the lower port is the server. Only the ports decide, so flows where both or
+/// neither side is the agent (loopback, two local addresses, transit) still get a consistent
+/// direction. Equal ports, and protocols without ports, give false.
+fn client_to_server(src_port: i32, dst_port: i32) -> bool {
+    dst_port < src_port
+}
+
+fn packet_from_key(key: FlowKey, stats: FlowStats) -> Packet {
+    // Both addresses share the family given by `is_ipv6`; a mixed pair is sent as IPv6,
+    // with the IPv4 side in its IPv4-mapped form
+    let (src_ip_bytes, dst_ip_bytes, is_ipv6) = match (key.src_ip, key.dst_ip) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => (s.octets().to_vec(), d.octets().to_vec(), false),
+        (IpAddr::V6(s), IpAddr::V6(d)) => (s.octets().to_vec(), d.octets().to_vec(), true),
+        (IpAddr::V4(s), IpAddr::V6(d)) => (s.to_ipv6_mapped().octets().to_vec(), d.octets().to_vec(), true),
+        (IpAddr::V6(s), IpAddr::V4(d)) => (s.octets().to_vec(), d.to_ipv6_mapped().octets().to_vec(), true),
+    };
+    // Mock and benchmark flows have no capture time; they are stamped as they are sent
+    let (first_seen, last_seen) = if stats.first_seen == 0 {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        (now, now)
+    } else {
+        (stats.first_seen / 1_000_000, stats.last_seen / 1_000_000)
+    };
+
+    Packet {
+        src_ip: src_ip_bytes,
+        dst_ip: dst_ip_bytes,
+        is_ipv6,
+        src_is_agent: key.src_is_agent,
+        dst_is_agent: key.dst_is_agent,
+        size: stats.size,
+        packets: stats.packets,
+        proto: key.proto,
+        src_port: key.src_port,
+        dst_port: key.dst_port,
+        client_to_server: client_to_server(key.src_port, key.dst_port),
+        sni: stats.sni.unwrap_or_default(),
+        dns_query: stats.dns_query.unwrap_or_default(),
+        icmp_type: key.icmp_type,
+        icmp_code: key.icmp_code,
+        ttl: stats.ttl.map(i32::from).unwrap_or(0),
+        dscp: stats.dscp as i32,
+        src_mac: key.src_mac.map(|mac| mac.to_vec()).unwrap_or_default(),
+        dst_mac: key.dst_mac.map(|mac| mac.to_vec()).unwrap_or_default(),
+        vlan: key.vlan.map(i32::from),
+        first_seen,
+        last_seen,
+        first_seen_ns: stats.first_seen,
+        last_seen_ns: stats.last_seen,
+        tcp_window: stats.tcp_window.map(i32::from),
+        tcp_mss: stats.tcp_mss.map(i32::from),
+        highlighted: stats.highlighted,
+        anomalous: stats.anomalous,
+        flags: stats.flags,
+        // avg_size and service are filled in at send time; the rest is set by the server
+        ..Default::default()
+    }
+}
+
+/// `--top-flows`: keep the `limit` flows with the most bytes and fold the rest into one
+/// entry per protocol (and address family) with unspecified addresses and no ports, flagged
+/// `PACKET_FLAG_OTHER_FLOWS`.
+fn keep_top_flows(buffer: &mut HashMap<FlowKey, FlowStats>, limit: usize) {
+    if buffer.len() <= limit {
+        return;
+    }
+    let mut flows: Vec<(FlowKey, FlowStats)> = buffer.drain().collect();
+    flows.sort_unstable_by_key(|(_, stats)| Reverse(stats.size));
+    let rest = flows.split_off(limit);
+    buffer.extend(flows);
+    for (key, stats) in rest {
+        let mut other = key.group(&[GroupField::Proto]);
+        other.icmp_type = 0;
+        other.icmp_code = 0;
+        // What identifies a single flow means nothing for the sum of many, and the flag tells
+        // consumers the unspecified addresses aren't a real peer
+        buffer.entry(other).or_default().add(FlowStats {
+            ttl: None,
+            sni: None,
+            dns_query: None,
+            tcp_window: None,
+            tcp_mss: None,
+            flags: stats.flags | packet::PacketFlag::OtherFlows as u32,
+            ..stats
+        });
+    }
+}
+
+/// Send the buffered flows, waiting as long as it takes. Used for the final flush on shutdown.
+fn flush_buffer_blocking(buffer: &mut HashMap<FlowKey, FlowStats>, top_flows: Option<usize>, tx: &mpsc::Sender<Vec<Packet>>, capture_stats: &mut CaptureStats) -> bool {
+    if let Some(limit) = top_flows {
+        keep_top_flows(buffer, limit);
+    }
+    let mut packets = Vec::with_capacity(buffer.len());
+    for (key, stats) in buffer.drain() {
+        capture_stats.aggregated_packets += stats.packets.max(0) as u64;
+        capture_stats.aggregated_bytes += stats.size.max(0) as u64;
+        packets.push(packet_from_key(key, stats));
+    }
+    capture_stats.aggregated_flows += packets.len() as u64;
+    packets.is_empty() || tx.blocking_send(packets).is_ok()
+}
+
+/// How long a full channel may stall the capture thread before a batch is dropped.
+const BACKPRESSURE_WAIT: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Send the buffered flows from the capture thread. Returns false once the channel is closed.
+///
+/// A full channel is retried for at most `BACKPRESSURE_WAIT`; after that the batch is dropped
+/// and counted, so a slow server costs us a batch instead of stalling capture while the
+/// kernel buffer silently overflows.
+fn flush_buffer(buffer: &mut HashMap<FlowKey, FlowStats>, top_flows: Option<usize>, tx: &mpsc::Sender<Vec<Packet>>, capture_stats: &mut CaptureStats) -> bool {
+    use tokio::sync::mpsc::error::TrySendError;
+
+    if let Some(limit) = top_flows {
+        keep_top_flows(buffer, limit);
+    }
+    let mut packets = Vec::with_capacity(buffer.len());
+    let mut input_packets: u64 = 0;
+    let mut input_bytes: u64 = 0;
+    for (key, stats) in buffer.drain() {
+        input_packets += stats.packets.max(0) as u64;
+        input_bytes += stats.size.max(0) as u64;
+        packets.push(packet_from_key(key, stats));
+    }
+    
+    if packets.is_empty() {
+        return true;
+    }
+
+    capture_stats.aggregated_packets += input_packets;
+    capture_stats.aggregated_bytes += input_bytes;
+    capture_stats.aggregated_flows += packets.len() as u64;
+    debug!(
+        packets = input_packets,
+        flows = packets.len(),
+        packets_per_flow = input_packets as f64 / packets.len() as f64,
+        "Sending batch"
+    );
+    let deadline = std::time::Instant::now() + BACKPRESSURE_WAIT;
+    loop {
+        match tx.try_send(packets) {
+            Ok(()) => {
+                let queued = tx.max_capacity() - tx.capacity();
+                capture_stats.send_queue_peak = capture_stats.send_queue_peak.max(queued);
+                return true;
+            }
+            Err(TrySendError::Closed(_)) => return false,
+            Err(TrySendError::Full(returned)) => {
+                if std::time::Instant::now() >= deadline {
+                    capture_stats.backpressure_dropped += 1;
+                    return true;
+                }
+                packets = returned;
+                std::thread::sleep(std::time::Duration::from_millis(5));
+            }
+        }
+    }
+}
+
+async fn flush_buffer_async(buffer: &mut HashMap<FlowKey, FlowStats>, top_flows: Option<usize>, tx: &mpsc::Sender<Vec<Packet>>) -> bool {
+    if let Some(limit) = top_flows {
+        keep_top_flows(buffer, limit);
+    }
+    let mut packets = Vec::with_capacity(buffer.len());
+    for (key, stats) in buffer.drain() {
+        packets.push(packet_from_key(key, stats));
+    }
+    
+    if packets.is_empty() {
+        return true;
+    }
+
+    debug!(flows = packets.len(), "Sending batch");
+    if tx.send(packets).await.is_err() {
+        return false;
+    }
+    true
+}
+
+/// How long an incomplete set of IPv4 fragments is held before it is dropped.
+const FRAGMENT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Application payload following a TCP (6) or UDP (17) header.
+fn transport_payload(protocol: u8, segment: &[u8]) -> &[u8] {
+    let header_len = match protocol {
+        6 => segment.get(12).map(|b| (b >> 4) as usize * 4).unwrap_or(segment.len()),
+        _ => 8,
+    };
+    segment.get(header_len..).unwrap_or(&[])
+}
+
+/// Upper bound on IPv6 extension headers walked before giving up on a packet.
+const MAX_IPV6_EXTENSIONS: usize = 8;
+
+/// IP protocol number of GRE.
+const IP_PROTOCOL_GRE: u8 = 47;
+
+/// Strip one level of GRE, returning the outer endpoints and the headers of the inner IP packet.
+///
+/// Only version 0 GRE carrying IPv4 or IPv6 is decapsulated, and the inner packet is never
+/// decapsulated again, so nested tunnels can't make us loop. The link headers of the outer
+/// frame are kept so MAC addresses still refer to the captured frame.
+fn gre_decapsulate<'a>(headers: &etherparse::PacketHeaders<'a>) -> Option<(IpAddr, IpAddr, etherparse::PacketHeaders<'a>)> {
+    use etherparse::IpHeader;
+
+    let (src, dst) = match headers.ip.as_ref()? {
+        IpHeader::Version4(ipv4, _) if ipv4.protocol == IP_PROTOCOL_GRE && !ipv4.more_fragments && ipv4.fragments_offset == 0 => {
+            (IpAddr::from(ipv4.source), IpAddr::from(ipv4.destination))
+        }
+        IpHeader::Version6(ipv6, _) if ipv6.next_header == IP_PROTOCOL_GRE => {
+            (IpAddr::from(ipv6.source), IpAddr::from(ipv6.destination))
+        }
+        _ => return None,
+    };
+
+    let gre = headers.payload;
+    let flags = u16::from_be_bytes([*gre.first()?, *gre.get(1)?]);
+    if flags & 0x0007 != 0 {
+        return None;
+    }
+    let protocol_type = u16::from_be_bytes([*gre.get(2)?, *gre.get(3)?]);
+    if protocol_type != 0x0800 && protocol_type != 0x86dd {
+        return None;
+    }
+    // Checksum, key and sequence number are each 4 optional bytes
+    let optional = [0x8000, 0x2000, 0x1000].iter().filter(|&&bit| flags & bit != 0).count() * 4;
+
+    let mut inner = etherparse::PacketHeaders::from_ip_slice(gre.get(4 + optional..)?).ok()?;
+    inner.ip.as_ref()?;
+    inner.link = headers.link.clone();
+    inner.vlan = headers.vlan.clone();
+    Some((src, dst, inner))
+}
+
+/// How often the capture counters are logged while any of them is non-zero.
+const STATS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Frames to see before judging the parse failure rate.
+const MIN_FRAMES_FOR_PARSE_WARNING: u64 = 100;
+
+/// Counters for frames the capture loop couldn't account for normally.
+#[derive(Debug, Default)]
+struct CaptureStats {
+    /// Every frame read from the capture, for putting the other counters in proportion
+    frames: u64,
+    /// Frames whose link or IP headers couldn't be parsed
+    parse_failed: u64,
+    /// Frames that parsed but carry neither IP nor (with `--track-arp`) ARP
+    non_ip: u64,
+    /// Frames with fewer bytes captured than were on the wire (`caplen < len`)
+    truncated: u64,
+    /// Truncated frames whose headers couldn't be parsed, so they were dropped
+    truncated_unparsed: u64,
+    /// Packets dropped because `--max-pps` was reached
+    rate_limited: u64,
+    /// Packets of new flows dropped because `--max-flows` was reached
+    flow_overflow: u64,
+    /// Batches dropped because the channel to the server stayed full
+    backpressure_dropped: u64,
+    /// Duplicate frames dropped by `--dedup-ms`
+    duplicates: u64,
+    /// Packets folded into the flows sent so far
+    aggregated_packets: u64,
+    /// Flows those packets became
+    aggregated_flows: u64,
+    /// Bytes of those packets
+    aggregated_bytes: u64,
+    /// Batches waiting to be sent when the stats were taken, out of `--send-queue-depth`
+    send_queue: usize,
+    /// Most batches ever waiting at once; near the depth means sending, not capture, is the bottleneck
+    send_queue_peak: usize,
+}
+
+impl CaptureStats {
+    fn is_empty(&self) -> bool {
+        self.parse_failed == 0
+            && self.non_ip == 0
+            && self.truncated == 0
+            && self.truncated_unparsed == 0
+            && self.rate_limited == 0
+            && self.flow_overflow == 0
+            && self.backpressure_dropped == 0
+            && self.duplicates == 0
+    }
+
+    /// How many packets each sent flow stands for on average; close to 1 means aggregation
+    /// saves almost nothing and a coarser `--aggregate` mode is worth considering.
+    fn packets_per_flow(&self) -> f64 {
+        if self.aggregated_flows == 0 {
+            return 0.0;
+        }
+        self.aggregated_packets as f64 / self.aggregated_flows as f64
+    }
+
+    fn log(&self, datalink: pcap::Linktype) {
+        let link_type = link_type_name(datalink);
+        info!(
+            link_type = %link_type,
+            frames = self.frames,
+            parse_failed = self.parse_failed,
+            non_ip = self.non_ip,
+            truncated = self.truncated,
+            truncated_unparsed = self.truncated_unparsed,
+            rate_limited = self.rate_limited,
+            flow_overflow = self.flow_overflow,
+            backpressure_dropped = self.backpressure_dropped,
+            duplicates = self.duplicates,
+            aggregated_packets = self.aggregated_packets,
+            aggregated_flows = self.aggregated_flows,
+            send_queue = self.send_queue,
+            send_queue_peak = self.send_queue_peak,
+            packets_per_flow = self.packets_per_flow(),
+            "Capture stats"
+        );
+        // Mostly unparseable frames point at an unsupported link type rather than bad packets
+        if self.frames >= MIN_FRAMES_FOR_PARSE_WARNING && self.parse_failed * 2 > self.frames {
+            warn!(
+                link_type = %link_type,
+                frames = self.frames,
+                parse_failed = self.parse_failed,
+                "Most captured frames failed to parse; the link type may be unsupported"
+            );
+        }
+    }
+}
+
+const LINKTYPE_ETHERNET: pcap::Linktype = pcap::Linktype(1);
+/// Linux cooked capture (SLL), what the `any` device delivers
+const LINKTYPE_SLL: pcap::Linktype = pcap::Linktype(113);
+/// Bare IPv4/IPv6 packets (DLT_RAW), e.g. on tun devices
+const LINKTYPE_RAW: pcap::Linktype = pcap::Linktype(12);
+
+/// Link types whose frames are bare IP packets: DLT_RAW, and its savefile number and
+/// single-family variants.
+fn is_raw_ip(datalink: pcap::Linktype) -> bool {
+    matches!(datalink.0, 12 | 101 | 228 | 229)
+}
+
+/// Read a frame's headers according to its link type; unknown link types are read as Ethernet.
+fn parse_headers(datalink: pcap::Linktype, data: &[u8]) -> Result<etherparse::PacketHeaders<'_>, etherparse::ReadError> {
+    use etherparse::PacketHeaders;
+
+    match datalink {
+        LINKTYPE_SLL => {
+            // A frame with nothing past the 16-byte header is as malformed as any other the
+            // parser rejects
+            match data.get(16..) {
+                Some(ip) if !ip.is_empty() => PacketHeaders::from_ip_slice(ip),
+                _ => Err(etherparse::ReadError::UnexpectedEndOfSlice(0)),
+            }
+        }
+        link if is_raw_ip(link) => PacketHeaders::from_ip_slice(data),
+        _ => PacketHeaders::from_ethernet_slice(data),
+    }
+}
+
+/// Offset of the IP header within a captured frame for the link types we understand.
+fn ip_header_offset(datalink: pcap::Linktype, data: &[u8]) -> Option<usize> {
+    match datalink {
+        LINKTYPE_SLL => Some(16),
+        link if is_raw_ip(link) => Some(0),
+        // Ethernet, skipping any 802.1Q / 802.1ad tags
+        _ => {
+            let mut offset = 12;
+            loop {
+                let ether_type = u16::from_be_bytes([*data.get(offset)?, *data.get(offset + 1)?]);
+                match ether_type {
+                    0x8100 | 0x88a8 | 0x9100 => offset += 4,
+                    _ => return Some(offset + 2),
+                }
+            }
+        }
+    }
+}
+
+/// Walk an IPv6 extension header chain to the upper-layer header.
+///
+/// Returns the upper-layer protocol number and its bytes, or `None` when the chain is
+/// truncated, too long, or this is a non-first fragment (which carries no transport header).
+fn ipv6_upper_layer(ip: &[u8]) -> Option<(u8, &[u8])> {
+    let mut next_header = *ip.get(6)?;
+    let mut offset = 40;
+    for _ in 0..MAX_IPV6_EXTENSIONS {
+        let len = match next_header {
+            // Hop-by-hop, routing, destination options: length in 8-octet units, excluding the first 8
+            0 | 43 | 60 => (*ip.get(offset + 1)? as usize + 1) * 8,
+            // Fragment
+            44 => {
+                let fragment_offset = u16::from_be_bytes([*ip.get(offset + 2)?, *ip.get(offset + 3)?]) >> 3;
+                if fragment_offset != 0 {
+                    return None;
+                }
+                8
+            }
+            // Authentication header: length in 4-octet units, minus 2
+            51 => (*ip.get(offset + 1)? as usize + 2) * 4,
+            _ => return Some((next_header, ip.get(offset..)?)),
+        };
+        next_header = *ip.get(offset)?;
+        offset += len;
+    }
+    None
+}
+
+fn open_capture(args: &Args) -> Result<Capture<pcap::Active>, AgentError> {
+    // The read timeout is kept in immediate mode too, so an idle capture still wakes up
+    // for flush-timer and shutdown checks
+    let mut inactive = Capture::from_device(args.device.as_str())
+        .map_err(|e| AgentError::Device(e.to_string()))?
+        .promisc(args.promiscuous)
+        .snaplen(args.snapshot)
+        .timeout(args.pcap_timeout_ms)
+        .immediate_mode(args.immediate);
+    if let Some(size) = args.buffer_size {
+        inactive = inactive.buffer_size(size);
+    }
+    if args.nano_timestamps {
+        inactive = inactive.precision(pcap::Precision::Nano);
+    }
+    let mut cap = inactive.open().map_err(|e| AgentError::Device(e.to_string()))?;
+
+    let available = cap.list_datalinks().unwrap_or_default();
+    let names: Vec<String> = available.iter().map(|&link| link_type_name(link)).collect();
+    info!(device = %args.device, link_types = ?names, "Available link types");
+    if let Some(requested) = &args.datalink {
+        let link = parse_datalink(requested).map_err(AgentError::Invalid)?;
+        if !available.contains(&link) {
+            return Err(AgentError::Invalid(format!("--datalink {} is not supported by {} (available: {})", requested, args.device, names.join(", "))));
+        }
+        cap.set_datalink(link).map_err(|e| AgentError::Device(e.to_string()))?;
+    }
+    Ok(cap)
+}
+
+/// A `--datalink` value: a DLT name such as `EN10MB` (case-insensitive) or its number.
+fn parse_datalink(value: &str) -> Result<pcap::Linktype, String> {
+    if let Ok(number) = value.parse::<i32>() {
+        return Ok(pcap::Linktype(number));
+    }
+    pcap::Linktype::from_name(value).map_err(|_| format!("Unknown link type {:?}", value))
+}
+
+fn link_type_name(link: pcap::Linktype) -> String {
+    link.get_name().unwrap_or_else(|_| format!("LINKTYPE_{}", link.0))
+}
+
+/// BPF filter keeping our own traffic on `excluded_ports`, and to the endpoints of
+/// `own_endpoints`, out of the capture.
+fn bpf_filter(excluded_ports: &[u16], endpoints: &[String]) -> String {
+    excluded_ports
+        .iter()
+        .map(|port| format!("not port {}", port))
+        .chain(endpoints.iter().map(|clause| format!("not ({})", clause)))
+        .collect::<Vec<_>>()
+        .join(" and ")
+}
+
+/// BPF clauses matching the agent's own connections besides those on the excluded ports:
+/// `--exclude-endpoint`, and the OTLP collector it exports to.
+fn own_endpoints(args: &Args) -> Vec<String> {
+    // --exclude-endpoint was checked in prepare(); an OTLP endpoint we can't read is left in
+    args.exclude_endpoint
+        .iter()
+        .chain(&args.otlp_endpoint)
+        .filter_map(|endpoint| endpoint_clause(endpoint).ok())
+        .collect()
+}
+
+/// `host H and port P` for `H:P`, or `host H` for a bare host. A URL scheme and path are
+/// ignored, and IPv6 addresses with a port go in brackets. Host names are resolved when
+/// the filter is compiled.
+fn endpoint_clause(endpoint: &str) -> Result<String, String> {
+    let address = endpoint.trim_start_matches("http://").trim_start_matches("https://");
+    let address = address.split('/').next().unwrap_or_default();
+    let parse_port = |port: &str| port.parse::<u16>().map_err(|_| format!("invalid port {:?}", port));
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        let (host, after) = rest.split_once(']').ok_or("missing ]")?;
+        let port = match after {
+            "" => None,
+            after => Some(parse_port(after.strip_prefix(':').ok_or("expected :PORT after ]")?)?),
+        };
+        (host, port)
+    } else {
+        match address.rsplit_once(':') {
+            // More than one colon without brackets is a bare IPv6 address
+            Some((host, port)) if !host.contains(':') => (host, Some(parse_port(port)?)),
+            _ => (address, None),
+        }
+    };
+    // Whatever ends up here goes into the filter expression
+    if host.is_empty() || !host.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':')) {
+        return Err(format!("invalid host {:?}", host));
+    }
+    Ok(match port {
+        Some(port) => format!("host {} and port {}", host, port),
+        None => format!("host {}", host),
+    })
+}
+
+/// `--include-loopback`, or whether the capture device is a loopback interface (lo, lo0)
+/// going by the flags pcap reports for it.
+fn include_loopback(args: &Args) -> bool {
+    args.include_loopback.unwrap_or_else(|| {
+        Device::list()
+            .map(|devices| devices.iter().any(|d| d.name == args.device && d.flags.is_loopback()))
+            .unwrap_or(false)
+    })
+}
+
+fn detect_local_addrs(args: &Args) -> LocalAddrs {
+    let mut local_ips = LocalAddrs::default();
+    if let Ok(devs) = Device::list() {
+        for d in devs {
+            for address in d.addresses {
+                local_ips.ips.insert(address.addr);
+            }
+        }
+    }
+    if include_loopback(args) {
+        local_ips.ips.insert(IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)));
+        local_ips.ips.insert(IpAddr::V6(std::net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)));
+    } else {
+        local_ips.ips.retain(|ip| !ip.is_loopback());
+    }
+    // Addresses autodetection can't see, e.g. inside containers
+    local_ips.ips.extend(args.local_ip.iter().copied());
+    local_ips.nets.extend(args.local_cidr.iter().copied());
+    local_ips
+}
+
+/// `--check`: print what the agent would do with these settings. Fails if the device can't
+/// be opened or the filter doesn't compile, so it can gate a rollout.
+fn check_plan(args: &Args, servers: &[String], excluded_ports: &[u16]) -> Result<(), AgentError> {
+    if args.tee || (args.netflow_collector.is_none() && args.output_tsv.is_none()) {
+        println!("Output: gRPC stream to {}", servers.join(", "));
+    }
+    if let Some(collector) = &args.netflow_collector {
+        println!("Output: IPFIX to {}", collector);
+    }
+    if let Some(path) = &args.output_tsv {
+        println!("Output: TSV to {}", path);
+    }
+    if let Some(endpoint) = &args.otlp_endpoint {
+        println!("OTLP metrics: {}", endpoint);
+    }
+    println!(
+        "Batching: size {}, interval {}ms, bytes {}, aggregate {}, max pps {}, max flows {}, top flows {}, heartbeat {}",
+        args.batch_size,
+        args.batch_interval,
+        args.batch_bytes.map_or_else(|| "unlimited".to_string(), |bytes| bytes.to_string()),
+        if args.group_by.is_empty() { format!("{:?}", args.aggregate) } else { format!("by {:?}", args.group_by) },
+        args.max_pps.map_or_else(|| "unlimited".to_string(), |pps| pps.to_string()),
+        args.max_flows.map_or_else(|| "unlimited".to_string(), |flows| flows.to_string()),
+        args.top_flows.map_or_else(|| "all".to_string(), |flows| flows.to_string()),
+        if args.heartbeat_interval > 0 { format!("{}ms", args.heartbeat_interval) } else { "off".to_string() },
+    );
+
+    if let Some(path) = &args.mock_trace {
+        println!("Capture: mock, replaying {} at {}x, no device is opened", path, args.mock_trace_speed);
+        return Ok(());
+    }
+    if args.mock {
+        println!("Capture: mock ({:?} scenario), no device is opened", args.mock_scenario);
+        return Ok(());
+    }
+
+    println!("Device: {}", args.device);
+    if args.device == "any" {
+        for device in Device::list().unwrap_or_default() {
+            println!("  {}", device.name);
+        }
+    }
+    println!(
+        "Snapshot length: {}, promiscuous: {}, immediate: {}, nano timestamps: {}",
+        args.snapshot, args.promiscuous, args.immediate, args.nano_timestamps
+    );
+    if args.no_local_filter {
+        println!("Local addresses: not used (--no-local-filter)");
+    } else {
+        let local_ips = detect_local_addrs(args);
+        let mut ips: Vec<_> = local_ips.ips.iter().collect();
+        ips.sort();
+        println!("Local addresses ({:?} traffic):", args.direction);
+        for ip in ips {
+            println!("  {}", ip);
+        }
+        for net in &local_ips.nets {
+            println!("  {}", net);
+        }
+    }
+
+    let filter = bpf_filter(excluded_ports, &own_endpoints(args));
+    println!("BPF filter: {}", if filter.is_empty() { "(none)" } else { filter.as_str() });
+
+    let mut cap = open_capture(args).map_err(|e| match e {
+        AgentError::Device(message) => match capture_error_hint(&message) {
+            Some(hint) => AgentError::Device(format!("Cannot open device {}: {} ({})", args.device, message, hint)),
+            None => AgentError::Device(format!("Cannot open device {}: {}", args.device, message)),
+        },
+        e => e,
+    })?;
+    println!("Link type: {}", link_type_name(cap.datalink()));
+    cap.filter(&filter, true).map_err(|e| AgentError::Invalid(format!("Invalid BPF filter {:?}: {}", filter, e)))?;
+    if let Some(expression) = &args.highlight_filter {
+        cap.compile(expression, true).map_err(|e| AgentError::Invalid(format!("Invalid --highlight-filter {:?}: {}", expression, e)))?;
+        println!("Highlight filter: {}", expression);
+    }
+    println!("Check passed: the device opens and the filter compiles");
+    Ok(())
+}
+
+fn run_live_capture(args: Args, tx: mpsc::Sender<Vec<Packet>>, server_ports: &[u16], stop: Arc<AtomicBool>, paused: Arc<AtomicBool>, link_type: Arc<std::sync::OnceLock<String>>) -> Result<(), AgentError> {
+    let mut cap = open_capture(&args)?;
+    if CAPTURE_LOST.swap(false, Ordering::SeqCst) {
+        info!(device = %args.device, "Capture device is back");
+    }
+
+    let filter = bpf_filter(server_ports, &own_endpoints(&args));
+    info!(filter = %filter, "Setting BPF filter");
+    cap.filter(&filter, true)
+        .map_err(|e| AgentError::Invalid(format!("Invalid BPF filter {:?}: {}", filter, e)))?;
+
+    let local_ips = detect_local_addrs(&args);
+
+    info!(device = %args.device, local_ips = ?local_ips, "Capturing");
+
+    let datalink = cap.datalink();
+    let link_name = link_type_name(datalink);
+    info!(
+        link_type = %link_name,
+        description = %datalink.get_description().unwrap_or_default(),
+        "Resolved capture link type"
+    );
+    if !matches!(datalink, LINKTYPE_ETHERNET | LINKTYPE_SLL) && !is_raw_ip(datalink) {
+        warn!(link_type = %link_name, "Unsupported link type, frames will be parsed as Ethernet; --datalink can pick another");
+    }
+    let _ = link_type.set(link_name);
+
+    let parser = FrameParser::new(&args, datalink, local_ips).map_err(AgentError::Invalid)?;
+    let (capture_stats, error) = if args.nano_timestamps {
+        capture_loop(&mut source::NanoCapture::new(&mut cap), parser, &tx, &stop, &paused)
+    } else {
+        capture_loop(&mut cap, parser, &tx, &stop, &paused)
+    };
+    summary::add_capture(&capture_stats);
+    // Frames the kernel or the interface dropped before we could read them
+    if let Ok(stat) = cap.stats() {
+        summary::add_pcap_dropped(u64::from(stat.dropped) + u64::from(stat.if_dropped));
+    }
+    if let Some(e) = error {
+        error!(
+            device = %args.device,
+            errors = MAX_CONSECUTIVE_CAPTURE_ERRORS,
+            error = %e,
+            "Capture device appears to be down; closing it to reopen once it is back"
+        );
+        CAPTURE_LOST.store(true, Ordering::SeqCst);
+        return Err(AgentError::CaptureLost(e));
+    }
+    Ok(())
+}
+
+/// The next time the wall clock is a whole multiple of `interval` since the Unix epoch.
+fn next_aligned_flush(interval: std::time::Duration) -> std::time::Instant {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    let interval_ns = interval.as_nanos().max(1);
+    let until = interval_ns - now.as_nanos() % interval_ns;
+    std::time::Instant::now() + std::time::Duration::from_nanos(until as u64)
+}
+
+/// Read errors in a row after which the device is taken to be gone (unplugged, brought
+/// down, migrated away) and the capture is reopened instead of read forever.
+const MAX_CONSECUTIVE_CAPTURE_ERRORS: u32 = 20;
+
+/// Set when a live capture was lost, until the device is opened again. While set, failing
+/// to open the device is retried rather than falling back to mock traffic.
+static CAPTURE_LOST: AtomicBool = AtomicBool::new(false);
+
+/// Read frames from `source` into flow entries and hand them to `tx` in batches until the
+/// channel closes, `stop` is set or the source fails. Returns what was counted along the
+/// way, and the last error when the source stopped yielding frames.
+fn capture_loop<S: PacketSource + ?Sized>(source: &mut S, mut parser: FrameParser, tx: &mpsc::Sender<Vec<Packet>>, stop: &AtomicBool, paused: &AtomicBool) -> (CaptureStats, Option<pcap::Error>) {
+    let args = parser.args;
+
+    // Local buffer for pre-aggregation
+    let mut buffer: HashMap<FlowKey, FlowStats> = HashMap::with_capacity(args.batch_size);
+    // Wire bytes of the frames in `buffer`, for --batch-bytes
+    let mut buffer_bytes: u64 = 0;
+    let mut last_flush = std::time::Instant::now();
+    let flush_interval = std::time::Duration::from_millis(args.batch_interval);
+    // With --align-flush the timer goes off on the wall-clock boundary instead
+    let mut aligned_flush = args.align_flush.then(|| next_aligned_flush(flush_interval));
+    let heartbeat_interval = (args.heartbeat_interval > 0).then(|| std::time::Duration::from_millis(args.heartbeat_interval));
+
+    let mut last_evict = std::time::Instant::now();
+
+    let mut capture_stats = CaptureStats::default();
+    let mut last_stats = std::time::Instant::now();
+
+    // Packets accepted in the current one-second window, for --max-pps
+    let mut rate_window = std::time::Instant::now();
+    let mut rate_count: u64 = 0;
+
+    // Flows started in the current batch interval, for --max-flows
+    let mut flow_window = std::time::Instant::now();
+    let mut window_flows: usize = 0;
+
+    // Read errors since the last frame or timeout
+    let mut consecutive_errors: u32 = 0;
+
+    loop {
+        if (!capture_stats.is_empty() || args.aggregation_stats) && last_stats.elapsed() >= STATS_INTERVAL {
+            capture_stats.send_queue = tx.max_capacity() - tx.capacity();
+            capture_stats.log(parser.datalink);
+            last_stats = std::time::Instant::now();
+        }
+
+        // Drop fragment sets whose missing pieces never showed up
+        if let Some(cache) = parser.fragments.as_mut() {
+            if last_evict.elapsed() >= std::time::Duration::from_secs(1) {
+                let evicted = cache.evict_expired(std::time::Instant::now());
+                if evicted > 0 {
+                    warn!(datagrams = evicted, "Dropped incomplete fragmented datagrams");
+                }
+                last_evict = std::time::Instant::now();
+            }
+        }
+
+        // Check flush timer
+        let flush_due = match aligned_flush {
+            Some(due) => std::time::Instant::now() >= due,
+            None => last_flush.elapsed() >= flush_interval,
+        };
+        if flush_due {
+            // A boundary passed with nothing buffered is skipped, not owed to the next frame
+            if let Some(due) = aligned_flush.as_mut() {
+                *due = next_aligned_flush(flush_interval);
+            }
+            if !buffer.is_empty() {
+                if !flush_buffer(&mut buffer, args.top_flows, tx, &mut capture_stats) {
+                    return (capture_stats, None);
+                }
+                buffer_bytes = 0;
+                last_flush = std::time::Instant::now();
+            }
+        }
+
+        // Nothing captured for a while: send an empty batch so the server knows we're alive
+        if let Some(heartbeat) = heartbeat_interval {
+            if buffer.is_empty() && last_flush.elapsed() >= heartbeat {
+                debug!("Sending heartbeat");
+                let _ = tx.try_send(Vec::new());
+                last_flush = std::time::Instant::now();
+            }
+        }
+
+        // Check if channel closed
+        if tx.is_closed() {
+            return (capture_stats, None);
+        }
+
+        // Shutdown requested: hand over the last partial batch before returning
+        if stop.load(Ordering::SeqCst) {
+            flush_buffer_blocking(&mut buffer, args.top_flows, tx, &mut capture_stats);
+            if !capture_stats.is_empty() {
+                capture_stats.log(parser.datalink);
+            }
+            return (capture_stats, None);
+        }
+
+        match source.next_frame() {
+            Ok(frame) => {
+                consecutive_errors = 0;
+                // Keep reading while paused so the kernel buffer doesn't overflow, but drop everything
+                if paused.load(Ordering::Relaxed) {
+                    continue;
+                }
+                let Some((mut key, mut frame_stats)) = parser.parse(&frame, &mut capture_stats) else {
+                    continue;
+                };
+                frame_stats.first_seen = frame.timestamp_ns;
+                frame_stats.last_seen = frame.timestamp_ns;
+                if let Some(program) = &parser.highlight {
+                    frame_stats.highlighted = program.filter(frame.data);
+                }
+                // Before aggregation, so pseudonyms are what flows are keyed by
+                if let Some(anonymizer) = parser.anonymizer.as_mut() {
+                    if !key.src_is_agent {
+                        key.src_ip = anonymizer.anonymize(key.src_ip);
+                        key.src_mac = key.src_mac.map(|mac| anonymizer.anonymize_mac(mac));
+                    }
+                    if !key.dst_is_agent {
+                        key.dst_ip = anonymizer.anonymize(key.dst_ip);
+                        key.dst_mac = key.dst_mac.map(|mac| anonymizer.anonymize_mac(mac));
+                    }
+                }
+
+                if let Some(max_pps) = args.max_pps {
+                    if rate_window.elapsed() >= std::time::Duration::from_secs(1) {
+                        rate_window = std::time::Instant::now();
+                        rate_count = 0;
+                    }
+                    if rate_count >= max_pps {
+                        capture_stats.rate_limited += 1;
+                        continue;
+                    }
+                    rate_count += 1;
+                }
+
+                let key = key.for_aggregation(args);
+                if args.max_flows.is_some() && flow_window.elapsed() >= flush_interval {
+                    flow_window = std::time::Instant::now();
+                    window_flows = 0;
+                }
+
+                // Aggregate, hashing the key once per frame; the key is moved into the map
+                // rather than cloned, and only a new flow counts against --max-flows
+                let stats = match buffer.entry(key) {
+                    std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                    std::collections::hash_map::Entry::Vacant(entry) => {
+                        if let Some(max_flows) = args.max_flows {
+                            if window_flows >= max_flows {
+                                capture_stats.flow_overflow += 1;
+                                continue;
+                            }
+                            window_flows += 1;
+                        }
+                        entry.insert(FlowStats::default())
+                    }
+                };
+                buffer_bytes = buffer_bytes.saturating_add(frame_stats.size.max(0) as u64);
+                stats.add(frame_stats);
+
+                // Buffer full check (soft limit based on entry count to avoid huge maps), or
+                // enough traffic behind it with --batch-bytes
+                if buffer.len() >= args.batch_size || args.batch_bytes.is_some_and(|limit| buffer_bytes >= limit) {
+                    if !flush_buffer(&mut buffer, args.top_flows, tx, &mut capture_stats) {
+                        return (capture_stats, None);
+                    }
+                    buffer_bytes = 0;
+                    last_flush = std::time::Instant::now();
+                }
+            },
+            Err(pcap::Error::TimeoutExpired) => {
+                consecutive_errors = 0;
+                continue;
+            },
+            // A savefile or other finite source ran out
+            Err(pcap::Error::NoMorePackets) => {
+                flush_buffer_blocking(&mut buffer, args.top_flows, tx, &mut capture_stats);
+                return (capture_stats, None);
+            }
+            Err(e) => {
+                warn!(error = %e, "Error reading packet");
+                consecutive_errors += 1;
+                if consecutive_errors >= MAX_CONSECUTIVE_CAPTURE_ERRORS {
+                    flush_buffer_blocking(&mut buffer, args.top_flows, tx, &mut capture_stats);
+                    return (capture_stats, Some(e));
+                }
+            }
+        }
+    }
+}
+
+/// Turns captured frames into flow entries, independent of where the frames come from.
+struct FrameParser<'a> {
+    args: &'a Args,
+    datalink: pcap::Linktype,
+    local_ips: LocalAddrs,
+    /// Present with --reassemble
+    fragments: Option<reassembly::FragmentCache>,
+    /// Present with --dedup-ms
+    dedup: Option<dedup::DedupWindow>,
+    /// Present with --anonymize
+    anonymizer: Option<anonymize::Anonymizer>,
+    /// Present with --highlight-filter
+    highlight: Option<pcap::BpfProgram>,
+    /// Checks the reported link type against the first frames, then is dropped
+    link_probe: Option<linkprobe::LinkProbe>,
+    include_loopback: bool,
+    warned_truncation: bool,
+}
+
+impl<'a> FrameParser<'a> {
+    fn new(args: &'a Args, datalink: pcap::Linktype, local_ips: LocalAddrs) -> Result<Self, String> {
+        let fragments = if args.reassemble {
+            info!(timeout = ?FRAGMENT_TIMEOUT, "IPv4 fragment reassembly enabled");
+            Some(reassembly::FragmentCache::new(FRAGMENT_TIMEOUT))
+        } else {
+            None
+        };
+        let dedup = (args.dedup_ms > 0).then(|| {
+            info!(window_ms = args.dedup_ms, "Dropping duplicate frames");
+            dedup::DedupWindow::new(std::time::Duration::from_millis(args.dedup_ms))
+        });
+        let anonymizer = match (args.anonymize, &args.anon_key) {
+            (true, Some(key)) => {
+                info!(preserve_private = args.anon_preserve_private, "Anonymizing addresses that aren't the agent's");
+                Some(anonymize::Anonymizer::new(anonymize::parse_key(key)?, args.anon_preserve_private))
+            }
+            _ => None,
+        };
+        let highlight = match &args.highlight_filter {
+            Some(expression) => {
+                // Compiled for the capture's link type but run in userspace, on the captured bytes
+                let program = Capture::dead(datalink)
+                    .and_then(|dead| dead.compile(expression, true))
+                    .map_err(|e| format!("Invalid --highlight-filter {:?}: {}", expression, e))?;
+                info!(filter = %expression, "Highlighting flows matching a BPF filter");
+                Some(program)
+            }
+            None => None,
+        };
+        Ok(FrameParser {
+            args,
+            datalink,
+            local_ips,
+            fragments,
+            dedup,
+            anonymizer,
+            highlight,
+            link_probe: Some(linkprobe::LinkProbe::new(datalink)),
+            include_loopback: include_loopback(args),
+            warned_truncation: false,
+        })
+    }
+
+    /// The flow a frame belongs to and what it adds to that flow, or `None` when the frame
+    /// is unparseable, filtered out, or a fragment still waiting for the rest of its datagram.
+    fn parse(&mut self, frame: &source::Frame, capture_stats: &mut CaptureStats) -> Option<(FlowKey, FlowStats)> {
+        use etherparse::{IpHeader, TransportHeader};
+
+        let args = self.args;
+        let datalink = self.datalink;
+
+        capture_stats.frames += 1;
+        let truncated = frame.caplen < frame.len;
+        if truncated {
+            capture_stats.truncated += 1;
+        }
+
+        let headers_result = parse_headers(datalink, frame.data);
+        if let Some(probe) = self.link_probe.as_mut() {
+            let found_ip = headers_result.as_ref().is_ok_and(|headers| headers.ip.is_some());
+            if probe.observe(frame.data, found_ip) {
+                self.link_probe = None;
+            }
+        }
+
+        // With --decap-gre, account tunnelled traffic to the inner endpoints
+        let mut tunnel = None;
+        let headers_result = match headers_result {
+            Ok(outer) if args.decap_gre => match gre_decapsulate(&outer) {
+                Some((outer_src, outer_dst, inner)) => {
+                    tunnel = Some((outer_src, outer_dst));
+                    Ok(inner)
+                }
+                None => Ok(outer),
+            },
+            other => other,
+        };
+        // Offsets into the captured frame only describe the outer packet
+        let decapsulated = tunnel.is_some();
+        let tunnel_is_local = tunnel.is_some_and(|(src, dst)| self.local_ips.contains(&src) || self.local_ips.contains(&dst));
+
+        let headers = match headers_result {
+            Ok(headers) => headers,
+            Err(_) => {
+                capture_stats.parse_failed += 1;
+                if truncated {
+                    capture_stats.truncated_unparsed += 1;
+                    if !self.warned_truncation {
+                        warn!(
+                            caplen = frame.caplen,
+                            len = frame.len,
+                            snaplen = args.snapshot,
+                            "Dropped a truncated frame whose headers couldn't be parsed; consider a larger --snapshot"
+                        );
+                        self.warned_truncation = true;
+                    }
+                }
+                return None;
+            }
+        };
+
+        // Decapsulated headers keep the outer frame's tags, so this holds for tunnels too
+        if !args.vlan.is_empty() && !VlanSelector::selects(&args.vlan, &headers.vlan) {
+            return None;
+        }
+
+        let Some(ip) = headers.ip.as_ref() else {
+            let arp = if args.track_arp { self.parse_arp(&headers, frame) } else { None };
+            if arp.is_none() {
+                capture_stats.non_ip += 1;
+            }
+            return arp;
+        };
+
+        // (id, protocol, offset in bytes, more fragments, declared payload length)
+        let mut fragment = None;
+        let (ttl, dscp) = match ip {
+            IpHeader::Version4(ipv4, _) => (ipv4.time_to_live, ipv4.differentiated_services_code_point),
+            // The traffic class carries the DSCP in its top 6 bits, like the IPv4 ToS
+            IpHeader::Version6(ipv6, _) => (ipv6.hop_limit, ipv6.traffic_class >> 2),
+        };
+        let (src_ip, dst_ip) = match ip {
+            IpHeader::Version4(ipv4, _) => {
+                if self.fragments.is_some() && !decapsulated && (ipv4.more_fragments || ipv4.fragments_offset != 0) {
+                    fragment = Some((
+                        ipv4.identification,
+                        ipv4.protocol,
+                        ipv4.fragments_offset as usize * 8,
+                        ipv4.more_fragments,
+                        ipv4.payload_len as usize,
+                    ));
+                }
+                (
+                    IpAddr::from(ipv4.source),
+                    IpAddr::from(ipv4.destination)
+                )
+            },
+            IpHeader::Version6(ipv6, _) => {
+                if !args.ipv6 {
+                    return None;
+                }
+                (
+                    IpAddr::from(ipv6.source),
+                    IpAddr::from(ipv6.destination)
+                )
+            } 
+        };
+        
+        // Loopback flows show up on `any` and the like; they are noise unless asked for
+        if !self.include_loopback && (src_ip.is_loopback() || dst_ip.is_loopback()) {
+            return None;
+        }
+        let (src_is_agent, dst_is_agent) = self.classify(src_ip, dst_ip);
+        
+         if !args.no_local_filter && !src_is_agent && !dst_is_agent && !tunnel_is_local {
+             return None;
+         }
+        if !args.direction.keeps(src_is_agent, dst_is_agent) {
+            return None;
+        }
+        if !cidr_allows(args, src_ip, dst_ip) {
+            return None;
+        }
+
+        // Drop second copies before they are counted (or fed to reassembly)
+        if let Some(dedup) = self.dedup.as_mut() {
+            let (ip_id, fragment_offset) = match ip {
+                IpHeader::Version4(ipv4, _) => (ipv4.identification, ipv4.fragments_offset),
+                IpHeader::Version6(_, _) => (0, 0),
+            };
+            let transport = match &headers.transport {
+                Some(TransportHeader::Tcp(tcp)) => (tcp.source_port, tcp.destination_port, tcp.sequence_number),
+                Some(TransportHeader::Udp(udp)) => (udp.source_port, udp.destination_port, 0),
+                _ => (0, 0, 0),
+            };
+            let payload_prefix = &headers.payload[..headers.payload.len().min(dedup::PAYLOAD_PREFIX)];
+            let fingerprint = dedup::fingerprint(&(src_ip, dst_ip, ip_id, fragment_offset, transport, frame.len, payload_prefix));
+            if dedup.is_duplicate(fingerprint, std::time::Instant::now()) {
+                capture_stats.duplicates += 1;
+                return None;
+            }
+        }
+
+        // Hold fragments until their datagram is complete, then account for it once
+        let datagram = match (fragment, self.fragments.as_mut(), src_ip, dst_ip) {
+            (Some((id, protocol, offset, more_fragments, payload_len)), Some(cache), IpAddr::V4(src), IpAddr::V4(dst)) => {
+                let data = ip_header_offset(datalink, frame.data)
+                    .and_then(|start| {
+                        let ihl = (*frame.data.get(start)? & 0x0f) as usize * 4;
+                        frame.data.get(start + ihl..)
+                    })
+                    .unwrap_or(&[]);
+                let data = &data[..data.len().min(payload_len)];
+                let fragment = reassembly::Fragment {
+                    src,
+                    dst,
+                    protocol,
+                    id,
+                    offset,
+                    more_fragments,
+                    payload_len,
+                    data,
+                    wire_len: frame.len,
+                };
+                Some(cache.add(fragment, std::time::Instant::now())?)
+            }
+            _ => None,
+        };
+
+        let mut src_port = 0;
+        let mut dst_port = 0;
+        // There is an IP header by now, so anything not recognised below is Other; Unknown is
+        // kept for packets whose transport couldn't be read at all
+        let mut proto = packet::Protocol::Other;
+        let mut payload: &[u8] = headers.payload;
+        let mut size = wire_size(frame.len);
+        let mut packet_count = 1;
+        let mut icmp_type = 0;
+        let mut icmp_code = 0;
+        // Window and MSS, read only with --tcp-detail
+        let mut tcp_detail: Option<(u16, Option<u16>)> = None;
+        
+        if let Some(datagram) = &datagram {
+            size = wire_size(datagram.wire_len);
+            packet_count = datagram.fragments as i64;
+            match datagram.protocol {
+                6 | 17 if datagram.payload.len() >= 4 => {
+                    let segment = &datagram.payload;
+                    src_port = u16::from_be_bytes([segment[0], segment[1]]) as i32;
+                    dst_port = u16::from_be_bytes([segment[2], segment[3]]) as i32;
+                    proto = if datagram.protocol == 6 { packet::Protocol::Tcp } else { packet::Protocol::Udp };
+                    payload = transport_payload(datagram.protocol, segment);
+                    if args.tcp_detail && datagram.protocol == 6 {
+                        tcp_detail = tcp::parse_segment(segment);
+                    }
+                }
+                1 | 58 if datagram.payload.len() >= 2 => {
+                    icmp_type = datagram.payload[0] as i32;
+                    icmp_code = datagram.payload[1] as i32;
+                    proto = packet::Protocol::Icmp;
+                }
+                _ => {}
+            }
+        } else if let Some(transport) = &headers.transport {
+            match transport {
+                TransportHeader::Tcp(tcp) => {
+                    src_port = tcp.source_port as i32;
+                    dst_port = tcp.destination_port as i32;
+                    proto = packet::Protocol::Tcp;
+                    if args.tcp_detail {
+                        tcp_detail = Some((tcp.window_size, tcp::parse_mss(tcp.options())));
+                    }
+                },
+                TransportHeader::Udp(udp) => {
+                    src_port = udp.source_port as i32;
+                    dst_port = udp.destination_port as i32;
+                    proto = packet::Protocol::Udp;
+                },
+                TransportHeader::Icmpv4(icmp) => {
+                    let bytes = icmp.to_bytes();
+                    icmp_type = bytes[0] as i32;
+                    icmp_code = bytes[1] as i32;
+                    proto = packet::Protocol::Icmp;
+                },
+                TransportHeader::Icmpv6(icmp) => {
+                    let bytes = icmp.to_bytes();
+                    icmp_type = bytes[0] as i32;
+                    icmp_code = bytes[1] as i32;
+                    proto = packet::Protocol::Icmp;
+                },
+            }
+        } else if src_ip.is_ipv6() && !decapsulated {
+            // etherparse stops at extension headers it can't follow; walk the chain ourselves
+            let upper = ip_header_offset(datalink, frame.data)
+                .and_then(|offset| frame.data.get(offset..))
+                .and_then(ipv6_upper_layer);
+            if let Some((next_header, transport)) = upper {
+                match next_header {
+                    6 | 17 if transport.len() >= 4 => {
+                        src_port = u16::from_be_bytes([transport[0], transport[1]]) as i32;
+                        dst_port = u16::from_be_bytes([transport[2], transport[3]]) as i32;
+                        proto = if next_header == 6 { packet::Protocol::Tcp } else { packet::Protocol::Udp };
+                        if args.tcp_detail && next_header == 6 {
+                            tcp_detail = tcp::parse_segment(transport);
+                        }
+                    }
+                    58 if transport.len() >= 2 => {
+                        icmp_type = transport[0] as i32;
+                        icmp_code = transport[1] as i32;
+                        proto = packet::Protocol::Icmp;
+                    }
+                    _ => {}
+                }
+            } else {
+                // Truncated or overlong extension chain, or a non-first fragment
+                proto = packet::Protocol::Unknown;
+            }
+        }
+
+        let key = FlowKey {
+            src_ip,
+            dst_ip,
+            src_is_agent,
+            dst_is_agent,
+            proto: proto.into(),
+            src_port,
+            dst_port,
+            icmp_type,
+            icmp_code,
+            // Only Ethernet frames carry a link header; SLL captures leave these unset
+            src_mac: headers.link.as_ref().map(|eth| eth.source),
+            dst_mac: headers.link.as_ref().map(|eth| eth.destination),
+            vlan: outer_vlan_id(&headers.vlan),
+        };
+
+        // Best-effort SNI from a ClientHello carried in this segment
+        let sni = if args.parse_sni && proto == packet::Protocol::Tcp && !payload.is_empty() {
+            tls::parse_sni(payload)
+        } else {
+            None
+        };
+
+        // Plaintext where TLS belongs; the payload is only captured in full with --parse-sni
+        let anomalous = args.flag_anomalies
+            && args.parse_sni
+            && proto == packet::Protocol::Tcp
+            && [src_port, dst_port].iter().any(|&port| u16::try_from(port).is_ok_and(|port| tls::TLS_PORTS.contains(&port)))
+            && tls::looks_plaintext(payload);
+
+        let dns_query = if args.parse_dns && proto == packet::Protocol::Udp && (src_port == 53 || dst_port == 53) {
+            dns::parse_query_name(payload)
+        } else {
+            None
+        };
+
+        let mut flags = 0;
+        if datagram.is_some() {
+            flags |= packet::PacketFlag::Reassembled as u32;
+        }
+        if truncated {
+            flags |= packet::PacketFlag::Truncated as u32;
+        }
+        if decapsulated {
+            flags |= packet::PacketFlag::Decapsulated as u32;
+        }
+
+        let stats = FlowStats {
+            size,
+            packets: packet_count,
+            ttl: Some(ttl),
+            dscp,
+            sni,
+            dns_query,
+            tcp_window: tcp_detail.map(|(window, _)| window),
+            tcp_mss: tcp_detail.and_then(|(_, mss)| mss),
+            anomalous,
+            flags,
+            ..Default::default()
+        };
+        Some((key, stats))
+    }
+
+    /// `(src_is_agent, dst_is_agent)` for a pair of endpoints.
+    fn classify(&self, src_ip: IpAddr, dst_ip: IpAddr) -> (bool, bool) {
+        // On a SPAN/mirror port nothing is ours, so claim nothing, unless --transit asks for
+        // the agent's own flows to be told apart from those passing through
+        if self.args.no_local_filter && !self.args.transit {
+            return (false, false);
+        }
+        (self.local_ips.contains(&src_ip), self.local_ips.contains(&dst_ip))
+    }
+
+    /// ARP has no IP header; keep its binding as an ARP entry between sender and target.
+    fn parse_arp(&self, headers: &etherparse::PacketHeaders, frame: &source::Frame) -> Option<(FlowKey, FlowStats)> {
+        use etherparse::VlanHeader;
+
+        let ether_type = match (&headers.link, &headers.vlan) {
+            (_, Some(VlanHeader::Single(vlan))) => Some(vlan.ether_type),
+            (_, Some(VlanHeader::Double(vlan))) => Some(vlan.inner.ether_type),
+            (Some(eth), None) => Some(eth.ether_type),
+            (None, None) => None,
+        };
+        if ether_type != Some(arp::ETHER_TYPE_ARP) {
+            return None;
+        }
+        let message = arp::parse(headers.payload)?;
+
+        let src_ip = IpAddr::V4(message.sender_ip);
+        let dst_ip = IpAddr::V4(message.target_ip);
+        if !cidr_allows(self.args, src_ip, dst_ip) {
+            return None;
+        }
+        let (src_is_agent, dst_is_agent) = self.classify(src_ip, dst_ip);
+        let key = FlowKey {
+            src_ip,
+            dst_ip,
+            src_is_agent,
+            dst_is_agent,
+            proto: packet::Protocol::Arp.into(),
+            src_port: 0,
+            dst_port: 0,
+            icmp_type: 0,
+            icmp_code: 0,
+            src_mac: Some(message.sender_mac),
+            dst_mac: Some(message.target_mac),
+            vlan: outer_vlan_id(&headers.vlan),
+        };
+        let stats = FlowStats {
+            size: wire_size(frame.len),
+            packets: 1,
+            ..Default::default()
+        };
+        Some((key, stats))
+    }
+}
+
+/// Traffic pattern produced by `--mock`.
+#[derive(clap::ValueEnum, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum MockScenario {
+    /// Random traffic between the agent and a handful of LAN peers
+    Steady,
+    /// Quiet periods interrupted by short high-volume bursts
+    Burst,
+    /// A single peer sweeping the agent's ports
+    Scan,
+    /// Sustained large uploads from the agent to one external host
+    Exfil,
+}
+
+/// Produce the next mock packet for a scenario: the flow it belongs to, its size and
+/// how long to wait (ms) before generating it.
+fn mock_packet(scenario: MockScenario, rng: &mut rand::rngs::StdRng, step: u64, peers: &[IpAddr], localhost: IpAddr) -> (FlowKey, i32, u64) {
+    use rand::Rng;
+
+    let tcp: i32 = packet::Protocol::Tcp.into();
+    let flow = |src: IpAddr, dst: IpAddr, src_port: i32, dst_port: i32| FlowKey {
+        src_ip: src,
+        dst_ip: dst,
+        src_is_agent: src == localhost,
+        dst_is_agent: dst == localhost,
+        proto: tcp,
+        src_port,
+        dst_port,
+        icmp_type: 0,
+        icmp_code: 0,
+        src_mac: None,
+        dst_mac: None,
+        vlan: None,
+    };
+
+    match scenario {
+        MockScenario::Steady => {
+            let peer = peers[rng.gen_range(0..peers.len())];
+            let (src, dst) = if rng.gen_bool(0.5) { (localhost, peer) } else { (peer, localhost) };
+            (flow(src, dst, 0, 0), rng.gen_range(64..1500), rng.gen_range(0..2))
+        }
+        MockScenario::Burst => {
+            // 1000 back-to-back packets, then 100 packets spaced 20ms apart
+            let bursting = step % 1100 < 1000;
+            let peer = peers[rng.gen_range(0..peers.len())];
+            let (src, dst) = if rng.gen_bool(0.5) { (localhost, peer) } else { (peer, localhost) };
+            if bursting {
+                (flow(src, dst, 0, 0), rng.gen_range(1000..1500), 0)
+            } else {
+                (flow(src, dst, 0, 0), rng.gen_range(64..200), 20)
+            }
+        }
+        MockScenario::Scan => {
+            let scanner = peers[0];
+            let dst_port = 1 + (step % 1024) as i32;
+            if rng.gen_bool(0.2) {
+                // RST from the agent for a closed port
+                (flow(localhost, scanner, dst_port, 40000), 54, 1)
+            } else {
+                (flow(scanner, localhost, 40000, dst_port), 60, 1)
+            }
+        }
+        MockScenario::Exfil => {
+            let sink = IpAddr::V4(std::net::Ipv4Addr::new(203, 0, 113, 50));
+            if step % 10 == 9 {
+                // Occasional ACK coming back
+                (flow(sink, localhost, 443, 51515), 66, rng.gen_range(0..2))
+            } else {
+                (flow(localhost, sink, 51515, 443), rng.gen_range(1400..1500), rng.gen_range(0..2))
+            }
+        }
+    }
+}
+
+/// Returns true once `--mock-count` packets have been generated and handed over; otherwise
+/// runs until stopped or the channel closes and returns false.
+async fn generate_mock_traffic(tx: mpsc::Sender<Vec<Packet>>, args: &Args, stop: Arc<AtomicBool>) -> bool {
+    if let Some(path) = &args.mock_trace {
+        // Read successfully at startup, but the file may have changed since
+        match trace::load(path, args) {
+            Ok(flows) => return trace::replay(flows, tx, args, stop).await,
+            Err(e) => error!(error = %e, "Failed to read --mock-trace, generating random mock traffic instead"),
+        }
+    }
+    let peers = vec![
+        IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 10)), 
+        IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 20)), 
+        IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 5)), 
+        IpAddr::V4(std::net::Ipv4Addr::new(172, 16, 0, 3))
+    ];
+    let localhost = IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1));
+
+    use rand::SeedableRng;
+    let mut rng = match args.mock_seed {
+        Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+        None => rand::rngs::StdRng::from_entropy(),
+    };
+
+    let mut buffer: HashMap<FlowKey, FlowStats> = HashMap::with_capacity(args.batch_size);
+    let mut last_flush = std::time::Instant::now();
+    let flush_interval = std::time::Duration::from_millis(args.batch_interval);
+    let mut buffer_bytes: u64 = 0;
+    let mut step: u64 = 0;
+
+    loop {
+        // Mock flush timer
+        if last_flush.elapsed() >= flush_interval {
+            if !buffer.is_empty() && !flush_buffer_async(&mut buffer, args.top_flows, &tx).await {
+                return false;
+            }
+            buffer_bytes = 0;
+            last_flush = std::time::Instant::now();
+        }
+
+        if args.mock_count.is_some_and(|count| step >= count) {
+            return flush_buffer_async(&mut buffer, args.top_flows, &tx).await;
+        }
+
+        let (key, size, delay) = mock_packet(args.mock_scenario, &mut rng, step, &peers, localhost);
+        step += 1;
+        if delay > 0 {
+             sleep(Duration::from_millis(delay)).await;
+        }
+
+        if tx.is_closed() { return false; }
+
+        if stop.load(Ordering::SeqCst) {
+            flush_buffer_async(&mut buffer, args.top_flows, &tx).await;
+            return false;
+        }
+
+        let stats = buffer.entry(key.for_aggregation(args)).or_default();
+        stats.size += size;
+        stats.packets += 1;
+        buffer_bytes = buffer_bytes.saturating_add(size.max(0) as u64);
+        
+        if buffer.len() >= args.batch_size || args.batch_bytes.is_some_and(|limit| buffer_bytes >= limit) {
+            if !flush_buffer_async(&mut buffer, args.top_flows, &tx).await { return false; }
+            buffer_bytes = 0;
+            last_flush = std::time::Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const AGENT_V4: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 10);
+    const REMOTE_V4: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+    const AGENT_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x10);
+    const REMOTE_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 0x7);
+    const AGENT_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x10];
+    const REMOTE_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x07];
+
+    fn args(extra: &[&str]) -> Args {
+        let mut args = Args::try_parse_from(std::iter::once("mikaboshi-agent").chain(extra.iter().copied())).unwrap();
+        // Otherwise the parser asks pcap whether the device is a loopback one
+        args.include_loopback = args.include_loopback.or(Some(false));
+        args
+    }
+
+    fn local_addrs() -> LocalAddrs {
+        LocalAddrs {
+            ips: [IpAddr::V4(AGENT_V4), IpAddr::V6(AGENT_V6)].into_iter().collect(),
+            nets: Vec::new(),
+        }
+    }
+
+    fn tcp(src_port: u16, dst_port: u16) -> Vec<u8> {
+        let mut segment = Vec::new();
+        segment.extend(src_port.to_be_bytes());
+        segment.extend(dst_port.to_be_bytes());
+        // Sequence and acknowledgement numbers, a 5-word header with SYN set, a full window
+        segment.extend([0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x02, 0xff, 0xff, 0, 0, 0, 0]);
+        segment
+    }
+
+    fn udp(src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::new();
+        datagram.extend(src_port.to_be_bytes());
+        datagram.extend(dst_port.to_be_bytes());
+        datagram.extend((8 + payload.len() as u16).to_be_bytes());
+        datagram.extend([0, 0]);
+        datagram.extend(payload);
+        datagram
+    }
+
+    fn ipv4(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0];
+        packet.extend((20 + payload.len() as u16).to_be_bytes());
+        // ID 0, don't fragment, TTL 64; the checksum isn't checked
+        packet.extend([0, 0, 0x40, 0, 64, protocol, 0, 0]);
+        packet.extend(src.octets());
+        packet.extend(dst.octets());
+        packet.extend(payload);
+        packet
+    }
+
+    fn ipv6(src: Ipv6Addr, dst: Ipv6Addr, next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend((payload.len() as u16).to_be_bytes());
+        packet.extend([next_header, 64]);
+        packet.extend(src.octets());
+        packet.extend(dst.octets());
+        packet.extend(payload);
+        packet
+    }
+
+    fn ethernet(src: [u8; 6], dst: [u8; 6], ether_type: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend(dst);
+        frame.extend(src);
+        frame.extend(ether_type.to_be_bytes());
+        frame.extend(payload);
+        frame
+    }
+
+    /// Run `frames` of `datalink` through a parser, returning what each one gave.
+    fn parse_all(args: &Args, datalink: pcap::Linktype, frames: Vec<Vec<u8>>) -> (Vec<Option<(FlowKey, FlowStats)>>, CaptureStats) {
+        let mut parser = FrameParser::new(args, datalink, local_addrs()).unwrap();
+        let mut source = SliceSource::new(datalink, frames);
+        let mut capture_stats = CaptureStats::default();
+        let mut parsed = Vec::new();
+        while let Ok(frame) = source.next_frame() {
+            parsed.push(parser.parse(&frame, &mut capture_stats));
+        }
+        (parsed, capture_stats)
+    }
+
+    fn parse_one(args: &Args, datalink: pcap::Linktype, frame: Vec<u8>) -> (Option<(FlowKey, FlowStats)>, CaptureStats) {
+        let (mut parsed, capture_stats) = parse_all(args, datalink, vec![frame]);
+        (parsed.remove(0), capture_stats)
+    }
+
+    #[test]
+    fn parses_ethernet_ipv4_tcp() {
+        let frame = ethernet(AGENT_MAC, REMOTE_MAC, 0x0800, &ipv4(AGENT_V4, REMOTE_V4, 6, &tcp(40000, 443)));
+        let (parsed, capture_stats) = parse_one(&args(&[]), LINKTYPE_ETHERNET, frame);
+
+        let (key, stats) = parsed.expect("frame dropped");
+        assert_eq!(
+            key,
+            FlowKey {
+                src_ip: IpAddr::V4(AGENT_V4),
+                dst_ip: IpAddr::V4(REMOTE_V4),
+                src_is_agent: true,
+                dst_is_agent: false,
+                proto: packet::Protocol::Tcp.into(),
+                src_port: 40000,
+                dst_port: 443,
+                icmp_type: 0,
+                icmp_code: 0,
+                src_mac: Some(AGENT_MAC),
+                dst_mac: Some(REMOTE_MAC),
+                vlan: None,
+            }
+        );
+        assert_eq!(stats.size, 54);
+        assert_eq!(stats.packets, 1);
+        assert_eq!(stats.ttl, Some(64));
+        assert_eq!(stats.flags, 0);
+        assert_eq!(capture_stats.frames, 1);
+        assert!(capture_stats.is_empty());
+    }
+
+    #[test]
+    fn parses_raw_ipv4_udp() {
+        let frame = ipv4(REMOTE_V4, AGENT_V4, 17, &udp(53, 50000, &[0; 12]));
+        let (parsed, _) = parse_one(&args(&[]), LINKTYPE_RAW, frame);
+
+        let (key, stats) = parsed.expect("frame dropped");
+        assert_eq!((key.src_ip, key.dst_ip), (IpAddr::V4(REMOTE_V4), IpAddr::V4(AGENT_V4)));
+        assert_eq!((key.src_is_agent, key.dst_is_agent), (false, true));
+        assert_eq!(key.proto, i32::from(packet::Protocol::Udp));
+        assert_eq!((key.src_port, key.dst_port), (53, 50000));
+        // No link header on a raw capture
+        assert_eq!((key.src_mac, key.dst_mac), (None, None));
+        assert_eq!(stats.size, 40);
+    }
+
+    #[test]
+    fn parses_ethernet_ipv6_tcp() {
+        let frame = ethernet(REMOTE_MAC, AGENT_MAC, 0x86dd, &ipv6(REMOTE_V6, AGENT_V6, 6, &tcp(443, 40000)));
+        let (parsed, capture_stats) = parse_one(&args(&["--ipv6"]), LINKTYPE_ETHERNET, frame);
+
+        let (key, stats) = parsed.expect("frame dropped");
+        assert_eq!((key.src_ip, key.dst_ip), (IpAddr::V6(REMOTE_V6), IpAddr::V6(AGENT_V6)));
+        assert_eq!((key.src_is_agent, key.dst_is_agent), (false, true));
+        assert_eq!(key.proto, i32::from(packet::Protocol::Tcp));
+        assert_eq!((key.src_port, key.dst_port), (443, 40000));
+        assert_eq!(stats.size, 74);
+        assert_eq!(stats.ttl, Some(64));
+        assert_eq!(capture_stats.frames, 1);
+    }
+
+    #[test]
+    fn drops_ipv6_without_the_flag() {
+        let frame = ethernet(REMOTE_MAC, AGENT_MAC, 0x86dd, &ipv6(REMOTE_V6, AGENT_V6, 6, &tcp(443, 40000)));
+        let (parsed, capture_stats) = parse_one(&args(&[]), LINKTYPE_ETHERNET, frame);
+        assert!(parsed.is_none());
+        assert_eq!(capture_stats.frames, 1);
+    }
+
+    #[test]
+    fn drops_flows_not_involving_the_agent() {
+        let other = Ipv4Addr::new(203, 0, 113, 1);
+        let frame = ethernet(REMOTE_MAC, AGENT_MAC, 0x0800, &ipv4(REMOTE_V4, other, 6, &tcp(40000, 80)));
+        let (parsed, _) = parse_one(&args(&[]), LINKTYPE_ETHERNET, frame.clone());
+        assert!(parsed.is_none());
+
+        let (parsed, _) = parse_one(&args(&["--no-local-filter"]), LINKTYPE_ETHERNET, frame);
+        assert!(parsed.is_some());
+    }
+
+    #[test]
+    fn counts_unparseable_and_truncated_frames() {
+        let mut frame = ethernet(AGENT_MAC, REMOTE_MAC, 0x0800, &ipv4(AGENT_V4, REMOTE_V4, 6, &tcp(40000, 443)));
+        frame.truncate(20);
+        let args = args(&[]);
+        let mut parser = FrameParser::new(&args, LINKTYPE_ETHERNET, local_addrs()).unwrap();
+        let mut capture_stats = CaptureStats::default();
+        let frame = source::Frame {
+            data: &frame,
+            caplen: 20,
+            len: 54,
+            timestamp_ns: 0,
+        };
+        assert!(parser.parse(&frame, &mut capture_stats).is_none());
+        assert_eq!(capture_stats.frames, 1);
+        assert_eq!(capture_stats.parse_failed, 1);
+        assert_eq!(capture_stats.truncated, 1);
+        assert_eq!(capture_stats.truncated_unparsed, 1);
+    }
+
+    #[test]
+    fn unrecognised_transport_is_other() {
+        // SCTP, which the parser has no reader for
+        let frame = ethernet(AGENT_MAC, REMOTE_MAC, 0x0800, &ipv4(AGENT_V4, REMOTE_V4, 132, &[0; 12]));
+        let (parsed, _) = parse_one(&args(&[]), LINKTYPE_ETHERNET, frame);
+        let (key, _) = parsed.expect("frame dropped");
+        assert_eq!(key.proto, i32::from(packet::Protocol::Other));
+        assert_eq!((key.src_port, key.dst_port), (0, 0));
+    }
+
+    #[test]
+    fn merging_keeps_the_earliest_and_latest_capture_times() {
+        let seen = |first_seen, last_seen| FlowStats {
+            size: 100,
+            packets: 1,
+            first_seen,
+            last_seen,
+            ..Default::default()
+        };
+        let mut stats = FlowStats::default();
+        stats.add(seen(2_000, 2_000));
+        stats.add(seen(1_000, 1_500));
+        stats.add(seen(3_000, 3_000));
+        // Out of order, and inside what's already covered
+        stats.add(seen(1_200, 2_500));
+        assert_eq!((stats.first_seen, stats.last_seen), (1_000, 3_000));
+        assert_eq!((stats.size, stats.packets), (400, 4));
+
+        // A packet without a capture time moves neither end
+        stats.add(seen(0, 0));
+        assert_eq!((stats.first_seen, stats.last_seen), (1_000, 3_000));
+    }
+
+    #[test]
+    fn capture_times_follow_the_frames() {
+        let frames = vec![ipv4(AGENT_V4, REMOTE_V4, 6, &tcp(40000, 443)); 3];
+        let (parsed, _) = parse_all(&args(&[]), LINKTYPE_RAW, frames);
+        let mut merged = FlowStats::default();
+        for (i, flow) in parsed.into_iter().enumerate() {
+            let (_, mut stats) = flow.expect("frame dropped");
+            // capture_loop stamps each frame with its capture time; here they are 1 ms apart
+            stats.first_seen = (i as u64 + 1) * 1_000_000;
+            stats.last_seen = stats.first_seen;
+            merged.add(stats);
+        }
+        let packet = packet_from_key(
+            FlowKey {
+                src_ip: IpAddr::V4(AGENT_V4),
+                dst_ip: IpAddr::V4(REMOTE_V4),
+                src_is_agent: true,
+                dst_is_agent: false,
+                proto: packet::Protocol::Tcp.into(),
+                src_port: 40000,
+                dst_port: 443,
+                icmp_type: 0,
+                icmp_code: 0,
+                src_mac: None,
+                dst_mac: None,
+                vlan: None,
+            },
+            merged,
+        );
+        assert_eq!((packet.first_seen, packet.last_seen), (1, 3));
+        assert_eq!((packet.first_seen_ns, packet.last_seen_ns), (1_000_000, 3_000_000));
+        assert_eq!(packet.packets, 3);
+    }
+
+    #[test]
+    fn client_to_server_ignores_which_side_is_the_agent() {
+        for (src_is_agent, dst_is_agent) in [(true, false), (false, true), (true, true), (false, false)] {
+            let flow = |src_port, dst_port| {
+                let key = FlowKey {
+                    src_ip: IpAddr::V4(AGENT_V4),
+                    dst_ip: IpAddr::V4(REMOTE_V4),
+                    src_is_agent,
+                    dst_is_agent,
+                    proto: packet::Protocol::Tcp.into(),
+                    src_port,
+                    dst_port,
+                    icmp_type: 0,
+                    icmp_code: 0,
+                    src_mac: None,
+                    dst_mac: None,
+                    vlan: None,
+                };
+                packet_from_key(key, FlowStats::default())
+            };
+            let agents = (src_is_agent, dst_is_agent);
+            assert!(flow(40000, 443).client_to_server, "{:?}", agents);
+            assert!(!flow(443, 40000).client_to_server, "{:?}", agents);
+            // Nothing to go by with equal ports, or none
+            assert!(!flow(5000, 5000).client_to_server, "{:?}", agents);
+            assert!(!flow(0, 0).client_to_server, "{:?}", agents);
+        }
+    }
+
+    #[test]
+    fn top_flows_flag_the_folded_rest() {
+        let mut buffer = HashMap::new();
+        for (port, size) in [(40000, 900), (40001, 50), (40002, 30)] {
+            let frame = ethernet(AGENT_MAC, REMOTE_MAC, 0x0800, &ipv4(AGENT_V4, REMOTE_V4, 6, &tcp(port, 443)));
+            let (key, stats) = parse_one(&args(&[]), LINKTYPE_ETHERNET, frame).0.expect("frame dropped");
+            buffer.insert(key, FlowStats { size, ..stats });
+        }
+        keep_top_flows(&mut buffer, 1);
+
+        assert_eq!(buffer.len(), 2);
+        let other_flows = packet::PacketFlag::OtherFlows as u32;
+        let (kept, kept_stats) = buffer.iter().find(|(key, _)| key.src_port == 40000).expect("top flow folded");
+        assert!(kept.src_is_agent);
+        assert_eq!(kept_stats.flags & other_flows, 0);
+        let (rest, rest_stats) = buffer.iter().find(|(key, _)| key.src_port == 0).expect("no entry for the rest");
+        assert_eq!(rest.src_ip, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert!(!rest.src_is_agent && !rest.dst_is_agent);
+        assert_eq!((rest_stats.size, rest_stats.packets), (80, 2));
+        assert_ne!(rest_stats.flags & other_flows, 0);
+    }
+
+    #[test]
+    fn anonymized_macs_are_stable_local_pseudonyms() {
+        let anonymizer = anonymize::Anonymizer::new([7; 32], false);
+        let mapped = anonymizer.anonymize_mac(REMOTE_MAC);
+        assert_ne!(mapped, REMOTE_MAC);
+        assert_eq!(mapped, anonymizer.anonymize_mac(REMOTE_MAC));
+        assert_ne!(mapped, anonymizer.anonymize_mac(AGENT_MAC));
+        // Unicast and locally administered
+        assert_eq!(mapped[0] & 0x03, 0x02);
+        assert_eq!(anonymizer.anonymize_mac([0xff; 6]), [0xff; 6]);
+        assert_eq!(anonymizer.anonymize_mac([0; 6]), [0; 6]);
+    }
+
+    /// `(src_is_agent, dst_is_agent)` of an agent-sourced, an agent-bound and a transit flow.
+    fn classifications(extra: &[&str]) -> Vec<(bool, bool)> {
+        let other = Ipv4Addr::new(203, 0, 113, 1);
+        let frames = [(AGENT_V4, REMOTE_V4), (REMOTE_V4, AGENT_V4), (REMOTE_V4, other)]
+            .into_iter()
+            .map(|(src, dst)| ipv4(src, dst, 6, &tcp(40000, 443)))
+            .collect();
+        let (parsed, _) = parse_all(&args(extra), LINKTYPE_RAW, frames);
+        parsed
+            .into_iter()
+            .map(|flow| {
+                let (key, _) = flow.expect("frame dropped");
+                (key.src_is_agent, key.dst_is_agent)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn transit_tells_agent_flows_apart() {
+        assert_eq!(
+            classifications(&["--no-local-filter", "--transit"]),
+            [(true, false), (false, true), (false, false)]
+        );
+        // A mirror port without --transit claims nothing
+        assert_eq!(classifications(&["--no-local-filter"]), [(false, false); 3]);
+    }
+
+    /// An 802.1Q tag for `vlan` followed by `ether_type`.
+    fn vlan_tag(vlan: u16, ether_type: u16, payload: &[u8]) -> Vec<u8> {
+        let mut tagged = Vec::new();
+        tagged.extend(vlan.to_be_bytes());
+        tagged.extend(ether_type.to_be_bytes());
+        tagged.extend(payload);
+        tagged
+    }
+
+    /// Which of an untagged, a VLAN 10, a VLAN 20 and a double-tagged (outer 10, inner 20)
+    /// frame `--vlan` keeps.
+    fn vlan_kept(selectors: &str) -> [bool; 4] {
+        let ip = ipv4(AGENT_V4, REMOTE_V4, 6, &tcp(40000, 443));
+        let frames = vec![
+            ethernet(AGENT_MAC, REMOTE_MAC, 0x0800, &ip),
+            ethernet(AGENT_MAC, REMOTE_MAC, 0x8100, &vlan_tag(10, 0x0800, &ip)),
+            ethernet(AGENT_MAC, REMOTE_MAC, 0x8100, &vlan_tag(20, 0x0800, &ip)),
+            ethernet(AGENT_MAC, REMOTE_MAC, 0x88a8, &vlan_tag(10, 0x8100, &vlan_tag(20, 0x0800, &ip))),
+        ];
+        let (parsed, _) = parse_all(&args(&["--vlan", selectors]), LINKTYPE_ETHERNET, frames);
+        let kept: Vec<bool> = parsed.iter().map(Option::is_some).collect();
+        kept.try_into().unwrap()
+    }
+
+    #[test]
+    fn vlan_filter_keeps_tagged_frames() {
+        assert_eq!(vlan_kept("10"), [false, true, false, true]);
+        assert_eq!(vlan_kept("20"), [false, false, true, false]);
+        assert_eq!(vlan_kept("10,20"), [false, true, true, true]);
+    }
+
+    #[test]
+    fn vlan_filter_keeps_untagged_frames() {
+        assert_eq!(vlan_kept("untagged"), [true, false, false, false]);
+        assert_eq!(vlan_kept("untagged,20"), [true, false, true, false]);
+    }
+
+    #[test]
+    fn tagged_frames_report_their_outer_vlan() {
+        let ip = ipv4(AGENT_V4, REMOTE_V4, 6, &tcp(40000, 443));
+        let frame = ethernet(AGENT_MAC, REMOTE_MAC, 0x88a8, &vlan_tag(10, 0x8100, &vlan_tag(20, 0x0800, &ip)));
+        let (parsed, _) = parse_one(&args(&[]), LINKTYPE_ETHERNET, frame);
+        let (key, stats) = parsed.expect("frame dropped");
+        assert_eq!(key.vlan, Some(10));
+        assert_eq!(key.dst_port, 443);
+        assert_eq!(stats.size, 62);
+    }
+
+    #[test]
+    fn batch_size_limits() {
+        assert!(validate_batching(&mut args(&["--batch-size", "0"])).is_err());
+
+        let cases = [
+            (1, 1),
+            (MAX_BATCH_SIZE, MAX_BATCH_SIZE),
+            (MAX_BATCH_SIZE + 1, MAX_BATCH_SIZE),
+            (usize::MAX, MAX_BATCH_SIZE),
+        ];
+        for (given, kept) in cases {
+            let mut args = args(&["--batch-size", &given.to_string()]);
+            validate_batching(&mut args).unwrap();
+            assert_eq!(args.batch_size, kept, "--batch-size {}", given);
+        }
+    }
+
+    #[test]
+    fn batch_interval_limits() {
+        let cases = [
+            (0, MIN_BATCH_INTERVAL_MS),
+            (MIN_BATCH_INTERVAL_MS - 1, MIN_BATCH_INTERVAL_MS),
+            (MIN_BATCH_INTERVAL_MS, MIN_BATCH_INTERVAL_MS),
+            (u64::MAX, u64::MAX),
+        ];
+        for (given, kept) in cases {
+            let mut args = args(&["--batch-interval", &given.to_string()]);
+            validate_batching(&mut args).unwrap();
+            assert_eq!(args.batch_interval, kept, "--batch-interval {}", given);
+        }
+    }
+
+    #[test]
+    fn unknown_datalink_fails_startup() {
+        assert!(matches!(prepare(&mut args(&["--datalink", "NOT_A_DLT"])), Err(AgentError::Invalid(_))));
+        prepare(&mut args(&["--datalink", "EN10MB"])).unwrap();
+        prepare(&mut args(&["--datalink", "1"])).unwrap();
+    }
+
+    /// A hop-by-hop options header (one PadN option) in front of `next_header`.
+    fn hop_by_hop(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = vec![next_header, 0, 1, 4, 0, 0, 0, 0];
+        header.extend(payload);
+        header
+    }
+
+    #[test]
+    fn walks_hop_by_hop_to_tcp() {
+        let segment = tcp(40000, 22);
+        let ip = ipv6(AGENT_V6, REMOTE_V6, 0, &hop_by_hop(6, &segment));
+
+        assert_eq!(ipv6_upper_layer(&ip), Some((6, &segment[..])));
+
+        let headers = parse_headers(LINKTYPE_RAW, &ip).unwrap();
+        match headers.transport {
+            Some(etherparse::TransportHeader::Tcp(header)) => assert_eq!((header.source_port, header.destination_port), (40000, 22)),
+            other => panic!("expected TCP, got {:?}", other),
+        }
+
+        let (parsed, _) = parse_one(&args(&["--ipv6"]), LINKTYPE_RAW, ip);
+        let (key, _) = parsed.expect("frame dropped");
+        assert_eq!(key.proto, i32::from(packet::Protocol::Tcp));
+        assert_eq!((key.src_port, key.dst_port), (40000, 22));
+    }
+
+    #[test]
+    fn gives_up_on_broken_ipv6_extension_chains() {
+        // Cut off inside the hop-by-hop header
+        let ip = ipv6(AGENT_V6, REMOTE_V6, 0, &[6, 0, 1]);
+        assert_eq!(ipv6_upper_layer(&ip), None);
+
+        // A fragment at a non-zero offset carries no transport header
+        let ip = ipv6(AGENT_V6, REMOTE_V6, 44, &[6, 0, 0, 0x08, 0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(ipv6_upper_layer(&ip), None);
+    }
+
+    /// EN10MB, LINUX_SLL, RAW and NULL (read as Ethernet).
+    const FUZZ_LINK_TYPES: [pcap::Linktype; 4] = [LINKTYPE_ETHERNET, LINKTYPE_SLL, LINKTYPE_RAW, pcap::Linktype(0)];
+
+    /// Random bytes, and valid frames with random bytes flipped or cut off, must never make
+    /// the parser panic.
+    #[test]
+    fn parsing_random_bytes_never_panics() {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(626);
+        // Every optional parser on, so their code paths see the garbage too
+        let args = args(&[
+            "--ipv6",
+            "--no-local-filter",
+            "--track-arp",
+            "--reassemble",
+            "--decap-gre",
+            "--parse-sni",
+            "--parse-dns",
+            "--tcp-detail",
+            "--flag-anomalies",
+        ]);
+        let seeds = [
+            ethernet(AGENT_MAC, REMOTE_MAC, 0x0800, &ipv4(AGENT_V4, REMOTE_V4, 6, &tcp(40000, 443))),
+            ethernet(AGENT_MAC, REMOTE_MAC, 0x86dd, &ipv6(AGENT_V6, REMOTE_V6, 0, &[6, 0, 0, 0, 0, 0, 0, 0])),
+            ipv4(AGENT_V4, REMOTE_V4, 17, &udp(50000, 53, &[0; 16])),
+            ipv4(AGENT_V4, REMOTE_V4, 47, &[0, 0, 0x08, 0, 0x45]),
+        ];
+        for datalink in FUZZ_LINK_TYPES {
+            let mut parser = FrameParser::new(&args, datalink, local_addrs()).unwrap();
+            let mut capture_stats = CaptureStats::default();
+            for round in 0..5000 {
+                let data: Vec<u8> = if round % 2 == 0 {
+                    let len = rng.gen_range(0..128);
+                    (0..len).map(|_| rng.gen()).collect()
+                } else {
+                    let mut data = seeds[rng.gen_range(0..seeds.len())].clone();
+                    for _ in 0..rng.gen_range(1..4) {
+                        let at = rng.gen_range(0..data.len());
+                        data[at] = rng.gen();
+                    }
+                    data.truncate(rng.gen_range(0..=data.len()));
+                    data
+                };
+                let frame = source::Frame {
+                    data: &data,
+                    caplen: data.len() as u32,
+                    len: rng.gen(),
+                    timestamp_ns: 0,
+                };
+                let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    let _ = parse_headers(datalink, &data);
+                    parser.parse(&frame, &mut capture_stats);
+                }));
+                assert!(outcome.is_ok(), "link type {} panicked on {:02x?}", datalink.0, data);
+            }
+        }
+    }
+
+    #[test]
+    fn slice_source_ends_like_a_savefile() {
+        let mut source = SliceSource::new(LINKTYPE_RAW, vec![vec![1, 2, 3]]);
+        let frame = source.next_frame().unwrap();
+        assert_eq!(frame.data, &[1, 2, 3]);
+        assert_eq!((frame.caplen, frame.len), (3, 3));
+        assert!(matches!(source.next_frame(), Err(pcap::Error::NoMorePackets)));
+    }
+}
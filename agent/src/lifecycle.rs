@@ -0,0 +1,89 @@
+//! Connection lifecycle events and the optional `--metrics-addr` endpoint.
+//!
+//! Every transition (connected, streaming, disconnected, reconnecting) is logged with an
+//! `event` field plus the server and attempt number, so `--log-format json` output can be
+//! alerted on directly. The same transitions are counted for scraping in Prometheus text format.
+
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+
+#[derive(Default)]
+pub struct Lifecycle {
+    connects: AtomicU64,
+    disconnects: AtomicU64,
+    reconnects: AtomicU64,
+    streaming: AtomicBool,
+}
+
+impl Lifecycle {
+    /// `attempt` counts connection attempts since the last successful one, starting at 1.
+    pub fn connected(&self, server: &str, attempt: u64) {
+        self.connects.fetch_add(1, Ordering::Relaxed);
+        info!(event = "connected", server = %server, attempt, "Connected to server");
+    }
+
+    pub fn streaming(&self, server: &str) {
+        self.streaming.store(true, Ordering::Relaxed);
+        info!(event = "streaming", server = %server, "Streaming packets to server");
+    }
+
+    /// `server` is `None` when no server could be reached at all.
+    pub fn disconnected(&self, server: Option<&str>, attempt: u64, error: &dyn Display) {
+        self.disconnects.fetch_add(1, Ordering::Relaxed);
+        self.streaming.store(false, Ordering::Relaxed);
+        warn!(event = "disconnected", server = server.unwrap_or(""), attempt, error = %error, "Agent disconnected or failed");
+    }
+
+    pub fn reconnecting(&self, attempt: u64, delay: Duration) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+        info!(event = "reconnecting", attempt, delay_secs = delay.as_secs(), "Reconnecting");
+    }
+
+    fn render(&self) -> String {
+        format!(
+            "# TYPE mikaboshi_agent_connects_total counter\n\
+             mikaboshi_agent_connects_total {}\n\
+             # TYPE mikaboshi_agent_disconnects_total counter\n\
+             mikaboshi_agent_disconnects_total {}\n\
+             # TYPE mikaboshi_agent_reconnects_total counter\n\
+             mikaboshi_agent_reconnects_total {}\n\
+             # TYPE mikaboshi_agent_streaming gauge\n\
+             mikaboshi_agent_streaming {}\n",
+            self.connects.load(Ordering::Relaxed),
+            self.disconnects.load(Ordering::Relaxed),
+            self.reconnects.load(Ordering::Relaxed),
+            u8::from(self.streaming.load(Ordering::Relaxed)),
+        )
+    }
+}
+
+/// Answer every HTTP request on `addr` with the lifecycle counters, whatever its path.
+pub async fn serve(addr: SocketAddr, lifecycle: Arc<Lifecycle>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(addr = %addr, "Serving agent metrics");
+    loop {
+        let (mut socket, peer) = listener.accept().await?;
+        let lifecycle = lifecycle.clone();
+        tokio::spawn(async move {
+            // The request itself doesn't matter; read it so the client sees a clean close
+            let mut request = [0u8; 1024];
+            let _ = socket.read(&mut request).await;
+            let body = lifecycle.render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            if let Err(e) = socket.write_all(response.as_bytes()).await {
+                debug!(peer = %peer, error = %e, "Failed to answer metrics request");
+            }
+        });
+    }
+}
@@ -0,0 +1,81 @@
+//! Link-type self-check over the first frames of a capture.
+//!
+//! Some drivers report Ethernet but deliver cooked (SLL) or raw IP frames, and then nothing
+//! shows up on the dashboard. While the probe runs, each frame is also parsed as the other
+//! link types we understand; if one of them finds IP headers clearly more often than the
+//! reported type, the agent says so once and suggests `--datalink`. The probe costs nothing
+//! after its sample is taken.
+
+use tracing::warn;
+
+use crate::{link_type_name, parse_headers, LINKTYPE_ETHERNET, LINKTYPE_RAW, LINKTYPE_SLL};
+
+/// Frames sampled before the probe reaches a verdict.
+const SAMPLE_FRAMES: u32 = 1000;
+
+pub struct LinkProbe {
+    reported: pcap::Linktype,
+    frames: u32,
+    /// Frames in which the reported link type led to an IP header
+    reported_ip: u32,
+    /// The same count for each alternative
+    alternatives: Vec<(pcap::Linktype, u32)>,
+}
+
+impl LinkProbe {
+    pub fn new(reported: pcap::Linktype) -> Self {
+        let alternatives = [LINKTYPE_ETHERNET, LINKTYPE_SLL, LINKTYPE_RAW]
+            .into_iter()
+            .filter(|&link| link != reported)
+            .map(|link| (link, 0))
+            .collect();
+        Self {
+            reported,
+            frames: 0,
+            reported_ip: 0,
+            alternatives,
+        }
+    }
+
+    /// Count one frame, given whether the reported link type found an IP header in it.
+    /// Returns true once the sample is complete and the probe can be dropped.
+    pub fn observe(&mut self, data: &[u8], reported_ip: bool) -> bool {
+        self.frames += 1;
+        if reported_ip {
+            self.reported_ip += 1;
+        }
+        for (link, ip) in &mut self.alternatives {
+            if parse_headers(*link, data).is_ok_and(|headers| headers.ip.is_some()) {
+                *ip += 1;
+            }
+        }
+        if self.frames < SAMPLE_FRAMES {
+            return false;
+        }
+        self.report();
+        true
+    }
+
+    fn report(&self) {
+        // Mostly non-IP traffic on the right link type isn't worth a warning
+        if self.reported_ip * 2 >= self.frames {
+            return;
+        }
+        let Some(&(best, best_ip)) = self.alternatives.iter().max_by_key(|(_, ip)| *ip) else {
+            return;
+        };
+        if best_ip <= self.reported_ip * 2 || best_ip * 2 < self.frames {
+            return;
+        }
+        let suggested = link_type_name(best);
+        warn!(
+            link_type = %link_type_name(self.reported),
+            frames = self.frames,
+            ip_frames = self.reported_ip,
+            looks_like = %suggested,
+            looks_like_ip_frames = best_ip,
+            "Captured frames parse better as another link type than the one the device reports; try --datalink {} if the device offers it",
+            suggested
+        );
+    }
+}
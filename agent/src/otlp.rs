@@ -0,0 +1,85 @@
+//! OpenTelemetry export of the aggregated flows (`--otlp-endpoint`).
+//!
+//! Every batch sent to the server is also counted into two OTLP counters, bytes and
+//! packets, with one series per flow. Attribute names follow the OpenTelemetry semantic
+//! conventions so the flows line up with other network telemetry in the same backend.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use opentelemetry::metrics::{Counter, MeterProvider as _, MetricsError, Unit};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::Resource;
+
+use crate::packet::{self, Packet};
+
+/// How often the accumulated counters are pushed to the collector.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct FlowExporter {
+    provider: SdkMeterProvider,
+    bytes: Counter<u64>,
+    packets: Counter<u64>,
+}
+
+impl FlowExporter {
+    /// Start a periodic OTLP/gRPC metrics exporter to `endpoint` (e.g. http://collector:4317).
+    pub fn new(endpoint: &str) -> Result<Self, MetricsError> {
+        let provider = opentelemetry_otlp::new_pipeline()
+            .metrics(opentelemetry_sdk::runtime::Tokio)
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+            .with_period(EXPORT_INTERVAL)
+            .with_resource(Resource::new(vec![KeyValue::new("service.name", "mikaboshi-agent")]))
+            .build()?;
+
+        let meter = provider.meter("mikaboshi-agent");
+        let bytes = meter
+            .u64_counter("mikaboshi.flow.bytes")
+            .with_description("Bytes seen per flow")
+            .with_unit(Unit::new("By"))
+            .init();
+        let packets = meter
+            .u64_counter("mikaboshi.flow.packets")
+            .with_description("Packets seen per flow")
+            .with_unit(Unit::new("{packet}"))
+            .init();
+
+        Ok(Self { provider, bytes, packets })
+    }
+
+    pub fn record(&self, flows: &[Packet]) {
+        for p in flows {
+            let attributes = [
+                KeyValue::new("source.address", address(&p.src_ip, p.is_ipv6)),
+                KeyValue::new("source.port", i64::from(p.src_port)),
+                KeyValue::new("destination.address", address(&p.dst_ip, p.is_ipv6)),
+                KeyValue::new("destination.port", i64::from(p.dst_port)),
+                KeyValue::new(
+                    "network.transport",
+                    packet::Protocol::try_from(p.proto).map(|p| p.as_str_name()).unwrap_or("UNKNOWN").to_lowercase(),
+                ),
+                KeyValue::new("mikaboshi.src_is_agent", p.src_is_agent),
+                KeyValue::new("mikaboshi.dst_is_agent", p.dst_is_agent),
+            ];
+            self.bytes.add(p.size.max(0) as u64, &attributes);
+            self.packets.add(p.packets.max(0) as u64, &attributes);
+        }
+    }
+
+    /// Push whatever has accumulated since the last export and stop the exporter.
+    pub fn shutdown(&self) -> Result<(), MetricsError> {
+        self.provider.shutdown()
+    }
+}
+
+/// The textual form of an address as carried in a `Packet`, or empty when its length is off.
+pub fn address(bytes: &[u8], is_ipv6: bool) -> String {
+    let ip = if is_ipv6 {
+        <[u8; 16]>::try_from(bytes).map(|octets| IpAddr::V6(Ipv6Addr::from(octets))).ok()
+    } else {
+        <[u8; 4]>::try_from(bytes).map(|octets| IpAddr::V4(Ipv4Addr::from(octets))).ok()
+    };
+    ip.map(|ip| ip.to_string()).unwrap_or_default()
+}
@@ -0,0 +1,204 @@
+//! IPv4 fragment reassembly for `--reassemble`.
+//!
+//! Every fragment of a datagram is held until the datagram is complete, so its bytes are
+//! counted against the flow (ports included) of the whole datagram instead of landing in a
+//! portless bucket. Completeness is tracked from the lengths declared in the IP headers, so
+//! it still works when the snapshot length truncates the captured data.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// Incomplete datagrams held at once; fragments of new datagrams are dropped beyond this.
+const MAX_PENDING: usize = 4096;
+/// Fragments held for one datagram; a datagram split finer than this is dropped. Even a
+/// 64 KiB datagram over a 576-byte MTU needs fewer than 128.
+const MAX_FRAGMENTS: usize = 256;
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+struct FragmentKey {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    id: u16,
+}
+
+/// One captured IPv4 fragment.
+pub struct Fragment<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub id: u16,
+    /// Offset of this fragment's data within the datagram payload, in bytes
+    pub offset: usize,
+    pub more_fragments: bool,
+    /// Payload length declared by the IP header
+    pub payload_len: usize,
+    /// Captured payload bytes (may be shorter than `payload_len`)
+    pub data: &'a [u8],
+    /// Original on-the-wire length of the frame carrying this fragment
+    pub wire_len: u32,
+}
+
+/// A datagram whose fragments have all arrived.
+pub struct Datagram {
+    pub protocol: u8,
+    /// Longest contiguous captured prefix of the reassembled payload
+    pub payload: Vec<u8>,
+    /// Sum of the wire lengths of all fragments
+    pub wire_len: u32,
+    /// Number of fragments the datagram arrived in
+    pub fragments: usize,
+}
+
+struct Pending {
+    first_seen: Instant,
+    /// (offset, declared length) of every fragment received, sorted by offset
+    ranges: Vec<(usize, usize)>,
+    data: BTreeMap<usize, Vec<u8>>,
+    total_len: Option<usize>,
+    wire_len: u32,
+}
+
+impl Pending {
+    fn is_complete(&self) -> bool {
+        let Some(total) = self.total_len else {
+            return false;
+        };
+        let mut covered = 0;
+        for &(offset, len) in &self.ranges {
+            if offset > covered {
+                return false;
+            }
+            covered = covered.max(offset + len);
+        }
+        covered >= total
+    }
+
+    fn contiguous_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for (&offset, bytes) in &self.data {
+            if offset > payload.len() {
+                break;
+            }
+            let skip = payload.len() - offset;
+            if skip < bytes.len() {
+                payload.extend_from_slice(&bytes[skip..]);
+            }
+        }
+        payload
+    }
+}
+
+pub struct FragmentCache {
+    pending: HashMap<FragmentKey, Pending>,
+    timeout: Duration,
+}
+
+impl FragmentCache {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            pending: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Add a fragment, returning the datagram once its last missing fragment arrives.
+    pub fn add(&mut self, fragment: Fragment, now: Instant) -> Option<Datagram> {
+        let key = FragmentKey {
+            src: fragment.src,
+            dst: fragment.dst,
+            protocol: fragment.protocol,
+            id: fragment.id,
+        };
+
+        if !self.pending.contains_key(&key) && self.pending.len() >= MAX_PENDING {
+            self.evict_expired(now);
+            if self.pending.len() >= MAX_PENDING {
+                return None;
+            }
+        }
+
+        let pending = self.pending.entry(key.clone()).or_insert_with(|| Pending {
+            first_seen: now,
+            ranges: Vec::new(),
+            data: BTreeMap::new(),
+            total_len: None,
+            wire_len: 0,
+        });
+        // A fragment seen twice (e.g. on both sides of a mirror) is only counted once
+        if pending.data.contains_key(&fragment.offset) {
+            return None;
+        }
+        if pending.ranges.len() >= MAX_FRAGMENTS {
+            self.pending.remove(&key);
+            return None;
+        }
+        let at = pending.ranges.partition_point(|&(offset, _)| offset < fragment.offset);
+        pending.ranges.insert(at, (fragment.offset, fragment.payload_len));
+        pending.data.insert(fragment.offset, fragment.data.to_vec());
+        pending.wire_len = pending.wire_len.saturating_add(fragment.wire_len);
+        if !fragment.more_fragments {
+            pending.total_len = Some(fragment.offset + fragment.payload_len);
+        }
+
+        if !pending.is_complete() {
+            return None;
+        }
+        let pending = self.pending.remove(&key)?;
+        Some(Datagram {
+            protocol: key.protocol,
+            payload: pending.contiguous_payload(),
+            wire_len: pending.wire_len,
+            fragments: pending.ranges.len(),
+        })
+    }
+
+    /// Drop datagrams that have waited longer than the timeout. Returns how many were dropped.
+    pub fn evict_expired(&mut self, now: Instant) -> usize {
+        let before = self.pending.len();
+        let timeout = self.timeout;
+        self.pending.retain(|_, p| now.duration_since(p.first_seen) < timeout);
+        before - self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(offset: usize, more_fragments: bool) -> Fragment<'static> {
+        Fragment {
+            src: Ipv4Addr::new(192, 0, 2, 1),
+            dst: Ipv4Addr::new(192, 0, 2, 2),
+            protocol: 17,
+            id: 7,
+            offset,
+            more_fragments,
+            payload_len: 8,
+            data: &[0; 8],
+            wire_len: 42,
+        }
+    }
+
+    #[test]
+    fn duplicate_fragments_count_once() {
+        let mut cache = FragmentCache::new(Duration::from_secs(30));
+        let now = Instant::now();
+        assert!(cache.add(fragment(0, true), now).is_none());
+        assert!(cache.add(fragment(0, true), now).is_none());
+        let datagram = cache.add(fragment(8, false), now).unwrap();
+        assert_eq!((datagram.fragments, datagram.wire_len, datagram.payload.len()), (2, 84, 16));
+    }
+
+    #[test]
+    fn drops_datagrams_split_into_too_many_fragments() {
+        let mut cache = FragmentCache::new(Duration::from_secs(30));
+        let now = Instant::now();
+        for i in 0..MAX_FRAGMENTS {
+            assert!(cache.add(fragment(i * 8, true), now).is_none());
+        }
+        assert!(cache.add(fragment(MAX_FRAGMENTS * 8, false), now).is_none());
+        assert!(cache.pending.is_empty());
+    }
+}
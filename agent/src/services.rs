@@ -0,0 +1,163 @@
+//! Service names for well-known ports (`--label-services`).
+//!
+//! A built-in table covers the common protocols; `--services-file` adds to it or overrides
+//! it in `/etc/services` format, so that file itself can be passed.
+
+use std::collections::HashMap;
+
+use crate::packet::{Packet, Protocol};
+
+/// Ports labelled out of the box, for TCP and UDP alike.
+const BUILTIN: &[(u16, &str)] = &[
+    (20, "ftp-data"),
+    (21, "ftp"),
+    (22, "ssh"),
+    (23, "telnet"),
+    (25, "smtp"),
+    (53, "dns"),
+    (67, "dhcp"),
+    (68, "dhcp"),
+    (69, "tftp"),
+    (80, "http"),
+    (110, "pop3"),
+    (123, "ntp"),
+    (137, "netbios-ns"),
+    (138, "netbios-dgm"),
+    (139, "netbios-ssn"),
+    (143, "imap"),
+    (161, "snmp"),
+    (162, "snmptrap"),
+    (389, "ldap"),
+    (443, "https"),
+    (445, "smb"),
+    (465, "smtps"),
+    (514, "syslog"),
+    (587, "submission"),
+    (636, "ldaps"),
+    (993, "imaps"),
+    (995, "pop3s"),
+    (1433, "mssql"),
+    (1883, "mqtt"),
+    (3306, "mysql"),
+    (3389, "rdp"),
+    (5353, "mdns"),
+    (5432, "postgresql"),
+    (5672, "amqp"),
+    (6379, "redis"),
+    (8080, "http-alt"),
+    (8443, "https-alt"),
+    (9092, "kafka"),
+    (27017, "mongodb"),
+];
+
+/// Built-in services that only ever run over one transport, for `--flag-anomalies`.
+const SINGLE_TRANSPORT: &[(u16, Protocol)] = &[
+    (20, Protocol::Tcp),
+    (21, Protocol::Tcp),
+    (22, Protocol::Tcp),
+    (23, Protocol::Tcp),
+    (25, Protocol::Tcp),
+    (67, Protocol::Udp),
+    (68, Protocol::Udp),
+    (69, Protocol::Udp),
+    (80, Protocol::Tcp),
+    (110, Protocol::Tcp),
+    (123, Protocol::Udp),
+    (143, Protocol::Tcp),
+    (161, Protocol::Udp),
+    (162, Protocol::Udp),
+    (445, Protocol::Tcp),
+    (465, Protocol::Tcp),
+    (587, Protocol::Tcp),
+    (993, Protocol::Tcp),
+    (995, Protocol::Tcp),
+    (1433, Protocol::Tcp),
+    (3306, Protocol::Tcp),
+    (5353, Protocol::Udp),
+    (5432, Protocol::Tcp),
+    (6379, Protocol::Tcp),
+    (27017, Protocol::Tcp),
+];
+
+pub struct ServiceTable {
+    names: HashMap<(Protocol, u16), String>,
+    /// The one transport a port's service runs over; ports missing here take either
+    transports: HashMap<u16, Protocol>,
+}
+
+impl ServiceTable {
+    pub fn builtin() -> Self {
+        let mut names = HashMap::new();
+        for &(port, name) in BUILTIN {
+            names.insert((Protocol::Tcp, port), name.to_string());
+            names.insert((Protocol::Udp, port), name.to_string());
+        }
+        Self {
+            names,
+            transports: SINGLE_TRANSPORT.iter().copied().collect(),
+        }
+    }
+
+    /// Add the entries of an `/etc/services`-style file (`name port/proto [aliases] [# comment]`),
+    /// replacing built-in names for the same port. A port the file lists for only one of TCP
+    /// and UDP is expected on that transport alone.
+    pub fn extend_from_file(&mut self, path: &str) -> Result<(), String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read services file {}: {}", path, e))?;
+        let mut declared: HashMap<u16, Vec<Protocol>> = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let (Some(name), Some(port_proto)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            let invalid = || format!("{}:{}: expected `name port/proto`, got {:?}", path, number + 1, line.trim());
+            let (port, proto) = port_proto.split_once('/').ok_or_else(invalid)?;
+            let port: u16 = port.parse().map_err(|_| invalid())?;
+            let proto = match proto.to_ascii_lowercase().as_str() {
+                "tcp" => Protocol::Tcp,
+                "udp" => Protocol::Udp,
+                // sctp, ddp and the like never reach a flow's ports
+                _ => continue,
+            };
+            self.names.insert((proto, port), name.to_string());
+            declared.entry(port).or_default().push(proto);
+        }
+        for (port, protos) in declared {
+            if protos.iter().all(|&proto| proto == protos[0]) {
+                self.transports.insert(port, protos[0]);
+            } else {
+                self.transports.remove(&port);
+            }
+        }
+        Ok(())
+    }
+
+    /// Name of the flow's server side: of its two ports, the lower one with a known service.
+    pub fn label(&self, p: &Packet) -> Option<&str> {
+        let proto = match Protocol::try_from(p.proto) {
+            Ok(proto @ (Protocol::Tcp | Protocol::Udp)) => proto,
+            _ => return None,
+        };
+        let (low, high) = if p.src_port <= p.dst_port { (p.src_port, p.dst_port) } else { (p.dst_port, p.src_port) };
+        [low, high]
+            .into_iter()
+            .filter_map(|port| u16::try_from(port).ok())
+            .find_map(|port| self.names.get(&(proto, port)))
+            .map(String::as_str)
+    }
+
+    /// Whether the flow uses the other transport than its server-side service runs over,
+    /// e.g. NTP over TCP (`--flag-anomalies`). Ports are tried lowest first, as for `label`.
+    pub fn transport_mismatch(&self, p: &Packet) -> bool {
+        let proto = match Protocol::try_from(p.proto) {
+            Ok(proto @ (Protocol::Tcp | Protocol::Udp)) => proto,
+            _ => return false,
+        };
+        let (low, high) = if p.src_port <= p.dst_port { (p.src_port, p.dst_port) } else { (p.dst_port, p.src_port) };
+        [low, high]
+            .into_iter()
+            .filter_map(|port| u16::try_from(port).ok())
+            .find_map(|port| self.transports.get(&port))
+            .is_some_and(|&expected| expected != proto)
+    }
+}
@@ -0,0 +1,228 @@
+//! Where the aggregated flows go: the server stream, `--output-tsv`, `--netflow-collector`,
+//! `--otlp-endpoint`, or an embedder's own [`FlowSink`].
+//!
+//! The local outputs replace the server stream unless `--tee` is given, in which case every
+//! batch goes to the stream and then to each of them. Each sink fails on its own: an error
+//! is logged and costs that sink the batch, while the others carry on.
+//!
+//! There is no pcap dump among them: sinks see flows, and the frames behind them are gone by
+//! the time a batch is aggregated. Record those with tcpdump on the same device instead.
+
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use tokio::sync::mpsc;
+use tracing::{debug, error, warn};
+
+use crate::error::AgentError;
+use crate::packet::{Packet, PacketBatch};
+use crate::{ipfix, otlp, summary, tsv, Args};
+
+/// Receives the batches of aggregated flows, as one of the sinks the agent fans out to.
+///
+/// Implement it with [`crate::async_trait`] to run an embedded [`crate::Agent`] into it.
+#[tonic::async_trait]
+pub trait FlowSink: Send {
+    /// Names the sink in logs.
+    fn name(&self) -> &str;
+
+    /// Write a batch, returning how many records went out. An error costs this sink the
+    /// batch and is logged; the pipeline carries on.
+    async fn send(&mut self, flows: &[Packet]) -> Result<usize, Box<dyn Error + Send + Sync>>;
+
+    /// Whether the sink is done for good, such as a server stream that ended; the pipeline
+    /// then stops.
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+struct TsvSink {
+    name: String,
+    writer: tsv::TsvWriter,
+}
+
+#[tonic::async_trait]
+impl FlowSink for TsvSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn send(&mut self, flows: &[Packet]) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        self.writer.write(flows)?;
+        Ok(flows.len())
+    }
+}
+
+struct IpfixSink {
+    name: String,
+    exporter: ipfix::Exporter,
+}
+
+#[tonic::async_trait]
+impl FlowSink for IpfixSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Connectionless, so a collector that is down only costs us this batch
+    async fn send(&mut self, flows: &[Packet]) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        Ok(self.exporter.export(flows).await?)
+    }
+}
+
+struct OtlpSink(Arc<otlp::FlowExporter>);
+
+#[tonic::async_trait]
+impl FlowSink for OtlpSink {
+    fn name(&self) -> &str {
+        "otlp"
+    }
+
+    async fn send(&mut self, flows: &[Packet]) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        self.0.record(flows);
+        Ok(flows.len())
+    }
+}
+
+/// The file and collector outputs enabled by `args`; these stand in for the server unless
+/// `--tee` is given.
+pub async fn build(args: &Args) -> Result<Vec<Box<dyn FlowSink>>, AgentError> {
+    let mut sinks: Vec<Box<dyn FlowSink>> = Vec::new();
+    if let Some(path) = &args.output_tsv {
+        let writer = tsv::TsvWriter::create(path).map_err(|e| AgentError::Output(format!("Failed to create {}: {}", path, e)))?;
+        sinks.push(Box::new(TsvSink {
+            name: format!("tsv:{}", path),
+            writer,
+        }));
+    }
+    if let Some(collector) = &args.netflow_collector {
+        let exporter = ipfix::Exporter::connect(collector)
+            .await
+            .map_err(|e| AgentError::Output(format!("Failed to open the IPFIX socket for {}: {}", collector, e)))?;
+        sinks.push(Box::new(IpfixSink {
+            name: format!("ipfix:{}", collector),
+            exporter,
+        }));
+    }
+    Ok(sinks)
+}
+
+/// `exporter` as an output, alongside the others or the server stream.
+pub fn otlp(exporter: Arc<otlp::FlowExporter>) -> Box<dyn FlowSink> {
+    Box::new(OtlpSink(exporter))
+}
+
+/// Number of the last batch handed to a server stream. It carries on across reconnects, so
+/// the server can tell batches lost with a broken stream from a fresh start.
+static BATCH_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// The server stream, as the first of the sinks while a session lasts.
+struct GrpcSink {
+    tx: mpsc::Sender<PacketBatch>,
+    /// Filled in by the capture thread once the device is open
+    link_type: Arc<OnceLock<String>>,
+    max_message_size: usize,
+}
+
+#[tonic::async_trait]
+impl FlowSink for GrpcSink {
+    fn name(&self) -> &str {
+        "grpc"
+    }
+
+    async fn send(&mut self, flows: &[Packet]) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        // Only taken once the batch is known to go out, so a batch dropped here isn't
+        // reported to the server as one lost in transit
+        let batch_seq = BATCH_SEQ.load(Ordering::Relaxed) + 1;
+        let batch = PacketBatch {
+            packets: flows.to_vec(),
+            link_type: self.link_type.get().cloned().unwrap_or_default(),
+            batch_seq,
+            ..Default::default()
+        };
+        // tonic would fail the whole stream over it with a terse error; drop just this batch
+        let size = prost::Message::encoded_len(&batch);
+        if size > self.max_message_size {
+            error!(
+                flows = flows.len(),
+                size,
+                max_message_size = self.max_message_size,
+                "Batch is larger than --max-message-size and was dropped; lower --batch-size, or raise --max-message-size on the agent and the server"
+            );
+            return Err("batch larger than --max-message-size".into());
+        }
+        self.tx.send(batch).await.map_err(|_| "stream closed")?;
+        BATCH_SEQ.store(batch_seq, Ordering::Relaxed);
+        summary::add_sent(flows.len());
+        Ok(flows.len())
+    }
+
+    fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+/// A sink feeding the returned receiver, which becomes the request stream to the server.
+pub fn grpc(link_type: Arc<OnceLock<String>>, max_message_size: usize) -> (Box<dyn FlowSink>, mpsc::Receiver<PacketBatch>) {
+    // The send queue sits in front of the sinks; this only hands a batch over to tonic
+    let (tx, rx) = mpsc::channel(1);
+    let sink = GrpcSink {
+        tx,
+        link_type,
+        max_message_size,
+    };
+    (Box::new(sink), rx)
+}
+
+/// Fills in whatever a batch should carry before any sink sees it.
+type Annotate = Box<dyn Fn(&mut [Packet]) + Send>;
+
+/// The channel feeding a spawned fan-out, and the task that gives the sinks back.
+type Fanout = (mpsc::Sender<Vec<Packet>>, tokio::task::JoinHandle<Vec<Box<dyn FlowSink>>>);
+
+/// Spawn the task that hands every batch received on the returned channel to each of
+/// `sinks` in turn, after `annotate` has had its go at it. It ends when the channel closes
+/// or a sink is closed, and gives the sinks back so local outputs can outlive a session.
+///
+/// With `count_sent` (the local outputs replace the server) a batch that reached any sink
+/// counts as sent in the run summary.
+pub fn spawn(
+    mut sinks: Vec<Box<dyn FlowSink>>,
+    depth: usize,
+    count_sent: bool,
+    annotate: Option<Annotate>,
+) -> Fanout {
+    let (tx, mut rx) = mpsc::channel::<Vec<Packet>>(depth);
+    let handle = tokio::spawn(async move {
+        while let Some(mut flows) = rx.recv().await {
+            if let Some(annotate) = &annotate {
+                annotate(&mut flows);
+            }
+            let mut delivered = false;
+            for sink in &mut sinks {
+                match sink.send(&flows).await {
+                    Ok(records) => {
+                        delivered = true;
+                        debug!(sink = %sink.name(), records, "Wrote flows");
+                    }
+                    Err(e) => warn!(sink = %sink.name(), error = %e, "Failed to write flows; the other sinks are unaffected"),
+                }
+            }
+            if count_sent && delivered {
+                summary::add_sent(flows.len());
+            }
+            if sinks.iter().any(|sink| sink.is_closed()) {
+                break;
+            }
+        }
+        sinks
+    });
+    (tx, handle)
+}
+
+/// Names of `sinks`, for logging which outputs are enabled.
+pub fn names(sinks: &[Box<dyn FlowSink>]) -> Vec<String> {
+    sinks.iter().map(|sink| sink.name().to_string()).collect()
+}
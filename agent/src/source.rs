@@ -0,0 +1,128 @@
+//! Where captured frames come from.
+//!
+//! The capture loop only needs the link type and one frame at a time, so anything that can
+//! hand those out (a live device, a savefile, canned frames) can drive it.
+
+use pcap::{Activated, Capture, Linktype};
+use tracing::{info, warn};
+
+/// One captured frame, borrowed from its source until the next call.
+pub struct Frame<'a> {
+    pub data: &'a [u8],
+    /// Bytes actually captured; less than `len` when the snapshot length cut the frame short
+    pub caplen: u32,
+    /// Length of the frame on the wire
+    pub len: u32,
+    /// Capture time in nanoseconds since the Unix epoch, 0 when the source doesn't know it.
+    /// Live captures resolve microseconds unless opened with `--nano-timestamps`.
+    pub timestamp_ns: u64,
+}
+
+pub trait PacketSource {
+    fn datalink(&self) -> Linktype;
+
+    /// The next frame, or `Err(pcap::Error::TimeoutExpired)` when none arrived within the
+    /// read timeout so the caller gets a chance to run its timers.
+    fn next_frame(&mut self) -> Result<Frame<'_>, pcap::Error>;
+}
+
+impl<T: Activated + ?Sized> PacketSource for Capture<T> {
+    fn datalink(&self) -> Linktype {
+        self.get_datalink()
+    }
+
+    fn next_frame(&mut self) -> Result<Frame<'_>, pcap::Error> {
+        let packet = self.next_packet()?;
+        Ok(Frame {
+            data: packet.data,
+            caplen: packet.header.caplen,
+            len: packet.header.len,
+            timestamp_ns: packet.header.ts.tv_sec as u64 * 1_000_000_000 + packet.header.ts.tv_usec as u64 * 1000,
+        })
+    }
+}
+
+/// Frames read before a capture that asked for nanosecond timestamps is taken to have been
+/// given microseconds instead.
+const NANO_CHECK_FRAMES: u32 = 1000;
+
+/// `--nano-timestamps`: a capture opened with nanosecond precision requested, whose
+/// `tv_usec` then holds nanoseconds.
+///
+/// libpcap keeps microseconds without telling us where the platform or device can't do
+/// better, so a fraction of a second is read as microseconds until one too large for that
+/// shows up. If none has after the first frames, we warn once and stop looking.
+pub struct NanoCapture<'a, T: Activated + ?Sized> {
+    capture: &'a mut Capture<T>,
+    /// None while undecided
+    nano: Option<bool>,
+    frames: u32,
+}
+
+impl<'a, T: Activated + ?Sized> NanoCapture<'a, T> {
+    pub fn new(capture: &'a mut Capture<T>) -> Self {
+        Self { capture, nano: None, frames: 0 }
+    }
+}
+
+impl<T: Activated + ?Sized> PacketSource for NanoCapture<'_, T> {
+    fn datalink(&self) -> Linktype {
+        self.capture.get_datalink()
+    }
+
+    fn next_frame(&mut self) -> Result<Frame<'_>, pcap::Error> {
+        let packet = self.capture.next_packet()?;
+        let fraction = packet.header.ts.tv_usec as u64;
+        if self.nano.is_none() {
+            self.frames += 1;
+            if fraction >= 1_000_000 {
+                info!("Capturing with nanosecond timestamps");
+                self.nano = Some(true);
+            } else if self.frames >= NANO_CHECK_FRAMES {
+                warn!(frames = self.frames, "--nano-timestamps: the capture appears to deliver microsecond timestamps only; keeping microseconds");
+                self.nano = Some(false);
+            }
+        }
+        let fraction_ns = if self.nano == Some(true) { fraction } else { fraction * 1000 };
+        Ok(Frame {
+            data: packet.data,
+            caplen: packet.header.caplen,
+            len: packet.header.len,
+            timestamp_ns: packet.header.ts.tv_sec as u64 * 1_000_000_000 + fraction_ns,
+        })
+    }
+}
+
+/// Frames held in memory, handed out in order with a made-up capture time each, then
+/// `Err(pcap::Error::NoMorePackets)` as at the end of a savefile. For driving the pipeline
+/// (or just the parser) without a device.
+pub struct SliceSource<T> {
+    datalink: Linktype,
+    frames: Vec<T>,
+    next: usize,
+}
+
+impl<T: AsRef<[u8]>> SliceSource<T> {
+    pub fn new(datalink: Linktype, frames: Vec<T>) -> Self {
+        Self { datalink, frames, next: 0 }
+    }
+}
+
+impl<T: AsRef<[u8]>> PacketSource for SliceSource<T> {
+    fn datalink(&self) -> Linktype {
+        self.datalink
+    }
+
+    fn next_frame(&mut self) -> Result<Frame<'_>, pcap::Error> {
+        let data = self.frames.get(self.next).ok_or(pcap::Error::NoMorePackets)?.as_ref();
+        self.next += 1;
+        let len = u32::try_from(data.len()).unwrap_or(u32::MAX);
+        Ok(Frame {
+            data,
+            caplen: len,
+            len,
+            // 1 ms, 2 ms, ... after the epoch
+            timestamp_ns: self.next as u64 * 1_000_000,
+        })
+    }
+}
@@ -0,0 +1,61 @@
+//! Totals over the whole run, logged once when the agent stops.
+//!
+//! Capture sessions restart with every reconnect, so their counters are added up here as
+//! each one ends, alongside what actually left the agent.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use tracing::info;
+
+use crate::CaptureStats;
+
+static FRAMES: AtomicU64 = AtomicU64::new(0);
+static AGGREGATED_PACKETS: AtomicU64 = AtomicU64::new(0);
+static AGGREGATED_FLOWS: AtomicU64 = AtomicU64::new(0);
+static AGGREGATED_BYTES: AtomicU64 = AtomicU64::new(0);
+static RATE_LIMITED: AtomicU64 = AtomicU64::new(0);
+static FLOW_OVERFLOW: AtomicU64 = AtomicU64::new(0);
+static BACKPRESSURE_DROPPED: AtomicU64 = AtomicU64::new(0);
+static PCAP_DROPPED: AtomicU64 = AtomicU64::new(0);
+static SENT_BATCHES: AtomicU64 = AtomicU64::new(0);
+static SENT_FLOWS: AtomicU64 = AtomicU64::new(0);
+
+/// Add the counters of a capture session that just ended.
+pub fn add_capture(stats: &CaptureStats) {
+    FRAMES.fetch_add(stats.frames, Ordering::Relaxed);
+    AGGREGATED_PACKETS.fetch_add(stats.aggregated_packets, Ordering::Relaxed);
+    AGGREGATED_FLOWS.fetch_add(stats.aggregated_flows, Ordering::Relaxed);
+    AGGREGATED_BYTES.fetch_add(stats.aggregated_bytes, Ordering::Relaxed);
+    RATE_LIMITED.fetch_add(stats.rate_limited, Ordering::Relaxed);
+    FLOW_OVERFLOW.fetch_add(stats.flow_overflow, Ordering::Relaxed);
+    BACKPRESSURE_DROPPED.fetch_add(stats.backpressure_dropped, Ordering::Relaxed);
+}
+
+/// Frames pcap reports as dropped by the kernel or the interface.
+pub fn add_pcap_dropped(dropped: u64) {
+    PCAP_DROPPED.fetch_add(dropped, Ordering::Relaxed);
+}
+
+/// A batch of `flows` handed to the server, collector or file.
+pub fn add_sent(flows: usize) {
+    SENT_BATCHES.fetch_add(1, Ordering::Relaxed);
+    SENT_FLOWS.fetch_add(flows as u64, Ordering::Relaxed);
+}
+
+pub fn log(started: Instant) {
+    info!(
+        uptime_secs = started.elapsed().as_secs(),
+        frames_captured = FRAMES.load(Ordering::Relaxed),
+        packets_aggregated = AGGREGATED_PACKETS.load(Ordering::Relaxed),
+        flows_aggregated = AGGREGATED_FLOWS.load(Ordering::Relaxed),
+        bytes = AGGREGATED_BYTES.load(Ordering::Relaxed),
+        batches_sent = SENT_BATCHES.load(Ordering::Relaxed),
+        flows_sent = SENT_FLOWS.load(Ordering::Relaxed),
+        rate_limited = RATE_LIMITED.load(Ordering::Relaxed),
+        flow_overflow = FLOW_OVERFLOW.load(Ordering::Relaxed),
+        backpressure_dropped = BACKPRESSURE_DROPPED.load(Ordering::Relaxed),
+        pcap_dropped = PCAP_DROPPED.load(Ordering::Relaxed),
+        "Agent summary"
+    );
+}
@@ -0,0 +1,47 @@
+//! TCP header details for `--tcp-detail`: the advertised window and the MSS option.
+//!
+//! Option lists are walked defensively; a bad length or a list running past the header
+//! just means no MSS.
+
+const MIN_HEADER_LEN: usize = 20;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// Window and MSS of a raw TCP segment, or `None` when even the fixed header is cut short.
+pub fn parse_segment(segment: &[u8]) -> Option<(u16, Option<u16>)> {
+    if segment.len() < MIN_HEADER_LEN {
+        return None;
+    }
+    let window = u16::from_be_bytes([segment[14], segment[15]]);
+    let header_len = (segment[12] >> 4) as usize * 4;
+    // A data offset below 5 words is malformed and leaves no room for options
+    let options = segment.get(MIN_HEADER_LEN..header_len).unwrap_or_default();
+    Some((window, parse_mss(options)))
+}
+
+/// The MSS carried in a TCP option list, if any.
+pub fn parse_mss(options: &[u8]) -> Option<u16> {
+    let mut pos = 0;
+    while pos < options.len() {
+        match options[pos] {
+            OPTION_END => return None,
+            OPTION_NOP => pos += 1,
+            kind => {
+                let len = *options.get(pos + 1)? as usize;
+                if len < 2 {
+                    return None;
+                }
+                if kind == OPTION_MSS {
+                    if len != 4 {
+                        return None;
+                    }
+                    let value = options.get(pos + 2..pos + 4)?;
+                    return Some(u16::from_be_bytes([value[0], value[1]]));
+                }
+                pos += len;
+            }
+        }
+    }
+    None
+}
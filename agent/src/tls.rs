@@ -0,0 +1,108 @@
+//! Minimal TLS ClientHello parsing, just enough to pull out the SNI host name.
+//!
+//! Everything here is best-effort: truncated or non-TLS payloads yield `None`.
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Bounds-checked reader over a byte slice.
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        let b = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.bytes(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let bytes = self.bytes(3)?;
+        Some(((bytes[0] as usize) << 16) | ((bytes[1] as usize) << 8) | bytes[2] as usize)
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(len)?;
+        let slice = self.data.get(self.pos..end)?;
+        self.pos = end;
+        Some(slice)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.bytes(len).map(|_| ())
+    }
+}
+
+/// Extract the `server_name` extension from a TLS ClientHello at the start of a TCP payload.
+pub fn parse_sni(payload: &[u8]) -> Option<String> {
+    let mut record = Cursor::new(payload);
+    if record.u8()? != CONTENT_TYPE_HANDSHAKE {
+        return None;
+    }
+    let _version = record.u16()?;
+    let record_len = record.u16()? as usize;
+    // The ClientHello may span several segments; parse whatever part we captured
+    let available = record_len.min(payload.len() - record.pos);
+    let mut hs = Cursor::new(record.bytes(available)?);
+
+    if hs.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let _hello_len = hs.u24()?;
+    let _client_version = hs.u16()?;
+    hs.skip(32)?; // random
+    let session_id_len = hs.u8()? as usize;
+    hs.skip(session_id_len)?;
+    let cipher_suites_len = hs.u16()? as usize;
+    hs.skip(cipher_suites_len)?;
+    let compression_len = hs.u8()? as usize;
+    hs.skip(compression_len)?;
+
+    let extensions_len = hs.u16()? as usize;
+    let mut extensions = Cursor::new(hs.bytes(extensions_len.min(hs.data.len() - hs.pos))?);
+    while let (Some(ext_type), Some(ext_len)) = (extensions.u16(), extensions.u16()) {
+        let ext = extensions.bytes(ext_len as usize)?;
+        if ext_type == EXTENSION_SERVER_NAME {
+            return parse_server_name_list(ext);
+        }
+    }
+    None
+}
+
+fn parse_server_name_list(ext: &[u8]) -> Option<String> {
+    let mut list = Cursor::new(ext);
+    let list_len = list.u16()? as usize;
+    let mut names = Cursor::new(list.bytes(list_len)?);
+    while let Some(name_type) = names.u8() {
+        let name_len = names.u16()? as usize;
+        let name = names.bytes(name_len)?;
+        if name_type == NAME_TYPE_HOST_NAME {
+            return std::str::from_utf8(name).ok().map(|s| s.to_string());
+        }
+    }
+    None
+}
+
+/// Ports whose traffic should be TLS from the first byte, for `--flag-anomalies`.
+pub const TLS_PORTS: &[u16] = &[443, 465, 636, 993, 995, 8443];
+
+/// Whether a TCP payload opens with a plaintext protocol (an HTTP request or response, or
+/// an SSH banner) rather than a TLS record. Only the start of a segment is looked at, so
+/// TLS records split across segments are never mistaken for plaintext.
+pub fn looks_plaintext(payload: &[u8]) -> bool {
+    const PREFIXES: &[&[u8]] = &[b"GET ", b"POST ", b"HEAD ", b"PUT ", b"DELETE ", b"OPTIONS ", b"CONNECT ", b"HTTP/1.", b"SSH-"];
+    PREFIXES.iter().any(|prefix| payload.starts_with(prefix))
+}
@@ -0,0 +1,158 @@
+//! Mock traffic replayed from a recorded flow trace (`--mock-trace`).
+//!
+//! A trace is what `--output-tsv` writes: `ts src src_port dst dst_port proto bytes packets`,
+//! tab-separated, with `#` lines ignored, so a capture recorded once (or a file written by
+//! hand) can be shown anywhere. Its flows are sent at their recorded spacing, scaled by
+//! `--mock-trace-speed`, and the trace starts over once it ends. The first pass is sent as
+//! recorded; later passes vary each flow's bytes and packets by up to 10% so the loop
+//! doesn't repeat exactly, drawn from `--mock-seed` when given.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::{Rng, SeedableRng};
+use tokio::sync::mpsc;
+
+use crate::packet::{Packet, Protocol};
+use crate::{flush_buffer_async, Args, FlowKey, FlowStats};
+
+/// One recorded flow, `offset` after the first.
+pub struct TraceFlow {
+    offset: Duration,
+    key: FlowKey,
+    bytes: i32,
+    packets: i64,
+}
+
+/// One line of a trace, as written.
+struct Row {
+    ts: f64,
+    src: IpAddr,
+    src_port: i32,
+    dst: IpAddr,
+    dst_port: i32,
+    proto: String,
+    bytes: i32,
+    packets: i64,
+}
+
+/// Read the trace at `path`. Addresses in `--local-ip`/`--local-cidr` are marked as the agent's.
+pub fn load(path: &str, args: &Args) -> Result<Vec<TraceFlow>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let rows: Vec<Row> = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| parse_line(line).map_err(|e| format!("{} line {}: {}", path, i + 1, e)))
+        .collect::<Result<_, _>>()?;
+    if rows.is_empty() {
+        return Err(format!("Trace {} has no flows", path));
+    }
+
+    let is_agent = |ip: &IpAddr| args.local_ip.contains(ip) || args.local_cidr.iter().any(|net| net.contains(ip));
+    let start = rows.iter().map(|row| row.ts).fold(f64::INFINITY, f64::min);
+    let mut flows = Vec::with_capacity(rows.len());
+    for row in rows {
+        let proto = Protocol::from_str_name(&row.proto.to_uppercase()).ok_or_else(|| format!("Unknown proto {:?} in {}", row.proto, path))?;
+        flows.push(TraceFlow {
+            offset: Duration::from_secs_f64((row.ts - start).max(0.0)),
+            key: FlowKey {
+                src_ip: row.src,
+                dst_ip: row.dst,
+                src_is_agent: is_agent(&row.src),
+                dst_is_agent: is_agent(&row.dst),
+                proto: proto.into(),
+                src_port: row.src_port,
+                dst_port: row.dst_port,
+                icmp_type: 0,
+                icmp_code: 0,
+                src_mac: None,
+                dst_mac: None,
+                vlan: None,
+            },
+            bytes: row.bytes.max(0),
+            packets: row.packets.max(1),
+        });
+    }
+    // The TSV writer stamps a batch's flows alike, so this keeps each batch together
+    flows.sort_by_key(|flow| flow.offset);
+    Ok(flows)
+}
+
+fn parse_line(line: &str) -> Result<Row, String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let [ts, src, src_port, dst, dst_port, proto, bytes, packets] = fields[..] else {
+        return Err(format!("expected 8 tab-separated fields, found {}", fields.len()));
+    };
+    let invalid = |name: &str, value: &str| format!("invalid {} {:?}", name, value);
+    Ok(Row {
+        ts: ts.parse().map_err(|_| invalid("ts", ts))?,
+        src: src.parse().map_err(|_| invalid("src", src))?,
+        src_port: src_port.parse().map_err(|_| invalid("src_port", src_port))?,
+        dst: dst.parse().map_err(|_| invalid("dst", dst))?,
+        dst_port: dst_port.parse().map_err(|_| invalid("dst_port", dst_port))?,
+        proto: proto.to_string(),
+        bytes: bytes.parse().map_err(|_| invalid("bytes", bytes))?,
+        packets: packets.parse().map_err(|_| invalid("packets", packets))?,
+    })
+}
+
+/// Send `flows` into `tx` over and over until `stop` is set, the channel closes or
+/// `--mock-count` flows were sent. Returns true when the count was reached.
+pub async fn replay(flows: Vec<TraceFlow>, tx: mpsc::Sender<Vec<Packet>>, args: &Args, stop: Arc<AtomicBool>) -> bool {
+    let mut rng = match args.mock_seed {
+        Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+        None => rand::rngs::StdRng::from_entropy(),
+    };
+    let speed = args.mock_trace_speed;
+    let mut buffer: HashMap<FlowKey, FlowStats> = HashMap::with_capacity(args.batch_size);
+    let flush_interval = Duration::from_millis(args.batch_interval);
+    let mut last_flush = Instant::now();
+    let mut sent: u64 = 0;
+
+    let mut pass: u64 = 0;
+    loop {
+        let started = Instant::now();
+        for flow in &flows {
+            let due = started + flow.offset.div_f64(speed);
+            // Batches keep going out on the interval while waiting for the flow's turn
+            loop {
+                if stop.load(Ordering::SeqCst) || tx.is_closed() {
+                    flush_buffer_async(&mut buffer, args.top_flows, &tx).await;
+                    return false;
+                }
+                if last_flush.elapsed() >= flush_interval {
+                    if !flush_buffer_async(&mut buffer, args.top_flows, &tx).await {
+                        return false;
+                    }
+                    last_flush = Instant::now();
+                }
+                let now = Instant::now();
+                if now >= due {
+                    break;
+                }
+                tokio::time::sleep((due - now).min(flush_interval)).await;
+            }
+
+            if args.mock_count.is_some_and(|count| sent >= count) {
+                return flush_buffer_async(&mut buffer, args.top_flows, &tx).await;
+            }
+            let scale = if pass == 0 { 1.0 } else { rng.gen_range(0.9..1.1) };
+            let stats = buffer.entry(flow.key.clone().for_aggregation(args)).or_default();
+            stats.size = stats.size.saturating_add((flow.bytes as f64 * scale) as i32);
+            stats.packets = stats.packets.saturating_add(((flow.packets as f64 * scale) as i64).max(1));
+            sent += 1;
+
+            if buffer.len() >= args.batch_size {
+                if !flush_buffer_async(&mut buffer, args.top_flows, &tx).await {
+                    return false;
+                }
+                last_flush = Instant::now();
+            }
+        }
+        pass += 1;
+    }
+}
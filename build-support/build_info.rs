@@ -0,0 +1,38 @@
+// Shared by agent/build.rs and server/build.rs through include!, so both binaries report
+// their build the same way.
+
+/// Commit and time of the build, as MIKABOSHI_GIT_HASH and MIKABOSHI_BUILD_TIME (Unix
+/// seconds). Outside a git checkout, e.g. from a source tarball, the hash is "unknown".
+fn embed_build_info() {
+    let git_hash = std::process::Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=MIKABOSHI_GIT_HASH={}", git_hash);
+
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let build_time = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        });
+    println!("cargo:rustc-env=MIKABOSHI_BUILD_TIME={}", build_time);
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    // Rebuilt on a new commit or checkout: HEAD, and the branch it points to
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    if let Ok(head) = std::fs::read_to_string("../.git/HEAD") {
+        if let Some(branch) = head.trim().strip_prefix("ref: ") {
+            println!("cargo:rerun-if-changed=../.git/{}", branch);
+        }
+    }
+}
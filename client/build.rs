@@ -0,0 +1,15 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let proto_file = if std::path::Path::new("../proto/packet.proto").exists() {
+        "../proto/packet.proto"
+    } else {
+        "proto/packet.proto"
+    };
+
+    println!("cargo:rerun-if-changed={}", proto_file);
+    // Subscribers only ever call the server
+    let include_dir = std::path::Path::new(proto_file).parent().unwrap_or(std::path::Path::new("."));
+    tonic_build::configure()
+        .build_server(false)
+        .compile_protos(&[proto_file], &[include_dir])?;
+    Ok(())
+}
@@ -0,0 +1,183 @@
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::packet::{Packet, PacketFlag, Protocol};
+
+/// One entry of the live feed: a flow's traffic since the last entry for it, decoded from the
+/// wire `Packet`. Text fields the agent or server left empty are `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Flow {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// Which ends are the reporting agent's own addresses
+    pub src_is_agent: bool,
+    pub dst_is_agent: bool,
+    pub proto: Protocol,
+    /// 0 for protocols without ports
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// ICMP / ICMPv6 type and code, for ICMP flows only
+    pub icmp: Option<(u8, u8)>,
+    pub bytes: u64,
+    pub packets: u64,
+    /// Capture times of the entry's first and last packets, when the agent reported them
+    pub first_seen: Option<SystemTime>,
+    pub last_seen: Option<SystemTime>,
+    pub sni: Option<String>,
+    pub dns_query: Option<String>,
+    pub service: Option<String>,
+    /// Site label of the reporting agent
+    pub site: Option<String>,
+    /// Location of the remote end, with the server's `--enrich-geoip`
+    pub country: Option<String>,
+    pub city: Option<String>,
+    pub asn: Option<u32>,
+    /// Neither end is the agent; the flow passes through it
+    pub transit: bool,
+    /// Re-broadcast from stored flows by `POST /replay`
+    pub replay: bool,
+    pub highlighted: bool,
+    pub anomalous: bool,
+    /// `PacketFlag` bits; see [`Flow::has_flag`]
+    pub flags: u32,
+}
+
+impl Flow {
+    /// Decode `packet`, or `None` when its addresses aren't 4 or 16 bytes as `is_ipv6` says.
+    pub fn from_packet(packet: Packet) -> Option<Self> {
+        let src = ip_from_bytes(&packet.src_ip, packet.is_ipv6)?;
+        let dst = ip_from_bytes(&packet.dst_ip, packet.is_ipv6)?;
+        let proto = Protocol::try_from(packet.proto).unwrap_or(Protocol::Unknown);
+        let icmp = (proto == Protocol::Icmp).then_some((packet.icmp_type as u8, packet.icmp_code as u8));
+        let text = |s: String| (!s.is_empty()).then_some(s);
+        Some(Self {
+            src,
+            dst,
+            src_is_agent: packet.src_is_agent,
+            dst_is_agent: packet.dst_is_agent,
+            proto,
+            src_port: u16::try_from(packet.src_port).unwrap_or(0),
+            dst_port: u16::try_from(packet.dst_port).unwrap_or(0),
+            icmp,
+            bytes: packet.size.max(0) as u64,
+            packets: packet.packets.max(0) as u64,
+            first_seen: seen_at(packet.first_seen_ns, packet.first_seen),
+            last_seen: seen_at(packet.last_seen_ns, packet.last_seen),
+            sni: text(packet.sni),
+            dns_query: text(packet.dns_query),
+            service: text(packet.service),
+            site: text(packet.site),
+            country: text(packet.country),
+            city: text(packet.city),
+            asn: u32::try_from(packet.asn).ok().filter(|&asn| asn != 0),
+            transit: packet.transit,
+            replay: packet.replay,
+            highlighted: packet.highlighted,
+            anomalous: packet.anomalous,
+            flags: packet.flags,
+        })
+    }
+
+    /// Whether the entry's numbers went through `flag`'s path, e.g. reassembly.
+    pub fn has_flag(&self, flag: PacketFlag) -> bool {
+        self.flags & flag as u32 != 0
+    }
+
+    /// The remote end: whichever side isn't the agent, or the source when neither is. `None`
+    /// for an entry flagged [`PacketFlag::OtherFlows`] (the flows the agent's `--top-flows`
+    /// left out) or [`PacketFlag::Grouped`] (`--group-by` without an address), whose
+    /// unspecified addresses name no peer.
+    pub fn peer(&self) -> Option<IpAddr> {
+        if self.has_flag(PacketFlag::OtherFlows) || self.has_flag(PacketFlag::Grouped) {
+            None
+        } else if self.src_is_agent {
+            Some(self.dst)
+        } else {
+            Some(self.src)
+        }
+    }
+}
+
+fn ip_from_bytes(bytes: &[u8], is_ipv6: bool) -> Option<IpAddr> {
+    if is_ipv6 {
+        <[u8; 16]>::try_from(bytes).ok().map(IpAddr::from)
+    } else {
+        <[u8; 4]>::try_from(bytes).ok().map(IpAddr::from)
+    }
+}
+
+/// The ns timestamp when set, else the older ms one; 0 in both means unknown.
+fn seen_at(ns: u64, ms: u64) -> Option<SystemTime> {
+    match (ns, ms) {
+        (0, 0) => None,
+        (0, ms) => Some(UNIX_EPOCH + Duration::from_millis(ms)),
+        (ns, _) => Some(UNIX_EPOCH + Duration::from_nanos(ns)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet() -> Packet {
+        Packet {
+            src_ip: vec![192, 0, 2, 1],
+            dst_ip: vec![198, 51, 100, 7],
+            proto: Protocol::Tcp as i32,
+            src_port: 40000,
+            dst_port: 443,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rejects_addresses_of_the_wrong_length() {
+        assert!(Flow::from_packet(Packet { src_ip: vec![192, 0, 2], ..packet() }).is_none());
+        assert!(Flow::from_packet(Packet { dst_ip: vec![0; 16], ..packet() }).is_none());
+        // 4-byte addresses on a packet marked IPv6
+        assert!(Flow::from_packet(Packet { is_ipv6: true, ..packet() }).is_none());
+
+        let v6 = Flow::from_packet(Packet { src_ip: vec![0; 16], dst_ip: vec![0; 16], is_ipv6: true, ..packet() }).unwrap();
+        assert!(v6.src.is_ipv6() && v6.dst.is_ipv6());
+    }
+
+    #[test]
+    fn decodes_icmp_type_and_code_for_icmp_only() {
+        let icmp = Flow::from_packet(Packet { proto: Protocol::Icmp as i32, icmp_type: 3, icmp_code: 1, ..packet() }).unwrap();
+        assert_eq!(icmp.icmp, Some((3, 1)));
+
+        let tcp = Flow::from_packet(Packet { icmp_type: 3, icmp_code: 1, ..packet() }).unwrap();
+        assert_eq!(tcp.icmp, None);
+    }
+
+    #[test]
+    fn empty_fields_are_none() {
+        let flow = Flow::from_packet(packet()).unwrap();
+        assert_eq!((flow.sni, flow.dns_query, flow.service, flow.site), (None, None, None, None));
+        assert_eq!((flow.country, flow.city, flow.asn), (None, None, None));
+
+        let flow = Flow::from_packet(Packet { sni: "example.com".into(), asn: 64496, ..packet() }).unwrap();
+        assert_eq!(flow.sni.as_deref(), Some("example.com"));
+        assert_eq!(flow.asn, Some(64496));
+    }
+
+    #[test]
+    fn seen_at_prefers_nanoseconds() {
+        assert_eq!(seen_at(0, 0), None);
+        assert_eq!(seen_at(0, 1_500), Some(UNIX_EPOCH + Duration::from_millis(1_500)));
+        assert_eq!(seen_at(1_500_000_001, 1_500), Some(UNIX_EPOCH + Duration::from_nanos(1_500_000_001)));
+    }
+
+    #[test]
+    fn peer_is_the_side_that_isnt_the_agent() {
+        let flow = Flow::from_packet(packet()).unwrap();
+        assert_eq!(flow.peer(), Some(flow.src));
+        let flow = Flow::from_packet(Packet { src_is_agent: true, ..packet() }).unwrap();
+        assert_eq!(flow.peer(), Some(flow.dst));
+
+        for flag in [PacketFlag::OtherFlows, PacketFlag::Grouped] {
+            let flow = Flow::from_packet(Packet { flags: flag as u32, ..packet() }).unwrap();
+            assert_eq!(flow.peer(), None, "{:?}", flag);
+        }
+    }
+}
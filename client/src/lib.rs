@@ -0,0 +1,153 @@
+//! A client for the server's live feed, for scripts that alert on or export flows.
+//!
+//! [`Subscriber::connect`] opens the gRPC `Subscribe` stream and yields each flow as a
+//! [`Flow`], with addresses and protocol already decoded. A dropped connection or a server
+//! restart doesn't end the stream: the subscriber reconnects with exponential [`Backoff`] and,
+//! on the server's numbered fan-in, resumes after the last batch it received so flows the
+//! server still buffers aren't lost.
+//!
+//! ```no_run
+//! use futures::StreamExt;
+//! use mikaboshi_client::{SubscribeFilter, Subscriber};
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut flows = Subscriber::connect("http://localhost:50051", SubscribeFilter::default())?;
+//! while let Some(flow) = flows.next().await {
+//!     println!("{} -> {} {:?} {} bytes", flow.src, flow.dst, flow.proto, flow.bytes);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::Stream;
+use tokio::sync::mpsc;
+use tonic::transport::Endpoint;
+use tracing::{debug, info, warn};
+
+pub mod packet {
+    tonic::include_proto!("packet");
+}
+
+mod flow;
+
+pub use flow::Flow;
+pub use packet::{PacketFlag, Protocol, SubscribeFilter};
+
+use packet::agent_service_client::AgentServiceClient;
+
+/// Flows decoded ahead of the caller before the subscriber stops reading from the server.
+const FLOW_BUFFER: usize = 4096;
+
+/// Delays between reconnect attempts: `initial` after the first failure, doubling up to `max`.
+/// A session that received anything starts over at `initial`.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The live feed as a [`Stream`] of flows. It only ends once dropped.
+pub struct Subscriber {
+    flows: tokio_stream::wrappers::ReceiverStream<Flow>,
+}
+
+impl Subscriber {
+    /// Subscribe to the server at `addr` (e.g. `http://localhost:50051`) with `filter`,
+    /// reconnecting with the default [`Backoff`]. Fails only on an invalid address; the
+    /// connection itself is made in the background, so this must be called inside a Tokio
+    /// runtime.
+    pub fn connect(addr: impl Into<String>, filter: SubscribeFilter) -> Result<Self, tonic::transport::Error> {
+        Ok(Self::with_endpoint(Endpoint::from_shared(addr.into())?, filter, Backoff::default()))
+    }
+
+    /// Like [`Subscriber::connect`], for an endpoint with its own TLS or timeout settings.
+    pub fn with_endpoint(endpoint: Endpoint, filter: SubscribeFilter, backoff: Backoff) -> Self {
+        let (tx, rx) = mpsc::channel(FLOW_BUFFER);
+        tokio::spawn(run(endpoint, filter, backoff, tx));
+        Self {
+            flows: tokio_stream::wrappers::ReceiverStream::new(rx),
+        }
+    }
+}
+
+impl Stream for Subscriber {
+    type Item = Flow;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Flow>> {
+        Pin::new(&mut self.flows).poll_next(cx)
+    }
+}
+
+/// Subscribe over and over until the `Subscriber` is dropped.
+async fn run(endpoint: Endpoint, mut filter: SubscribeFilter, backoff: Backoff, tx: mpsc::Sender<Flow>) {
+    // Only the aggregated fan-in is numbered, so only it can resume from `since_seq`
+    let resumable = filter.agent_id.is_none() && filter.aggregated != Some(false);
+    let mut delay = backoff.initial;
+    loop {
+        let mut received = false;
+        let outcome = tokio::select! {
+            outcome = session(&endpoint, &mut filter, resumable, &tx, &mut received) => outcome,
+            _ = tx.closed() => return,
+        };
+        if tx.is_closed() {
+            return;
+        }
+        if received {
+            delay = backoff.initial;
+        }
+        match outcome {
+            Ok(()) => info!(uri = %endpoint.uri(), retry_in = ?delay, "Server ended the subscription"),
+            Err(e) => warn!(uri = %endpoint.uri(), error = %e, retry_in = ?delay, "Subscription failed"),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = tx.closed() => return,
+        }
+        delay = (delay * 2).min(backoff.max);
+    }
+}
+
+/// One subscription, from connecting until the stream ends. `received` is set once a batch
+/// arrives.
+async fn session(
+    endpoint: &Endpoint,
+    filter: &mut SubscribeFilter,
+    resumable: bool,
+    tx: &mpsc::Sender<Flow>,
+    received: &mut bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut client = AgentServiceClient::new(endpoint.connect().await?);
+    let mut stream = client.subscribe(filter.clone()).await?.into_inner();
+    debug!(uri = %endpoint.uri(), since_seq = ?filter.since_seq, "Subscribed");
+    while let Some(batch) = stream.message().await? {
+        *received = true;
+        for packet in batch.packets {
+            match Flow::from_packet(packet) {
+                Some(flow) => {
+                    if tx.send(flow).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                None => debug!("Skipped a flow with malformed addresses"),
+            }
+        }
+        // Only once its flows are handed over, so a reconnect doesn't skip them
+        if resumable && batch.seq > 0 {
+            filter.since_seq = Some(batch.seq);
+        }
+    }
+    Ok(())
+}
@@ -6,6 +6,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     println!("cargo:rerun-if-changed={}", proto_file);
-    tonic_build::compile_protos(proto_file)?;
+    // The descriptor set backs --grpc-reflection
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR")?);
+    let include_dir = std::path::Path::new(proto_file).parent().unwrap_or(std::path::Path::new("."));
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("packet_descriptor.bin"))
+        .compile_protos(&[proto_file], &[include_dir])?;
+    embed_build_info();
     Ok(())
 }
+
+include!("../build-support/build_info.rs");
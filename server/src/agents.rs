@@ -0,0 +1,293 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tonic::metadata::MetadataMap;
+
+use crate::packet::PacketBatch;
+
+/// Request metadata key carrying the agent's self-reported name.
+pub const AGENT_ID_HEADER: &str = "mikaboshi-agent-id";
+/// Request metadata key carrying the address the agent's `*_is_agent` flags refer to.
+pub const AGENT_IP_HEADER: &str = "mikaboshi-agent-ip";
+/// Request metadata key carrying the agent's `--site-label`.
+pub const AGENT_SITE_HEADER: &str = "mikaboshi-agent-site";
+/// Request metadata key carrying the optional `Packet` fields the agent fills in, comma-separated.
+pub const AGENT_CAPABILITIES_HEADER: &str = "mikaboshi-agent-capabilities";
+/// Request metadata key carrying the agent's version and commit (`0.1.0+<hash>`).
+pub const AGENT_VERSION_HEADER: &str = "mikaboshi-agent-version";
+
+/// Identity an agent announces when it opens its packet stream.
+#[derive(Debug, Clone)]
+pub struct AgentIdentity {
+    pub id: String,
+    pub ip: Option<IpAddr>,
+    pub site: Option<String>,
+    pub remote_addr: Option<SocketAddr>,
+    /// `None` for agents from before capabilities were announced
+    pub capabilities: Option<Vec<String>>,
+    /// `None` for agents from before versions were announced
+    pub version: Option<String>,
+}
+
+impl AgentIdentity {
+    /// Read the identity from stream metadata, falling back to the agent IP and then the
+    /// peer address for agents that don't send an id.
+    pub fn from_metadata(metadata: &MetadataMap, remote_addr: Option<SocketAddr>) -> Self {
+        let header = |key: &str| metadata.get(key).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let ip = header(AGENT_IP_HEADER).and_then(|ip| ip.parse().ok());
+        let id = header(AGENT_ID_HEADER)
+            .or_else(|| ip.map(|ip: IpAddr| ip.to_string()))
+            .or_else(|| remote_addr.map(|addr| addr.ip().to_string()))
+            .unwrap_or_else(|| "unknown".to_string());
+        let site = header(AGENT_SITE_HEADER).filter(|site| !site.is_empty());
+        let capabilities = header(AGENT_CAPABILITIES_HEADER).map(|fields| {
+            fields
+                .split(',')
+                .filter(|field| !field.is_empty())
+                .map(str::to_string)
+                .collect()
+        });
+        let version = header(AGENT_VERSION_HEADER).filter(|version| !version.is_empty());
+        Self {
+            id,
+            ip,
+            site,
+            remote_addr,
+            capabilities,
+            version,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentInfo {
+    pub id: String,
+    pub ip: Option<IpAddr>,
+    /// Site label the agent reported, if any
+    pub site: Option<String>,
+    pub remote_addr: Option<SocketAddr>,
+    pub connected: bool,
+    /// pcap link type the agent captures on, once it has reported one
+    pub link_type: Option<String>,
+    /// Optional `Packet` fields the agent fills in; `None` when it doesn't say, in which
+    /// case any of them may be set
+    pub capabilities: Option<Vec<String>>,
+    /// Version and commit the agent reported, `None` for agents that don't say
+    pub version: Option<String>,
+    /// Open streams using this id; an agent may reconnect before its old stream is torn down
+    #[serde(skip)]
+    streams: usize,
+    /// Unix seconds of the last batch (or connection) from this agent
+    pub last_seen: i64,
+    /// Bytes and packets this agent has sent since the server started
+    pub bytes: i64,
+    pub packets: i64,
+    /// This agent's fraction of the bytes received from all agents, filled in by `list`
+    pub byte_share: f64,
+    /// `batch_seq` of the last numbered batch, 0 before the first
+    #[serde(skip)]
+    last_batch_seq: u64,
+    /// Batches missing from the agent's numbering, i.e. lost between agent and server
+    pub lost_batches: u64,
+}
+
+/// Held by a subscriber of one agent's channel. Declared before the receiver, so it is
+/// dropped after it, it lets the registry drop the channel once the agent is gone too.
+pub struct ChannelRelease(pub Arc<AgentRegistry>);
+
+impl Drop for ChannelRelease {
+    fn drop(&mut self) {
+        self.0.release_idle_channels();
+    }
+}
+
+/// Agents that have streamed to this server since it started, each with its own
+/// broadcast channel so a busy agent can't make subscribers of a quiet one lag. A channel
+/// lives while the agent has a stream open or anyone subscribes to it.
+pub struct AgentRegistry {
+    agents: Mutex<HashMap<String, AgentInfo>>,
+    channels: Mutex<HashMap<String, broadcast::Sender<PacketBatch>>>,
+    channel_capacity: usize,
+}
+
+impl AgentRegistry {
+    pub fn new(channel_capacity: usize) -> Self {
+        Self {
+            agents: Mutex::new(HashMap::new()),
+            channels: Mutex::new(HashMap::new()),
+            channel_capacity,
+        }
+    }
+
+    /// The channel the agent's stream broadcasts on, created when the agent connects.
+    pub fn stream_sender(&self, id: &str) -> broadcast::Sender<PacketBatch> {
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(id.to_string())
+            .or_insert_with(|| broadcast::channel(self.channel_capacity).0)
+            .clone()
+    }
+
+    /// A receiver on the agent's channel, or `None` when it has none: the agent never
+    /// streamed here, or disconnected and nobody was left subscribed to it. Subscribers
+    /// never create channels, so made-up ids can't pile them up.
+    pub fn subscribe(&self, id: &str) -> Option<broadcast::Receiver<PacketBatch>> {
+        self.channels.lock().unwrap().get(id).map(broadcast::Sender::subscribe)
+    }
+
+    /// Drop the channels of agents without an open stream that nobody subscribes to anymore.
+    pub fn release_idle_channels(&self) {
+        let agents = self.agents.lock().unwrap();
+        self.channels
+            .lock()
+            .unwrap()
+            .retain(|id, tx| tx.receiver_count() > 0 || agents.get(id).is_some_and(|agent| agent.streams > 0));
+    }
+
+    pub fn connected(&self, identity: &AgentIdentity) {
+        let mut agents = self.agents.lock().unwrap();
+        let agent = agents.entry(identity.id.clone()).or_insert_with(|| AgentInfo {
+            id: identity.id.clone(),
+            ip: None,
+            site: None,
+            remote_addr: None,
+            connected: false,
+            link_type: None,
+            capabilities: None,
+            version: None,
+            streams: 0,
+            last_seen: 0,
+            bytes: 0,
+            packets: 0,
+            byte_share: 0.0,
+            last_batch_seq: 0,
+            lost_batches: 0,
+        });
+        agent.ip = identity.ip;
+        agent.site = identity.site.clone();
+        agent.remote_addr = identity.remote_addr;
+        agent.capabilities = identity.capabilities.clone();
+        agent.version = identity.version.clone();
+        agent.streams += 1;
+        agent.connected = true;
+        agent.last_seen = crate::store::now_secs();
+    }
+
+    /// Record a batch from the agent, adding its traffic to the agent's totals.
+    ///
+    /// Returns how many batches the agent numbered but never delivered before this one.
+    pub fn seen(&self, id: &str, batch: &PacketBatch) -> u64 {
+        let mut agents = self.agents.lock().unwrap();
+        let Some(agent) = agents.get_mut(id) else {
+            return 0;
+        };
+        agent.last_seen = crate::store::now_secs();
+        for p in &batch.packets {
+            agent.bytes += p.size as i64;
+            agent.packets += p.packets.max(1);
+        }
+
+        // Older agents don't number their batches
+        if batch.batch_seq == 0 {
+            return 0;
+        }
+        // A number that doesn't move forward means the agent restarted and counts from 1 again
+        let lost = if agent.last_batch_seq > 0 && batch.batch_seq > agent.last_batch_seq {
+            batch.batch_seq - agent.last_batch_seq - 1
+        } else {
+            0
+        };
+        agent.last_batch_seq = batch.batch_seq;
+        agent.lost_batches += lost;
+        lost
+    }
+
+    pub fn set_link_type(&self, id: &str, link_type: &str) {
+        if let Some(agent) = self.agents.lock().unwrap().get_mut(id) {
+            if agent.link_type.as_deref() != Some(link_type) {
+                agent.link_type = Some(link_type.to_string());
+            }
+        }
+    }
+
+    pub fn disconnected(&self, id: &str) {
+        if let Some(agent) = self.agents.lock().unwrap().get_mut(id) {
+            agent.streams = agent.streams.saturating_sub(1);
+            agent.connected = agent.streams > 0;
+        }
+        self.release_idle_channels();
+    }
+
+    /// Agents with at least one open stream.
+    pub fn connected_count(&self) -> usize {
+        self.agents.lock().unwrap().values().filter(|agent| agent.connected).count()
+    }
+
+    /// Snapshot of every known agent, most recently seen first.
+    pub fn list(&self) -> Vec<AgentInfo> {
+        let mut agents: Vec<AgentInfo> = self.agents.lock().unwrap().values().cloned().collect();
+        let total_bytes: i64 = agents.iter().map(|agent| agent.bytes).sum();
+        if total_bytes > 0 {
+            for agent in &mut agents {
+                agent.byte_share = agent.bytes as f64 / total_bytes as f64;
+            }
+        }
+        agents.sort_by(|a, b| b.last_seen.cmp(&a.last_seen).then_with(|| a.id.cmp(&b.id)));
+        agents
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(id: &str) -> AgentIdentity {
+        AgentIdentity {
+            id: id.to_string(),
+            ip: None,
+            site: None,
+            remote_addr: None,
+            capabilities: None,
+            version: None,
+        }
+    }
+
+    #[test]
+    fn subscribers_never_create_channels() {
+        let registry = AgentRegistry::new(4);
+        assert!(registry.subscribe("made-up").is_none());
+        assert!(registry.channels.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn channels_live_while_streamed_or_subscribed() {
+        let registry = AgentRegistry::new(4);
+        registry.connected(&identity("a"));
+        let _tx = registry.stream_sender("a");
+        let rx = registry.subscribe("a").unwrap();
+
+        // The subscriber keeps the channel across the agent's disconnect, so it picks the
+        // agent back up when it reconnects
+        registry.disconnected("a");
+        assert!(registry.subscribe("a").is_some());
+
+        drop(rx);
+        registry.release_idle_channels();
+        assert!(registry.subscribe("a").is_none());
+    }
+
+    #[test]
+    fn disconnect_drops_an_unwatched_channel() {
+        let registry = AgentRegistry::new(4);
+        registry.connected(&identity("a"));
+        registry.stream_sender("a");
+        registry.release_idle_channels();
+        assert!(registry.subscribe("a").is_some(), "dropped while streaming");
+
+        registry.disconnected("a");
+        assert!(registry.channels.lock().unwrap().is_empty());
+    }
+}
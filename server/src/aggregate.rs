@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::metrics::FanoutMetrics;
+use crate::packet::{Packet, PacketBatch};
+
+/// Flow identity used when merging packets on the server.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+struct FlowKey {
+    src_ip: Vec<u8>,
+    dst_ip: Vec<u8>,
+    is_ipv6: bool,
+    src_is_agent: bool,
+    dst_is_agent: bool,
+    proto: i32,
+    src_port: i32,
+    dst_port: i32,
+    icmp_type: i32,
+    icmp_code: i32,
+    src_mac: Vec<u8>,
+    dst_mac: Vec<u8>,
+    vlan: Option<i32>,
+    site: String,
+}
+
+impl FlowKey {
+    fn of(p: &Packet) -> Self {
+        Self {
+            src_ip: p.src_ip.clone(),
+            dst_ip: p.dst_ip.clone(),
+            is_ipv6: p.is_ipv6,
+            src_is_agent: p.src_is_agent,
+            dst_is_agent: p.dst_is_agent,
+            proto: p.proto,
+            src_port: p.src_port,
+            dst_port: p.dst_port,
+            icmp_type: p.icmp_type,
+            icmp_code: p.icmp_code,
+            src_mac: p.src_mac.clone(),
+            dst_mac: p.dst_mac.clone(),
+            vlan: p.vlan,
+            site: p.site.clone(),
+        }
+    }
+}
+
+/// Sums packet sizes per flow until the accumulated batch is taken.
+#[derive(Default)]
+pub struct WindowAggregator {
+    flows: HashMap<FlowKey, Packet>,
+}
+
+impl WindowAggregator {
+    pub fn add(&mut self, batch: PacketBatch) {
+        for p in batch.packets {
+            self.add_packet(FlowKey::of(&p), p);
+        }
+    }
+
+    /// Like `add`, but a new flow that would take the window past `max_flows` flows first
+    /// cuts the window off. The windows cut off, to be broadcast early, are returned.
+    pub fn add_capped(&mut self, batch: PacketBatch, max_flows: usize) -> Vec<PacketBatch> {
+        let mut full = Vec::new();
+        for p in batch.packets {
+            let key = FlowKey::of(&p);
+            if self.flows.len() >= max_flows && !self.flows.contains_key(&key) {
+                full.extend(self.take());
+            }
+            self.add_packet(key, p);
+        }
+        full
+    }
+
+    fn add_packet(&mut self, key: FlowKey, p: Packet) {
+        match self.flows.entry(key) {
+            std::collections::hash_map::Entry::Occupied(mut e) => merge(e.get_mut(), p),
+            std::collections::hash_map::Entry::Vacant(e) => {
+                e.insert(p);
+            }
+        }
+    }
+
+    /// Drain everything accumulated so far, or `None` if the window was empty.
+    pub fn take(&mut self) -> Option<PacketBatch> {
+        if self.flows.is_empty() {
+            return None;
+        }
+        Some(PacketBatch {
+            packets: self.flows.drain().map(|(_, p)| p).collect(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Fold a newer packet of the same flow into the accumulated one.
+fn merge(acc: &mut Packet, p: Packet) {
+    acc.size = acc.size.saturating_add(p.size);
+    acc.packets = acc.packets.saturating_add(p.packets);
+    // 0 means unknown; otherwise keep the lowest TTL of the flow
+    if p.ttl != 0 && (acc.ttl == 0 || p.ttl < acc.ttl) {
+        acc.ttl = p.ttl;
+    }
+    acc.dscp = p.dscp;
+    // Only agents running with --sketch send a mean size; keep it consistent with the sums
+    if acc.avg_size != 0 || p.avg_size != 0 {
+        acc.avg_size = (acc.size as i64 / acc.packets.max(1)) as i32;
+    }
+    if !p.sni.is_empty() {
+        acc.sni = p.sni;
+    }
+    if !p.dns_query.is_empty() {
+        acc.dns_query = p.dns_query;
+    }
+    if p.first_seen != 0 && (acc.first_seen == 0 || p.first_seen < acc.first_seen) {
+        acc.first_seen = p.first_seen;
+    }
+    acc.last_seen = acc.last_seen.max(p.last_seen);
+    if p.first_seen_ns != 0 && (acc.first_seen_ns == 0 || p.first_seen_ns < acc.first_seen_ns) {
+        acc.first_seen_ns = p.first_seen_ns;
+    }
+    acc.last_seen_ns = acc.last_seen_ns.max(p.last_seen_ns);
+    acc.flags |= p.flags;
+    if p.tcp_window.is_some() {
+        acc.tcp_window = p.tcp_window;
+    }
+    if p.tcp_mss.is_some() {
+        acc.tcp_mss = p.tcp_mss;
+    }
+    acc.highlighted |= p.highlighted;
+    acc.anomalous |= p.anomalous;
+}
+
+/// Spawn a task that aggregates incoming batches and broadcasts one batch per window.
+/// With `max_flows`, a window is broadcast early once it holds that many flows, and each
+/// time is counted in `metrics`.
+///
+/// The task flushes what it holds and exits once every returned sender is dropped.
+pub fn spawn(
+    window: Duration,
+    max_flows: Option<usize>,
+    tx: broadcast::Sender<PacketBatch>,
+    overflow: crate::Overflow,
+    metrics: Arc<FanoutMetrics>,
+) -> (mpsc::Sender<PacketBatch>, tokio::task::JoinHandle<()>) {
+    let (in_tx, mut in_rx) = mpsc::channel::<PacketBatch>(1024);
+
+    let handle = tokio::spawn(async move {
+        let mut aggregator = WindowAggregator::default();
+        let mut ticker = tokio::time::interval(window);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                batch = in_rx.recv() => match (batch, max_flows) {
+                    (Some(batch), Some(max_flows)) => {
+                        for full in aggregator.add_capped(batch, max_flows) {
+                            metrics.aggregate_overflow();
+                            overflow.send(&tx, full).await;
+                        }
+                    }
+                    (Some(batch), None) => aggregator.add(batch),
+                    (None, _) => break,
+                },
+                _ = ticker.tick() => {
+                    if let Some(batch) = aggregator.take() {
+                        overflow.send(&tx, batch).await;
+                    }
+                }
+            }
+        }
+
+        if let Some(batch) = aggregator.take() {
+            overflow.send(&tx, batch).await;
+        }
+    });
+
+    (in_tx, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(ports: impl IntoIterator<Item = i32>) -> PacketBatch {
+        PacketBatch {
+            packets: ports
+                .into_iter()
+                .map(|port| Packet {
+                    src_ip: vec![192, 0, 2, 10],
+                    dst_ip: vec![198, 51, 100, 7],
+                    src_port: port,
+                    dst_port: 443,
+                    size: 100,
+                    packets: 1,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cuts_the_window_at_max_flows() {
+        let mut aggregator = WindowAggregator::default();
+        // Known flows never cut the window
+        assert!(aggregator.add_capped(batch([1, 2, 1, 2]), 2).is_empty());
+        let full = aggregator.add_capped(batch([3, 1, 4, 5]), 2);
+        // 3 cut off {1, 2}; 1 joined 3; 4 cut off {3, 1}; 5 joined 4
+        assert_eq!(full.len(), 2);
+        assert_eq!(full[0].packets.len(), 2);
+        assert!(full[0].packets.iter().all(|p| p.size == 200));
+        assert_eq!(full[1].packets.len(), 2);
+        assert_eq!(aggregator.take().unwrap().packets.len(), 2);
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+use crate::packet::Packet;
+use crate::{AppState, SharedState};
+
+/// How often a GeoIP database that failed to load is tried again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// Database files looked for when `--geoip-path` isn't given, best first.
+const DETECT_FILES: [&str; 2] = ["GeoLite2-City.mmdb", "GeoLite2-Country.mmdb"];
+/// Where geoipupdate and the distribution packages put them, after `--geoip-dir`.
+const DETECT_DIRS: [&str; 3] = ["/usr/share/GeoIP", "/var/lib/GeoIP", "/usr/local/share/GeoIP"];
+/// Addresses `--enrich-geoip` remembers; the cache starts over once it is full.
+const ENRICH_CACHE_ENTRIES: usize = 65_536;
+
+/// A loaded MMDB file and what the dashboard should credit for it.
+pub struct GeoIpDatabase {
+    reader: maxminddb::Reader<Vec<u8>>,
+    /// GeoLite2-Country / DBIP-Country-Lite and friends; everything else is read as a City DB
+    country_only: bool,
+    pub attribution_text: String,
+    pub attribution_url: Option<String>,
+    path: String,
+    /// Found in a conventional location rather than given with `--geoip-path`
+    detected: bool,
+    /// When this database was loaded, which with retries can be well after startup
+    loaded_at: SystemTime,
+}
+
+impl GeoIpDatabase {
+    pub fn open(path: &str, detected: bool) -> Result<Self, maxminddb::MaxMindDBError> {
+        let reader = maxminddb::Reader::open_readfile(path)?;
+
+        // Auto-detect attribution
+        let metadata = &reader.metadata;
+        let db_type = &metadata.database_type;
+        let description = metadata.description.get("en").map(|s| s.as_str()).unwrap_or("");
+        info!(database_type = %db_type, description = %description, "GeoIP database metadata");
+
+        let (attribution_text, attribution_url) = if db_type.contains("DBIP") || description.contains("DB-IP") {
+            info!("Detected DB-IP database, setting attribution");
+            ("IP Geolocation by DB-IP".to_string(), Some("https://db-ip.com".to_string()))
+        } else {
+            // Fallback to database type
+            (db_type.clone(), None)
+        };
+        let country_only = db_type.contains("Country");
+
+        Ok(Self {
+            reader,
+            country_only,
+            attribution_text,
+            attribution_url,
+            path: path.to_string(),
+            detected,
+            loaded_at: SystemTime::now(),
+        })
+    }
+
+    /// The database's metadata, for `/geoip/info`.
+    pub fn info(&self) -> serde_json::Value {
+        let metadata = &self.reader.metadata;
+        let unix_secs = |time: SystemTime| time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        serde_json::json!({
+            "path": self.path,
+            "detected": self.detected,
+            "database_type": metadata.database_type,
+            "build_epoch": metadata.build_epoch,
+            "description": metadata.description,
+            "ip_version": metadata.ip_version,
+            "languages": metadata.languages,
+            "node_count": metadata.node_count,
+            "record_size": metadata.record_size,
+            "binary_format_version": format!("{}.{}", metadata.binary_format_major_version, metadata.binary_format_minor_version),
+            "country_only": self.country_only,
+            "loaded_at": unix_secs(self.loaded_at),
+        })
+    }
+
+    /// English country and city names for `ip`; the city is always `None` for Country DBs.
+    pub fn lookup(&self, ip: IpAddr) -> Result<(Option<String>, Option<String>), maxminddb::MaxMindDBError> {
+        // Country DBs have no city record, so they need their own lookup type
+        if self.country_only {
+            self.reader.lookup::<maxminddb::geoip2::Country>(ip).map(|country| {
+                let country_name = country.country.and_then(|c| c.names).and_then(|n| n.get("en").map(|s| s.to_string()));
+                (country_name, None)
+            })
+        } else {
+            self.reader.lookup::<maxminddb::geoip2::City>(ip).map(|city| {
+                let country_name = city.country.and_then(|c| c.names).and_then(|n| n.get("en").map(|s| s.to_string()));
+                let city_name = city.city.and_then(|c| c.names).and_then(|n| n.get("en").map(|s| s.to_string()));
+                (country_name, city_name)
+            })
+        }
+    }
+
+    /// The autonomous system of `ip`, only found in ASN databases (GeoLite2-ASN and the like).
+    pub fn asn(&self, ip: IpAddr) -> Option<u32> {
+        if !self.reader.metadata.database_type.contains("ASN") {
+            return None;
+        }
+        self.reader
+            .lookup::<maxminddb::geoip2::Asn>(ip)
+            .ok()
+            .and_then(|asn| asn.autonomous_system_number)
+    }
+}
+
+/// What `--enrich-geoip` stamps on a packet; empty / 0 when the database doesn't know.
+#[derive(Clone, Default)]
+struct Location {
+    country: String,
+    city: String,
+    asn: i32,
+}
+
+/// Stamps packets from agents with the location of their remote endpoint
+/// (`--enrich-geoip`), so subscribers get it without a `/geoip` call per address.
+pub struct Enricher {
+    /// The state holds the enricher, so it is referred to weakly
+    state: Weak<AppState>,
+    cache: Mutex<HashMap<IpAddr, Location>>,
+}
+
+impl Enricher {
+    pub fn new(state: Weak<AppState>) -> Self {
+        Self {
+            state,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fill in `country`, `city` and `asn` of each packet. Until the database has loaded
+    /// packets go out as they came, and nothing is cached.
+    pub fn enrich(&self, packets: &mut [Packet]) {
+        let Some(state) = self.state.upgrade() else {
+            return;
+        };
+        let Some(database) = state.geoip.read().unwrap().clone() else {
+            return;
+        };
+        let mut cache = self.cache.lock().unwrap();
+        for p in packets {
+            let Some(ip) = remote_ip(p) else {
+                continue;
+            };
+            if cache.len() >= ENRICH_CACHE_ENTRIES && !cache.contains_key(&ip) {
+                cache.clear();
+            }
+            let location = cache.entry(ip).or_insert_with(|| locate(&database, ip));
+            p.country = location.country.clone();
+            p.city = location.city.clone();
+            p.asn = location.asn;
+        }
+    }
+}
+
+/// The endpoint that isn't the agent; the destination when neither (or both) is. Entries
+/// summing flows left out by `--top-flows` have none.
+fn remote_ip(p: &Packet) -> Option<IpAddr> {
+    if crate::has_unspecified_hosts(p) {
+        None
+    } else if p.dst_is_agent && !p.src_is_agent {
+        crate::ip_from_bytes(&p.src_ip, p.is_ipv6)
+    } else {
+        crate::ip_from_bytes(&p.dst_ip, p.is_ipv6)
+    }
+}
+
+fn locate(database: &GeoIpDatabase, ip: IpAddr) -> Location {
+    // Private and unallocated addresses aren't in the database, a miss like any other
+    let (country, city) = database.lookup(ip).unwrap_or_default();
+    Location {
+        country: country.unwrap_or_default(),
+        city: city.unwrap_or_default(),
+        asn: database.asn(ip).map_or(0, |asn| asn as i32),
+    }
+}
+
+/// The first database in `dir` or the usual locations, for when `--geoip-path` isn't set.
+pub fn detect(dir: Option<&Path>) -> Option<String> {
+    dir.into_iter()
+        .chain(DETECT_DIRS.iter().map(Path::new))
+        .flat_map(|dir| DETECT_FILES.iter().map(move |file| dir.join(file)))
+        .find(|path| path.is_file())
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
+fn install(state: &SharedState, database: GeoIpDatabase) {
+    *state.geoip.write().unwrap() = Some(Arc::new(database));
+    state.geoip_ready.store(true, Ordering::SeqCst);
+    info!("GeoIP database loaded successfully");
+}
+
+/// Load the database at `path` into `state`. If that fails (e.g. a sidecar hasn't
+/// downloaded it yet), keep retrying in the background until it loads or shutdown is
+/// requested; until then the dashboard falls back to ipapi.co.
+pub async fn load(path: String, detected: bool, state: SharedState) {
+    info!(path = %path, "Loading GeoIP database");
+    let open_path = path.clone();
+    match tokio::task::spawn_blocking(move || GeoIpDatabase::open(&open_path, detected)).await {
+        Ok(Ok(database)) => {
+            install(&state, database);
+            return;
+        }
+        Ok(Err(e)) => warn!(error = %e, retry_interval = ?RETRY_INTERVAL, "Failed to load GeoIP database, using ipapi.co until it loads"),
+        Err(e) => warn!(error = %e, retry_interval = ?RETRY_INTERVAL, "Failed to load GeoIP database, using ipapi.co until it loads"),
+    }
+
+    tokio::spawn(async move {
+        let shutdown = crate::wait_for_shutdown(state.shutdown.clone());
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => return,
+                _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+            }
+            let open_path = path.clone();
+            match tokio::task::spawn_blocking(move || GeoIpDatabase::open(&open_path, detected)).await {
+                Ok(Ok(database)) => {
+                    install(&state, database);
+                    return;
+                }
+                Ok(Err(e)) => debug!(error = %e, "GeoIP database still not loadable"),
+                Err(e) => debug!(error = %e, "GeoIP database still not loadable"),
+            }
+        }
+    });
+}
@@ -0,0 +1,93 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, watch};
+use tracing::warn;
+
+use crate::packet::PacketBatch;
+
+/// Batches on their way to fan-in subscribers, numbered with `seq`, plus the most recent
+/// ones so a subscriber that reconnects with `since_seq` can catch up on what it missed.
+pub struct History {
+    state: Mutex<State>,
+    tx: broadcast::Sender<PacketBatch>,
+    capacity: usize,
+}
+
+struct State {
+    /// Sequence number of the last batch; the first one is 1
+    last_seq: u64,
+    recent: VecDeque<PacketBatch>,
+}
+
+impl History {
+    fn push(&self, mut batch: PacketBatch) {
+        let mut state = self.state.lock().unwrap();
+        state.last_seq += 1;
+        batch.seq = state.last_seq;
+        if state.recent.len() == self.capacity {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(batch.clone());
+        // Sent under the lock so `subscribe` never sees a batch both buffered and in flight
+        let _ = self.tx.send(batch);
+    }
+
+    /// Subscribe to numbered batches. With `since`, the buffered batches after that sequence
+    /// number come back too, to be sent before anything from the receiver.
+    pub fn subscribe(&self, since: Option<u64>) -> (Vec<PacketBatch>, broadcast::Receiver<PacketBatch>) {
+        let state = self.state.lock().unwrap();
+        let rx = self.tx.subscribe();
+        let Some(since) = since else {
+            return (Vec::new(), rx);
+        };
+        if let Some(oldest) = state.recent.front() {
+            if oldest.seq > since.saturating_add(1) {
+                warn!(since_seq = since, oldest_seq = oldest.seq, "Resuming subscriber is older than the replay buffer; some batches are lost");
+            }
+        }
+        let missed = state.recent.iter().filter(|batch| batch.seq > since).cloned().collect();
+        (missed, rx)
+    }
+}
+
+/// Spawn the task that numbers every broadcast batch and keeps the last `capacity` of them.
+///
+/// The task exits once the broadcast channel closes or shutdown is requested.
+pub fn spawn(
+    capacity: usize,
+    mut rx: broadcast::Receiver<PacketBatch>,
+    shutdown: watch::Receiver<bool>,
+) -> Arc<History> {
+    let capacity = capacity.max(1);
+    let history = Arc::new(History {
+        state: Mutex::new(State {
+            last_seq: 0,
+            recent: VecDeque::with_capacity(capacity),
+        }),
+        tx: broadcast::channel(capacity).0,
+        capacity,
+    });
+
+    let task_history = history.clone();
+    tokio::spawn(async move {
+        let shutdown = crate::wait_for_shutdown(shutdown);
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                result = rx.recv() => match result {
+                    Ok(batch) => task_history.push(batch),
+                    // Numbers stay contiguous, so resuming clients can't see this gap
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!(skipped = n, "Subscriber history lagged behind");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            }
+        }
+    });
+
+    history
+}
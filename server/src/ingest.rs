@@ -0,0 +1,173 @@
+//! `POST /ingest`: flows sent as JSON, for senders that can't run the agent or speak gRPC
+//! (scripts, serverless functions, agents in other languages).
+//!
+//! A flow object has the fields of `Packet` as they appear on `/ws` and `/events`, so a
+//! frame from either can be posted back as-is. Fields the server derives (`is_ipv6`, `type`)
+//! or only sets itself (`replay`, `avg_size`) are ignored.
+
+use serde::Deserialize;
+use std::net::IpAddr;
+
+use crate::packet::{Packet, PacketBatch};
+
+/// Most flows one `POST /ingest` may carry.
+pub const MAX_INGEST_FLOWS: usize = 10_000;
+
+/// `proto` as a name (`TCP`, `udp`) or a protocol enum value.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Proto {
+    Name(String),
+    Value(i32),
+}
+
+#[derive(Deserialize)]
+struct IngestFlow {
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    #[serde(default)]
+    src_is_agent: bool,
+    #[serde(default)]
+    dst_is_agent: bool,
+    size: i32,
+    /// A flow object without a count is a single packet
+    packets: Option<i64>,
+    proto: Proto,
+    #[serde(default)]
+    src_port: i32,
+    #[serde(default)]
+    dst_port: i32,
+    #[serde(default)]
+    icmp_type: i32,
+    #[serde(default)]
+    icmp_code: i32,
+    #[serde(default)]
+    sni: String,
+    #[serde(default)]
+    dns_query: String,
+    #[serde(default)]
+    service: String,
+    #[serde(default)]
+    transit: bool,
+    #[serde(default)]
+    first_seen: u64,
+    #[serde(default)]
+    last_seen: u64,
+    #[serde(default)]
+    first_seen_ns: u64,
+    #[serde(default)]
+    last_seen_ns: u64,
+    #[serde(default)]
+    flags: u32,
+    tcp_window: Option<i32>,
+    tcp_mss: Option<i32>,
+    #[serde(default)]
+    highlighted: bool,
+    #[serde(default)]
+    anomalous: bool,
+    #[serde(default)]
+    client_to_server: bool,
+    vlan: Option<i32>,
+    #[serde(default)]
+    ttl: i32,
+    #[serde(default)]
+    dscp: i32,
+    #[serde(default)]
+    site: String,
+    src_mac: Option<String>,
+    dst_mac: Option<String>,
+    #[serde(default)]
+    country: String,
+    #[serde(default)]
+    city: String,
+    #[serde(default)]
+    asn: i32,
+}
+
+/// Decode a `POST /ingest` body into a batch, or say what is wrong with it.
+pub fn decode(body: &[u8]) -> Result<PacketBatch, String> {
+    let flows: Vec<IngestFlow> = serde_json::from_slice(body).map_err(|e| format!("Invalid flows: {}", e))?;
+    if flows.len() > MAX_INGEST_FLOWS {
+        return Err(format!("At most {} flows per request", MAX_INGEST_FLOWS));
+    }
+    let packets = flows
+        .into_iter()
+        .enumerate()
+        .map(|(i, flow)| packet_from_flow(flow).map_err(|e| format!("Flow {}: {}", i, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(PacketBatch {
+        packets,
+        ..Default::default()
+    })
+}
+
+fn packet_from_flow(flow: IngestFlow) -> Result<Packet, String> {
+    let proto = match flow.proto {
+        Proto::Name(name) => crate::store::parse_proto(&name).ok_or_else(|| format!("unknown proto {:?}", name))?,
+        Proto::Value(value) => value,
+    };
+    let packets = flow.packets.unwrap_or(1);
+    if flow.size < 0 || packets < 0 {
+        return Err("size and packets can't be negative".to_string());
+    }
+    for port in [flow.src_port, flow.dst_port] {
+        if !(0..=65535).contains(&port) {
+            return Err(format!("port {} out of range", port));
+        }
+    }
+    let mac = |mac: Option<String>| -> Result<Vec<u8>, String> {
+        match mac {
+            None => Ok(Vec::new()),
+            Some(mac) => parse_mac(&mac).ok_or_else(|| format!("invalid MAC address {:?}", mac)),
+        }
+    };
+
+    let (src_ip, dst_ip, is_ipv6) = crate::ip_pair_to_bytes(flow.src_ip, flow.dst_ip);
+    Ok(Packet {
+        src_ip,
+        dst_ip,
+        is_ipv6,
+        src_is_agent: flow.src_is_agent,
+        dst_is_agent: flow.dst_is_agent,
+        size: flow.size,
+        packets,
+        proto,
+        src_port: flow.src_port,
+        dst_port: flow.dst_port,
+        icmp_type: flow.icmp_type,
+        icmp_code: flow.icmp_code,
+        sni: flow.sni,
+        dns_query: flow.dns_query,
+        service: flow.service,
+        transit: flow.transit,
+        first_seen: flow.first_seen,
+        last_seen: flow.last_seen,
+        first_seen_ns: flow.first_seen_ns,
+        last_seen_ns: flow.last_seen_ns,
+        flags: flow.flags,
+        tcp_window: flow.tcp_window,
+        tcp_mss: flow.tcp_mss,
+        highlighted: flow.highlighted,
+        anomalous: flow.anomalous,
+        client_to_server: flow.client_to_server,
+        vlan: flow.vlan,
+        ttl: flow.ttl,
+        dscp: flow.dscp,
+        site: flow.site,
+        src_mac: mac(flow.src_mac)?,
+        dst_mac: mac(flow.dst_mac)?,
+        country: flow.country,
+        city: flow.city,
+        asn: flow.asn,
+        ..Default::default()
+    })
+}
+
+/// `aa:bb:cc:dd:ee:ff` as 6 bytes.
+fn parse_mac(mac: &str) -> Option<Vec<u8>> {
+    let bytes = mac
+        .split(':')
+        .map(|octet| u8::from_str_radix(octet, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    (bytes.len() == 6).then_some(bytes)
+}
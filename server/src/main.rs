@@ -2,27 +2,208 @@ use axum::Router;
 use futures::stream::StreamExt;
 use std::net::SocketAddr;
 
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, watch};
 use tonic::{transport::Server, Request, Response, Status};
-use tower_http::services::ServeDir;
+use tracing::{error, info, warn};
+use tower_http::services::{ServeDir, ServeFile};
 use tower_http::cors::{CorsLayer, Any};
 use base64;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 pub mod packet {
     tonic::include_proto!("packet");
+
+    /// Encoded descriptors of packet.proto, for --grpc-reflection
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("packet_descriptor");
 }
 
+mod agents;
+mod aggregate;
+mod geoip;
+mod history;
+mod ingest;
+mod metrics;
+mod peers;
+mod ranking;
+mod replay;
+mod stats;
+mod store;
+#[cfg(unix)]
+mod uds;
+mod upstream;
+
 use packet::agent_service_server::{AgentService, AgentServiceServer};
-use packet::{Empty, Packet, PacketBatch};
+use packet::{Empty, Packet, PacketBatch, Stats, SubscribeFilter, TopTalkersRequest, TopTalkersSnapshot};
 
 // Shared state
 struct AppState {
     tx: broadcast::Sender<PacketBatch>,
+    store: Option<Arc<store::FlowStore>>,
+    agents: Arc<agents::AgentRegistry>,
+    counters: Arc<stats::TrafficCounters>,
+    metrics: Arc<metrics::FanoutMetrics>,
+    /// Per-flow traffic over the `--top-talkers-window`, for `/snapshot`
+    flows: Arc<ranking::FlowWindow>,
+    /// Where `POST /ingest` hands flows on, as agent streams do
+    ingest: Ingest,
+    /// Set with `--enrich-geoip`
+    enricher: Option<Arc<geoip::Enricher>>,
+    shutdown: watch::Receiver<bool>,
+    /// The gRPC listener is bound and serving
+    grpc_ready: AtomicBool,
+    /// The GeoIP database is loaded, or none was configured
+    geoip_ready: AtomicBool,
+    /// Set once `--geoip-path` loads, which may be well after startup
+    geoip: std::sync::RwLock<Option<Arc<geoip::GeoIpDatabase>>>,
+    /// The running `/replay`, cancelled when another one is requested
+    replay: std::sync::Mutex<Option<tokio::task::JoinHandle<()>>>,
+}
+
+/// Resolves once SIGINT or (on Unix) SIGTERM is received.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// How long a subscriber has to take its last frames once the server starts shutting down.
+const SUBSCRIBE_FAREWELL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Resolves once shutdown has been requested through the watch channel.
+async fn wait_for_shutdown(mut rx: watch::Receiver<bool>) {
+    let _ = rx.wait_for(|stopping| *stopping).await;
+}
+
+/// Waits for the next tick of a per-client downsampling interval, or forever without one.
+async fn next_tick(ticker: &mut Option<tokio::time::Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Compiled form of a `SubscribeFilter`; an empty filter matches every packet.
+#[derive(Debug, Clone, Default)]
+struct PacketFilter {
+    ip: Option<std::net::IpAddr>,
+    proto: Option<i32>,
+    port: Option<i32>,
+}
+
+impl PacketFilter {
+    fn from_request(filter: &SubscribeFilter) -> Result<Self, String> {
+        let ip = match &filter.ip {
+            Some(ip) => Some(ip.parse().map_err(|_| format!("Invalid IP: {}", ip))?),
+            None => None,
+        };
+        Ok(Self {
+            ip,
+            proto: filter.proto,
+            port: filter.port,
+        })
+    }
+
+    fn matches(&self, p: &Packet) -> bool {
+        if let Some(ip) = self.ip {
+            let (src, dst) = (ip_from_bytes(&p.src_ip, p.is_ipv6), ip_from_bytes(&p.dst_ip, p.is_ipv6));
+            if src != Some(ip) && dst != Some(ip) {
+                return false;
+            }
+        }
+        if let Some(proto) = self.proto {
+            if p.proto != proto {
+                return false;
+            }
+        }
+        if let Some(port) = self.port {
+            if p.src_port != port && p.dst_port != port {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Strip non-matching packets from a batch, returning `None` if nothing is left.
+    ///
+    /// Batches without packets are agent heartbeats or peer expiries and pass through as-is.
+    fn apply(&self, mut batch: PacketBatch) -> Option<PacketBatch> {
+        if batch.packets.is_empty() {
+            return Some(batch);
+        }
+        batch.packets.retain(|p| self.matches(p));
+        if batch.packets.is_empty() {
+            None
+        } else {
+            Some(batch)
+        }
+    }
+}
+
+/// Where batches received from agents go on their way to subscribers.
+#[derive(Clone)]
+enum Ingest {
+    /// Forward every batch to the broadcast channel as-is
+    Direct(broadcast::Sender<PacketBatch>, Overflow),
+    /// Merge batches in the windowed aggregator, which broadcasts once per window. The
+    /// batches are also broadcast as they arrive on the raw channel while it has subscribers
+    Windowed(tokio::sync::mpsc::Sender<PacketBatch>, broadcast::Sender<PacketBatch>, Overflow),
+}
+
+impl Ingest {
+    async fn send(&self, batch: PacketBatch) {
+        match self {
+            Ingest::Direct(tx, overflow) => {
+                overflow.send(tx, batch).await;
+            }
+            Ingest::Windowed(tx, raw, overflow) => {
+                if raw.receiver_count() > 0 {
+                    overflow.send(raw, batch.clone()).await;
+                }
+                let _ = tx.send(batch).await;
+            }
+        }
+    }
+
+    /// The channel of un-aggregated batches, when the main broadcast is aggregated.
+    fn raw(&self) -> Option<&broadcast::Sender<PacketBatch>> {
+        match self {
+            Ingest::Direct(..) => None,
+            Ingest::Windowed(_, raw, _) => Some(raw),
+        }
+    }
 }
 
-#[derive(Default)]
 struct GrpcService {
-    tx: Option<broadcast::Sender<PacketBatch>>,
+    history: Arc<history::History>,
+    ingest: Ingest,
+    agents: Arc<agents::AgentRegistry>,
+    counters: Arc<stats::TrafficCounters>,
+    metrics: Arc<metrics::FanoutMetrics>,
+    shutdown: watch::Receiver<bool>,
+    subscribe_buffer: usize,
+    rankings: watch::Receiver<Arc<TopTalkersSnapshot>>,
+    overflow: Overflow,
+    /// Set with `--enrich-geoip`
+    enricher: Option<Arc<geoip::Enricher>>,
 }
 
 #[tonic::async_trait]
@@ -31,51 +212,222 @@ impl AgentService for GrpcService {
         &self,
         request: Request<tonic::Streaming<PacketBatch>>,
     ) -> Result<Response<Empty>, Status> {
+        let identity = agents::AgentIdentity::from_metadata(request.metadata(), request.remote_addr());
+        info!(agent_id = %identity.id, agent_ip = ?identity.ip, site = ?identity.site, remote_addr = ?identity.remote_addr, "Agent connected");
+        self.agents.connected(&identity);
+        let agent_tx = self.agents.stream_sender(&identity.id);
+
         let mut stream = request.into_inner();
-        let tx = self.tx.clone().ok_or(Status::internal("Internal error"))?;
+        let shutdown = wait_for_shutdown(self.shutdown.clone());
+        tokio::pin!(shutdown);
 
-        while let Some(result) = stream.next().await {
+        let outcome = loop {
+            let result = tokio::select! {
+                result = stream.next() => result,
+                // End the stream so graceful shutdown isn't held open by a connected agent
+                _ = &mut shutdown => break Err(Status::unavailable("Server is shutting down")),
+            };
             match result {
-                Ok(batch) => {
+                Some(Ok(mut batch)) => {
+                     let lost = self.agents.seen(&identity.id, &batch);
+                     if lost > 0 {
+                         warn!(agent_id = %identity.id, lost, batch_seq = batch.batch_seq, "Batches lost in transit from agent");
+                     }
+                     if let Some(site) = &identity.site {
+                         for p in &mut batch.packets {
+                             p.site = site.clone();
+                         }
+                     }
+                     if let Some(enricher) = &self.enricher {
+                         enricher.enrich(&mut batch.packets);
+                     }
+                     self.counters.record(&batch);
+                     if !batch.link_type.is_empty() {
+                         self.agents.set_link_type(&identity.id, &batch.link_type);
+                     }
+                     let fanout_started = std::time::Instant::now();
+                     // Subscribers of this agent only see its raw batches
+                     if agent_tx.receiver_count() > 0 {
+                         self.overflow.send(&agent_tx, batch.clone()).await;
+                     }
                      // Broadcast packet batch to all subscribers
-                     let _ = tx.send(batch);
+                     self.ingest.send(batch).await;
+                     self.metrics.observe_send(fanout_started.elapsed());
                 }
-                Err(e) => return Err(e),
+                Some(Err(e)) => break Err(e),
+                None => break Ok(Response::new(Empty {})),
             }
-        }
+        };
 
-        Ok(Response::new(Empty {}))
+        self.agents.disconnected(&identity.id);
+        info!(agent_id = %identity.id, "Agent disconnected");
+        outcome
     }
 
     type SubscribeStream = tokio_stream::wrappers::ReceiverStream<Result<PacketBatch, Status>>;
 
     async fn subscribe(
         &self,
-        _request: Request<Empty>,
+        request: Request<SubscribeFilter>,
     ) -> Result<Response<Self::SubscribeStream>, Status> {
-        let tx = self.tx.clone().ok_or(Status::internal("Internal error"))?;
-        let mut rx = tx.subscribe();
+        let filter = PacketFilter::from_request(request.get_ref()).map_err(Status::invalid_argument)?;
+        // A single agent's own channel, the fan-in of every agent, or with
+        // --aggregate-window-ms and `aggregated: false` the fan-in before aggregation. Only the
+        // aggregated fan-in is numbered, so only it can resume from `since_seq`
+        let raw = self.ingest.raw().filter(|_| request.get_ref().aggregated == Some(false));
+        let (missed, rx) = match (&request.get_ref().agent_id, raw) {
+            (Some(id), _) => {
+                let rx = self.agents.subscribe(id).ok_or_else(|| Status::not_found(format!("No agent {:?} is streaming to this server", id)))?;
+                (Vec::new(), rx)
+            }
+            (None, Some(raw)) => (Vec::new(), raw.subscribe()),
+            (None, None) => self.history.subscribe(request.get_ref().since_seq),
+        };
+        let release = request.get_ref().agent_id.is_some().then(|| agents::ChannelRelease(self.agents.clone()));
+        // Flows are coalesced per client, so a low-power dashboard can ask for less than the firehose
+        let mut ticker = request
+            .get_ref()
+            .min_interval_ms
+            .filter(|&ms| ms > 0)
+            .map(|ms| tokio::time::interval(std::time::Duration::from_millis(ms.into())));
+        let shutdown = self.shutdown.clone();
 
-        // Create a channel for this specific client stream
-        let (client_tx, client_rx) = tokio::sync::mpsc::channel(100);
+        // Per-client buffer. While it is full the forwarder waits and the broadcast channel
+        // keeps filling behind it, so a slow client ends up losing the oldest batches (Lagged)
+        // rather than stalling anyone else
+        let (client_tx, client_rx) = tokio::sync::mpsc::channel(self.subscribe_buffer.max(1));
+        let metrics = self.metrics.clone();
 
         tokio::spawn(async move {
-            while let Ok(batch) = rx.recv().await {
+            let _release = release;
+            let mut rx = rx;
+            let _subscriber = metrics.subscriber();
+            let shutdown = wait_for_shutdown(shutdown);
+            tokio::pin!(shutdown);
+            let mut skipped: u64 = 0;
+            let mut coalesced = aggregate::WindowAggregator::default();
+            // Seq of the newest batch coalesced, carried by the merged batch so resuming still works
+            let mut coalesced_seq: u64 = 0;
+
+            for batch in missed {
+                let Some(batch) = filter.apply(batch) else {
+                    continue;
+                };
+                if ticker.is_some() && !batch.packets.is_empty() {
+                    coalesced_seq = batch.seq;
+                    coalesced.add(batch);
+                    continue;
+                }
+                if client_tx.send(Ok(batch)).await.is_err() {
+                    return;
+                }
+            }
+
+            loop {
+                let batch = tokio::select! {
+                    _ = next_tick(&mut ticker) => {
+                        if let Some(mut batch) = coalesced.take() {
+                            batch.seq = coalesced_seq;
+                            if client_tx.send(Ok(batch)).await.is_err() {
+                                return;
+                            }
+                        }
+                        continue;
+                    }
+                    result = rx.recv() => match result {
+                        Ok(batch) => batch,
+                        // A slow client only misses the skipped batches; keep it subscribed
+                        Err(broadcast::error::RecvError::Lagged(n)) => {
+                            skipped += n;
+                            warn!(skipped = n, total_skipped = skipped, "Subscriber lagged behind");
+                            continue;
+                        }
+                        // Senders are only dropped when the server stops
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = &mut shutdown => break,
+                };
+                let Some(batch) = filter.apply(batch) else {
+                    continue;
+                };
+                // Heartbeats and peer expiries aren't flows and go out right away
+                if ticker.is_some() && !batch.packets.is_empty() {
+                    coalesced_seq = batch.seq;
+                    coalesced.add(batch);
+                    continue;
+                }
                 if client_tx.send(Ok(batch)).await.is_err() {
+                    return;
+                }
+            }
+
+            // The server is stopping. Hand over what was still being coalesced, then end with a
+            // status so the client can tell a shutdown from a dropped connection and reconnect.
+            // A client that isn't reading gets a moment, not the power to hold shutdown open
+            let farewell = async {
+                if let Some(mut batch) = coalesced.take() {
+                    batch.seq = coalesced_seq;
+                    if client_tx.send(Ok(batch)).await.is_err() {
+                        return;
+                    }
+                }
+                let _ = client_tx.send(Err(Status::unavailable("Server is shutting down"))).await;
+            };
+            let _ = tokio::time::timeout(SUBSCRIBE_FAREWELL_TIMEOUT, farewell).await;
+        });
+
+        Ok(Response::new(tokio_stream::wrappers::ReceiverStream::new(client_rx)))
+    }
+
+    type TopTalkersStream = tokio_stream::wrappers::ReceiverStream<Result<TopTalkersSnapshot, Status>>;
+
+    async fn top_talkers(
+        &self,
+        request: Request<TopTalkersRequest>,
+    ) -> Result<Response<Self::TopTalkersStream>, Status> {
+        let limit = match request.get_ref().limit as usize {
+            0 => ranking::DEFAULT_TOP_TALKERS,
+            n => n.min(ranking::MAX_TOP_TALKERS),
+        };
+        let mut rankings = self.rankings.clone();
+        let shutdown = self.shutdown.clone();
+        let (client_tx, client_rx) = tokio::sync::mpsc::channel(4);
+
+        tokio::spawn(async move {
+            let shutdown = wait_for_shutdown(shutdown);
+            tokio::pin!(shutdown);
+
+            loop {
+                let mut snapshot = TopTalkersSnapshot::clone(&rankings.borrow_and_update());
+                snapshot.peers.truncate(limit);
+                if client_tx.send(Ok(snapshot)).await.is_err() {
                     break;
                 }
+                tokio::select! {
+                    changed = rankings.changed() => if changed.is_err() {
+                        break;
+                    },
+                    _ = &mut shutdown => break,
+                }
             }
         });
 
         Ok(Response::new(tokio_stream::wrappers::ReceiverStream::new(client_rx)))
     }
+
+    async fn get_stats(&self, _request: Request<Empty>) -> Result<Response<Stats>, Status> {
+        Ok(Response::new(self.counters.snapshot(self.agents.connected_count())))
+    }
 }
 
 
 use clap::Parser;
 
+/// Version and commit of this build, as `--version` prints it and `/version` returns it.
+const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("MIKABOSHI_GIT_HASH"));
+
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version = VERSION, about, long_about = None)]
 struct Args {
     /// Port for the gRPC server (including gRPC-Web)
     #[arg(long, env = "GRPC_PORT", default_value_t = 50051)]
@@ -85,18 +437,66 @@ struct Args {
     #[arg(long, env = "HTTP_PORT", default_value_t = 8080)]
     http_port: u16,
 
+    /// Serve gRPC (native and gRPC-Web) on the HTTP port instead of a separate gRPC port
+    #[arg(long, env = "SINGLE_PORT", default_value_t = false)]
+    single_port: bool,
+
+    /// Serve gRPC server reflection, so grpcurl and similar tools can list and call the
+    /// RPCs without packet.proto
+    #[arg(long, env = "GRPC_REFLECTION", default_value_t = false)]
+    grpc_reflection: bool,
+
+    /// Address both servers bind to (IPv4 or IPv6 literal, e.g. 127.0.0.1 or ::1)
+    #[arg(long, env = "BIND_ADDRESS", default_value = "0.0.0.0")]
+    bind_address: std::net::IpAddr,
+
+    /// Serve HTTP on this Unix socket instead of the HTTP port (Unix only)
+    #[arg(long, env = "HTTP_UDS")]
+    http_uds: Option<std::path::PathBuf>,
+
+    /// Serve gRPC on this Unix socket instead of the gRPC port (Unix only)
+    #[arg(long, env = "GRPC_UDS")]
+    grpc_uds: Option<std::path::PathBuf>,
+
     /// Capacity of the broadcast channel (buffer size)
     #[arg(long, env = "CHANNEL_CAPACITY", default_value_t = 4096)]
     channel_capacity: usize,
 
+    /// Largest gRPC message (bytes) accepted from agents or sent to subscribers; raise it for
+    /// agents with a --max-message-size above this
+    #[arg(long, env = "MAX_MESSAGE_SIZE", default_value_t = 64 * 1024 * 1024)]
+    max_message_size: usize,
+
+    /// Batches buffered per gRPC subscriber before it starts lagging on the broadcast channel
+    #[arg(long, env = "SUBSCRIBE_BUFFER", default_value_t = 100)]
+    subscribe_buffer: usize,
+
+    /// What to do when a broadcast channel is full because a receiver fell behind
+    #[arg(long, env = "OVERFLOW_POLICY", value_enum, default_value_t = OverflowPolicy::DropOldest)]
+    overflow_policy: OverflowPolicy,
+
     /// Timeout for peer inactivity (seconds)
     #[arg(long, env = "PEER_TIMEOUT", default_value_t = 30)]
     peer_timeout: u64,
 
-    /// Path to the GeoIP MMDB file (optional)
+    /// Path to the GeoIP MMDB file (optional). Without it a GeoLite2 City or Country database
+    /// is looked for in --geoip-dir, /usr/share/GeoIP, /var/lib/GeoIP and /usr/local/share/GeoIP
     #[arg(long, env = "GEOIP_PATH")]
     geoip_path: Option<String>,
 
+    /// Directory searched first for a GeoIP database when --geoip-path isn't set
+    #[arg(long, env = "GEOIP_DIR")]
+    geoip_dir: Option<std::path::PathBuf>,
+
+    /// Look up the remote endpoint of every flow from agents in the GeoIP database and send
+    /// its country, city and ASN along to subscribers. Needs --geoip-path
+    #[arg(long, env = "ENRICH_GEOIP", default_value_t = false)]
+    enrich_geoip: bool,
+
+    /// Take the client address for `/geoip/me` from X-Forwarded-For (set by a reverse proxy in front of the server)
+    #[arg(long, env = "TRUST_PROXY", default_value_t = false)]
+    trust_proxy: bool,
+
     /// Basic Auth Username
     #[arg(long, env = "BASIC_AUTH_USER")]
     basic_auth_user: Option<String>,
@@ -108,142 +508,875 @@ struct Args {
     /// Threshold for traffic visualization coloring (bytes)
     #[arg(long, env = "TRAFFIC_MAX_THRESHOLD", default_value_t = 1000000.0)]
     traffic_max_threshold: f64,
+
+    /// Path to the SQLite database used to persist flows (optional)
+    #[arg(long, env = "DB_PATH")]
+    db_path: Option<String>,
+
+    /// Delete stored flows older than this, checked every minute (e.g. 30d or 12h; s, m, h, d or w).
+    /// Flows are kept forever when unset
+    #[arg(long, env = "DB_RETENTION", value_parser = store::parse_duration)]
+    db_retention: Option<std::time::Duration>,
+
+    /// Delete the oldest stored flows once the database holds more rows than this
+    #[arg(long, env = "DB_MAX_ROWS")]
+    db_max_rows: Option<u64>,
+
+    /// VACUUM the database after pruning so the file shrinks, not just the table. Rewrites the
+    /// whole file each time rows are deleted
+    #[arg(long, env = "DB_VACUUM", default_value_t = false)]
+    db_vacuum: bool,
+
+    /// Merge incoming packets per flow and broadcast once per window (ms). 0 forwards batches as they arrive
+    #[arg(long, env = "AGGREGATE_WINDOW_MS", default_value_t = 0)]
+    aggregate_window_ms: u64,
+
+    /// Broadcast an --aggregate-window-ms window early once it holds this many flows, so a
+    /// flood of distinct flows can't grow it without bound. Counted on /metrics
+    #[arg(long, env = "AGGREGATE_MAX_FLOWS")]
+    aggregate_max_flows: Option<usize>,
+
+    /// Forward every flow received (after --aggregate-window-ms, when set) to this server
+    /// (e.g. http://central:50051), acting as an agent of it
+    #[arg(long, env = "UPSTREAM")]
+    upstream: Option<String>,
+
+    /// Rolling window (seconds) used by the live TopTalkers ranking
+    #[arg(long, env = "TOP_TALKERS_WINDOW", default_value_t = 60)]
+    top_talkers_window: u64,
+
+    /// Directory containing the built web dashboard
+    #[arg(long, env = "WEB_DIR", default_value = "web/dist")]
+    web_dir: std::path::PathBuf,
+
+    /// Log output format
+    #[arg(long, env = "LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per event, for log ingestion
+    Json,
+}
+
+/// Pause between checks for room on a full channel under `OverflowPolicy::Block`.
+const OVERFLOW_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(5);
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OverflowPolicy {
+    /// Overwrite the oldest batch; receivers that fell behind skip ahead (Lagged)
+    DropOldest,
+    /// Wait for room, which stalls the agent streams feeding the channel
+    Block,
+}
+
+/// Sends to a broadcast channel according to `--overflow-policy`.
+#[derive(Debug, Clone, Copy)]
+struct Overflow {
+    policy: OverflowPolicy,
+    capacity: usize,
+}
+
+impl Overflow {
+    async fn send(&self, tx: &broadcast::Sender<PacketBatch>, batch: PacketBatch) {
+        if self.policy == OverflowPolicy::Block {
+            // broadcast has no async send; poll until the slowest receiver frees a slot. `len`
+            // follows the slowest receiver, so every receiver of these channels must be read
+            while tx.len() >= self.capacity && tx.receiver_count() > 0 {
+                tokio::time::sleep(OVERFLOW_POLL_INTERVAL).await;
+            }
+        }
+        let _ = tx.send(batch);
+    }
+}
+
+type SharedState = Arc<AppState>;
+
+/// Convert the raw `bytes` IP field of a packet into an address of the family given by
+/// the packet's `is_ipv6`. Bytes of the wrong length for that family yield `None`.
+fn ip_from_bytes(bytes: &[u8], is_ipv6: bool) -> Option<std::net::IpAddr> {
+    if is_ipv6 {
+        <[u8; 16]>::try_from(bytes).ok().map(std::net::IpAddr::from)
+    } else {
+        <[u8; 4]>::try_from(bytes).ok().map(std::net::IpAddr::from)
+    }
+}
+
+/// Whether `p` is an agent's `--top-flows` sum of the flows it left out of a batch, or a
+/// `--group-by` entry without one of its addresses, whose unspecified addresses aren't a
+/// host to rank, expire, store or locate.
+fn has_unspecified_hosts(p: &packet::Packet) -> bool {
+    p.flags & (packet::PacketFlag::OtherFlows as u32 | packet::PacketFlag::Grouped as u32) != 0
+}
+
+/// Raw `bytes` IP fields for a packet between `src` and `dst`, and its `is_ipv6`. A
+/// mixed-family pair is sent as IPv4-mapped IPv6 addresses.
+fn ip_pair_to_bytes(src: std::net::IpAddr, dst: std::net::IpAddr) -> (Vec<u8>, Vec<u8>, bool) {
+    use std::net::IpAddr;
+    match (src, dst) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => (s.octets().to_vec(), d.octets().to_vec(), false),
+        (s, d) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            (v6(s).octets().to_vec(), v6(d).octets().to_vec(), true)
+        }
+    }
+}
+
+/// JSON form of a packet shared by the non-gRPC streaming endpoints.
+fn packet_to_json(p: &Packet) -> serde_json::Value {
+    serde_json::json!({
+        "type": "packet",
+        "src_ip": ip_from_bytes(&p.src_ip, p.is_ipv6).map(|ip| ip.to_string()),
+        "dst_ip": ip_from_bytes(&p.dst_ip, p.is_ipv6).map(|ip| ip.to_string()),
+        "is_ipv6": p.is_ipv6,
+        "src_is_agent": p.src_is_agent,
+        "dst_is_agent": p.dst_is_agent,
+        "size": p.size,
+        "packets": p.packets,
+        "proto": store::proto_name(p.proto),
+        "src_port": p.src_port,
+        "dst_port": p.dst_port,
+        "icmp_type": p.icmp_type,
+        "icmp_code": p.icmp_code,
+        "sni": p.sni,
+        "dns_query": p.dns_query,
+        "service": p.service,
+        "transit": p.transit,
+        "first_seen": p.first_seen,
+        "last_seen": p.last_seen,
+        "first_seen_ns": p.first_seen_ns,
+        "last_seen_ns": p.last_seen_ns,
+        "flags": p.flags,
+        "tcp_window": p.tcp_window,
+        "tcp_mss": p.tcp_mss,
+        "highlighted": p.highlighted,
+        "anomalous": p.anomalous,
+        "client_to_server": p.client_to_server,
+        "vlan": p.vlan,
+        "country": p.country,
+        "city": p.city,
+        "asn": p.asn,
+        "ttl": p.ttl,
+        "dscp": p.dscp,
+        "avg_size": p.avg_size,
+        "site": p.site,
+        "replay": p.replay,
+        "src_mac": mac_to_string(&p.src_mac),
+        "dst_mac": mac_to_string(&p.dst_mac)
+    })
+}
+
+/// Format a 6-byte MAC as `aa:bb:cc:dd:ee:ff`; anything else (usually empty) is `None`.
+fn mac_to_string(bytes: &[u8]) -> Option<String> {
+    if bytes.len() != 6 {
+        return None;
+    }
+    Some(bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":"))
+}
+
+fn json_error(status: axum::http::StatusCode, message: impl std::fmt::Display) -> axum::response::Response {
+    use axum::response::IntoResponse;
+    (status, axum::Json(serde_json::json!({ "error": message.to_string() }))).into_response()
+}
+
+/// Run a blocking store query off the async runtime and render the rows as JSON.
+async fn query_store<T, F>(state: &SharedState, query: F) -> axum::response::Response
+where
+    T: serde::Serialize + Send + 'static,
+    F: FnOnce(&store::FlowStore) -> rusqlite::Result<T> + Send + 'static,
+{
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    let Some(store) = state.store.clone() else {
+        return json_error(StatusCode::NOT_FOUND, "Persistence not configured");
+    };
+    match tokio::task::spawn_blocking(move || query(&store)).await {
+        Ok(Ok(rows)) => axum::Json(rows).into_response(),
+        Ok(Err(e)) => json_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+async fn ws_handler(
+    ws: axum::extract::ws::WebSocketUpgrade,
+    axum::extract::State(state): axum::extract::State<SharedState>,
+) -> axum::response::Response {
+    let rx = state.tx.subscribe();
+    let shutdown = state.shutdown.clone();
+    let subscriber = state.metrics.subscriber();
+    ws.on_upgrade(move |socket| async move {
+        let _subscriber = subscriber;
+        ws_forward(socket, rx, shutdown).await
+    })
+}
+
+/// Receive the next batch as JSON frames for the streaming endpoints: one per matching
+/// packet, or a single `lagged` notice when the receiver fell behind. `None` once closed.
+async fn recv_frames(
+    rx: &mut broadcast::Receiver<PacketBatch>,
+    filter: &PacketFilter,
+) -> Option<Vec<serde_json::Value>> {
+    use tokio::sync::broadcast::error::RecvError;
+
+    match rx.recv().await {
+        Ok(batch) if !batch.expired_peers.is_empty() => Some(
+            batch
+                .expired_peers
+                .iter()
+                .map(|peer| serde_json::json!({ "type": "peer_expired", "ip": ip_from_bytes(&peer.ip, peer.is_ipv6).map(|ip| ip.to_string()) }))
+                .collect(),
+        ),
+        Ok(batch) if batch.packets.is_empty() => Some(vec![serde_json::json!({ "type": "heartbeat" })]),
+        Ok(batch) => Some(
+            filter
+                .apply(batch)
+                .map(|batch| batch.packets.iter().map(packet_to_json).collect())
+                .unwrap_or_default(),
+        ),
+        // Tell the client it missed data instead of silently skipping ahead
+        Err(RecvError::Lagged(n)) => Some(vec![serde_json::json!({ "type": "lagged", "skipped": n })]),
+        Err(RecvError::Closed) => None,
+    }
+}
+
+async fn ws_forward(
+    mut socket: axum::extract::ws::WebSocket,
+    mut rx: broadcast::Receiver<PacketBatch>,
+    shutdown: watch::Receiver<bool>,
+) {
+    use axum::extract::ws::Message;
+
+    let shutdown = wait_for_shutdown(shutdown);
+    tokio::pin!(shutdown);
+    let filter = PacketFilter::default();
+
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            frames = recv_frames(&mut rx, &filter) => {
+                let Some(frames) = frames else {
+                    break;
+                };
+                for frame in frames {
+                    if socket.send(Message::Text(frame.to_string())).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            msg = socket.recv() => {
+                match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct EventsQuery {
+    filter: Option<String>,
+}
+
+/// Parse the `?filter=` syntax of `/events`: comma-separated `key:value` pairs named after
+/// the `SubscribeFilter` fields, e.g. `ip:10.0.0.5,proto:TCP,port:443`.
+fn parse_filter_param(text: &str) -> Result<SubscribeFilter, String> {
+    let mut filter = SubscribeFilter::default();
+    for part in text.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let (key, value) = part
+            .split_once(':')
+            .ok_or_else(|| format!("Expected key:value, got {:?}", part))?;
+        match key {
+            "ip" => filter.ip = Some(value.to_string()),
+            "proto" => filter.proto = Some(store::parse_proto(value).ok_or_else(|| format!("Invalid protocol: {}", value))?),
+            "port" => filter.port = Some(value.parse().map_err(|_| format!("Invalid port: {}", value))?),
+            "agent_id" => filter.agent_id = Some(value.to_string()),
+            _ => return Err(format!("Unknown filter key: {}", key)),
+        }
+    }
+    Ok(filter)
+}
+
+async fn events_handler(
+    axum::extract::State(state): axum::extract::State<SharedState>,
+    axum::extract::Query(query): axum::extract::Query<EventsQuery>,
+) -> axum::response::Response {
+    use axum::http::StatusCode;
+    use axum::response::sse::{Event, KeepAlive, Sse};
+    use axum::response::IntoResponse;
+
+    let request = match query.filter.as_deref().map(parse_filter_param).transpose() {
+        Ok(request) => request.unwrap_or_default(),
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    let filter = match PacketFilter::from_request(&request) {
+        Ok(filter) => filter,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    let rx = match &request.agent_id {
+        Some(id) => match state.agents.subscribe(id) {
+            Some(rx) => rx,
+            None => return json_error(StatusCode::NOT_FOUND, format!("No agent {:?} is streaming to this server", id)),
+        },
+        None => state.tx.subscribe(),
+    };
+    let release = request.agent_id.is_some().then(|| agents::ChannelRelease(state.agents.clone()));
+    let shutdown = state.shutdown.clone();
+
+    let (event_tx, event_rx) = tokio::sync::mpsc::channel::<Result<Event, std::convert::Infallible>>(100);
+    let subscriber = state.metrics.subscriber();
+    tokio::spawn(async move {
+        let _release = release;
+        let mut rx = rx;
+        let _subscriber = subscriber;
+        let shutdown = wait_for_shutdown(shutdown);
+        tokio::pin!(shutdown);
+
+        loop {
+            let frames = tokio::select! {
+                _ = &mut shutdown => break,
+                _ = event_tx.closed() => break,
+                frames = recv_frames(&mut rx, &filter) => match frames {
+                    Some(frames) => frames,
+                    None => break,
+                },
+            };
+            for frame in frames {
+                if event_tx.send(Ok(Event::default().data(frame.to_string()))).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    Sse::new(tokio_stream::wrappers::ReceiverStream::new(event_rx))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+async fn flows_handler(
+    axum::extract::State(state): axum::extract::State<SharedState>,
+    axum::extract::Query(query): axum::extract::Query<store::FlowQuery>,
+) -> axum::response::Response {
+    query_store(&state, move |store| store.query_flows(&query)).await
+}
+
+/// `/flows` as a streamed CSV download.
+async fn flows_csv_handler(
+    axum::extract::State(state): axum::extract::State<SharedState>,
+    axum::extract::Query(query): axum::extract::Query<store::FlowQuery>,
+) -> axum::response::Response {
+    use axum::http::{header, StatusCode};
+    use axum::response::IntoResponse;
+
+    let Some(store) = state.store.clone() else {
+        return json_error(StatusCode::NOT_FOUND, "Persistence not configured");
+    };
+
+    let (chunk_tx, chunk_rx) = tokio::sync::mpsc::channel::<Result<String, std::io::Error>>(8);
+    tokio::task::spawn_blocking(move || {
+        let result = store.export_csv(&query, |chunk| chunk_tx.blocking_send(Ok(chunk)).is_ok());
+        if let Err(e) = result {
+            // Aborts the response mid-stream; the client sees a truncated download
+            error!(error = %e, "CSV export failed");
+            let _ = chunk_tx.blocking_send(Err(std::io::Error::other(e)));
+        }
+    });
+
+    (
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"flows.csv\""),
+        ],
+        axum::body::Body::from_stream(tokio_stream::wrappers::ReceiverStream::new(chunk_rx)),
+    )
+        .into_response()
+}
+
+/// Start replaying stored flows into the live broadcast, replacing any replay in progress.
+async fn replay_handler(
+    axum::extract::State(state): axum::extract::State<SharedState>,
+    axum::extract::Query(query): axum::extract::Query<replay::ReplayQuery>,
+) -> axum::response::Response {
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    let Some(store) = state.store.clone() else {
+        return json_error(StatusCode::NOT_FOUND, "Persistence not configured");
+    };
+    let speed = query.speed.unwrap_or(1.0);
+    if !(replay::MIN_SPEED..=replay::MAX_SPEED).contains(&speed) {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            format!("speed must be between {} and {}", replay::MIN_SPEED, replay::MAX_SPEED),
+        );
+    }
+    let to = query.to.unwrap_or_else(store::now_secs);
+
+    let mut running = state.replay.lock().unwrap();
+    if let Some(previous) = running.take() {
+        if !previous.is_finished() {
+            info!("Cancelling the running replay");
+            previous.abort();
+        }
+    }
+    info!(from = query.from, to, speed, "Starting replay");
+    *running = Some(replay::spawn(store, state.tx.clone(), query.from, to, speed, state.shutdown.clone()));
+
+    (
+        StatusCode::ACCEPTED,
+        axum::Json(serde_json::json!({ "from": query.from, "to": to, "speed": speed })),
+    )
+        .into_response()
+}
+
+/// Accept flows posted as JSON (see `ingest`) and hand them on like a batch from an agent.
+async fn ingest_handler(
+    axum::extract::State(state): axum::extract::State<SharedState>,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    let mut batch = match ingest::decode(&body) {
+        Ok(batch) => batch,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    let flows = batch.packets.len();
+    // An empty batch would pass for an agent heartbeat
+    if flows > 0 {
+        if let Some(enricher) = &state.enricher {
+            enricher.enrich(&mut batch.packets);
+        }
+        state.counters.record(&batch);
+        let fanout_started = std::time::Instant::now();
+        state.ingest.send(batch).await;
+        state.metrics.observe_send(fanout_started.elapsed());
+    }
+    (StatusCode::ACCEPTED, axum::Json(serde_json::json!({ "accepted": flows }))).into_response()
+}
+
+/// JSON for a GeoIP lookup of `ip`, shared by `/geoip/:ip` and `/geoip/me`.
+fn geoip_lookup(state: &AppState, ip: &str) -> axum::response::Json<serde_json::Value> {
+    let database = state.geoip.read().unwrap().clone();
+    axum::response::Json(geoip_result(database.as_deref(), ip))
+}
+
+/// Most IPs one `POST /geoip/batch` may look up.
+const MAX_GEOIP_BATCH: usize = 500;
+
+/// Look up a JSON array of IPs at once, answering with an object keyed by IP whose values
+/// are what `GET /geoip/:ip` returns for each.
+async fn geoip_batch_handler(
+    axum::extract::State(state): axum::extract::State<SharedState>,
+    axum::Json(ips): axum::Json<Vec<String>>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    if ips.len() > MAX_GEOIP_BATCH {
+        return json_error(
+            axum::http::StatusCode::PAYLOAD_TOO_LARGE,
+            format!("At most {} IPs per batch", MAX_GEOIP_BATCH),
+        );
+    }
+    let database = state.geoip.read().unwrap().clone();
+    let results: serde_json::Map<String, serde_json::Value> = ips
+        .into_iter()
+        .map(|ip| {
+            let result = geoip_result(database.as_deref(), &ip);
+            (ip, result)
+        })
+        .collect();
+    axum::Json(results).into_response()
+}
+
+fn geoip_result(database: Option<&geoip::GeoIpDatabase>, ip: &str) -> serde_json::Value {
+    let Some(database) = database else {
+        return serde_json::json!({ "error": "GeoIP not configured" });
+    };
+    let ip_addr: std::net::IpAddr = match ip.parse() {
+        Ok(addr) => addr,
+        Err(_) => return serde_json::json!({ "error": "Invalid IP" }),
+    };
+
+    match database.lookup(ip_addr) {
+        Ok((country_name, city_name)) => {
+            serde_json::json!({
+                "ip": ip,
+                "country_name": country_name,
+                "city": city_name,
+                "org": null, // Not available in City DB
+                "asn": null  // Not available in City DB
+            })
+        },
+        Err(_) => serde_json::json!({ "error": "IP not found" })
+    }
+}
+
+/// The requesting client's address: the peer of the connection, or with `--trust-proxy`
+/// the last X-Forwarded-For entry, which is the one our proxy appended.
+fn client_ip(remote: SocketAddr, headers: &axum::http::HeaderMap, trust_proxy: bool) -> std::net::IpAddr {
+    if trust_proxy {
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|entry| entry.trim().parse().ok())
+            .next_back();
+        if let Some(ip) = forwarded {
+            return ip;
+        }
+    }
+    remote.ip()
+}
+
+async fn agents_handler(
+    axum::extract::State(state): axum::extract::State<SharedState>,
+) -> axum::Json<Vec<agents::AgentInfo>> {
+    axum::Json(state.agents.list())
+}
+
+async fn top_talkers_handler(
+    axum::extract::State(state): axum::extract::State<SharedState>,
+    axum::extract::Query(query): axum::extract::Query<store::TopTalkersQuery>,
+) -> axum::response::Response {
+    query_store(&state, move |store| store.top_talkers(&query)).await
+}
+
+async fn db_stats_handler(axum::extract::State(state): axum::extract::State<SharedState>) -> axum::response::Response {
+    query_store(&state, |store| store.stats()).await
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct SnapshotQuery {
+    n: Option<usize>,
+    proto: Option<String>,
+}
+
+/// The top flows by bytes over the rolling window, as one JSON document rather than a stream.
+async fn snapshot_handler(
+    state: SharedState,
+    axum::extract::Query(query): axum::extract::Query<SnapshotQuery>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let proto = match query.proto.as_deref().map(|proto| store::parse_proto(proto).ok_or(proto)).transpose() {
+        Ok(proto) => proto,
+        Err(proto) => return json_error(axum::http::StatusCode::BAD_REQUEST, format!("Invalid protocol: {}", proto)),
+    };
+    let limit = match query.n.unwrap_or(ranking::DEFAULT_TOP_TALKERS) {
+        0 => ranking::DEFAULT_TOP_TALKERS,
+        n => n.min(ranking::MAX_TOP_TALKERS),
+    };
+    axum::Json(serde_json::json!({
+        "window_secs": state.flows.window_secs(),
+        "flows": state.flows.top(limit, proto),
+    }))
+    .into_response()
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct TopologyQuery {
+    n: Option<usize>,
+}
+
+/// The rolling window as a host graph, so a dashboard can draw it on load instead of
+/// building it up from the stream.
+async fn topology_handler(
+    axum::extract::State(state): axum::extract::State<SharedState>,
+    axum::extract::Query(query): axum::extract::Query<TopologyQuery>,
+) -> axum::Json<ranking::Topology> {
+    let limit = match query.n.unwrap_or(ranking::DEFAULT_TOPOLOGY_EDGES) {
+        0 => ranking::DEFAULT_TOPOLOGY_EDGES,
+        n => n.min(ranking::MAX_TOPOLOGY_EDGES),
+    };
+    axum::Json(state.flows.topology(limit))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
+    let mut args = Args::parse();
+    match args.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::init(),
+        LogFormat::Json => tracing_subscriber::fmt().json().init(),
+    }
+    // An explicit --geoip-path is used as given, even while it doesn't exist yet
+    let geoip_detected = args.geoip_path.is_none();
+    if geoip_detected {
+        args.geoip_path = geoip::detect(args.geoip_dir.as_deref());
+        if let Some(path) = &args.geoip_path {
+            info!(path = %path, "Found a GeoIP database, using it (set --geoip-path to choose another)");
+        }
+    }
+    if cfg!(not(unix)) && (args.http_uds.is_some() || args.grpc_uds.is_some()) {
+        return Err("--http-uds and --grpc-uds need Unix domain sockets, which this platform lacks".into());
+    }
+    if args.enrich_geoip && args.geoip_path.is_none() {
+        return Err("--enrich-geoip needs a database to look flows up in; set --geoip-path (none was found in the usual locations)".into());
+    }
+    let retention = store::Retention {
+        max_age: args.db_retention,
+        max_rows: args.db_max_rows,
+        vacuum: args.db_vacuum,
+    };
+    if args.aggregate_max_flows.is_some() && args.aggregate_window_ms == 0 {
+        return Err("--aggregate-max-flows caps the --aggregate-window-ms window; set that too".into());
+    }
+    if args.aggregate_max_flows == Some(0) {
+        return Err("--aggregate-max-flows must be at least 1".into());
+    }
+    if (retention.is_enabled() || retention.vacuum) && args.db_path.is_none() {
+        return Err("--db-retention, --db-max-rows and --db-vacuum prune the flow database; set --db-path".into());
+    }
+
+    // Channel for broadcasting packets. No receiver is kept here: one that is never read would
+    // keep the queue full forever, and --overflow-policy block would wait on it
+    let tx = broadcast::channel(args.channel_capacity).0;
+
+    // Flipped to true once SIGINT/SIGTERM is received; every server and stream watches it
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutdown signal received, stopping servers");
+        let _ = shutdown_tx.send(true);
+    });
+
+    // --- Persistence Setup ---
+    let (flow_store, store_writer) = if let Some(path) = &args.db_path {
+        info!(path = %path, "Persisting flows to SQLite database");
+        let store = Arc::new(store::FlowStore::open(path)?);
+        let writer = store::spawn_writer(store.clone(), tx.subscribe(), shutdown_rx.clone());
+        if retention.is_enabled() {
+            info!(retention = ?retention.max_age, max_rows = ?retention.max_rows, vacuum = retention.vacuum, "Pruning the flow database");
+            store::spawn_pruner(store.clone(), retention, shutdown_rx.clone());
+        }
+        (Some(store), Some(writer))
+    } else {
+        (None, None)
+    };
 
-    let args = Args::parse();
+    let overflow = Overflow {
+        policy: args.overflow_policy,
+        capacity: args.channel_capacity,
+    };
+    if overflow.policy == OverflowPolicy::Block {
+        info!("Blocking agent streams while subscribers catch up (--overflow-policy block)");
+    }
+    let metrics = Arc::new(metrics::FanoutMetrics::new());
+    let (ingest, aggregator_handle) = if args.aggregate_window_ms > 0 {
+        info!(window_ms = args.aggregate_window_ms, max_flows = ?args.aggregate_max_flows, "Aggregating packets server-side");
+        let (ingest_tx, handle) = aggregate::spawn(
+            std::time::Duration::from_millis(args.aggregate_window_ms),
+            args.aggregate_max_flows,
+            tx.clone(),
+            overflow,
+            metrics.clone(),
+        );
+        (Ingest::Windowed(ingest_tx, broadcast::channel(args.channel_capacity).0, overflow), Some(handle))
+    } else {
+        (Ingest::Direct(tx.clone(), overflow), None)
+    };
 
-    // Channel for broadcasting packets
-    let (tx, _rx) = broadcast::channel(args.channel_capacity);
+    let app_state = Arc::new_cyclic(|state| AppState {
+        tx: tx.clone(),
+        store: flow_store,
+        agents: Arc::new(agents::AgentRegistry::new(args.channel_capacity)),
+        counters: Arc::new(stats::TrafficCounters::new()),
+        metrics,
+        flows: ranking::spawn_flows(args.top_talkers_window, tx.subscribe(), shutdown_rx.clone()),
+        ingest,
+        enricher: args.enrich_geoip.then(|| Arc::new(geoip::Enricher::new(state.clone()))),
+        shutdown: shutdown_rx.clone(),
+        grpc_ready: AtomicBool::new(false),
+        geoip_ready: AtomicBool::new(args.geoip_path.is_none()),
+        geoip: std::sync::RwLock::new(None),
+        replay: std::sync::Mutex::new(None),
+    });
 
     // --- gRPC Server (including gRPC-Web) ---
-    let grpc_addr = SocketAddr::from(([0, 0, 0, 0], args.grpc_port));
-    let grpc_service = GrpcService { tx: Some(tx.clone()) }; 
+    let grpc_addr = SocketAddr::new(args.bind_address, args.grpc_port);
+
+    let peers_handle = peers::spawn(
+        std::time::Duration::from_secs(args.peer_timeout),
+        tx.subscribe(),
+        tx.clone(),
+        shutdown_rx.clone(),
+    );
+
+    let upstream_handle = args
+        .upstream
+        .clone()
+        .map(|url| upstream::spawn(url, args.max_message_size, tx.subscribe(), shutdown_rx.clone()));
+
+    let grpc_service = GrpcService {
+        history: history::spawn(args.channel_capacity, tx.subscribe(), shutdown_rx.clone()),
+        ingest: app_state.ingest.clone(),
+        agents: app_state.agents.clone(),
+        counters: app_state.counters.clone(),
+        metrics: app_state.metrics.clone(),
+        shutdown: shutdown_rx.clone(),
+        subscribe_buffer: args.subscribe_buffer,
+        rankings: ranking::spawn(args.top_talkers_window, tx.subscribe(), shutdown_rx.clone()),
+        overflow,
+        enricher: app_state.enricher.clone(),
+    };
     
     // Enable gRPC-Web and CORS
-    let service = AgentServiceServer::new(grpc_service);
+    // Agents may gzip their batches; responses stay uncompressed so gRPC-Web clients keep working
+    let service = AgentServiceServer::new(grpc_service)
+        .accept_compressed(tonic::codec::CompressionEncoding::Gzip)
+        .max_decoding_message_size(args.max_message_size)
+        .max_encoding_message_size(args.max_message_size);
     let service = tonic_web::enable(service);
 
-    println!("gRPC (Native + Web) server listening on {}", grpc_addr);
-    
-    // Spawn gRPC server
-    tokio::spawn(async move {
-        Server::builder()
-        .accept_http1(true) // Required for gRPC-Web
-        .layer(CorsLayer::new()
-            .allow_origin(Any)
-            .allow_headers(Any)
-            .allow_methods(Any)
-        )
-        .add_service(service)
-        .serve(grpc_addr)
-        .await
-        .unwrap();
-    });
-
-    // --- GeoIP Setup ---
-    let mut attribution_text: Option<String> = None;
-    let mut attribution_url: Option<String> = None;
-
-    let geoip_reader = if let Some(path) = &args.geoip_path {
-        println!("Loading GeoIP database from: {}", path);
-        match maxminddb::Reader::open_readfile(path) {
-            Ok(reader) => Some(std::sync::Arc::new(reader)),
-            Err(e) => {
-                eprintln!("Failed to load GeoIP database: {}. Continuing without local GeoIP.", e);
-                None
-            }
-        }
+    // Both reflection versions, since tools differ in which one they ask for
+    let reflection = if args.grpc_reflection {
+        let builder = || tonic_reflection::server::Builder::configure().register_encoded_file_descriptor_set(packet::FILE_DESCRIPTOR_SET);
+        info!("gRPC server reflection enabled");
+        Some((builder().build_v1()?, builder().build_v1alpha()?))
     } else {
         None
     };
 
-    if let Some(reader) = &geoip_reader {
-        println!("GeoIP database loaded successfully.");
-        
-        // Auto-detect attribution
-        let metadata = &reader.metadata;
-        let db_type = &metadata.database_type;
-        let description = metadata.description.get("en").map(|s| s.as_str()).unwrap_or("");
-        
-        println!("Database Type: {}", db_type);
-        println!("Description: {}", description);
+    let (grpc_handle, single_port_grpc) = if args.single_port {
+        // Served by the HTTP server further down
+        (None, Some((service, reflection)))
+    } else {
+        let router = Server::builder()
+            .accept_http1(true) // Required for gRPC-Web
+            .layer(CorsLayer::new()
+                .allow_origin(Any)
+                .allow_headers(Any)
+                .allow_methods(Any)
+            )
+            .add_service(service)
+            .add_optional_service(reflection.as_ref().map(|(v1, _)| v1.clone()))
+            .add_optional_service(reflection.map(|(_, v1alpha)| v1alpha));
+        let grpc_shutdown = wait_for_shutdown(shutdown_rx.clone());
 
-        if db_type.contains("DBIP") || description.contains("DB-IP") {
-            println!("Detected DB-IP database. Setting attribution.");
-            attribution_text = Some("IP Geolocation by DB-IP".to_string());
-            attribution_url = Some("https://db-ip.com".to_string());
-        } else {
-            // Fallback to database type
-            attribution_text = Some(db_type.clone());
-            attribution_url = None;
-        }
+        // Bind up front so a port conflict fails startup instead of a background task
+        let serve: futures::future::BoxFuture<'static, Result<(), tonic::transport::Error>> = match &args.grpc_uds {
+            #[cfg(unix)]
+            Some(path) => {
+                let grpc_listener = uds::bind(path)?;
+                info!(path = %path.display(), "gRPC (Native + Web) server listening on a Unix socket");
+                Box::pin(router.serve_with_incoming_shutdown(
+                    tokio_stream::wrappers::UnixListenerStream::new(grpc_listener),
+                    grpc_shutdown,
+                ))
+            }
+            _ => {
+                let grpc_listener = tokio::net::TcpListener::bind(grpc_addr).await?;
+                info!(addr = %grpc_addr, "gRPC (Native + Web) server listening");
+                Box::pin(router.serve_with_incoming_shutdown(
+                    tokio_stream::wrappers::TcpListenerStream::new(grpc_listener),
+                    grpc_shutdown,
+                ))
+            }
+        };
+        app_state.grpc_ready.store(true, Ordering::SeqCst);
+
+        // Spawn gRPC server
+        let grpc_state = app_state.clone();
+        let handle = tokio::spawn(async move {
+            let result = serve.await;
+            grpc_state.grpc_ready.store(false, Ordering::SeqCst);
+            if let Err(e) = result {
+                error!(error = %e, "gRPC server error");
+            }
+        });
+        (Some(handle), None)
+    };
+
+    // --- GeoIP Setup ---
+    if let Some(path) = args.geoip_path.clone() {
+        geoip::load(path, geoip_detected, app_state.clone()).await;
     } else {
-        // Fallback to ipapi
-        println!("Using ipapi.co for GeoIP.");
-        attribution_text = Some("IP Geolocation by ipapi.co".to_string());
-        attribution_url = Some("https://ipapi.co".to_string());
+        info!("Using ipapi.co for GeoIP");
     }
 
-    let geoip_state = geoip_reader.clone();
     let config_args = std::sync::Arc::new(args);
     let config_args_monitor = config_args.clone();
-
-    // Capture attributions for move
-    let attr_text = attribution_text.clone();
-    let attr_url = attribution_url.clone();
+    let trust_proxy = config_args.trust_proxy;
 
     // --- HTTP Server (Static Files) ---
-    // Serve static files from web/dist
+    // Serve static files from the web dir, falling back to index.html so client-side routes deep-link
+    let web_dir = config_args.web_dir.clone();
+    if !web_dir.is_dir() {
+        warn!(web_dir = %web_dir.display(), "Web directory does not exist; the dashboard will not load, set --web-dir / WEB_DIR");
+    }
+    let static_files = ServeDir::new(&web_dir).fallback(ServeFile::new(web_dir.join("index.html")));
+
     let mut app = Router::new()
-        .route("/config", axum::routing::get(move || async move {
+        .route("/config", axum::routing::get(move |axum::extract::State(state): axum::extract::State<SharedState>| async move {
+            // Follows the database once a retried load succeeds
+            let geoip = state.geoip.read().unwrap().clone();
+            let (attr_text, attr_url) = match &geoip {
+                Some(database) => (database.attribution_text.clone(), database.attribution_url.clone()),
+                None => ("IP Geolocation by ipapi.co".to_string(), Some("https://ipapi.co".to_string())),
+            };
             axum::Json(serde_json::json!({
                 "grpcPort": config_args_monitor.grpc_port,
                 "peerTimeout": config_args_monitor.peer_timeout * 1000, // Convert to ms
-                "geoipEnabled": geoip_state.is_some(),
+                "geoipEnabled": geoip.is_some(),
                 "geoipAttributionText": attr_text,
                 "geoipAttributionUrl": attr_url,
                 "trafficMaxThreshold": config_args_monitor.traffic_max_threshold
             }))
         }))
-        .route("/geoip/:ip", axum::routing::get(move |axum::extract::Path(ip): axum::extract::Path<String>| {
-             let reader = geoip_reader.clone();
-             async move {
-                 if let Some(reader) = reader {
-                     let ip_addr: std::net::IpAddr = match ip.parse() {
-                         Ok(addr) => addr,
-                         Err(_) => return axum::response::Json(serde_json::json!({ "error": "Invalid IP" })),
-                     };
-
-                     // Use maxminddb::geoip2::City for standard City DB
-                     match reader.lookup::<maxminddb::geoip2::City>(ip_addr) {
-                         Ok(city) => {
-                             let country_name = city.country.and_then(|c| c.names).and_then(|n| n.get("en").map(|s| s.to_string()));
-                             let city_name = city.city.and_then(|c| c.names).and_then(|n| n.get("en").map(|s| s.to_string()));
-                             
-                             axum::response::Json(serde_json::json!({
-                                 "ip": ip,
-                                 "country_name": country_name,
-                                 "city": city_name,
-                                 "org": null, // Not available in City DB
-                                 "asn": null  // Not available in City DB
-                             }))
-                         },
-                         Err(_) => axum::response::Json(serde_json::json!({ "error": "IP not found" }))
-                     }
-                 } else {
-                     axum::response::Json(serde_json::json!({ "error": "GeoIP not configured" }))
-                 }
-             }
+        .route("/geoip/info", axum::routing::get(|axum::extract::State(state): axum::extract::State<SharedState>| async move {
+            use axum::response::IntoResponse;
+            let database = state.geoip.read().unwrap().clone();
+            match database {
+                Some(database) => axum::Json(database.info()).into_response(),
+                None => json_error(axum::http::StatusCode::NOT_FOUND, "GeoIP database not loaded"),
+            }
+        }))
+        .route("/geoip/me", axum::routing::get(move |axum::extract::State(state): axum::extract::State<SharedState>, axum::extract::ConnectInfo(remote): axum::extract::ConnectInfo<SocketAddr>, headers: axum::http::HeaderMap| async move {
+            let ip = client_ip(remote, &headers, trust_proxy);
+            geoip_lookup(&state, &ip.to_string())
         }))
-        .nest_service("/", ServeDir::new("web/dist"));
+        .route("/geoip/batch", axum::routing::post(geoip_batch_handler))
+        .route("/geoip/:ip", axum::routing::get(|axum::extract::State(state): axum::extract::State<SharedState>, axum::extract::Path(ip): axum::extract::Path<String>| async move {
+            geoip_lookup(&state, &ip)
+        }))
+        .route("/ws", axum::routing::get(ws_handler))
+        .route("/events", axum::routing::get(events_handler))
+        .route("/flows", axum::routing::get(flows_handler))
+        .route("/flows.csv", axum::routing::get(flows_csv_handler))
+        .route("/flows/top-talkers", axum::routing::get(top_talkers_handler))
+        .route("/db/stats", axum::routing::get(db_stats_handler))
+        .route("/topology", axum::routing::get(topology_handler))
+        .route("/agents", axum::routing::get(agents_handler))
+        .route("/version", axum::routing::get(|| async {
+            axum::Json(serde_json::json!({
+                "version": env!("CARGO_PKG_VERSION"),
+                "git_hash": env!("MIKABOSHI_GIT_HASH"),
+                // Unix seconds
+                "build_time": env!("MIKABOSHI_BUILD_TIME").parse::<u64>().unwrap_or(0),
+            }))
+        }))
+        .route("/replay", axum::routing::post(replay_handler))
+        .route("/ingest", axum::routing::post(ingest_handler))
+        .route("/metrics", axum::routing::get(|axum::extract::State(state): axum::extract::State<SharedState>| async move {
+            ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], state.metrics.render())
+        }))
+        .nest_service("/", static_files)
+        .with_state(app_state.clone());
 
     // Enable Basic Auth if configured
     if let (Some(user), Some(pass)) = (config_args.basic_auth_user.clone(), config_args.basic_auth_password.clone()) {
-        println!("Basic Authentication enabled for user: {}", user);
+        info!(user = %user, "Basic Authentication enabled");
         let auth_string = format!("{}:{}", user, pass);
         let encoded_auth = base64::encode(auth_string);
         let expected_header_value = format!("Basic {}", encoded_auth);
@@ -270,14 +1403,113 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }));
     } else {
-        println!("Basic Authentication disabled (credentials not set).");
+        info!("Basic Authentication disabled (credentials not set)");
     }
 
-    let http_addr = SocketAddr::from(([0, 0, 0, 0], config_args.http_port));
-    println!("HTTP server listening on {}", http_addr);
-    
-    let listener = tokio::net::TcpListener::bind(http_addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    // Probes and /snapshot are added after the auth layer so load balancers and scripts can
+    // reach them without credentials
+    let ready_state = app_state.clone();
+    let snapshot_state = app_state.clone();
+    app = app
+        .route("/healthz", axum::routing::get(|| async { "ok" }))
+        .route("/snapshot", axum::routing::get(move |query: axum::extract::Query<SnapshotQuery>| {
+            snapshot_handler(snapshot_state.clone(), query)
+        }))
+        .route("/readyz", axum::routing::get(move || {
+            let state = ready_state.clone();
+            async move {
+                let grpc = state.grpc_ready.load(Ordering::SeqCst);
+                let geoip = state.geoip_ready.load(Ordering::SeqCst);
+                let status = if grpc && geoip {
+                    axum::http::StatusCode::OK
+                } else {
+                    axum::http::StatusCode::SERVICE_UNAVAILABLE
+                };
+                (status, axum::Json(serde_json::json!({ "grpc": grpc, "geoip": geoip })))
+            }
+        }));
+
+    // gRPC paths are /<package>.<Service>/<Method>, so they can't collide with the dashboard.
+    // Like the two-port setup, gRPC sits outside Basic Auth
+    // tonic services take tonic's body type, so axum's is boxed into it on the way in
+    if let Some((service, reflection)) = single_port_grpc {
+        let to_tonic = |request: axum::http::Request<axum::body::Body>| request.map(tonic::body::boxed);
+        app = app.route_service(
+            "/packet.AgentService/*rpc",
+            tower::ServiceBuilder::new()
+                .map_request(to_tonic)
+                .layer(CorsLayer::new().allow_origin(Any).allow_headers(Any).allow_methods(Any))
+                .service(service),
+        );
+        if let Some((v1, v1alpha)) = reflection {
+            app = app
+                .route_service(
+                    "/grpc.reflection.v1.ServerReflection/*rpc",
+                    tower::ServiceBuilder::new().map_request(to_tonic).service(v1),
+                )
+                .route_service(
+                    "/grpc.reflection.v1alpha.ServerReflection/*rpc",
+                    tower::ServiceBuilder::new().map_request(to_tonic).service(v1alpha),
+                );
+        }
+    }
+
+    match &config_args.http_uds {
+        #[cfg(unix)]
+        Some(path) => {
+            let listener = uds::bind(path)?;
+            if config_args.single_port {
+                app_state.grpc_ready.store(true, Ordering::SeqCst);
+                info!(path = %path.display(), "HTTP and gRPC (Native + Web) server listening on a Unix socket");
+            } else {
+                info!(path = %path.display(), "HTTP server listening on a Unix socket");
+            }
+            drop(app_state);
+
+            uds::serve_http(listener, app, shutdown_rx).await?;
+        }
+        _ => {
+            let http_addr = SocketAddr::new(config_args.bind_address, config_args.http_port);
+            let listener = tokio::net::TcpListener::bind(http_addr).await.unwrap();
+            if config_args.single_port {
+                app_state.grpc_ready.store(true, Ordering::SeqCst);
+                info!(addr = %http_addr, "HTTP and gRPC (Native + Web) server listening on a single port");
+            } else {
+                info!(addr = %http_addr, "HTTP server listening");
+            }
+            drop(app_state);
+
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(wait_for_shutdown(shutdown_rx))
+                .await
+                .unwrap();
+        }
+    }
+
+    if let Some(handle) = grpc_handle {
+        let _ = handle.await;
+    }
+    if let Some(handle) = aggregator_handle {
+        // The gRPC service and the HTTP state (and their ingest senders) are gone, so the
+        // aggregator flushes its last window
+        let _ = handle.await;
+    }
+
+    // The peer tracker exits on shutdown and drops its sender with it
+    let _ = peers_handle.await;
+    if let Some(handle) = upstream_handle {
+        let _ = handle.await;
+    }
+
+    // With every sender gone the store writer drains what is left in the channel and exits
+    drop(tx);
+    if let Some(writer) = store_writer {
+        match writer.join() {
+            Ok(flushed) => info!(packets = flushed, "Flushed packets to the flow store during shutdown"),
+            Err(_) => error!("Flow store writer panicked during shutdown"),
+        }
+    }
+    info!("Server stopped");
 
     Ok(())
 }
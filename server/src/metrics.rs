@@ -0,0 +1,93 @@
+//! Fan-out cost, served on `/metrics` in Prometheus text format.
+//!
+//! Every batch an agent streams is cloned to each subscriber of the broadcast channel, so
+//! the cost grows with the number of open dashboards. The subscriber gauge and the
+//! histogram of how long handing a received batch on takes show when that is the
+//! bottleneck, and `--aggregate-window-ms` is worth enabling.
+
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bounds (seconds) of the send-time histogram buckets.
+const SEND_BUCKETS_SECS: [f64; 10] = [0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5];
+
+pub struct FanoutMetrics {
+    subscribers: AtomicI64,
+    /// Observations per bucket, not cumulative; the last slot is `+Inf`
+    send_buckets: [AtomicU64; SEND_BUCKETS_SECS.len() + 1],
+    send_count: AtomicU64,
+    send_sum_micros: AtomicU64,
+    /// Windows broadcast early because they reached --aggregate-max-flows
+    aggregate_overflows: AtomicU64,
+}
+
+impl FanoutMetrics {
+    pub fn new() -> Self {
+        Self {
+            subscribers: AtomicI64::new(0),
+            send_buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            send_count: AtomicU64::new(0),
+            send_sum_micros: AtomicU64::new(0),
+            aggregate_overflows: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a subscriber for as long as the returned guard is held by its forwarding task.
+    pub fn subscriber(self: &Arc<Self>) -> SubscriberGuard {
+        self.subscribers.fetch_add(1, Ordering::Relaxed);
+        SubscriberGuard(self.clone())
+    }
+
+    /// Record how long a batch received from an agent took to hand on. Under
+    /// `--overflow-policy block` this includes waiting for slow subscribers; with
+    /// `--aggregate-window-ms` it is only the handoff to the aggregator.
+    pub fn observe_send(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = SEND_BUCKETS_SECS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(SEND_BUCKETS_SECS.len());
+        self.send_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.send_count.fetch_add(1, Ordering::Relaxed);
+        self.send_sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Count an aggregation window cut short by `--aggregate-max-flows`.
+    pub fn aggregate_overflow(&self) {
+        self.aggregate_overflows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::from("# TYPE mikaboshi_server_subscribers gauge\n");
+        out.push_str(&format!("mikaboshi_server_subscribers {}\n", self.subscribers.load(Ordering::Relaxed)));
+        out.push_str("# TYPE mikaboshi_server_fanout_send_seconds histogram\n");
+        let mut cumulative = 0;
+        for (bound, bucket) in SEND_BUCKETS_SECS.iter().zip(&self.send_buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            out.push_str(&format!("mikaboshi_server_fanout_send_seconds_bucket{{le=\"{}\"}} {}\n", bound, cumulative));
+        }
+        cumulative += self.send_buckets[SEND_BUCKETS_SECS.len()].load(Ordering::Relaxed);
+        out.push_str(&format!("mikaboshi_server_fanout_send_seconds_bucket{{le=\"+Inf\"}} {}\n", cumulative));
+        out.push_str(&format!(
+            "mikaboshi_server_fanout_send_seconds_sum {}\n",
+            self.send_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        ));
+        out.push_str(&format!("mikaboshi_server_fanout_send_seconds_count {}\n", self.send_count.load(Ordering::Relaxed)));
+        out.push_str("# TYPE mikaboshi_server_aggregate_overflows_total counter\n");
+        out.push_str(&format!(
+            "mikaboshi_server_aggregate_overflows_total {}\n",
+            self.aggregate_overflows.load(Ordering::Relaxed)
+        ));
+        out
+    }
+}
+
+/// Decrements the subscriber gauge when the forwarding task holding it ends.
+pub struct SubscriberGuard(Arc<FanoutMetrics>);
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        self.0.subscribers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch};
+use tracing::debug;
+
+use crate::{has_unspecified_hosts, ip_from_bytes};
+use crate::packet::{PacketBatch, PeerExpired};
+
+/// Spawn the task that tracks when each peer (the non-agent side of a packet) was last seen
+/// and, once a second, broadcasts the peers idle for longer than `timeout` as expired.
+///
+/// The task exits once the broadcast channel closes or shutdown is requested.
+pub fn spawn(
+    timeout: Duration,
+    mut rx: broadcast::Receiver<PacketBatch>,
+    tx: broadcast::Sender<PacketBatch>,
+    shutdown: watch::Receiver<bool>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let shutdown = crate::wait_for_shutdown(shutdown);
+        tokio::pin!(shutdown);
+        let mut last_seen: HashMap<IpAddr, Instant> = HashMap::new();
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                result = rx.recv() => match result {
+                    Ok(batch) => {
+                        let now = Instant::now();
+                        for p in batch.packets.iter().filter(|p| !has_unspecified_hosts(p)) {
+                            let peer = if p.src_is_agent { &p.dst_ip } else { &p.src_ip };
+                            if let Some(ip) = ip_from_bytes(peer, p.is_ipv6) {
+                                last_seen.insert(ip, now);
+                            }
+                        }
+                    }
+                    // Missing a few batches can at worst expire a peer early
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = ticker.tick() => {
+                    let mut expired = Vec::new();
+                    last_seen.retain(|ip, seen| {
+                        if seen.elapsed() < timeout {
+                            return true;
+                        }
+                        expired.push(PeerExpired {
+                            ip: match ip {
+                                IpAddr::V4(ip) => ip.octets().to_vec(),
+                                IpAddr::V6(ip) => ip.octets().to_vec(),
+                            },
+                            is_ipv6: ip.is_ipv6(),
+                        });
+                        false
+                    });
+                    if !expired.is_empty() {
+                        debug!(peers = expired.len(), "Peers expired");
+                        let _ = tx.send(PacketBatch {
+                            expired_peers: expired,
+                            ..Default::default()
+                        });
+                    }
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,353 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+
+use crate::{has_unspecified_hosts, ip_from_bytes};
+use crate::packet::{Packet, PacketBatch, PeerTraffic, TopTalkersSnapshot};
+
+/// Peers kept in each published snapshot; subscribers may ask for fewer.
+pub const MAX_TOP_TALKERS: usize = 100;
+pub const DEFAULT_TOP_TALKERS: usize = 10;
+/// Edges in a `/topology` graph unless the request asks for another number, and at most.
+pub const DEFAULT_TOPOLOGY_EDGES: usize = 500;
+pub const MAX_TOPOLOGY_EDGES: usize = 5000;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Traffic {
+    bytes: i64,
+    packets: i64,
+}
+
+/// Traffic per key (a peer, a flow) over a rolling window of one-second buckets.
+struct RollingWindow<K> {
+    buckets: VecDeque<HashMap<K, Traffic>>,
+    window_secs: usize,
+}
+
+impl<K: Hash + Eq + Ord + Copy> RollingWindow<K> {
+    fn new(window_secs: usize) -> Self {
+        let mut buckets = VecDeque::with_capacity(window_secs);
+        buckets.push_back(HashMap::new());
+        Self { buckets, window_secs }
+    }
+
+    /// Count a batch, each packet against the key `key_of` gives it (packets without one are skipped).
+    fn add(&mut self, batch: &PacketBatch, key_of: impl Fn(&Packet) -> Option<K>) {
+        let current = self.buckets.back_mut().expect("window always has a bucket");
+        for p in &batch.packets {
+            let Some(key) = key_of(p) else {
+                continue;
+            };
+            let traffic = current.entry(key).or_default();
+            traffic.bytes += p.size as i64;
+            // Agents that predate packet counts send 0; count those entries once
+            traffic.packets += p.packets.max(1);
+        }
+    }
+
+    /// Start a new second, dropping the bucket that fell out of the window.
+    fn rotate(&mut self) {
+        if self.buckets.len() >= self.window_secs {
+            self.buckets.pop_front();
+        }
+        self.buckets.push_back(HashMap::new());
+    }
+
+    /// Totals over the window, largest by bytes first.
+    fn ranked(&self) -> Vec<(K, Traffic)> {
+        let mut totals: HashMap<K, Traffic> = HashMap::new();
+        for bucket in &self.buckets {
+            for (key, traffic) in bucket {
+                let total = totals.entry(*key).or_default();
+                total.bytes += traffic.bytes;
+                total.packets += traffic.packets;
+            }
+        }
+
+        let mut ranked: Vec<(K, Traffic)> = totals.into_iter().collect();
+        ranked.sort_unstable_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then_with(|| a.0.cmp(&b.0)));
+        ranked
+    }
+}
+
+/// The peer (non-agent side) of a packet, like `/flows/top-talkers`.
+fn peer_of(p: &Packet) -> Option<IpAddr> {
+    if has_unspecified_hosts(p) {
+        return None;
+    }
+    let peer = if p.src_is_agent { &p.dst_ip } else { &p.src_ip };
+    ip_from_bytes(peer, p.is_ipv6)
+}
+
+impl RollingWindow<IpAddr> {
+    fn snapshot(&self) -> TopTalkersSnapshot {
+        let mut ranked = self.ranked();
+        ranked.truncate(MAX_TOP_TALKERS);
+
+        TopTalkersSnapshot {
+            peers: ranked
+                .into_iter()
+                .map(|(ip, traffic)| PeerTraffic {
+                    ip: match ip {
+                        IpAddr::V4(ip) => ip.octets().to_vec(),
+                        IpAddr::V6(ip) => ip.octets().to_vec(),
+                    },
+                    bytes: traffic.bytes,
+                    packets: traffic.packets,
+                })
+                .collect(),
+            window_secs: self.window_secs as u32,
+        }
+    }
+}
+
+/// Spawn the task that ranks peers by bytes over the last `window_secs` seconds and
+/// publishes a fresh snapshot every second.
+///
+/// The task exits once the broadcast channel closes or shutdown is requested.
+pub fn spawn(
+    window_secs: u64,
+    mut rx: broadcast::Receiver<PacketBatch>,
+    shutdown: watch::Receiver<bool>,
+) -> watch::Receiver<Arc<TopTalkersSnapshot>> {
+    let window_secs = window_secs.max(1) as usize;
+    let (snapshot_tx, snapshot_rx) = watch::channel(Arc::new(TopTalkersSnapshot {
+        peers: Vec::new(),
+        window_secs: window_secs as u32,
+    }));
+
+    tokio::spawn(async move {
+        let shutdown = crate::wait_for_shutdown(shutdown);
+        tokio::pin!(shutdown);
+        let mut window = RollingWindow::new(window_secs);
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                result = rx.recv() => match result {
+                    Ok(batch) => window.add(&batch, peer_of),
+                    // The ranking is approximate anyway; carry on with what arrives next
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = ticker.tick() => {
+                    let _ = snapshot_tx.send(Arc::new(window.snapshot()));
+                    window.rotate();
+                }
+            }
+        }
+    });
+
+    snapshot_rx
+}
+
+/// Identity of a flow in `/snapshot`.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+struct FlowId {
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    proto: i32,
+    src_port: i32,
+    dst_port: i32,
+}
+
+fn flow_of(p: &Packet) -> Option<FlowId> {
+    if has_unspecified_hosts(p) {
+        return None;
+    }
+    Some(FlowId {
+        src_ip: ip_from_bytes(&p.src_ip, p.is_ipv6)?,
+        dst_ip: ip_from_bytes(&p.dst_ip, p.is_ipv6)?,
+        proto: p.proto,
+        src_port: p.src_port,
+        dst_port: p.dst_port,
+    })
+}
+
+/// A flow's traffic over the window, as `/snapshot` returns it.
+#[derive(Debug, serde::Serialize)]
+pub struct FlowTotal {
+    src_ip: String,
+    dst_ip: String,
+    proto: String,
+    src_port: i32,
+    dst_port: i32,
+    bytes: i64,
+    packets: i64,
+}
+
+/// The agent side of a packet, if either side is one.
+fn agent_of(p: &Packet) -> Option<IpAddr> {
+    let agent = if p.src_is_agent {
+        &p.src_ip
+    } else if p.dst_is_agent {
+        &p.dst_ip
+    } else {
+        return None;
+    };
+    ip_from_bytes(agent, p.is_ipv6)
+}
+
+/// A host in `/topology`.
+#[derive(Debug, serde::Serialize)]
+pub struct TopologyNode {
+    id: String,
+    is_agent: bool,
+    /// Sent and received over the window, on the edges kept
+    bytes: i64,
+    packets: i64,
+}
+
+/// Traffic from one host to another in `/topology`, all protocols and ports together.
+#[derive(Debug, serde::Serialize)]
+pub struct TopologyEdge {
+    source: String,
+    target: String,
+    bytes: i64,
+    packets: i64,
+    /// Distinct flows (protocol and port pairs) behind the edge
+    flows: usize,
+}
+
+/// The window's traffic as a graph, in the node/edge shape graph libraries take.
+#[derive(Debug, serde::Serialize)]
+pub struct Topology {
+    window_secs: u32,
+    nodes: Vec<TopologyNode>,
+    edges: Vec<TopologyEdge>,
+}
+
+/// Per-flow traffic over the same kind of rolling window as the TopTalkers ranking.
+///
+/// Unlike the ranking it isn't published every second; `top` and `topology` read it when asked.
+pub struct FlowWindow {
+    window: Mutex<RollingWindow<FlowId>>,
+    /// Agent addresses seen over the window, for `is_agent` in `/topology`
+    agents: Mutex<RollingWindow<IpAddr>>,
+    window_secs: u32,
+}
+
+impl FlowWindow {
+    pub fn window_secs(&self) -> u32 {
+        self.window_secs
+    }
+
+    /// The `limit` flows with the most bytes, only those of `proto` when given.
+    pub fn top(&self, limit: usize, proto: Option<i32>) -> Vec<FlowTotal> {
+        let ranked = self.window.lock().unwrap().ranked();
+        ranked
+            .into_iter()
+            .filter(|(flow, _)| proto.is_none_or(|proto| flow.proto == proto))
+            .take(limit)
+            .map(|(flow, traffic)| FlowTotal {
+                src_ip: flow.src_ip.to_string(),
+                dst_ip: flow.dst_ip.to_string(),
+                proto: crate::store::proto_name(flow.proto).into_owned(),
+                src_port: flow.src_port,
+                dst_port: flow.dst_port,
+                bytes: traffic.bytes,
+                packets: traffic.packets,
+            })
+            .collect()
+    }
+
+    /// The `limit` host pairs with the most bytes as edges, and the hosts they connect.
+    pub fn topology(&self, limit: usize) -> Topology {
+        let ranked = self.window.lock().unwrap().ranked();
+        let agents: HashSet<IpAddr> = self.agents.lock().unwrap().ranked().into_iter().map(|(ip, _)| ip).collect();
+
+        let mut pairs: HashMap<(IpAddr, IpAddr), (Traffic, usize)> = HashMap::new();
+        for (flow, traffic) in ranked {
+            let (total, flows) = pairs.entry((flow.src_ip, flow.dst_ip)).or_default();
+            total.bytes += traffic.bytes;
+            total.packets += traffic.packets;
+            *flows += 1;
+        }
+        let mut pairs: Vec<_> = pairs.into_iter().collect();
+        pairs.sort_unstable_by(|a, b| b.1 .0.bytes.cmp(&a.1 .0.bytes).then_with(|| a.0.cmp(&b.0)));
+        pairs.truncate(limit);
+
+        let mut hosts: HashMap<IpAddr, Traffic> = HashMap::new();
+        for ((src, dst), (traffic, _)) in &pairs {
+            for ip in [src, dst] {
+                let total = hosts.entry(*ip).or_default();
+                total.bytes += traffic.bytes;
+                total.packets += traffic.packets;
+            }
+        }
+        let mut nodes: Vec<TopologyNode> = hosts
+            .into_iter()
+            .map(|(ip, traffic)| TopologyNode {
+                id: ip.to_string(),
+                is_agent: agents.contains(&ip),
+                bytes: traffic.bytes,
+                packets: traffic.packets,
+            })
+            .collect();
+        nodes.sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.id.cmp(&b.id)));
+
+        Topology {
+            window_secs: self.window_secs,
+            nodes,
+            edges: pairs
+                .into_iter()
+                .map(|((src, dst), (traffic, flows))| TopologyEdge {
+                    source: src.to_string(),
+                    target: dst.to_string(),
+                    bytes: traffic.bytes,
+                    packets: traffic.packets,
+                    flows,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Spawn the task that keeps per-flow traffic over the last `window_secs` seconds.
+///
+/// The task exits once the broadcast channel closes or shutdown is requested.
+pub fn spawn_flows(
+    window_secs: u64,
+    mut rx: broadcast::Receiver<PacketBatch>,
+    shutdown: watch::Receiver<bool>,
+) -> Arc<FlowWindow> {
+    let window_secs = window_secs.max(1) as usize;
+    let flows = Arc::new(FlowWindow {
+        window: Mutex::new(RollingWindow::new(window_secs)),
+        agents: Mutex::new(RollingWindow::new(window_secs)),
+        window_secs: window_secs as u32,
+    });
+
+    let task_flows = flows.clone();
+    tokio::spawn(async move {
+        let shutdown = crate::wait_for_shutdown(shutdown);
+        tokio::pin!(shutdown);
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                result = rx.recv() => match result {
+                    Ok(batch) => {
+                        task_flows.window.lock().unwrap().add(&batch, flow_of);
+                        task_flows.agents.lock().unwrap().add(&batch, agent_of);
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = ticker.tick() => {
+                    task_flows.window.lock().unwrap().rotate();
+                    task_flows.agents.lock().unwrap().rotate();
+                }
+            }
+        }
+    });
+
+    flows
+}
@@ -0,0 +1,126 @@
+//! `POST /replay`: re-broadcast stored flows as if they were live traffic.
+
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing::{error, info};
+
+use crate::packet::{Packet, PacketBatch};
+use crate::store::{FlowQuery, FlowRow, FlowStore};
+
+/// Rows read ahead of playback by the SQLite reader thread.
+const ROW_BUFFER: usize = 1024;
+/// Slowest and fastest `speed` accepted: 0.001 stretches a stored minute to about 17 hours,
+/// and 1000000 plays a stored year in about 30 seconds.
+pub const MIN_SPEED: f64 = 0.001;
+pub const MAX_SPEED: f64 = 1_000_000.0;
+/// Stand-in for a batch due later than a clock can express.
+const FAR_FUTURE: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+#[derive(Debug, Deserialize)]
+pub struct ReplayQuery {
+    pub from: i64,
+    /// Defaults to now
+    pub to: Option<i64>,
+    /// Playback speed multiplier; 2.0 replays an hour in 30 minutes
+    pub speed: Option<f64>,
+}
+
+fn packet_from_row(row: FlowRow) -> Option<Packet> {
+    let src: IpAddr = row.src_ip.parse().ok()?;
+    let dst: IpAddr = row.dst_ip.parse().ok()?;
+    let (src_ip, dst_ip, is_ipv6) = crate::ip_pair_to_bytes(src, dst);
+    Some(Packet {
+        src_ip,
+        dst_ip,
+        is_ipv6,
+        src_is_agent: row.src_is_agent,
+        dst_is_agent: row.dst_is_agent,
+        size: row.bytes.min(i32::MAX as i64) as i32,
+        packets: row.packets,
+        proto: row.proto,
+        src_port: row.src_port,
+        dst_port: row.dst_port,
+        replay: true,
+        ..Default::default()
+    })
+}
+
+fn send_batch(tx: &broadcast::Sender<PacketBatch>, packets: &mut Vec<Packet>) {
+    if !packets.is_empty() {
+        let _ = tx.send(PacketBatch {
+            packets: std::mem::take(packets),
+            ..Default::default()
+        });
+    }
+}
+
+/// Broadcast the flows stored between `from` and `to`, one batch per stored second, spaced
+/// out by their original timestamps divided by `speed`.
+///
+/// The task ends when the range is exhausted, shutdown is requested, or it is aborted.
+pub fn spawn(
+    store: Arc<FlowStore>,
+    tx: broadcast::Sender<PacketBatch>,
+    from: i64,
+    to: i64,
+    speed: f64,
+    shutdown: watch::Receiver<bool>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let (row_tx, mut row_rx) = mpsc::channel::<FlowRow>(ROW_BUFFER);
+        let query = FlowQuery {
+            from,
+            to,
+            src_ip: None,
+            dst_ip: None,
+            proto: None,
+            limit: None,
+        };
+        // Stops by itself once this task is gone and the channel closes
+        let reader = tokio::task::spawn_blocking(move || store.scan_flows(&query, |row| row_tx.blocking_send(row).is_ok()));
+
+        let shutdown = crate::wait_for_shutdown(shutdown);
+        tokio::pin!(shutdown);
+        let started = tokio::time::Instant::now();
+        let mut first_ts = None;
+        let mut batch_ts = None;
+        let mut packets = Vec::new();
+        let mut replayed = 0;
+
+        loop {
+            let row = tokio::select! {
+                row = row_rx.recv() => row,
+                _ = &mut shutdown => return,
+            };
+            let Some(row) = row else {
+                break;
+            };
+            if batch_ts != Some(row.ts) {
+                send_batch(&tx, &mut packets);
+                let first = *first_ts.get_or_insert(row.ts);
+                // A wide enough range saturates rather than panics; such a batch is only reached
+                // by shutdown or a new replay
+                let offset = Duration::try_from_secs_f64((row.ts - first) as f64 / speed).unwrap_or(Duration::MAX);
+                let due = started.checked_add(offset).unwrap_or_else(|| started + FAR_FUTURE);
+                tokio::select! {
+                    _ = tokio::time::sleep_until(due) => {}
+                    _ = &mut shutdown => return,
+                }
+                batch_ts = Some(row.ts);
+            }
+            if let Some(packet) = packet_from_row(row) {
+                packets.push(packet);
+                replayed += 1;
+            }
+        }
+        send_batch(&tx, &mut packets);
+
+        if let Ok(Err(e)) = reader.await {
+            error!(error = %e, "Replay failed to read stored flows");
+        }
+        info!(flows = replayed, "Replay finished");
+    })
+}
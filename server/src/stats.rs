@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Instant;
+
+use crate::packet::{PacketBatch, Protocol, ProtocolStats, Stats};
+
+#[derive(Default)]
+struct Counter {
+    bytes: AtomicI64,
+    packets: AtomicI64,
+}
+
+impl Counter {
+    fn snapshot(&self) -> ProtocolStats {
+        ProtocolStats {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            packets: self.packets.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Per-protocol totals of what agents have streamed since startup, for `GetStats`.
+pub struct TrafficCounters {
+    started: Instant,
+    tcp: Counter,
+    udp: Counter,
+    icmp: Counter,
+    other: Counter,
+}
+
+impl TrafficCounters {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            tcp: Counter::default(),
+            udp: Counter::default(),
+            icmp: Counter::default(),
+            other: Counter::default(),
+        }
+    }
+
+    pub fn record(&self, batch: &PacketBatch) {
+        for p in &batch.packets {
+            let counter = match Protocol::try_from(p.proto) {
+                Ok(Protocol::Tcp) => &self.tcp,
+                Ok(Protocol::Udp) => &self.udp,
+                Ok(Protocol::Icmp) => &self.icmp,
+                _ => &self.other,
+            };
+            counter.bytes.fetch_add(p.size as i64, Ordering::Relaxed);
+            // Agents that predate packet counts send 0; count those entries once
+            counter.packets.fetch_add(p.packets.max(1), Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self, connected_agents: usize) -> Stats {
+        Stats {
+            tcp: Some(self.tcp.snapshot()),
+            udp: Some(self.udp.snapshot()),
+            icmp: Some(self.icmp.snapshot()),
+            other: Some(self.other.snapshot()),
+            uptime_secs: self.started.elapsed().as_secs(),
+            connected_agents: connected_agents as u32,
+        }
+    }
+}
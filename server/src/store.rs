@@ -0,0 +1,569 @@
+use rusqlite::{params, Connection, ToSql};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+
+use crate::ip_from_bytes;
+use crate::packet::PacketBatch;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS flows (
+    ts INTEGER NOT NULL,
+    src_ip TEXT NOT NULL,
+    dst_ip TEXT NOT NULL,
+    src_is_agent INTEGER NOT NULL,
+    dst_is_agent INTEGER NOT NULL,
+    src_port INTEGER NOT NULL,
+    dst_port INTEGER NOT NULL,
+    proto INTEGER NOT NULL,
+    bytes INTEGER NOT NULL,
+    packets INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS flows_ts ON flows (ts);
+";
+
+/// Upper bound for `limit` on `/flows` so a single request can't dump the whole table.
+const MAX_LIMIT: u32 = 10000;
+const DEFAULT_LIMIT: u32 = 1000;
+/// Rows per chunk handed out by `export_csv`.
+const CSV_CHUNK_ROWS: usize = 500;
+/// Rows deleted per statement while pruning, so the writer gets the connection back in between.
+const PRUNE_CHUNK_ROWS: i64 = 10_000;
+/// How often the pruner looks for rows past `--db-retention` or `--db-max-rows`.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct FlowStore {
+    conn: Mutex<Connection>,
+    path: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FlowQuery {
+    pub from: i64,
+    pub to: i64,
+    pub src_ip: Option<String>,
+    pub dst_ip: Option<String>,
+    pub proto: Option<String>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TopTalkersQuery {
+    pub from: i64,
+    pub to: i64,
+    pub n: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FlowRow {
+    pub ts: i64,
+    pub src_ip: String,
+    pub dst_ip: String,
+    pub src_is_agent: bool,
+    pub dst_is_agent: bool,
+    pub src_port: i32,
+    pub dst_port: i32,
+    pub proto: i32,
+    pub bytes: i64,
+    pub packets: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TopTalker {
+    pub ip: String,
+    pub bytes: i64,
+}
+
+/// Size of the `flows` table, for `/db/stats`.
+#[derive(Debug, Serialize)]
+pub struct DbStats {
+    pub rows: i64,
+    /// Unix seconds of the oldest and newest rows; `null` while the table is empty
+    pub oldest_ts: Option<i64>,
+    pub newest_ts: Option<i64>,
+}
+
+/// What the pruner keeps: `--db-retention`, `--db-max-rows` and `--db-vacuum`.
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    pub max_age: Option<Duration>,
+    pub max_rows: Option<u64>,
+    pub vacuum: bool,
+}
+
+impl Retention {
+    /// Whether there is anything to prune at all.
+    pub fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || self.max_rows.is_some()
+    }
+}
+
+/// A duration such as `30d`, `12h`, `90m`, `3600s` or `2w`; a bare number is seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+    let number: u64 = number.parse().map_err(|_| format!("Invalid duration {:?}, expected e.g. 30d or 12h", value))?;
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("Unknown unit {:?} in duration {:?}; use s, m, h, d or w", unit, value)),
+    };
+    match number.checked_mul(seconds) {
+        Some(0) => Err("Duration must be greater than zero".to_string()),
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => Err(format!("Duration {:?} is too long", value)),
+    }
+}
+
+/// Accepts either a protocol name (`tcp`, `UDP`, ...) or its numeric value.
+pub fn parse_proto(value: &str) -> Option<i32> {
+    if let Ok(n) = value.parse::<i32>() {
+        return Some(n);
+    }
+    crate::packet::Protocol::from_str_name(&value.to_uppercase()).map(|p| p as i32)
+}
+
+/// Display name of a protocol value. Values this server doesn't know, e.g. from an agent
+/// built against a newer proto, show as `UNKNOWN(n)` rather than passing for `UNKNOWN` (0).
+pub fn proto_name(proto: i32) -> std::borrow::Cow<'static, str> {
+    match crate::packet::Protocol::try_from(proto) {
+        Ok(p) => p.as_str_name().into(),
+        Err(_) => format!("UNKNOWN({})", proto).into(),
+    }
+}
+
+impl FlowStore {
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        // WAL lets exports read on their own connection while the writer keeps committing
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        conn.execute_batch(SCHEMA)?;
+        // Databases created before packet counts were stored
+        let has_packets = conn
+            .prepare("SELECT 1 FROM pragma_table_info('flows') WHERE name = 'packets'")?
+            .exists([])?;
+        if !has_packets {
+            conn.execute("ALTER TABLE flows ADD COLUMN packets INTEGER NOT NULL DEFAULT 0", [])?;
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+            path: path.to_string(),
+        })
+    }
+
+    /// Insert every packet of a batch in a single transaction. Returns the number of rows written.
+    pub fn insert_batch(&self, ts: i64, batch: &PacketBatch) -> rusqlite::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let txn = conn.transaction()?;
+        let mut written = 0;
+        {
+            let mut stmt = txn.prepare_cached(
+                "INSERT INTO flows (ts, src_ip, dst_ip, src_is_agent, dst_is_agent, src_port, dst_port, proto, bytes, packets)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            )?;
+            for p in &batch.packets {
+                // Replayed flows are already stored, and summed or grouped entries have no hosts to query by
+                if p.replay || crate::has_unspecified_hosts(p) {
+                    continue;
+                }
+                let (Some(src), Some(dst)) = (ip_from_bytes(&p.src_ip, p.is_ipv6), ip_from_bytes(&p.dst_ip, p.is_ipv6)) else {
+                    continue;
+                };
+                stmt.execute(params![
+                    ts,
+                    src.to_string(),
+                    dst.to_string(),
+                    p.src_is_agent,
+                    p.dst_is_agent,
+                    p.src_port,
+                    p.dst_port,
+                    p.proto,
+                    p.size as i64,
+                    p.packets,
+                ])?;
+                written += 1;
+            }
+        }
+        txn.commit()?;
+        Ok(written)
+    }
+
+    /// `WHERE` clause and arguments shared by the flow queries.
+    fn flow_filter(q: &FlowQuery) -> (String, Vec<Box<dyn ToSql>>) {
+        let mut sql = String::from(" WHERE ts >= ? AND ts <= ?");
+        let mut args: Vec<Box<dyn ToSql>> = vec![Box::new(q.from), Box::new(q.to)];
+
+        if let Some(ip) = &q.src_ip {
+            sql.push_str(" AND src_ip = ?");
+            args.push(Box::new(ip.clone()));
+        }
+        if let Some(ip) = &q.dst_ip {
+            sql.push_str(" AND dst_ip = ?");
+            args.push(Box::new(ip.clone()));
+        }
+        if let Some(proto) = q.proto.as_deref().and_then(parse_proto) {
+            sql.push_str(" AND proto = ?");
+            args.push(Box::new(proto));
+        }
+        (sql, args)
+    }
+
+    pub fn query_flows(&self, q: &FlowQuery) -> rusqlite::Result<Vec<FlowRow>> {
+        let (filter, mut args) = Self::flow_filter(q);
+        let mut sql = String::from(
+            "SELECT ts, src_ip, dst_ip, src_is_agent, dst_is_agent, src_port, dst_port, proto, bytes, packets FROM flows",
+        );
+        sql.push_str(&filter);
+        sql.push_str(" ORDER BY ts LIMIT ?");
+        args.push(Box::new(q.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT)));
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(args.iter()), |row| {
+            Ok(FlowRow {
+                ts: row.get(0)?,
+                src_ip: row.get(1)?,
+                dst_ip: row.get(2)?,
+                src_is_agent: row.get(3)?,
+                dst_is_agent: row.get(4)?,
+                src_port: row.get(5)?,
+                dst_port: row.get(6)?,
+                proto: row.get(7)?,
+                bytes: row.get(8)?,
+                packets: row.get(9)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Visit the flows matching `q` in timestamp order until `visit` returns false.
+    ///
+    /// Runs on its own read-only connection and is only limited by `limit` when given, so
+    /// large scans neither hold the writer's lock nor sit in memory.
+    pub fn scan_flows(&self, q: &FlowQuery, mut visit: impl FnMut(FlowRow) -> bool) -> rusqlite::Result<()> {
+        let conn = Connection::open_with_flags(&self.path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let (filter, mut args) = Self::flow_filter(q);
+        let mut sql = String::from(
+            "SELECT ts, src_ip, dst_ip, src_is_agent, dst_is_agent, src_port, dst_port, proto, bytes, packets FROM flows",
+        );
+        sql.push_str(&filter);
+        sql.push_str(" ORDER BY ts");
+        if let Some(limit) = q.limit {
+            sql.push_str(" LIMIT ?");
+            args.push(Box::new(limit));
+        }
+
+        let mut stmt = conn.prepare(&sql)?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(args.iter()))?;
+        while let Some(row) = rows.next()? {
+            let flow = FlowRow {
+                ts: row.get(0)?,
+                src_ip: row.get(1)?,
+                dst_ip: row.get(2)?,
+                src_is_agent: row.get(3)?,
+                dst_is_agent: row.get(4)?,
+                src_port: row.get(5)?,
+                dst_port: row.get(6)?,
+                proto: row.get(7)?,
+                bytes: row.get(8)?,
+                packets: row.get(9)?,
+            };
+            if !visit(flow) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Render the flows matching `q` as CSV, passing it to `emit` a chunk of rows at a time
+    /// (the first chunk starts with the header). Stops early once `emit` returns false.
+    pub fn export_csv(&self, q: &FlowQuery, mut emit: impl FnMut(String) -> bool) -> rusqlite::Result<()> {
+        let mut chunk = String::from("timestamp,src_ip,src_port,dst_ip,dst_port,proto,bytes,packets\n");
+        let mut in_chunk = 0;
+        let mut open = true;
+        self.scan_flows(q, |flow| {
+            let proto = proto_name(flow.proto);
+            chunk.push_str(&format!(
+                "{},{},{},{},{},{},{},{}\n",
+                flow.ts, flow.src_ip, flow.src_port, flow.dst_ip, flow.dst_port, proto, flow.bytes, flow.packets,
+            ));
+            in_chunk += 1;
+            if in_chunk == CSV_CHUNK_ROWS {
+                in_chunk = 0;
+                open = emit(std::mem::take(&mut chunk));
+            }
+            open
+        })?;
+        if open && !chunk.is_empty() {
+            emit(chunk);
+        }
+        Ok(())
+    }
+
+    /// Sum bytes per peer (the non-agent side of each flow) and return the top `n`.
+    pub fn top_talkers(&self, q: &TopTalkersQuery) -> rusqlite::Result<Vec<TopTalker>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(
+            "SELECT peer, SUM(bytes) AS total FROM (
+                 SELECT CASE WHEN src_is_agent THEN dst_ip ELSE src_ip END AS peer, bytes
+                 FROM flows WHERE ts >= ?1 AND ts <= ?2
+             ) GROUP BY peer ORDER BY total DESC LIMIT ?3",
+        )?;
+        let rows = stmt.query_map(params![q.from, q.to, q.n.unwrap_or(10).min(MAX_LIMIT)], |row| {
+            Ok(TopTalker {
+                ip: row.get(0)?,
+                bytes: row.get(1)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn stats(&self) -> rusqlite::Result<DbStats> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT COUNT(*), MIN(ts), MAX(ts) FROM flows", [], |row| {
+            Ok(DbStats {
+                rows: row.get(0)?,
+                oldest_ts: row.get(1)?,
+                newest_ts: row.get(2)?,
+            })
+        })
+    }
+
+    /// Delete rows older than `before` (unix seconds), then the oldest rows beyond `max_rows`.
+    /// Returns the number of rows deleted.
+    pub fn prune(&self, before: Option<i64>, max_rows: Option<u64>) -> rusqlite::Result<usize> {
+        let mut deleted = 0;
+        if let Some(before) = before {
+            loop {
+                let n = self.conn.lock().unwrap().execute(
+                    "DELETE FROM flows WHERE rowid IN (SELECT rowid FROM flows WHERE ts < ?1 LIMIT ?2)",
+                    params![before, PRUNE_CHUNK_ROWS],
+                )?;
+                deleted += n;
+                if (n as i64) < PRUNE_CHUNK_ROWS {
+                    break;
+                }
+            }
+        }
+        if let Some(max_rows) = max_rows {
+            let rows: i64 = self.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM flows", [], |row| row.get(0))?;
+            let mut excess = rows.saturating_sub(i64::try_from(max_rows).unwrap_or(i64::MAX));
+            while excess > 0 {
+                let n = self.conn.lock().unwrap().execute(
+                    "DELETE FROM flows WHERE rowid IN (SELECT rowid FROM flows ORDER BY ts, rowid LIMIT ?1)",
+                    params![excess.min(PRUNE_CHUNK_ROWS)],
+                )?;
+                if n == 0 {
+                    break;
+                }
+                deleted += n;
+                excess -= n as i64;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Give the space of deleted rows back to the filesystem.
+    pub fn vacuum(&self) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute_batch("VACUUM")
+    }
+}
+
+pub fn now_secs() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Persist every broadcast batch on a dedicated thread so SQLite writes never stall the runtime.
+///
+/// The thread runs until every sender is dropped and returns how many packets it
+/// wrote after shutdown was requested.
+pub fn spawn_writer(
+    store: Arc<FlowStore>,
+    mut rx: broadcast::Receiver<PacketBatch>,
+    shutdown: watch::Receiver<bool>,
+) -> std::thread::JoinHandle<usize> {
+    std::thread::spawn(move || {
+        let mut flushed = 0;
+        loop {
+            match rx.blocking_recv() {
+                Ok(batch) => match store.insert_batch(now_secs(), &batch) {
+                    Ok(written) => {
+                        if *shutdown.borrow() {
+                            flushed += written;
+                        }
+                    }
+                    Err(e) => tracing::error!(error = %e, "Failed to persist batch"),
+                },
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!(skipped = n, "Flow store fell behind, batches were not persisted");
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+        flushed
+    })
+}
+
+/// Prune the store every `PRUNE_INTERVAL` as `retention` says, until shutdown is requested.
+pub fn spawn_pruner(store: Arc<FlowStore>, retention: Retention, shutdown: watch::Receiver<bool>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let shutdown = crate::wait_for_shutdown(shutdown);
+        tokio::pin!(shutdown);
+        let mut ticker = tokio::time::interval(PRUNE_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = &mut shutdown => break,
+            }
+            let store = store.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                let before = retention.max_age.map(|age| now_secs().saturating_sub(age.as_secs() as i64));
+                let deleted = store.prune(before, retention.max_rows)?;
+                // Only worth rewriting the file when something was freed
+                if retention.vacuum && deleted > 0 {
+                    store.vacuum()?;
+                }
+                Ok::<_, rusqlite::Error>(deleted)
+            })
+            .await;
+            match result {
+                Ok(Ok(0)) => {}
+                Ok(Ok(deleted)) => tracing::info!(deleted, "Pruned old flows from the flow store"),
+                Ok(Err(e)) => tracing::warn!(error = %e, "Failed to prune the flow store"),
+                Err(e) => tracing::error!(error = %e, "Flow store pruner panicked"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::Protocol;
+
+    /// An in-memory store with one row at each of `timestamps`.
+    fn store_with_rows(timestamps: impl IntoIterator<Item = i64>) -> FlowStore {
+        let store = FlowStore::open(":memory:").unwrap();
+        {
+            let mut conn = store.conn.lock().unwrap();
+            let txn = conn.transaction().unwrap();
+            for ts in timestamps {
+                txn.execute(
+                    "INSERT INTO flows (ts, src_ip, dst_ip, src_is_agent, dst_is_agent, src_port, dst_port, proto, bytes)
+                     VALUES (?1, '192.0.2.1', '192.0.2.2', 1, 0, 40000, 443, 1, 100)",
+                    params![ts],
+                )
+                .unwrap();
+            }
+            txn.commit().unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("3600"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(12 * 3600)));
+        assert_eq!(parse_duration(" 30d "), Ok(Duration::from_secs(30 * 86400)));
+        assert_eq!(parse_duration("2w"), Ok(Duration::from_secs(14 * 86400)));
+    }
+
+    #[test]
+    fn rejects_bad_durations() {
+        for value in ["", "d", "0", "0d", "-1d", "1.5h", "10y", "12 h", "99999999999999999999d", "30000000000000000w"] {
+            assert!(parse_duration(value).is_err(), "{:?} was accepted", value);
+        }
+    }
+
+    #[test]
+    fn prunes_by_age() {
+        let store = store_with_rows([100, 200, 300, 400]);
+        assert_eq!(store.prune(Some(300), None).unwrap(), 2);
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.rows, stats.oldest_ts, stats.newest_ts), (2, Some(300), Some(400)));
+        // Nothing left to do the second time
+        assert_eq!(store.prune(Some(300), None).unwrap(), 0);
+    }
+
+    #[test]
+    fn prunes_the_oldest_rows_beyond_max_rows() {
+        let store = store_with_rows([400, 100, 300, 200, 500]);
+        assert_eq!(store.prune(None, Some(2)).unwrap(), 3);
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.rows, stats.oldest_ts, stats.newest_ts), (2, Some(400), Some(500)));
+        assert_eq!(store.prune(None, Some(2)).unwrap(), 0);
+        assert_eq!(store.prune(None, Some(10)).unwrap(), 0);
+    }
+
+    #[test]
+    fn prunes_by_age_then_size() {
+        let store = store_with_rows(1..=10);
+        // Rows 1-4 are too old, then 5 and 6 are the oldest of the six left
+        assert_eq!(store.prune(Some(5), Some(4)).unwrap(), 6);
+        assert_eq!(store.stats().unwrap().oldest_ts, Some(7));
+    }
+
+    #[test]
+    fn prunes_in_chunks() {
+        let rows = PRUNE_CHUNK_ROWS * 2 + 500;
+        let store = store_with_rows(0..rows);
+        // Exactly one chunk's worth, then the remainder across several chunks
+        assert_eq!(store.prune(Some(PRUNE_CHUNK_ROWS), None).unwrap(), PRUNE_CHUNK_ROWS as usize);
+        assert_eq!(store.prune(None, Some(100)).unwrap(), (rows - PRUNE_CHUNK_ROWS - 100) as usize);
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.rows, stats.oldest_ts), (100, Some(rows - 100)));
+    }
+
+    #[test]
+    fn empty_store_stats() {
+        let store = store_with_rows([]);
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.rows, stats.oldest_ts, stats.newest_ts), (0, None, None));
+        assert_eq!(store.prune(Some(i64::MAX), Some(0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn protocols_round_trip_through_i32() {
+        let all = [Protocol::Unknown, Protocol::Tcp, Protocol::Udp, Protocol::Icmp, Protocol::Other, Protocol::Arp];
+        for proto in all {
+            let value = proto as i32;
+            assert_eq!(Protocol::try_from(value), Ok(proto));
+            assert_eq!(proto_name(value), proto.as_str_name());
+            assert_eq!(parse_proto(proto.as_str_name()), Some(value));
+            assert_eq!(parse_proto(&proto.as_str_name().to_lowercase()), Some(value));
+            assert_eq!(parse_proto(&value.to_string()), Some(value));
+        }
+        // Every value past the last variant is one this server doesn't know
+        let next = all.iter().map(|&p| p as i32).max().unwrap() + 1;
+        assert!(Protocol::try_from(next).is_err());
+    }
+
+    #[test]
+    fn skips_the_top_flows_remainder() {
+        use crate::packet::{Packet, PacketFlag};
+
+        let flow = Packet { src_ip: vec![192, 0, 2, 1], dst_ip: vec![192, 0, 2, 2], size: 100, ..Default::default() };
+        let rest = Packet { src_ip: vec![0; 4], dst_ip: vec![0; 4], flags: PacketFlag::OtherFlows as u32, ..flow.clone() };
+        let batch = PacketBatch { packets: vec![flow, rest], ..Default::default() };
+        let store = store_with_rows([]);
+        assert_eq!(store.insert_batch(100, &batch).unwrap(), 1);
+        assert_eq!(store.stats().unwrap().rows, 1);
+    }
+
+    #[test]
+    fn unknown_protocol_values_show_their_number() {
+        assert_eq!(proto_name(99), "UNKNOWN(99)");
+        assert_eq!(proto_name(-1), "UNKNOWN(-1)");
+        assert_eq!(proto_name(0), "UNKNOWN");
+    }
+}
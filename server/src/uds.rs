@@ -0,0 +1,82 @@
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::time::Duration;
+
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use tokio::net::UnixListener;
+use tokio::sync::watch;
+use tracing::{debug, warn};
+
+/// Bind a Unix socket at `path`, first removing a socket left behind by an earlier run.
+///
+/// Anything at `path` that isn't a socket is left alone and binding fails.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            debug!(path = %path.display(), "Removing stale socket");
+            std::fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(path)
+}
+
+/// Pause after a failed accept, e.g. when out of file descriptors, before trying again.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// Serve `app` on `listener` until shutdown is requested, then wait for the open
+/// connections to finish their requests, as the TCP server does.
+///
+/// axum 0.7 only serves TCP, so connections are handed to hyper directly. Unix peers have
+/// no IP address; handlers asking for one see loopback, which `--trust-proxy` overrides with
+/// the forwarding proxy's header.
+pub async fn serve_http(listener: UnixListener, app: Router, shutdown: watch::Receiver<bool>) -> io::Result<()> {
+    let app = app.layer(axum::Extension(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0)))));
+    let shutdown = crate::wait_for_shutdown(shutdown);
+    tokio::pin!(shutdown);
+    let connections = GracefulShutdown::new();
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                // Failing one accept (a peer that gave up, too many open files) doesn't
+                // take the listener down
+                Err(e) => {
+                    warn!(error = %e, "Failed to accept a Unix socket connection");
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let service = TowerToHyperService::new(app.clone());
+        let watcher = connections.watcher();
+        tokio::spawn(async move {
+            // With upgrades so /ws works over the socket too
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            if let Err(e) = watcher.watch(connection).await {
+                debug!(error = %e, "Unix socket connection ended with an error");
+            }
+        });
+    }
+
+    debug!(connections = connections.count(), "Waiting for Unix socket connections to finish");
+    connections.shutdown().await;
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing::{info, warn};
+
+use crate::packet::agent_service_client::AgentServiceClient;
+use crate::packet::PacketBatch;
+
+/// How long to wait before connecting to the upstream server again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Batches waiting for the upstream stream; further ones are dropped and counted.
+const FORWARD_BUFFER: usize = 32;
+
+/// What of a broadcast batch goes upstream: live flows only. Replayed flows are already
+/// stored upstream, and peer expiries are this server's own bookkeeping.
+fn relayable(mut batch: PacketBatch, batch_seq: &mut u64) -> Option<PacketBatch> {
+    batch.packets.retain(|p| !p.replay);
+    if batch.packets.is_empty() {
+        return None;
+    }
+    *batch_seq += 1;
+    Some(PacketBatch {
+        packets: batch.packets,
+        // Numbered like an agent's batches so the upstream can count what got lost on the way
+        batch_seq: *batch_seq,
+        ..Default::default()
+    })
+}
+
+/// Spawn the task that streams every broadcast batch (after `--aggregate-window-ms`, when
+/// set) to the server at `url`, as an agent would, reconnecting whenever the stream ends.
+///
+/// Batches broadcast while disconnected are not kept. The task exits on shutdown.
+pub fn spawn(
+    url: String,
+    max_message_size: usize,
+    mut rx: broadcast::Receiver<PacketBatch>,
+    shutdown: watch::Receiver<bool>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let shutdown = crate::wait_for_shutdown(shutdown);
+        tokio::pin!(shutdown);
+        let mut batch_seq: u64 = 0;
+
+        loop {
+            info!(upstream = %url, "Connecting to upstream server");
+            let connected = tokio::select! {
+                _ = &mut shutdown => return,
+                result = AgentServiceClient::connect(url.clone()) => result,
+            };
+            match connected {
+                Ok(client) => {
+                    let mut client = client
+                        .max_encoding_message_size(max_message_size)
+                        .max_decoding_message_size(max_message_size);
+                    info!(upstream = %url, "Forwarding flows to upstream server");
+                    let (batch_tx, batch_rx) = mpsc::channel::<PacketBatch>(FORWARD_BUFFER);
+                    let stream = client.stream_packets(tokio_stream::wrappers::ReceiverStream::new(batch_rx));
+                    tokio::pin!(stream);
+                    let mut dropped: u64 = 0;
+
+                    let outcome = loop {
+                        tokio::select! {
+                            _ = &mut shutdown => {
+                                // Closing our side lets the upstream end the stream cleanly
+                                drop(batch_tx);
+                                let _ = tokio::time::timeout(RECONNECT_DELAY, &mut stream).await;
+                                return;
+                            }
+                            result = &mut stream => break result,
+                            result = rx.recv() => match result {
+                                Ok(batch) => {
+                                    let Some(batch) = relayable(batch, &mut batch_seq) else {
+                                        continue;
+                                    };
+                                    if batch_tx.try_send(batch).is_err() {
+                                        dropped += 1;
+                                        warn!(upstream = %url, total_dropped = dropped, "Upstream is not keeping up, dropped a batch");
+                                    }
+                                }
+                                Err(broadcast::error::RecvError::Lagged(n)) => {
+                                    warn!(skipped = n, "Upstream forwarder lagged behind");
+                                }
+                                Err(broadcast::error::RecvError::Closed) => return,
+                            },
+                        }
+                    };
+                    match outcome {
+                        Ok(_) => warn!(upstream = %url, "Upstream server closed the stream"),
+                        Err(e) => warn!(upstream = %url, error = %e, "Upstream stream failed"),
+                    }
+                }
+                Err(e) => warn!(upstream = %url, error = %e, "Failed to connect to upstream server"),
+            }
+
+            info!(upstream = %url, delay_secs = RECONNECT_DELAY.as_secs(), "Reconnecting to upstream server");
+            tokio::select! {
+                _ = &mut shutdown => return,
+                _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            }
+        }
+    })
+}
@@ -147,12 +147,13 @@ const isPrivateIP = (ip) => {
 };
 
 // --- Helper: Convert Bytes to IP String ---
-const bytesToIp = (bytes) => {
+// The family comes from the packet's isIpv6, not from the byte count
+const bytesToIp = (bytes, isIpv6) => {
   if (!bytes || bytes.length === 0) return "";
-  if (bytes.length === 4) {
+  if (!isIpv6 && bytes.length === 4) {
     return bytes.join('.');
   }
-  if (bytes.length === 16) {
+  if (isIpv6 && bytes.length === 16) {
     // IPv6
     const parts = [];
     for (let i = 0; i < 16; i += 2) {
@@ -532,7 +533,7 @@ function InfoPanel({ peerIp, peerData, agentIp, agentData, onClose, geoipEnabled
 
 
 import { retry, delay, repeat } from 'rxjs/operators';
-import { AgentServiceClientImpl, Empty, GrpcWebImpl, protocolToJSON } from './proto/packet';
+import { AgentServiceClientImpl, GrpcWebImpl, SubscribeFilter, protocolToJSON } from './proto/packet';
 
 export default function TrafficVisualizer() {
   // useRefs for data (Source of Truth)
@@ -591,7 +592,7 @@ export default function TrafficVisualizer() {
         const client = new AgentServiceClientImpl(rpc);
 
         // Subscribe with Retry Logic
-        const sub = client.Subscribe(Empty.create({}))
+        const sub = client.Subscribe(SubscribeFilter.create({}))
           .pipe(
             retry({
               delay: (errors) => errors.pipe(delay(2000))
@@ -608,8 +609,8 @@ export default function TrafficVisualizer() {
 
                 const timestamp = Date.now();
 
-                const srcIp = bytesToIp(data.srcIp);
-                const dstIp = bytesToIp(data.dstIp);
+                const srcIp = bytesToIp(data.srcIp, data.isIpv6);
+                const dstIp = bytesToIp(data.dstIp, data.isIpv6);
 
                 // Identity Check - Agents
                 const isLoopback = (ip) => ip === '127.0.0.1' || ip === '::1' || ip === 'localhost';
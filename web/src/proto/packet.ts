@@ -13,12 +13,86 @@ import { share } from "rxjs/operators";
 
 export const protobufPackage = "packet";
 
+/** Bits of Packet.flags, set when any packet of the entry went through that path */
+export enum PacketFlag {
+  PACKET_FLAG_NONE = 0,
+  /** PACKET_FLAG_SAMPLED - Counts are extrapolated from a sample; reserved, no agent samples yet */
+  PACKET_FLAG_SAMPLED = 1,
+  /** PACKET_FLAG_REASSEMBLED - Counted as a datagram reassembled from IPv4 fragments (the agent's --reassemble) */
+  PACKET_FLAG_REASSEMBLED = 2,
+  /** PACKET_FLAG_TRUNCATED - A packet was captured short of its wire length, so payload fields (sni, dns_query) may be missing */
+  PACKET_FLAG_TRUNCATED = 4,
+  /** PACKET_FLAG_DECAPSULATED - Accounted to the endpoints inside a tunnel (the agent's --decap-gre) */
+  PACKET_FLAG_DECAPSULATED = 8,
+  /** PACKET_FLAG_OTHER_FLOWS - The sum of the flows the agent's --top-flows left out of the batch, per protocol; addresses and ports are unspecified */
+  PACKET_FLAG_OTHER_FLOWS = 16,
+  /** PACKET_FLAG_GROUPED - Aggregated by the agent's --group-by without src_ip or dst_ip; the address left out is unspecified */
+  PACKET_FLAG_GROUPED = 32,
+  UNRECOGNIZED = -1,
+}
+
+export function packetFlagFromJSON(object: any): PacketFlag {
+  switch (object) {
+    case 0:
+    case "PACKET_FLAG_NONE":
+      return PacketFlag.PACKET_FLAG_NONE;
+    case 1:
+    case "PACKET_FLAG_SAMPLED":
+      return PacketFlag.PACKET_FLAG_SAMPLED;
+    case 2:
+    case "PACKET_FLAG_REASSEMBLED":
+      return PacketFlag.PACKET_FLAG_REASSEMBLED;
+    case 4:
+    case "PACKET_FLAG_TRUNCATED":
+      return PacketFlag.PACKET_FLAG_TRUNCATED;
+    case 8:
+    case "PACKET_FLAG_DECAPSULATED":
+      return PacketFlag.PACKET_FLAG_DECAPSULATED;
+    case 16:
+    case "PACKET_FLAG_OTHER_FLOWS":
+      return PacketFlag.PACKET_FLAG_OTHER_FLOWS;
+    case 32:
+    case "PACKET_FLAG_GROUPED":
+      return PacketFlag.PACKET_FLAG_GROUPED;
+    case -1:
+    case "UNRECOGNIZED":
+    default:
+      return PacketFlag.UNRECOGNIZED;
+  }
+}
+
+export function packetFlagToJSON(object: PacketFlag): string {
+  switch (object) {
+    case PacketFlag.PACKET_FLAG_NONE:
+      return "PACKET_FLAG_NONE";
+    case PacketFlag.PACKET_FLAG_SAMPLED:
+      return "PACKET_FLAG_SAMPLED";
+    case PacketFlag.PACKET_FLAG_REASSEMBLED:
+      return "PACKET_FLAG_REASSEMBLED";
+    case PacketFlag.PACKET_FLAG_TRUNCATED:
+      return "PACKET_FLAG_TRUNCATED";
+    case PacketFlag.PACKET_FLAG_DECAPSULATED:
+      return "PACKET_FLAG_DECAPSULATED";
+    case PacketFlag.PACKET_FLAG_OTHER_FLOWS:
+      return "PACKET_FLAG_OTHER_FLOWS";
+    case PacketFlag.PACKET_FLAG_GROUPED:
+      return "PACKET_FLAG_GROUPED";
+    case PacketFlag.UNRECOGNIZED:
+    default:
+      return "UNRECOGNIZED";
+  }
+}
+
 export enum Protocol {
+  /** UNKNOWN - The transport header couldn't be read (e.g. a truncated IPv6 extension chain) */
   UNKNOWN = 0,
   TCP = 1,
   UDP = 2,
   ICMP = 3,
+  /** OTHER - An IP packet whose transport isn't TCP, UDP or ICMP (GRE, ESP, ...) */
   OTHER = 4,
+  /** ARP - IP-to-MAC binding: src/dst are the sender/target IP and MAC, no ports */
+  ARP = 5,
   UNRECOGNIZED = -1,
 }
 
@@ -39,6 +113,9 @@ export function protocolFromJSON(object: any): Protocol {
     case 4:
     case "OTHER":
       return Protocol.OTHER;
+    case 5:
+    case "ARP":
+      return Protocol.ARP;
     case -1:
     case "UNRECOGNIZED":
     default:
@@ -58,6 +135,8 @@ export function protocolToJSON(object: Protocol): string {
       return "ICMP";
     case Protocol.OTHER:
       return "OTHER";
+    case Protocol.ARP:
+      return "ARP";
     case Protocol.UNRECOGNIZED:
     default:
       return "UNRECOGNIZED";
@@ -67,8 +146,70 @@ export function protocolToJSON(object: Protocol): string {
 export interface Empty {
 }
 
+/** Restricts a subscription to matching packets. Unset fields match everything. */
+export interface SubscribeFilter {
+  ip?: string | undefined;
+  proto?: Protocol | undefined;
+  port?: number | undefined;
+  /** Only this agent's traffic, read from its own channel */
+  agentId?: string | undefined;
+  /** Resume after this PacketBatch.seq, replaying buffered batches first */
+  sinceSeq?: number | undefined;
+  /** Coalesce each flow, sending it at most once per interval with its sums */
+  minIntervalMs?: number | undefined;
+  /** false: batches as agents sent them, even when the server runs --aggregate-window-ms; unset: the server's stream */
+  aggregated?: boolean | undefined;
+}
+
+export interface TopTalkersRequest {
+  /** Peers per snapshot; 0 uses the server default */
+  limit: number;
+}
+
+/** Peers ranked by bytes over the server's rolling window, sent once per second. */
+export interface TopTalkersSnapshot {
+  peers: PeerTraffic[];
+  windowSecs: number;
+}
+
+export interface PeerTraffic {
+  ip: Uint8Array;
+  bytes: number;
+  packets: number;
+}
+
+/** Traffic received from agents since the server started. */
+export interface Stats {
+  tcp: ProtocolStats | undefined;
+  udp: ProtocolStats | undefined;
+  icmp: ProtocolStats | undefined;
+  /** Everything else, including ARP */
+  other: ProtocolStats | undefined;
+  uptimeSecs: number;
+  connectedAgents: number;
+}
+
+export interface ProtocolStats {
+  bytes: number;
+  packets: number;
+}
+
 export interface PacketBatch {
   packets: Packet[];
+  /** pcap link-type name of the agent's capture (e.g. EN10MB), empty when unknown */
+  linkType: string;
+  /** Sent by the server, in batches without packets */
+  expiredPeers: PeerExpired[];
+  /** Numbered by the server for Subscribe without agent_id; 0 elsewhere */
+  seq: number;
+  /** Numbered by the agent from 1, continuing across reconnects; 0 from older agents */
+  batchSeq: number;
+}
+
+/** A peer that has been idle for longer than the server's --peer-timeout. */
+export interface PeerExpired {
+  ip: Uint8Array;
+  isIpv6: boolean;
 }
 
 export interface Packet {
@@ -80,6 +221,64 @@ export interface Packet {
   proto: Protocol;
   srcPort: number;
   dstPort: number;
+  /** TLS server name, empty when unknown */
+  sni: string;
+  /** First DNS question name, empty when unknown */
+  dnsQuery: string;
+  /** Ethernet source address, empty for non-Ethernet link types */
+  srcMac: Uint8Array;
+  /** Ethernet destination address, empty for non-Ethernet link types */
+  dstMac: Uint8Array;
+  /** Lowest IPv4 TTL / IPv6 hop limit seen in the flow, 0 when unknown */
+  ttl: number;
+  /** DiffServ code point of the most recent packet in the flow */
+  dscp: number;
+  /** Number of packets aggregated into this entry */
+  packets: number;
+  /** ICMP / ICMPv6 type, 0 for other protocols */
+  icmpType: number;
+  /** ICMP / ICMPv6 code, 0 for other protocols */
+  icmpCode: number;
+  /** src_ip/dst_ip are 16-byte IPv6 addresses; otherwise both are 4-byte IPv4 */
+  isIpv6: boolean;
+  /** Mean packet size (size / packets) with the agent's --sketch, 0 otherwise */
+  avgSize: number;
+  /** Site label of the reporting agent, stamped by the server; empty when unset */
+  site: string;
+  /** Re-broadcast from stored flows by POST /replay rather than live traffic */
+  replay: boolean;
+  /** Well-known service of the flow's server-side port with the agent's --label-services, empty otherwise */
+  service: string;
+  /** Neither endpoint is the agent: the flow passes through it (the agent's --transit) */
+  transit: boolean;
+  /** Capture time of the flow's first packet in this entry, Unix ms (send time for mock traffic) */
+  firstSeen: number;
+  /** Capture time of the flow's last packet in this entry, Unix ms (send time for mock traffic) */
+  lastSeen: number;
+  /** Last advertised TCP window (unscaled) with the agent's --tcp-detail */
+  tcpWindow?: number | undefined;
+  /** Last MSS option seen in the flow's SYNs with the agent's --tcp-detail */
+  tcpMss?: number | undefined;
+  /** Some packet of the entry matched the agent's --highlight-filter */
+  highlighted: boolean;
+  /** src is the client side, going by ports (lower port = server); set whatever src_is_agent/dst_is_agent say */
+  clientToServer: boolean;
+  /** Outer VLAN ID, only when the agent groups by it (--group-by vlan) */
+  vlan?: number | undefined;
+  /** Country of the remote endpoint with the server's --enrich-geoip, empty when unknown */
+  country: string;
+  /** City of the remote endpoint with --enrich-geoip; empty when unknown or for Country databases */
+  city: string;
+  /** Autonomous system of the remote endpoint with --enrich-geoip and an ASN database, 0 otherwise */
+  asn: number;
+  /** Transport or payload didn't fit the flow's port, with the agent's --flag-anomalies */
+  anomalous: boolean;
+  /** first_seen in Unix ns: microsecond resolution unless the agent runs with --nano-timestamps; 0 for mock traffic */
+  firstSeenNs: number;
+  /** last_seen in Unix ns, likewise */
+  lastSeenNs: number;
+  /** PacketFlag bits: how the entry's numbers were derived; 0 for plainly captured packets */
+  flags: number;
 }
 
 function createBaseEmpty(): Empty {
@@ -125,8 +324,624 @@ export const Empty: MessageFns<Empty> = {
   },
 };
 
+function createBaseSubscribeFilter(): SubscribeFilter {
+  return {
+    ip: undefined,
+    proto: undefined,
+    port: undefined,
+    agentId: undefined,
+    sinceSeq: undefined,
+    minIntervalMs: undefined,
+    aggregated: undefined,
+  };
+}
+
+export const SubscribeFilter: MessageFns<SubscribeFilter> = {
+  encode(message: SubscribeFilter, writer: BinaryWriter = new BinaryWriter()): BinaryWriter {
+    if (message.ip !== undefined) {
+      writer.uint32(10).string(message.ip);
+    }
+    if (message.proto !== undefined) {
+      writer.uint32(16).int32(message.proto);
+    }
+    if (message.port !== undefined) {
+      writer.uint32(24).int32(message.port);
+    }
+    if (message.agentId !== undefined) {
+      writer.uint32(34).string(message.agentId);
+    }
+    if (message.sinceSeq !== undefined) {
+      writer.uint32(40).uint64(message.sinceSeq);
+    }
+    if (message.minIntervalMs !== undefined) {
+      writer.uint32(48).uint32(message.minIntervalMs);
+    }
+    if (message.aggregated !== undefined) {
+      writer.uint32(56).bool(message.aggregated);
+    }
+    return writer;
+  },
+
+  decode(input: BinaryReader | Uint8Array, length?: number): SubscribeFilter {
+    const reader = input instanceof BinaryReader ? input : new BinaryReader(input);
+    const end = length === undefined ? reader.len : reader.pos + length;
+    const message = createBaseSubscribeFilter();
+    while (reader.pos < end) {
+      const tag = reader.uint32();
+      switch (tag >>> 3) {
+        case 1: {
+          if (tag !== 10) {
+            break;
+          }
+
+          message.ip = reader.string();
+          continue;
+        }
+        case 2: {
+          if (tag !== 16) {
+            break;
+          }
+
+          message.proto = reader.int32() as any;
+          continue;
+        }
+        case 3: {
+          if (tag !== 24) {
+            break;
+          }
+
+          message.port = reader.int32();
+          continue;
+        }
+        case 4: {
+          if (tag !== 34) {
+            break;
+          }
+
+          message.agentId = reader.string();
+          continue;
+        }
+        case 5: {
+          if (tag !== 40) {
+            break;
+          }
+
+          message.sinceSeq = longToNumber(reader.uint64());
+          continue;
+        }
+        case 6: {
+          if (tag !== 48) {
+            break;
+          }
+
+          message.minIntervalMs = reader.uint32();
+          continue;
+        }
+        case 7: {
+          if (tag !== 56) {
+            break;
+          }
+
+          message.aggregated = reader.bool();
+          continue;
+        }
+      }
+      if ((tag & 7) === 4 || tag === 0) {
+        break;
+      }
+      reader.skip(tag & 7);
+    }
+    return message;
+  },
+
+  fromJSON(object: any): SubscribeFilter {
+    return {
+      ip: isSet(object.ip) ? globalThis.String(object.ip) : undefined,
+      proto: isSet(object.proto) ? protocolFromJSON(object.proto) : undefined,
+      port: isSet(object.port) ? globalThis.Number(object.port) : undefined,
+      agentId: isSet(object.agentId) ? globalThis.String(object.agentId) : undefined,
+      sinceSeq: isSet(object.sinceSeq) ? globalThis.Number(object.sinceSeq) : undefined,
+      minIntervalMs: isSet(object.minIntervalMs) ? globalThis.Number(object.minIntervalMs) : undefined,
+      aggregated: isSet(object.aggregated) ? globalThis.Boolean(object.aggregated) : undefined,
+    };
+  },
+
+  toJSON(message: SubscribeFilter): unknown {
+    const obj: any = {};
+    if (message.ip !== undefined) {
+      obj.ip = message.ip;
+    }
+    if (message.proto !== undefined) {
+      obj.proto = protocolToJSON(message.proto);
+    }
+    if (message.port !== undefined) {
+      obj.port = Math.round(message.port);
+    }
+    if (message.agentId !== undefined) {
+      obj.agentId = message.agentId;
+    }
+    if (message.sinceSeq !== undefined) {
+      obj.sinceSeq = Math.round(message.sinceSeq);
+    }
+    if (message.minIntervalMs !== undefined) {
+      obj.minIntervalMs = Math.round(message.minIntervalMs);
+    }
+    if (message.aggregated !== undefined) {
+      obj.aggregated = message.aggregated;
+    }
+    return obj;
+  },
+
+  create<I extends Exact<DeepPartial<SubscribeFilter>, I>>(base?: I): SubscribeFilter {
+    return SubscribeFilter.fromPartial(base ?? ({} as any));
+  },
+  fromPartial<I extends Exact<DeepPartial<SubscribeFilter>, I>>(object: I): SubscribeFilter {
+    const message = createBaseSubscribeFilter();
+    message.ip = object.ip ?? undefined;
+    message.proto = object.proto ?? undefined;
+    message.port = object.port ?? undefined;
+    message.agentId = object.agentId ?? undefined;
+    message.sinceSeq = object.sinceSeq ?? undefined;
+    message.minIntervalMs = object.minIntervalMs ?? undefined;
+    message.aggregated = object.aggregated ?? undefined;
+    return message;
+  },
+};
+
+function createBaseTopTalkersRequest(): TopTalkersRequest {
+  return { limit: 0 };
+}
+
+export const TopTalkersRequest: MessageFns<TopTalkersRequest> = {
+  encode(message: TopTalkersRequest, writer: BinaryWriter = new BinaryWriter()): BinaryWriter {
+    if (message.limit !== 0) {
+      writer.uint32(8).uint32(message.limit);
+    }
+    return writer;
+  },
+
+  decode(input: BinaryReader | Uint8Array, length?: number): TopTalkersRequest {
+    const reader = input instanceof BinaryReader ? input : new BinaryReader(input);
+    const end = length === undefined ? reader.len : reader.pos + length;
+    const message = createBaseTopTalkersRequest();
+    while (reader.pos < end) {
+      const tag = reader.uint32();
+      switch (tag >>> 3) {
+        case 1: {
+          if (tag !== 8) {
+            break;
+          }
+
+          message.limit = reader.uint32();
+          continue;
+        }
+      }
+      if ((tag & 7) === 4 || tag === 0) {
+        break;
+      }
+      reader.skip(tag & 7);
+    }
+    return message;
+  },
+
+  fromJSON(object: any): TopTalkersRequest {
+    return {
+      limit: isSet(object.limit) ? globalThis.Number(object.limit) : 0,
+    };
+  },
+
+  toJSON(message: TopTalkersRequest): unknown {
+    const obj: any = {};
+    if (message.limit !== 0) {
+      obj.limit = Math.round(message.limit);
+    }
+    return obj;
+  },
+
+  create<I extends Exact<DeepPartial<TopTalkersRequest>, I>>(base?: I): TopTalkersRequest {
+    return TopTalkersRequest.fromPartial(base ?? ({} as any));
+  },
+  fromPartial<I extends Exact<DeepPartial<TopTalkersRequest>, I>>(object: I): TopTalkersRequest {
+    const message = createBaseTopTalkersRequest();
+    message.limit = object.limit ?? 0;
+    return message;
+  },
+};
+
+function createBaseTopTalkersSnapshot(): TopTalkersSnapshot {
+  return { peers: [], windowSecs: 0 };
+}
+
+export const TopTalkersSnapshot: MessageFns<TopTalkersSnapshot> = {
+  encode(message: TopTalkersSnapshot, writer: BinaryWriter = new BinaryWriter()): BinaryWriter {
+    for (const v of message.peers) {
+      PeerTraffic.encode(v!, writer.uint32(10).fork()).join();
+    }
+    if (message.windowSecs !== 0) {
+      writer.uint32(16).uint32(message.windowSecs);
+    }
+    return writer;
+  },
+
+  decode(input: BinaryReader | Uint8Array, length?: number): TopTalkersSnapshot {
+    const reader = input instanceof BinaryReader ? input : new BinaryReader(input);
+    const end = length === undefined ? reader.len : reader.pos + length;
+    const message = createBaseTopTalkersSnapshot();
+    while (reader.pos < end) {
+      const tag = reader.uint32();
+      switch (tag >>> 3) {
+        case 1: {
+          if (tag !== 10) {
+            break;
+          }
+
+          message.peers.push(PeerTraffic.decode(reader, reader.uint32()));
+          continue;
+        }
+        case 2: {
+          if (tag !== 16) {
+            break;
+          }
+
+          message.windowSecs = reader.uint32();
+          continue;
+        }
+      }
+      if ((tag & 7) === 4 || tag === 0) {
+        break;
+      }
+      reader.skip(tag & 7);
+    }
+    return message;
+  },
+
+  fromJSON(object: any): TopTalkersSnapshot {
+    return {
+      peers: globalThis.Array.isArray(object?.peers) ? object.peers.map((e: any) => PeerTraffic.fromJSON(e)) : [],
+      windowSecs: isSet(object.windowSecs) ? globalThis.Number(object.windowSecs) : 0,
+    };
+  },
+
+  toJSON(message: TopTalkersSnapshot): unknown {
+    const obj: any = {};
+    if (message.peers?.length) {
+      obj.peers = message.peers.map((e) => PeerTraffic.toJSON(e));
+    }
+    if (message.windowSecs !== 0) {
+      obj.windowSecs = Math.round(message.windowSecs);
+    }
+    return obj;
+  },
+
+  create<I extends Exact<DeepPartial<TopTalkersSnapshot>, I>>(base?: I): TopTalkersSnapshot {
+    return TopTalkersSnapshot.fromPartial(base ?? ({} as any));
+  },
+  fromPartial<I extends Exact<DeepPartial<TopTalkersSnapshot>, I>>(object: I): TopTalkersSnapshot {
+    const message = createBaseTopTalkersSnapshot();
+    message.peers = object.peers?.map((e) => PeerTraffic.fromPartial(e)) || [];
+    message.windowSecs = object.windowSecs ?? 0;
+    return message;
+  },
+};
+
+function createBasePeerTraffic(): PeerTraffic {
+  return { ip: new Uint8Array(0), bytes: 0, packets: 0 };
+}
+
+export const PeerTraffic: MessageFns<PeerTraffic> = {
+  encode(message: PeerTraffic, writer: BinaryWriter = new BinaryWriter()): BinaryWriter {
+    if (message.ip.length !== 0) {
+      writer.uint32(10).bytes(message.ip);
+    }
+    if (message.bytes !== 0) {
+      writer.uint32(16).int64(message.bytes);
+    }
+    if (message.packets !== 0) {
+      writer.uint32(24).int64(message.packets);
+    }
+    return writer;
+  },
+
+  decode(input: BinaryReader | Uint8Array, length?: number): PeerTraffic {
+    const reader = input instanceof BinaryReader ? input : new BinaryReader(input);
+    const end = length === undefined ? reader.len : reader.pos + length;
+    const message = createBasePeerTraffic();
+    while (reader.pos < end) {
+      const tag = reader.uint32();
+      switch (tag >>> 3) {
+        case 1: {
+          if (tag !== 10) {
+            break;
+          }
+
+          message.ip = reader.bytes();
+          continue;
+        }
+        case 2: {
+          if (tag !== 16) {
+            break;
+          }
+
+          message.bytes = longToNumber(reader.int64());
+          continue;
+        }
+        case 3: {
+          if (tag !== 24) {
+            break;
+          }
+
+          message.packets = longToNumber(reader.int64());
+          continue;
+        }
+      }
+      if ((tag & 7) === 4 || tag === 0) {
+        break;
+      }
+      reader.skip(tag & 7);
+    }
+    return message;
+  },
+
+  fromJSON(object: any): PeerTraffic {
+    return {
+      ip: isSet(object.ip) ? bytesFromBase64(object.ip) : new Uint8Array(0),
+      bytes: isSet(object.bytes) ? globalThis.Number(object.bytes) : 0,
+      packets: isSet(object.packets) ? globalThis.Number(object.packets) : 0,
+    };
+  },
+
+  toJSON(message: PeerTraffic): unknown {
+    const obj: any = {};
+    if (message.ip.length !== 0) {
+      obj.ip = base64FromBytes(message.ip);
+    }
+    if (message.bytes !== 0) {
+      obj.bytes = Math.round(message.bytes);
+    }
+    if (message.packets !== 0) {
+      obj.packets = Math.round(message.packets);
+    }
+    return obj;
+  },
+
+  create<I extends Exact<DeepPartial<PeerTraffic>, I>>(base?: I): PeerTraffic {
+    return PeerTraffic.fromPartial(base ?? ({} as any));
+  },
+  fromPartial<I extends Exact<DeepPartial<PeerTraffic>, I>>(object: I): PeerTraffic {
+    const message = createBasePeerTraffic();
+    message.ip = object.ip ?? new Uint8Array(0);
+    message.bytes = object.bytes ?? 0;
+    message.packets = object.packets ?? 0;
+    return message;
+  },
+};
+
+function createBaseStats(): Stats {
+  return { tcp: undefined, udp: undefined, icmp: undefined, other: undefined, uptimeSecs: 0, connectedAgents: 0 };
+}
+
+export const Stats: MessageFns<Stats> = {
+  encode(message: Stats, writer: BinaryWriter = new BinaryWriter()): BinaryWriter {
+    if (message.tcp !== undefined) {
+      ProtocolStats.encode(message.tcp, writer.uint32(10).fork()).join();
+    }
+    if (message.udp !== undefined) {
+      ProtocolStats.encode(message.udp, writer.uint32(18).fork()).join();
+    }
+    if (message.icmp !== undefined) {
+      ProtocolStats.encode(message.icmp, writer.uint32(26).fork()).join();
+    }
+    if (message.other !== undefined) {
+      ProtocolStats.encode(message.other, writer.uint32(34).fork()).join();
+    }
+    if (message.uptimeSecs !== 0) {
+      writer.uint32(40).uint64(message.uptimeSecs);
+    }
+    if (message.connectedAgents !== 0) {
+      writer.uint32(48).uint32(message.connectedAgents);
+    }
+    return writer;
+  },
+
+  decode(input: BinaryReader | Uint8Array, length?: number): Stats {
+    const reader = input instanceof BinaryReader ? input : new BinaryReader(input);
+    const end = length === undefined ? reader.len : reader.pos + length;
+    const message = createBaseStats();
+    while (reader.pos < end) {
+      const tag = reader.uint32();
+      switch (tag >>> 3) {
+        case 1: {
+          if (tag !== 10) {
+            break;
+          }
+
+          message.tcp = ProtocolStats.decode(reader, reader.uint32());
+          continue;
+        }
+        case 2: {
+          if (tag !== 18) {
+            break;
+          }
+
+          message.udp = ProtocolStats.decode(reader, reader.uint32());
+          continue;
+        }
+        case 3: {
+          if (tag !== 26) {
+            break;
+          }
+
+          message.icmp = ProtocolStats.decode(reader, reader.uint32());
+          continue;
+        }
+        case 4: {
+          if (tag !== 34) {
+            break;
+          }
+
+          message.other = ProtocolStats.decode(reader, reader.uint32());
+          continue;
+        }
+        case 5: {
+          if (tag !== 40) {
+            break;
+          }
+
+          message.uptimeSecs = longToNumber(reader.uint64());
+          continue;
+        }
+        case 6: {
+          if (tag !== 48) {
+            break;
+          }
+
+          message.connectedAgents = reader.uint32();
+          continue;
+        }
+      }
+      if ((tag & 7) === 4 || tag === 0) {
+        break;
+      }
+      reader.skip(tag & 7);
+    }
+    return message;
+  },
+
+  fromJSON(object: any): Stats {
+    return {
+      tcp: isSet(object.tcp) ? ProtocolStats.fromJSON(object.tcp) : undefined,
+      udp: isSet(object.udp) ? ProtocolStats.fromJSON(object.udp) : undefined,
+      icmp: isSet(object.icmp) ? ProtocolStats.fromJSON(object.icmp) : undefined,
+      other: isSet(object.other) ? ProtocolStats.fromJSON(object.other) : undefined,
+      uptimeSecs: isSet(object.uptimeSecs) ? globalThis.Number(object.uptimeSecs) : 0,
+      connectedAgents: isSet(object.connectedAgents) ? globalThis.Number(object.connectedAgents) : 0,
+    };
+  },
+
+  toJSON(message: Stats): unknown {
+    const obj: any = {};
+    if (message.tcp !== undefined) {
+      obj.tcp = ProtocolStats.toJSON(message.tcp);
+    }
+    if (message.udp !== undefined) {
+      obj.udp = ProtocolStats.toJSON(message.udp);
+    }
+    if (message.icmp !== undefined) {
+      obj.icmp = ProtocolStats.toJSON(message.icmp);
+    }
+    if (message.other !== undefined) {
+      obj.other = ProtocolStats.toJSON(message.other);
+    }
+    if (message.uptimeSecs !== 0) {
+      obj.uptimeSecs = Math.round(message.uptimeSecs);
+    }
+    if (message.connectedAgents !== 0) {
+      obj.connectedAgents = Math.round(message.connectedAgents);
+    }
+    return obj;
+  },
+
+  create<I extends Exact<DeepPartial<Stats>, I>>(base?: I): Stats {
+    return Stats.fromPartial(base ?? ({} as any));
+  },
+  fromPartial<I extends Exact<DeepPartial<Stats>, I>>(object: I): Stats {
+    const message = createBaseStats();
+    message.tcp = (object.tcp !== undefined && object.tcp !== null)
+      ? ProtocolStats.fromPartial(object.tcp)
+      : undefined;
+    message.udp = (object.udp !== undefined && object.udp !== null)
+      ? ProtocolStats.fromPartial(object.udp)
+      : undefined;
+    message.icmp = (object.icmp !== undefined && object.icmp !== null)
+      ? ProtocolStats.fromPartial(object.icmp)
+      : undefined;
+    message.other = (object.other !== undefined && object.other !== null)
+      ? ProtocolStats.fromPartial(object.other)
+      : undefined;
+    message.uptimeSecs = object.uptimeSecs ?? 0;
+    message.connectedAgents = object.connectedAgents ?? 0;
+    return message;
+  },
+};
+
+function createBaseProtocolStats(): ProtocolStats {
+  return { bytes: 0, packets: 0 };
+}
+
+export const ProtocolStats: MessageFns<ProtocolStats> = {
+  encode(message: ProtocolStats, writer: BinaryWriter = new BinaryWriter()): BinaryWriter {
+    if (message.bytes !== 0) {
+      writer.uint32(8).int64(message.bytes);
+    }
+    if (message.packets !== 0) {
+      writer.uint32(16).int64(message.packets);
+    }
+    return writer;
+  },
+
+  decode(input: BinaryReader | Uint8Array, length?: number): ProtocolStats {
+    const reader = input instanceof BinaryReader ? input : new BinaryReader(input);
+    const end = length === undefined ? reader.len : reader.pos + length;
+    const message = createBaseProtocolStats();
+    while (reader.pos < end) {
+      const tag = reader.uint32();
+      switch (tag >>> 3) {
+        case 1: {
+          if (tag !== 8) {
+            break;
+          }
+
+          message.bytes = longToNumber(reader.int64());
+          continue;
+        }
+        case 2: {
+          if (tag !== 16) {
+            break;
+          }
+
+          message.packets = longToNumber(reader.int64());
+          continue;
+        }
+      }
+      if ((tag & 7) === 4 || tag === 0) {
+        break;
+      }
+      reader.skip(tag & 7);
+    }
+    return message;
+  },
+
+  fromJSON(object: any): ProtocolStats {
+    return {
+      bytes: isSet(object.bytes) ? globalThis.Number(object.bytes) : 0,
+      packets: isSet(object.packets) ? globalThis.Number(object.packets) : 0,
+    };
+  },
+
+  toJSON(message: ProtocolStats): unknown {
+    const obj: any = {};
+    if (message.bytes !== 0) {
+      obj.bytes = Math.round(message.bytes);
+    }
+    if (message.packets !== 0) {
+      obj.packets = Math.round(message.packets);
+    }
+    return obj;
+  },
+
+  create<I extends Exact<DeepPartial<ProtocolStats>, I>>(base?: I): ProtocolStats {
+    return ProtocolStats.fromPartial(base ?? ({} as any));
+  },
+  fromPartial<I extends Exact<DeepPartial<ProtocolStats>, I>>(object: I): ProtocolStats {
+    const message = createBaseProtocolStats();
+    message.bytes = object.bytes ?? 0;
+    message.packets = object.packets ?? 0;
+    return message;
+  },
+};
+
 function createBasePacketBatch(): PacketBatch {
-  return { packets: [] };
+  return { packets: [], linkType: "", expiredPeers: [], seq: 0, batchSeq: 0 };
 }
 
 export const PacketBatch: MessageFns<PacketBatch> = {
@@ -134,6 +949,18 @@ export const PacketBatch: MessageFns<PacketBatch> = {
     for (const v of message.packets) {
       Packet.encode(v!, writer.uint32(10).fork()).join();
     }
+    if (message.linkType !== "") {
+      writer.uint32(18).string(message.linkType);
+    }
+    for (const v of message.expiredPeers) {
+      PeerExpired.encode(v!, writer.uint32(26).fork()).join();
+    }
+    if (message.seq !== 0) {
+      writer.uint32(32).uint64(message.seq);
+    }
+    if (message.batchSeq !== 0) {
+      writer.uint32(40).uint64(message.batchSeq);
+    }
     return writer;
   },
 
@@ -152,6 +979,38 @@ export const PacketBatch: MessageFns<PacketBatch> = {
           message.packets.push(Packet.decode(reader, reader.uint32()));
           continue;
         }
+        case 2: {
+          if (tag !== 18) {
+            break;
+          }
+
+          message.linkType = reader.string();
+          continue;
+        }
+        case 3: {
+          if (tag !== 26) {
+            break;
+          }
+
+          message.expiredPeers.push(PeerExpired.decode(reader, reader.uint32()));
+          continue;
+        }
+        case 4: {
+          if (tag !== 32) {
+            break;
+          }
+
+          message.seq = longToNumber(reader.uint64());
+          continue;
+        }
+        case 5: {
+          if (tag !== 40) {
+            break;
+          }
+
+          message.batchSeq = longToNumber(reader.uint64());
+          continue;
+        }
       }
       if ((tag & 7) === 4 || tag === 0) {
         break;
@@ -164,6 +1023,10 @@ export const PacketBatch: MessageFns<PacketBatch> = {
   fromJSON(object: any): PacketBatch {
     return {
       packets: globalThis.Array.isArray(object?.packets) ? object.packets.map((e: any) => Packet.fromJSON(e)) : [],
+      linkType: isSet(object.linkType) ? globalThis.String(object.linkType) : "",
+      expiredPeers: globalThis.Array.isArray(object?.expiredPeers) ? object.expiredPeers.map((e: any) => PeerExpired.fromJSON(e)) : [],
+      seq: isSet(object.seq) ? globalThis.Number(object.seq) : 0,
+      batchSeq: isSet(object.batchSeq) ? globalThis.Number(object.batchSeq) : 0,
     };
   },
 
@@ -172,15 +1035,107 @@ export const PacketBatch: MessageFns<PacketBatch> = {
     if (message.packets?.length) {
       obj.packets = message.packets.map((e) => Packet.toJSON(e));
     }
+    if (message.linkType !== "") {
+      obj.linkType = message.linkType;
+    }
+    if (message.expiredPeers?.length) {
+      obj.expiredPeers = message.expiredPeers.map((e) => PeerExpired.toJSON(e));
+    }
+    if (message.seq !== 0) {
+      obj.seq = Math.round(message.seq);
+    }
+    if (message.batchSeq !== 0) {
+      obj.batchSeq = Math.round(message.batchSeq);
+    }
+    return obj;
+  },
+
+  create<I extends Exact<DeepPartial<PacketBatch>, I>>(base?: I): PacketBatch {
+    return PacketBatch.fromPartial(base ?? ({} as any));
+  },
+  fromPartial<I extends Exact<DeepPartial<PacketBatch>, I>>(object: I): PacketBatch {
+    const message = createBasePacketBatch();
+    message.packets = object.packets?.map((e) => Packet.fromPartial(e)) || [];
+    message.linkType = object.linkType ?? "";
+    message.expiredPeers = object.expiredPeers?.map((e) => PeerExpired.fromPartial(e)) || [];
+    message.seq = object.seq ?? 0;
+    message.batchSeq = object.batchSeq ?? 0;
+    return message;
+  },
+};
+
+function createBasePeerExpired(): PeerExpired {
+  return { ip: new Uint8Array(0), isIpv6: false };
+}
+
+export const PeerExpired: MessageFns<PeerExpired> = {
+  encode(message: PeerExpired, writer: BinaryWriter = new BinaryWriter()): BinaryWriter {
+    if (message.ip.length !== 0) {
+      writer.uint32(10).bytes(message.ip);
+    }
+    if (message.isIpv6 !== false) {
+      writer.uint32(16).bool(message.isIpv6);
+    }
+    return writer;
+  },
+
+  decode(input: BinaryReader | Uint8Array, length?: number): PeerExpired {
+    const reader = input instanceof BinaryReader ? input : new BinaryReader(input);
+    const end = length === undefined ? reader.len : reader.pos + length;
+    const message = createBasePeerExpired();
+    while (reader.pos < end) {
+      const tag = reader.uint32();
+      switch (tag >>> 3) {
+        case 1: {
+          if (tag !== 10) {
+            break;
+          }
+
+          message.ip = reader.bytes();
+          continue;
+        }
+        case 2: {
+          if (tag !== 16) {
+            break;
+          }
+
+          message.isIpv6 = reader.bool();
+          continue;
+        }
+      }
+      if ((tag & 7) === 4 || tag === 0) {
+        break;
+      }
+      reader.skip(tag & 7);
+    }
+    return message;
+  },
+
+  fromJSON(object: any): PeerExpired {
+    return {
+      ip: isSet(object.ip) ? bytesFromBase64(object.ip) : new Uint8Array(0),
+      isIpv6: isSet(object.isIpv6) ? globalThis.Boolean(object.isIpv6) : false,
+    };
+  },
+
+  toJSON(message: PeerExpired): unknown {
+    const obj: any = {};
+    if (message.ip.length !== 0) {
+      obj.ip = base64FromBytes(message.ip);
+    }
+    if (message.isIpv6 !== false) {
+      obj.isIpv6 = message.isIpv6;
+    }
     return obj;
   },
 
-  create<I extends Exact<DeepPartial<PacketBatch>, I>>(base?: I): PacketBatch {
-    return PacketBatch.fromPartial(base ?? ({} as any));
+  create<I extends Exact<DeepPartial<PeerExpired>, I>>(base?: I): PeerExpired {
+    return PeerExpired.fromPartial(base ?? ({} as any));
   },
-  fromPartial<I extends Exact<DeepPartial<PacketBatch>, I>>(object: I): PacketBatch {
-    const message = createBasePacketBatch();
-    message.packets = object.packets?.map((e) => Packet.fromPartial(e)) || [];
+  fromPartial<I extends Exact<DeepPartial<PeerExpired>, I>>(object: I): PeerExpired {
+    const message = createBasePeerExpired();
+    message.ip = object.ip ?? new Uint8Array(0);
+    message.isIpv6 = object.isIpv6 ?? false;
     return message;
   },
 };
@@ -195,6 +1150,35 @@ function createBasePacket(): Packet {
     proto: 0,
     srcPort: 0,
     dstPort: 0,
+    sni: "",
+    dnsQuery: "",
+    srcMac: new Uint8Array(0),
+    dstMac: new Uint8Array(0),
+    ttl: 0,
+    dscp: 0,
+    packets: 0,
+    icmpType: 0,
+    icmpCode: 0,
+    isIpv6: false,
+    avgSize: 0,
+    site: "",
+    replay: false,
+    service: "",
+    transit: false,
+    firstSeen: 0,
+    lastSeen: 0,
+    tcpWindow: undefined,
+    tcpMss: undefined,
+    highlighted: false,
+    clientToServer: false,
+    vlan: undefined,
+    country: "",
+    city: "",
+    asn: 0,
+    anomalous: false,
+    firstSeenNs: 0,
+    lastSeenNs: 0,
+    flags: 0,
   };
 }
 
@@ -224,6 +1208,93 @@ export const Packet: MessageFns<Packet> = {
     if (message.dstPort !== 0) {
       writer.uint32(64).int32(message.dstPort);
     }
+    if (message.sni !== "") {
+      writer.uint32(74).string(message.sni);
+    }
+    if (message.dnsQuery !== "") {
+      writer.uint32(82).string(message.dnsQuery);
+    }
+    if (message.srcMac.length !== 0) {
+      writer.uint32(90).bytes(message.srcMac);
+    }
+    if (message.dstMac.length !== 0) {
+      writer.uint32(98).bytes(message.dstMac);
+    }
+    if (message.ttl !== 0) {
+      writer.uint32(104).int32(message.ttl);
+    }
+    if (message.dscp !== 0) {
+      writer.uint32(112).int32(message.dscp);
+    }
+    if (message.packets !== 0) {
+      writer.uint32(120).int64(message.packets);
+    }
+    if (message.icmpType !== 0) {
+      writer.uint32(128).int32(message.icmpType);
+    }
+    if (message.icmpCode !== 0) {
+      writer.uint32(136).int32(message.icmpCode);
+    }
+    if (message.isIpv6 !== false) {
+      writer.uint32(144).bool(message.isIpv6);
+    }
+    if (message.avgSize !== 0) {
+      writer.uint32(152).int32(message.avgSize);
+    }
+    if (message.site !== "") {
+      writer.uint32(162).string(message.site);
+    }
+    if (message.replay !== false) {
+      writer.uint32(168).bool(message.replay);
+    }
+    if (message.service !== "") {
+      writer.uint32(178).string(message.service);
+    }
+    if (message.transit !== false) {
+      writer.uint32(184).bool(message.transit);
+    }
+    if (message.firstSeen !== 0) {
+      writer.uint32(192).uint64(message.firstSeen);
+    }
+    if (message.lastSeen !== 0) {
+      writer.uint32(200).uint64(message.lastSeen);
+    }
+    if (message.tcpWindow !== undefined) {
+      writer.uint32(208).int32(message.tcpWindow);
+    }
+    if (message.tcpMss !== undefined) {
+      writer.uint32(216).int32(message.tcpMss);
+    }
+    if (message.highlighted !== false) {
+      writer.uint32(224).bool(message.highlighted);
+    }
+    if (message.clientToServer !== false) {
+      writer.uint32(232).bool(message.clientToServer);
+    }
+    if (message.vlan !== undefined) {
+      writer.uint32(240).int32(message.vlan);
+    }
+    if (message.country !== "") {
+      writer.uint32(250).string(message.country);
+    }
+    if (message.city !== "") {
+      writer.uint32(258).string(message.city);
+    }
+    if (message.asn !== 0) {
+      writer.uint32(264).int32(message.asn);
+    }
+    if (message.anomalous !== false) {
+      writer.uint32(272).bool(message.anomalous);
+    }
+    if (message.firstSeenNs !== 0) {
+      writer.uint32(280).uint64(message.firstSeenNs);
+    }
+    if (message.lastSeenNs !== 0) {
+      writer.uint32(288).uint64(message.lastSeenNs);
+    }
+    if (message.flags !== 0) {
+      writer.uint32(296).uint32(message.flags);
+    }
     return writer;
   },
 
@@ -298,6 +1369,238 @@ export const Packet: MessageFns<Packet> = {
           message.dstPort = reader.int32();
           continue;
         }
+        case 9: {
+          if (tag !== 74) {
+            break;
+          }
+
+          message.sni = reader.string();
+          continue;
+        }
+        case 10: {
+          if (tag !== 82) {
+            break;
+          }
+
+          message.dnsQuery = reader.string();
+          continue;
+        }
+        case 11: {
+          if (tag !== 90) {
+            break;
+          }
+
+          message.srcMac = reader.bytes();
+          continue;
+        }
+        case 12: {
+          if (tag !== 98) {
+            break;
+          }
+
+          message.dstMac = reader.bytes();
+          continue;
+        }
+        case 13: {
+          if (tag !== 104) {
+            break;
+          }
+
+          message.ttl = reader.int32();
+          continue;
+        }
+        case 14: {
+          if (tag !== 112) {
+            break;
+          }
+
+          message.dscp = reader.int32();
+          continue;
+        }
+        case 15: {
+          if (tag !== 120) {
+            break;
+          }
+
+          message.packets = longToNumber(reader.int64());
+          continue;
+        }
+        case 16: {
+          if (tag !== 128) {
+            break;
+          }
+
+          message.icmpType = reader.int32();
+          continue;
+        }
+        case 17: {
+          if (tag !== 136) {
+            break;
+          }
+
+          message.icmpCode = reader.int32();
+          continue;
+        }
+        case 18: {
+          if (tag !== 144) {
+            break;
+          }
+
+          message.isIpv6 = reader.bool();
+          continue;
+        }
+        case 19: {
+          if (tag !== 152) {
+            break;
+          }
+
+          message.avgSize = reader.int32();
+          continue;
+        }
+        case 20: {
+          if (tag !== 162) {
+            break;
+          }
+
+          message.site = reader.string();
+          continue;
+        }
+        case 21: {
+          if (tag !== 168) {
+            break;
+          }
+
+          message.replay = reader.bool();
+          continue;
+        }
+        case 22: {
+          if (tag !== 178) {
+            break;
+          }
+
+          message.service = reader.string();
+          continue;
+        }
+        case 23: {
+          if (tag !== 184) {
+            break;
+          }
+
+          message.transit = reader.bool();
+          continue;
+        }
+        case 24: {
+          if (tag !== 192) {
+            break;
+          }
+
+          message.firstSeen = longToNumber(reader.uint64());
+          continue;
+        }
+        case 25: {
+          if (tag !== 200) {
+            break;
+          }
+
+          message.lastSeen = longToNumber(reader.uint64());
+          continue;
+        }
+        case 26: {
+          if (tag !== 208) {
+            break;
+          }
+
+          message.tcpWindow = reader.int32();
+          continue;
+        }
+        case 27: {
+          if (tag !== 216) {
+            break;
+          }
+
+          message.tcpMss = reader.int32();
+          continue;
+        }
+        case 28: {
+          if (tag !== 224) {
+            break;
+          }
+
+          message.highlighted = reader.bool();
+          continue;
+        }
+        case 29: {
+          if (tag !== 232) {
+            break;
+          }
+
+          message.clientToServer = reader.bool();
+          continue;
+        }
+        case 30: {
+          if (tag !== 240) {
+            break;
+          }
+
+          message.vlan = reader.int32();
+          continue;
+        }
+        case 31: {
+          if (tag !== 250) {
+            break;
+          }
+
+          message.country = reader.string();
+          continue;
+        }
+        case 32: {
+          if (tag !== 258) {
+            break;
+          }
+
+          message.city = reader.string();
+          continue;
+        }
+        case 33: {
+          if (tag !== 264) {
+            break;
+          }
+
+          message.asn = reader.int32();
+          continue;
+        }
+        case 34: {
+          if (tag !== 272) {
+            break;
+          }
+
+          message.anomalous = reader.bool();
+          continue;
+        }
+        case 35: {
+          if (tag !== 280) {
+            break;
+          }
+
+          message.firstSeenNs = longToNumber(reader.uint64());
+          continue;
+        }
+        case 36: {
+          if (tag !== 288) {
+            break;
+          }
+
+          message.lastSeenNs = longToNumber(reader.uint64());
+          continue;
+        }
+        case 37: {
+          if (tag !== 296) {
+            break;
+          }
+
+          message.flags = reader.uint32();
+          continue;
+        }
       }
       if ((tag & 7) === 4 || tag === 0) {
         break;
@@ -317,6 +1620,35 @@ export const Packet: MessageFns<Packet> = {
       proto: isSet(object.proto) ? protocolFromJSON(object.proto) : 0,
       srcPort: isSet(object.srcPort) ? globalThis.Number(object.srcPort) : 0,
       dstPort: isSet(object.dstPort) ? globalThis.Number(object.dstPort) : 0,
+      sni: isSet(object.sni) ? globalThis.String(object.sni) : "",
+      dnsQuery: isSet(object.dnsQuery) ? globalThis.String(object.dnsQuery) : "",
+      srcMac: isSet(object.srcMac) ? bytesFromBase64(object.srcMac) : new Uint8Array(0),
+      dstMac: isSet(object.dstMac) ? bytesFromBase64(object.dstMac) : new Uint8Array(0),
+      ttl: isSet(object.ttl) ? globalThis.Number(object.ttl) : 0,
+      dscp: isSet(object.dscp) ? globalThis.Number(object.dscp) : 0,
+      packets: isSet(object.packets) ? globalThis.Number(object.packets) : 0,
+      icmpType: isSet(object.icmpType) ? globalThis.Number(object.icmpType) : 0,
+      icmpCode: isSet(object.icmpCode) ? globalThis.Number(object.icmpCode) : 0,
+      isIpv6: isSet(object.isIpv6) ? globalThis.Boolean(object.isIpv6) : false,
+      avgSize: isSet(object.avgSize) ? globalThis.Number(object.avgSize) : 0,
+      site: isSet(object.site) ? globalThis.String(object.site) : "",
+      replay: isSet(object.replay) ? globalThis.Boolean(object.replay) : false,
+      service: isSet(object.service) ? globalThis.String(object.service) : "",
+      transit: isSet(object.transit) ? globalThis.Boolean(object.transit) : false,
+      firstSeen: isSet(object.firstSeen) ? globalThis.Number(object.firstSeen) : 0,
+      lastSeen: isSet(object.lastSeen) ? globalThis.Number(object.lastSeen) : 0,
+      tcpWindow: isSet(object.tcpWindow) ? globalThis.Number(object.tcpWindow) : undefined,
+      tcpMss: isSet(object.tcpMss) ? globalThis.Number(object.tcpMss) : undefined,
+      highlighted: isSet(object.highlighted) ? globalThis.Boolean(object.highlighted) : false,
+      clientToServer: isSet(object.clientToServer) ? globalThis.Boolean(object.clientToServer) : false,
+      vlan: isSet(object.vlan) ? globalThis.Number(object.vlan) : undefined,
+      country: isSet(object.country) ? globalThis.String(object.country) : "",
+      city: isSet(object.city) ? globalThis.String(object.city) : "",
+      asn: isSet(object.asn) ? globalThis.Number(object.asn) : 0,
+      anomalous: isSet(object.anomalous) ? globalThis.Boolean(object.anomalous) : false,
+      firstSeenNs: isSet(object.firstSeenNs) ? globalThis.Number(object.firstSeenNs) : 0,
+      lastSeenNs: isSet(object.lastSeenNs) ? globalThis.Number(object.lastSeenNs) : 0,
+      flags: isSet(object.flags) ? globalThis.Number(object.flags) : 0,
     };
   },
 
@@ -346,6 +1678,93 @@ export const Packet: MessageFns<Packet> = {
     if (message.dstPort !== 0) {
       obj.dstPort = Math.round(message.dstPort);
     }
+    if (message.sni !== "") {
+      obj.sni = message.sni;
+    }
+    if (message.dnsQuery !== "") {
+      obj.dnsQuery = message.dnsQuery;
+    }
+    if (message.srcMac.length !== 0) {
+      obj.srcMac = base64FromBytes(message.srcMac);
+    }
+    if (message.dstMac.length !== 0) {
+      obj.dstMac = base64FromBytes(message.dstMac);
+    }
+    if (message.ttl !== 0) {
+      obj.ttl = Math.round(message.ttl);
+    }
+    if (message.dscp !== 0) {
+      obj.dscp = Math.round(message.dscp);
+    }
+    if (message.packets !== 0) {
+      obj.packets = Math.round(message.packets);
+    }
+    if (message.icmpType !== 0) {
+      obj.icmpType = Math.round(message.icmpType);
+    }
+    if (message.icmpCode !== 0) {
+      obj.icmpCode = Math.round(message.icmpCode);
+    }
+    if (message.isIpv6 !== false) {
+      obj.isIpv6 = message.isIpv6;
+    }
+    if (message.avgSize !== 0) {
+      obj.avgSize = Math.round(message.avgSize);
+    }
+    if (message.site !== "") {
+      obj.site = message.site;
+    }
+    if (message.replay !== false) {
+      obj.replay = message.replay;
+    }
+    if (message.service !== "") {
+      obj.service = message.service;
+    }
+    if (message.transit !== false) {
+      obj.transit = message.transit;
+    }
+    if (message.firstSeen !== 0) {
+      obj.firstSeen = Math.round(message.firstSeen);
+    }
+    if (message.lastSeen !== 0) {
+      obj.lastSeen = Math.round(message.lastSeen);
+    }
+    if (message.tcpWindow !== undefined) {
+      obj.tcpWindow = Math.round(message.tcpWindow);
+    }
+    if (message.tcpMss !== undefined) {
+      obj.tcpMss = Math.round(message.tcpMss);
+    }
+    if (message.highlighted !== false) {
+      obj.highlighted = message.highlighted;
+    }
+    if (message.clientToServer !== false) {
+      obj.clientToServer = message.clientToServer;
+    }
+    if (message.vlan !== undefined) {
+      obj.vlan = Math.round(message.vlan);
+    }
+    if (message.country !== "") {
+      obj.country = message.country;
+    }
+    if (message.city !== "") {
+      obj.city = message.city;
+    }
+    if (message.asn !== 0) {
+      obj.asn = Math.round(message.asn);
+    }
+    if (message.anomalous !== false) {
+      obj.anomalous = message.anomalous;
+    }
+    if (message.firstSeenNs !== 0) {
+      obj.firstSeenNs = Math.round(message.firstSeenNs);
+    }
+    if (message.lastSeenNs !== 0) {
+      obj.lastSeenNs = Math.round(message.lastSeenNs);
+    }
+    if (message.flags !== 0) {
+      obj.flags = Math.round(message.flags);
+    }
     return obj;
   },
 
@@ -362,13 +1781,44 @@ export const Packet: MessageFns<Packet> = {
     message.proto = object.proto ?? 0;
     message.srcPort = object.srcPort ?? 0;
     message.dstPort = object.dstPort ?? 0;
+    message.sni = object.sni ?? "";
+    message.dnsQuery = object.dnsQuery ?? "";
+    message.srcMac = object.srcMac ?? new Uint8Array(0);
+    message.dstMac = object.dstMac ?? new Uint8Array(0);
+    message.ttl = object.ttl ?? 0;
+    message.dscp = object.dscp ?? 0;
+    message.packets = object.packets ?? 0;
+    message.icmpType = object.icmpType ?? 0;
+    message.icmpCode = object.icmpCode ?? 0;
+    message.isIpv6 = object.isIpv6 ?? false;
+    message.avgSize = object.avgSize ?? 0;
+    message.site = object.site ?? "";
+    message.replay = object.replay ?? false;
+    message.service = object.service ?? "";
+    message.transit = object.transit ?? false;
+    message.firstSeen = object.firstSeen ?? 0;
+    message.lastSeen = object.lastSeen ?? 0;
+    message.tcpWindow = object.tcpWindow ?? undefined;
+    message.tcpMss = object.tcpMss ?? undefined;
+    message.highlighted = object.highlighted ?? false;
+    message.clientToServer = object.clientToServer ?? false;
+    message.vlan = object.vlan ?? undefined;
+    message.country = object.country ?? "";
+    message.city = object.city ?? "";
+    message.asn = object.asn ?? 0;
+    message.anomalous = object.anomalous ?? false;
+    message.firstSeenNs = object.firstSeenNs ?? 0;
+    message.lastSeenNs = object.lastSeenNs ?? 0;
+    message.flags = object.flags ?? 0;
     return message;
   },
 };
 
 export interface AgentService {
   StreamPackets(request: Observable<DeepPartial<PacketBatch>>, metadata?: grpc.Metadata): Promise<Empty>;
-  Subscribe(request: DeepPartial<Empty>, metadata?: grpc.Metadata): Observable<PacketBatch>;
+  Subscribe(request: DeepPartial<SubscribeFilter>, metadata?: grpc.Metadata): Observable<PacketBatch>;
+  TopTalkers(request: DeepPartial<TopTalkersRequest>, metadata?: grpc.Metadata): Observable<TopTalkersSnapshot>;
+  GetStats(request: DeepPartial<Empty>, metadata?: grpc.Metadata): Promise<Stats>;
 }
 
 export class AgentServiceClientImpl implements AgentService {
@@ -378,14 +1828,24 @@ export class AgentServiceClientImpl implements AgentService {
     this.rpc = rpc;
     this.StreamPackets = this.StreamPackets.bind(this);
     this.Subscribe = this.Subscribe.bind(this);
+    this.TopTalkers = this.TopTalkers.bind(this);
+    this.GetStats = this.GetStats.bind(this);
   }
 
   StreamPackets(request: Observable<DeepPartial<PacketBatch>>, metadata?: grpc.Metadata): Promise<Empty> {
     throw new Error("ts-proto does not yet support client streaming!");
   }
 
-  Subscribe(request: DeepPartial<Empty>, metadata?: grpc.Metadata): Observable<PacketBatch> {
-    return this.rpc.invoke(AgentServiceSubscribeDesc, Empty.fromPartial(request), metadata);
+  Subscribe(request: DeepPartial<SubscribeFilter>, metadata?: grpc.Metadata): Observable<PacketBatch> {
+    return this.rpc.invoke(AgentServiceSubscribeDesc, SubscribeFilter.fromPartial(request), metadata);
+  }
+
+  TopTalkers(request: DeepPartial<TopTalkersRequest>, metadata?: grpc.Metadata): Observable<TopTalkersSnapshot> {
+    return this.rpc.invoke(AgentServiceTopTalkersDesc, TopTalkersRequest.fromPartial(request), metadata);
+  }
+
+  GetStats(request: DeepPartial<Empty>, metadata?: grpc.Metadata): Promise<Stats> {
+    return this.rpc.unary(AgentServiceGetStatsDesc, Empty.fromPartial(request), metadata);
   }
 }
 
@@ -398,7 +1858,7 @@ export const AgentServiceSubscribeDesc: UnaryMethodDefinitionish = {
   responseStream: true,
   requestType: {
     serializeBinary() {
-      return Empty.encode(this).finish();
+      return SubscribeFilter.encode(this).finish();
     },
   } as any,
   responseType: {
@@ -414,6 +1874,52 @@ export const AgentServiceSubscribeDesc: UnaryMethodDefinitionish = {
   } as any,
 };
 
+export const AgentServiceTopTalkersDesc: UnaryMethodDefinitionish = {
+  methodName: "TopTalkers",
+  service: AgentServiceDesc,
+  requestStream: false,
+  responseStream: true,
+  requestType: {
+    serializeBinary() {
+      return TopTalkersRequest.encode(this).finish();
+    },
+  } as any,
+  responseType: {
+    deserializeBinary(data: Uint8Array) {
+      const value = TopTalkersSnapshot.decode(data);
+      return {
+        ...value,
+        toObject() {
+          return value;
+        },
+      };
+    },
+  } as any,
+};
+
+export const AgentServiceGetStatsDesc: UnaryMethodDefinitionish = {
+  methodName: "GetStats",
+  service: AgentServiceDesc,
+  requestStream: false,
+  responseStream: false,
+  requestType: {
+    serializeBinary() {
+      return Empty.encode(this).finish();
+    },
+  } as any,
+  responseType: {
+    deserializeBinary(data: Uint8Array) {
+      const value = Stats.decode(data);
+      return {
+        ...value,
+        toObject() {
+          return value;
+        },
+      };
+    },
+  } as any,
+};
+
 interface UnaryMethodDefinitionishR extends grpc.UnaryMethodDefinition<any, any> {
   requestStream: any;
   responseStream: any;
@@ -556,6 +2062,17 @@ type KeysOfUnion<T> = T extends T ? keyof T : never;
 export type Exact<P, I extends P> = P extends Builtin ? P
   : P & { [K in keyof P]: Exact<P[K], I[K]> } & { [K in Exclude<keyof I, KeysOfUnion<P>>]: never };
 
+function longToNumber(int64: { toString(): string }): number {
+  const num = globalThis.Number(int64.toString());
+  if (num > globalThis.Number.MAX_SAFE_INTEGER) {
+    throw new globalThis.Error("Value is larger than Number.MAX_SAFE_INTEGER");
+  }
+  if (num < globalThis.Number.MIN_SAFE_INTEGER) {
+    throw new globalThis.Error("Value is smaller than Number.MIN_SAFE_INTEGER");
+  }
+  return num;
+}
+
 function isSet(value: any): boolean {
   return value !== null && value !== undefined;
 }